@@ -0,0 +1,93 @@
+use std::{env, process};
+
+use criterion::{criterion_group, criterion_main, BenchmarkId, Criterion};
+use serpent_automation_executor::run::{
+    CallStack, HistoryLimits, NestedBlock, StackFrame, ThreadRunState,
+};
+
+fn child_states(c: &mut Criterion) {
+    let mut group = c.benchmark_group("child_states");
+
+    for node_count in [100, 1000, 10000] {
+        let thread_run_state = run(ThreadRunState::default(), node_count, CHILD_COUNT);
+        let call_stack = CallStack::new().push_cloned(node_frame(node_count / 2));
+
+        group.bench_with_input(
+            BenchmarkId::new("index", node_count),
+            &call_stack,
+            |b, call_stack| b.iter(|| thread_run_state.child_states(call_stack)),
+        );
+        group.bench_with_input(
+            BenchmarkId::new("scan", node_count),
+            &call_stack,
+            |b, call_stack| b.iter(|| thread_run_state.scan_child_states(call_stack)),
+        );
+    }
+
+    group.finish();
+}
+
+fn paged_out_child_states(c: &mut Criterion) {
+    let mut group = c.benchmark_group("paged_out_child_states");
+
+    for node_count in [100, 1000, 10000] {
+        let call_stack = CallStack::new().push_cloned(node_frame(node_count / 2));
+        let in_memory = run(ThreadRunState::default(), node_count, CHILD_COUNT);
+        let spilled = run(
+            ThreadRunState::with_history_limits(HistoryLimits::new(
+                0,
+                env::temp_dir().join(format!(
+                    "serpent-automation-bench-{}-{node_count}.history",
+                    process::id()
+                )),
+            )),
+            node_count,
+            CHILD_COUNT,
+        );
+
+        group.bench_with_input(
+            BenchmarkId::new("in_memory", node_count),
+            &call_stack,
+            |b, call_stack| b.iter(|| in_memory.child_states(call_stack)),
+        );
+        group.bench_with_input(
+            BenchmarkId::new("spilled", node_count),
+            &call_stack,
+            |b, call_stack| b.iter(|| spilled.child_states(call_stack)),
+        );
+    }
+
+    group.finish();
+}
+
+/// Run `node_count` nodes, each with `child_count` children, and `child_count`
+/// grandchildren for each child.
+fn run(thread_run_state: ThreadRunState, node_count: usize, child_count: usize) -> ThreadRunState {
+    for index in 0..node_count {
+        thread_run_state.push(node_frame(index));
+
+        for child_index in 0..child_count {
+            thread_run_state.push(node_frame(child_index));
+
+            for grandchild_index in 0..child_count {
+                thread_run_state.push(node_frame(grandchild_index));
+                thread_run_state.pop_success();
+            }
+
+            thread_run_state.pop_success();
+        }
+
+        thread_run_state.pop_success();
+    }
+
+    thread_run_state
+}
+
+fn node_frame(index: usize) -> StackFrame {
+    StackFrame::NestedBlock(index, NestedBlock::Predicate)
+}
+
+const CHILD_COUNT: usize = 10;
+
+criterion_group!(benches, child_states, paged_out_child_states);
+criterion_main!(benches);
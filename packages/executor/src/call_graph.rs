@@ -0,0 +1,403 @@
+//! The static call graph of a linked workflow.
+//!
+//! There's an edge for each call site, so a function that calls another
+//! function twice has 2 edges to it. Host functions are external, and have no
+//! outgoing edges.
+use std::fmt::Write;
+
+use serde::Serialize;
+
+use crate::{
+    library::{FunctionId, Library},
+    syntax_tree::{
+        visit::{walk_call, Visit},
+        Expression, LinkedBody, SrcSpan,
+    },
+};
+
+#[derive(Serialize, Debug)]
+pub struct CallGraph {
+    functions: Vec<Node>,
+    calls: Vec<Call>,
+    cycles: Vec<Vec<FunctionId>>,
+}
+
+impl CallGraph {
+    /// Constructor
+    pub fn new(library: &Library) -> Self {
+        let mut functions = Vec::new();
+        let mut calls = Vec::new();
+
+        for (id, function) in library.functions() {
+            let external = match function.body() {
+                LinkedBody::Local(body) => {
+                    let mut call_sites = CallSites {
+                        caller: id,
+                        calls: &mut calls,
+                    };
+                    call_sites.visit_body(body);
+                    false
+                }
+                LinkedBody::Python => true,
+            };
+
+            functions.push(Node {
+                id,
+                name: function.name().to_owned(),
+                span: function.span(),
+                external,
+                recursive: false,
+            });
+        }
+
+        let cycles = Cycles::find(functions.len(), &calls);
+
+        for id in cycles.iter().flatten() {
+            functions[id.index()].recursive = true;
+        }
+
+        Self {
+            functions,
+            calls,
+            cycles,
+        }
+    }
+
+    /// All the functions, in [`FunctionId`] order.
+    pub fn functions(&self) -> &[Node] {
+        &self.functions
+    }
+
+    /// All the call sites, in the order they appear in the source.
+    pub fn calls(&self) -> &[Call] {
+        &self.calls
+    }
+
+    /// Groups of functions that call each other recursively.
+    ///
+    /// Each group is sorted by [`FunctionId`]. A function that calls itself is
+    /// a group on it's own.
+    pub fn cycles(&self) -> &[Vec<FunctionId>] {
+        &self.cycles
+    }
+
+    /// Render the graph in Graphviz DOT format.
+    ///
+    /// External functions are drawn with a dashed outline, and calls that are
+    /// part of a cycle are drawn in red. Call edges are labelled with the line
+    /// of the call site.
+    pub fn to_dot(&self) -> String {
+        let mut dot = String::from("digraph calls {\n");
+
+        for function in &self.functions {
+            write!(
+                dot,
+                "    f{} [label={}",
+                function.id.index(),
+                quote(&function.name)
+            )
+            .unwrap();
+
+            if function.external {
+                dot.push_str(", style=dashed");
+            }
+
+            dot.push_str("];\n");
+        }
+
+        for call in &self.calls {
+            write!(
+                dot,
+                "    f{} -> f{} [label=\"{}\"",
+                call.caller.index(),
+                call.callee.index(),
+                call.span.line()
+            )
+            .unwrap();
+
+            if self.is_in_cycle(call) {
+                dot.push_str(", color=red");
+            }
+
+            dot.push_str("];\n");
+        }
+
+        dot.push_str("}\n");
+        dot
+    }
+
+    /// Render the graph as JSON.
+    pub fn to_json(&self) -> String {
+        serde_json::to_string_pretty(self).unwrap()
+    }
+
+    fn is_in_cycle(&self, call: &Call) -> bool {
+        self.cycles
+            .iter()
+            .any(|cycle| cycle.contains(&call.caller) && cycle.contains(&call.callee))
+    }
+}
+
+#[derive(Serialize, Debug)]
+pub struct Node {
+    id: FunctionId,
+    name: String,
+    span: Option<SrcSpan>,
+    external: bool,
+    recursive: bool,
+}
+
+impl Node {
+    pub fn id(&self) -> FunctionId {
+        self.id
+    }
+
+    pub fn name(&self) -> &str {
+        &self.name
+    }
+
+    /// The span of the function name, or `None` for external functions.
+    pub fn span(&self) -> Option<SrcSpan> {
+        self.span
+    }
+
+    /// Is this a host function, rather than one defined in the workflow?
+    pub fn is_external(&self) -> bool {
+        self.external
+    }
+
+    /// Is this function part of a cycle?
+    pub fn is_recursive(&self) -> bool {
+        self.recursive
+    }
+}
+
+/// A call site.
+#[derive(Serialize, Copy, Clone, Debug, Eq, PartialEq)]
+pub struct Call {
+    caller: FunctionId,
+    callee: FunctionId,
+    span: SrcSpan,
+}
+
+impl Call {
+    pub fn caller(&self) -> FunctionId {
+        self.caller
+    }
+
+    pub fn callee(&self) -> FunctionId {
+        self.callee
+    }
+
+    pub fn span(&self) -> SrcSpan {
+        self.span
+    }
+}
+
+struct CallSites<'a> {
+    caller: FunctionId,
+    calls: &'a mut Vec<Call>,
+}
+
+impl<'ast> Visit<'ast, FunctionId> for CallSites<'_> {
+    fn visit_call(
+        &mut self,
+        span: SrcSpan,
+        name: &'ast FunctionId,
+        args: &'ast [Expression<FunctionId>],
+    ) {
+        self.calls.push(Call {
+            caller: self.caller,
+            callee: *name,
+            span,
+        });
+        walk_call(self, span, name, args);
+    }
+}
+
+/// Tarjan's strongly connected components algorithm.
+struct Cycles {
+    callees: Vec<Vec<usize>>,
+    index: Vec<Option<usize>>,
+    low_link: Vec<usize>,
+    stack: Vec<usize>,
+    on_stack: Vec<bool>,
+    next_index: usize,
+    cycles: Vec<Vec<FunctionId>>,
+}
+
+impl Cycles {
+    fn find(function_count: usize, calls: &[Call]) -> Vec<Vec<FunctionId>> {
+        let mut callees = vec![Vec::new(); function_count];
+
+        for call in calls {
+            callees[call.caller.index()].push(call.callee.index());
+        }
+
+        let mut cycles = Self {
+            callees,
+            index: vec![None; function_count],
+            low_link: vec![0; function_count],
+            stack: Vec::new(),
+            on_stack: vec![false; function_count],
+            next_index: 0,
+            cycles: Vec::new(),
+        };
+
+        for function in 0..function_count {
+            if cycles.index[function].is_none() {
+                cycles.connect(function);
+            }
+        }
+
+        cycles.cycles.sort();
+        cycles.cycles
+    }
+
+    fn connect(&mut self, function: usize) {
+        self.index[function] = Some(self.next_index);
+        self.low_link[function] = self.next_index;
+        self.next_index += 1;
+        self.stack.push(function);
+        self.on_stack[function] = true;
+
+        for callee_index in 0..self.callees[function].len() {
+            let callee = self.callees[function][callee_index];
+
+            match self.index[callee] {
+                None => {
+                    self.connect(callee);
+                    self.low_link[function] = self.low_link[function].min(self.low_link[callee]);
+                }
+                Some(index) if self.on_stack[callee] => {
+                    self.low_link[function] = self.low_link[function].min(index);
+                }
+                Some(_) => (),
+            }
+        }
+
+        if Some(self.low_link[function]) == self.index[function] {
+            let mut component = Vec::new();
+
+            loop {
+                let member = self.stack.pop().unwrap();
+                self.on_stack[member] = false;
+                component.push(member);
+
+                if member == function {
+                    break;
+                }
+            }
+
+            if component.len() > 1 || self.callees[function].contains(&function) {
+                component.sort_unstable();
+                self.cycles
+                    .push(component.into_iter().map(FunctionId::new).collect());
+            }
+        }
+    }
+}
+
+/// Quote `text` as a DOT string.
+fn quote(text: &str) -> String {
+    format!("\"{}\"", text.replace('\\', "\\\\").replace('"', "\\\""))
+}
+
+#[cfg(test)]
+mod tests {
+    use indoc::indoc;
+
+    use super::CallGraph;
+    use crate::{library::Library, syntax_tree::parse};
+
+    const SOURCE: &str = indoc! {"
+        def main():
+            a()
+            a()
+            print()
+
+        def a():
+            b()
+
+        def b():
+            a()
+
+        def c():
+            c()
+    "};
+
+    #[test]
+    fn graph() {
+        let library = Library::link(parse(SOURCE).unwrap()).unwrap();
+        let graph = CallGraph::new(&library);
+        let id = |name| library.function_id(name).unwrap();
+        let calls: Vec<_> = graph
+            .calls()
+            .iter()
+            .map(|call| (call.caller(), call.callee(), call.span().line()))
+            .collect();
+
+        assert_eq!(
+            calls,
+            [
+                (id("main"), id("a"), 2),
+                (id("main"), id("a"), 3),
+                (id("main"), id("print"), 4),
+                (id("a"), id("b"), 7),
+                (id("b"), id("a"), 10),
+                (id("c"), id("c"), 13),
+            ]
+        );
+
+        let external: Vec<_> = graph
+            .functions()
+            .iter()
+            .filter(|function| function.is_external())
+            .map(|function| function.name())
+            .collect();
+        assert_eq!(external, ["print"]);
+
+        assert_eq!(graph.cycles(), [vec![id("a"), id("b")], vec![id("c")]]);
+        assert!(!graph.functions()[id("main").index()].is_recursive());
+        assert!(graph.functions()[id("b").index()].is_recursive());
+    }
+
+    #[test]
+    fn dot() {
+        let library = Library::link(parse(SOURCE).unwrap()).unwrap();
+
+        assert_eq!(
+            CallGraph::new(&library).to_dot(),
+            indoc! {r#"
+                digraph calls {
+                    f0 [label="main"];
+                    f1 [label="a"];
+                    f2 [label="b"];
+                    f3 [label="c"];
+                    f4 [label="print", style=dashed];
+                    f0 -> f1 [label="2"];
+                    f0 -> f1 [label="3"];
+                    f0 -> f4 [label="4"];
+                    f1 -> f2 [label="7", color=red];
+                    f2 -> f1 [label="10", color=red];
+                    f3 -> f3 [label="13", color=red];
+                }
+            "#}
+        );
+    }
+
+    #[test]
+    fn json() {
+        let library = Library::link(parse("def main():\n    print()\n").unwrap()).unwrap();
+        let json: serde_json::Value =
+            serde_json::from_str(&CallGraph::new(&library).to_json()).unwrap();
+
+        assert_eq!(json["functions"][1]["name"], "print");
+        assert_eq!(json["functions"][1]["external"], true);
+        assert_eq!(json["functions"][1]["span"], serde_json::Value::Null);
+        assert_eq!(json["calls"][0]["caller"], 0);
+        assert_eq!(json["calls"][0]["callee"], 1);
+        assert_eq!(json["calls"][0]["span"]["start"]["line"], 2);
+        assert_eq!(json["cycles"], serde_json::json!([]));
+    }
+}
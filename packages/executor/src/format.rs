@@ -0,0 +1,597 @@
+//! Format workflow source code.
+//!
+//! Indentation is normalised to 4 spaces, and spacing within lines is
+//! normalised. Comments are kept, as are blank lines between statements,
+//! although long runs of blank lines are shortened. Inline bodies, like
+//! `if x: pass`, are moved onto their own line.
+use std::{fmt::Write, iter::Peekable, slice};
+
+use crate::syntax_tree::{
+    parse, Body, Comment, Decorator, Expression, Function, Import, Literal, Module, ParseError,
+    SrcPos, SrcSpan, Statement, Trigger,
+};
+
+/// Format `source`.
+///
+/// Formatting is idempotent, so formatting the output again won't change it.
+pub fn format(source: &str) -> Result<String, ParseError> {
+    let module = parse(source)?;
+    let mut formatter = Formatter::new(source, module.comments());
+    formatter.module(&module);
+
+    Ok(formatter.finish())
+}
+
+struct Formatter<'a> {
+    source: &'a str,
+    lines: Vec<&'a str>,
+    comments: Peekable<slice::Iter<'a, Comment>>,
+    output: String,
+    depth: usize,
+    /// Are we at the start of a block, where blank lines are removed?
+    block_start: bool,
+}
+
+enum Item<'a> {
+    Import(&'a Import),
+    Function(&'a Function),
+    Triggers(&'a [Trigger]),
+}
+
+impl<'a> Formatter<'a> {
+    fn new(source: &'a str, comments: &'a [Comment]) -> Self {
+        Self {
+            source,
+            lines: source.lines().collect(),
+            comments: comments.iter().peekable(),
+            output: String::new(),
+            depth: 0,
+            block_start: true,
+        }
+    }
+
+    fn module(&mut self, module: &'a Module) {
+        let mut items: Vec<(SrcPos, Item)> = module
+            .imports()
+            .iter()
+            .map(|import| (import.span().start(), Item::Import(import)))
+            .chain(
+                module
+                    .functions()
+                    .iter()
+                    .map(|function| (function.whole_span().start(), Item::Function(function))),
+            )
+            .chain(
+                module
+                    .triggers_span()
+                    .map(|span| (span.start(), Item::Triggers(module.triggers()))),
+            )
+            .collect();
+        items.sort_by_key(|(start, _item)| start.offset());
+
+        let next_starts: Vec<usize> = items
+            .iter()
+            .skip(1)
+            .map(|(start, _item)| start.offset())
+            .chain([self.source.len()])
+            .collect();
+
+        for ((start, item), next_start) in items.into_iter().zip(next_starts) {
+            self.start_line(start, MAX_MODULE_BLANK_LINES);
+
+            match item {
+                Item::Import(import) => {
+                    self.output.push_str(&import_text(import));
+                    self.end_line(next_start);
+                }
+                Item::Function(function) => self.function(function),
+                Item::Triggers(triggers) => {
+                    let triggers = list(triggers.iter().map(trigger_text));
+                    write!(self.output, "triggers = [{triggers}]").unwrap();
+                    self.end_line(next_start);
+                }
+            }
+        }
+    }
+
+    fn function(&mut self, function: &'a Function) {
+        let decorators = function.decorators();
+
+        for (index, decorator) in decorators.iter().enumerate() {
+            if index > 0 {
+                self.start_line(decorator.span().start(), 0);
+            }
+
+            self.output.push_str(&decorator_text(decorator));
+            self.end_line(self.line_end(decorator.span().end()));
+        }
+
+        if !decorators.is_empty() {
+            self.start_line(function.span().start(), 0);
+        }
+
+        let body = function.body();
+        write!(self.output, "def {}():", function.name()).unwrap();
+        self.end_line(body.span().start().offset());
+        self.body(function.whole_span().start(), body);
+    }
+
+    /// Format a block, indented from the `header` that introduces it.
+    fn body(&mut self, header: SrcPos, body: &'a Body<String>) {
+        self.depth += 1;
+        self.block_start = true;
+
+        for (span, statement) in body.spanned() {
+            self.statement(span, statement);
+        }
+
+        // Comments indented after the last statement stay in the block.
+        let body_end = body.span().end().offset();
+
+        while let Some(comment) = self.comments.next_if(|comment| {
+            let start = comment.span().start();
+
+            start.column() > header.column()
+                && is_own_line(self.source, start)
+                && is_trivia(&self.source[body_end..start.offset()])
+        }) {
+            self.comment_line(comment, MAX_BODY_BLANK_LINES);
+        }
+
+        self.depth -= 1;
+    }
+
+    fn statement(&mut self, span: SrcSpan, statement: &'a Statement<String>) {
+        self.start_line(span.start(), MAX_BODY_BLANK_LINES);
+
+        match statement {
+            Statement::Pass => {
+                self.output.push_str("pass");
+                self.end_line(self.line_end(span.end()));
+            }
+            Statement::Expression(expression) => {
+                self.output.push_str(&expression_text(expression));
+                self.end_line(self.line_end(span.end()));
+            }
+            Statement::If {
+                if_span,
+                condition,
+                then_block,
+                else_block,
+            } => {
+                write!(self.output, "if {}:", expression_text(condition)).unwrap();
+                self.end_line(then_block.span().start().offset());
+                self.body(if_span.start(), then_block);
+
+                if let Some(else_block) = else_block {
+                    let else_start = else_block.span().start();
+                    let else_body = else_block.body();
+                    self.start_line(else_start, 0);
+                    self.output.push_str("else:");
+                    self.end_line(else_body.span().start().offset());
+                    self.body(else_start, else_body);
+                }
+            }
+            Statement::With {
+                with_span,
+                context_manager,
+                target,
+                body,
+            } => {
+                write!(self.output, "with {}", expression_text(context_manager)).unwrap();
+
+                if let Some((_span, target)) = target {
+                    write!(self.output, " as {target}").unwrap();
+                }
+
+                self.output.push(':');
+                self.end_line(body.span().start().offset());
+                self.body(with_span.start(), body);
+            }
+            Statement::Global(names) => {
+                write!(self.output, "global {}", names.join(", ")).unwrap();
+                self.end_line(self.line_end(span.end()));
+            }
+            Statement::Nonlocal(names) => {
+                write!(self.output, "nonlocal {}", names.join(", ")).unwrap();
+                self.end_line(self.line_end(span.end()));
+            }
+            Statement::Break => {
+                self.output.push_str("break");
+                self.end_line(self.line_end(span.end()));
+            }
+            Statement::Continue => {
+                self.output.push_str("continue");
+                self.end_line(self.line_end(span.end()));
+            }
+        }
+    }
+
+    /// Start a line of code at `start`.
+    ///
+    /// Any comments before `start` are written first, on their own lines.
+    fn start_line(&mut self, start: SrcPos, max_blank_lines: usize) {
+        while let Some(comment) = self
+            .comments
+            .next_if(|comment| comment.span().start().offset() < start.offset())
+        {
+            self.comment_line(comment, max_blank_lines);
+        }
+
+        self.blank_lines(start.line(), max_blank_lines);
+        self.indent();
+    }
+
+    /// End a line of code, adding the comment that follows it, if there is one
+    /// before `limit`.
+    fn end_line(&mut self, limit: usize) {
+        let source = self.source;
+
+        if let Some(comment) = self.comments.next_if(|comment| {
+            let start = comment.span().start();
+            start.offset() < limit && !is_own_line(source, start)
+        }) {
+            self.output.push_str("  ");
+            self.output.push_str(&comment_text(comment.text()));
+        }
+
+        self.output.push('\n');
+    }
+
+    fn comment_line(&mut self, comment: &Comment, max_blank_lines: usize) {
+        self.blank_lines(comment.span().line(), max_blank_lines);
+        self.indent();
+        self.output.push_str(&comment_text(comment.text()));
+        self.output.push('\n');
+    }
+
+    /// Keep up to `max` of the blank lines before `line` in the source.
+    fn blank_lines(&mut self, line: usize, max: usize) {
+        if self.block_start {
+            self.block_start = false;
+            return;
+        }
+
+        let count = self.lines[..line - 1]
+            .iter()
+            .rev()
+            .take_while(|line| line.trim().is_empty())
+            .count();
+
+        for _ in 0..count.min(max) {
+            self.output.push('\n');
+        }
+    }
+
+    fn indent(&mut self) {
+        for _ in 0..self.depth {
+            self.output.push_str(INDENT);
+        }
+    }
+
+    /// The offset of the end of the line containing `pos`.
+    fn line_end(&self, pos: SrcPos) -> usize {
+        let offset = pos.offset();
+
+        self.source[offset..]
+            .find('\n')
+            .map_or(self.source.len(), |len| offset + len)
+    }
+
+    fn finish(mut self) -> String {
+        while let Some(comment) = self.comments.next() {
+            self.comment_line(comment, MAX_MODULE_BLANK_LINES);
+        }
+
+        self.output
+    }
+}
+
+fn import_text(import: &Import) -> String {
+    match import {
+        Import::Module { module, .. } => format!("import {module}"),
+        Import::Names { module, names, .. } => format!(
+            "from {module} import {}",
+            list(names.iter().map(|(_span, name)| name.clone()))
+        ),
+    }
+}
+
+fn trigger_text(trigger: &Trigger) -> String {
+    match trigger {
+        Trigger::Cron { schedule, .. } => format!("cron(\"{schedule}\")"),
+        Trigger::Watch { path, .. } => format!("watch(\"{path}\")"),
+    }
+}
+
+fn decorator_text(decorator: &Decorator) -> String {
+    match decorator {
+        Decorator::Retry {
+            attempts,
+            backoff: 0,
+            ..
+        } => format!("@retry({attempts})"),
+        Decorator::Retry {
+            attempts, backoff, ..
+        } => format!("@retry({attempts}, backoff={backoff})"),
+        Decorator::Timeout { seconds, .. } => format!("@timeout({seconds})"),
+        Decorator::Step { name, .. } => format!("@step(name=\"{name}\")"),
+        Decorator::Cache { key, files, .. } => {
+            let mut args = Vec::new();
+
+            if let Some(key) = key {
+                args.push(format!("key=\"{key}\""));
+            }
+
+            if !files.is_empty() {
+                let files = list(files.iter().map(|file| format!("\"{file}\"")));
+                args.push(format!("files=[{files}]"));
+            }
+
+            if args.is_empty() {
+                "@cache".to_owned()
+            } else {
+                format!("@cache({})", args.join(", "))
+            }
+        }
+    }
+}
+
+fn expression_text(expression: &Expression<String>) -> String {
+    match expression {
+        Expression::Literal(Literal::String(string)) => format!("\"{string}\""),
+        Expression::Literal(Literal::Bool(true)) => "True".to_owned(),
+        Expression::Literal(Literal::Bool(false)) => "False".to_owned(),
+        Expression::Variable { name } => name.clone(),
+        Expression::Call { name, args, .. } => {
+            format!("{name}({})", list(args.iter().map(expression_text)))
+        }
+        Expression::Parallel { branches, .. } => format!(
+            "parallel({})",
+            list(branches.iter().map(|branch| match branch {
+                Expression::Call { name, .. } => name.clone(),
+                branch => expression_text(branch),
+            }))
+        ),
+        Expression::Lambda { params, body, .. } if params.is_empty() => {
+            format!("lambda: {}", expression_text(body))
+        }
+        Expression::Lambda { params, body, .. } => {
+            format!(
+                "lambda {}: {}",
+                list(params.iter().map(|(_span, name)| name.clone())),
+                expression_text(body)
+            )
+        }
+    }
+}
+
+fn list(items: impl Iterator<Item = String>) -> String {
+    items.collect::<Vec<_>>().join(", ")
+}
+
+/// Put a space after the `#`, unless the comment is empty or it's a `#!` or
+/// `##` comment.
+fn comment_text(text: &str) -> String {
+    let text = text.trim_end();
+    let body = &text[1..];
+
+    if body.is_empty() || body.starts_with([' ', '!', '#']) {
+        text.to_owned()
+    } else {
+        format!("# {body}")
+    }
+}
+
+/// Is there only whitespace before `pos` on it's line?
+fn is_own_line(source: &str, pos: SrcPos) -> bool {
+    let before = &source[..pos.offset()];
+    let line_start = before.rfind('\n').map_or(0, |newline| newline + 1);
+
+    before[line_start..].trim().is_empty()
+}
+
+/// Is `text` only whitespace and comments?
+fn is_trivia(text: &str) -> bool {
+    text.lines().all(|line| {
+        let line = line.trim();
+        line.is_empty() || line.starts_with('#')
+    })
+}
+
+const INDENT: &str = "    ";
+const MAX_MODULE_BLANK_LINES: usize = 2;
+const MAX_BODY_BLANK_LINES: usize = 1;
+
+#[cfg(test)]
+mod tests {
+    use indoc::indoc;
+
+    use super::format;
+    use crate::{syntax_tree::parse, CODE};
+
+    #[test]
+    fn normalise() {
+        assert_formats(
+            indoc! {r#"
+                import   helpers
+                from build import compile,test
+                #Build things
+                def main()  :  # Entry point
+                  if  True :   pass
+                  else:
+                        helpers.setup( "a" ,  "b" )
+
+
+
+                  parallel( compile,test )
+                  with  lock( "a" )  as  l :
+                        global  x,y
+                        sorted( lambda  a ,b : a , lambda : True )
+                @retry( 3 , backoff = 2 )
+                @step("Other")  # Named
+                @cache( files = [ "src", "Cargo.toml", ] )
+                def other(): pass
+                triggers = [
+                    cron("0 * * * *"),
+                    watch("src"),
+                ]
+            "#},
+            indoc! {r#"
+                import helpers
+                from build import compile, test
+                # Build things
+                def main():  # Entry point
+                    if True:
+                        pass
+                    else:
+                        helpers.setup("a", "b")
+
+                    parallel(compile, test)
+                    with lock("a") as l:
+                        global x, y
+                        sorted(lambda a, b: a, lambda: True)
+                @retry(3, backoff=2)
+                @step(name="Other")  # Named
+                @cache(files=["src", "Cargo.toml"])
+                def other():
+                    pass
+                triggers = [cron("0 * * * *"), watch("src")]
+            "#},
+        );
+    }
+
+    #[test]
+    fn comments() {
+        assert_formats(
+            indoc! {"
+                # Module comment
+
+
+                def main():
+                  # Leading comment
+
+                  if True:  # Header comment
+                      pass  # Trailing comment
+                      # End of if block
+                  # Before else
+                  else: pass  # Inline body
+                  # End of main
+                # After main
+                #
+            "},
+            indoc! {"
+                # Module comment
+
+
+                def main():
+                    # Leading comment
+
+                    if True:  # Header comment
+                        pass  # Trailing comment
+                        # End of if block
+                    # Before else
+                    else:
+                        pass  # Inline body
+                    # End of main
+                # After main
+                #
+            "},
+        );
+    }
+
+    #[test]
+    fn blank_lines() {
+        assert_formats(
+            indoc! {"
+
+
+                def a():
+
+                    pass
+
+
+
+
+                    pass
+                    if True:
+                        pass
+
+                    else:
+                        pass
+
+
+
+                def b():
+                    pass
+            "},
+            indoc! {"
+                def a():
+                    pass
+
+                    pass
+                    if True:
+                        pass
+                    else:
+                        pass
+
+
+                def b():
+                    pass
+            "},
+        );
+    }
+
+    #[test]
+    fn idempotent() {
+        let sources = [
+            CODE,
+            "",
+            "# Only a comment",
+            indoc! {r#"
+                from a import b,c
+                def main():  #Comment
+                    b( "x",True, c() )
+
+
+                # Comment
+
+
+
+                # Comment
+
+                def c(): pass
+            "#},
+            indoc! {"
+                def main():
+                        if False:
+                                pass
+                                # Nested
+                        # Dedented
+
+                        else:
+                          pass
+            "},
+        ];
+
+        for source in sources {
+            let formatted = format(source).unwrap();
+            assert_eq!(format(&formatted).unwrap(), formatted, "Source:\n{source}");
+            assert_eq!(
+                parse(&formatted).unwrap().comments().len(),
+                parse(source).unwrap().comments().len()
+            );
+        }
+    }
+
+    #[test]
+    fn parse_error() {
+        assert!(format("def main(:\n").is_err());
+    }
+
+    fn assert_formats(source: &str, expected: &str) {
+        let formatted = format(source).unwrap();
+        assert_eq!(formatted, expected);
+        assert_eq!(format(&formatted).unwrap(), formatted);
+    }
+}
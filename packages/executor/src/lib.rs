@@ -1,7 +1,13 @@
 use indoc::indoc;
 
+pub mod call_graph;
+pub mod format;
 pub mod library;
+pub mod lint;
 pub mod run;
+pub mod secrets;
+pub mod sources;
+pub mod step_cache;
 pub mod syntax_tree;
 
 pub const CODE: &str = indoc! {r#"
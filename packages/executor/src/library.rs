@@ -1,10 +1,12 @@
 use std::collections::{BTreeSet, HashMap};
 
 use serde::{Deserialize, Serialize};
+use thiserror::Error;
 
 use crate::{
-    run::ThreadRunState,
-    syntax_tree::{run_call, IdMap, LinkedFunction, Module},
+    call_graph::CallGraph,
+    run::{RunError, ThreadRunState},
+    syntax_tree::{run_call, IdMap, Import, LinkedFunction, Module, Scope, SrcSpan},
 };
 
 pub struct Library {
@@ -17,33 +19,90 @@ impl Library {
     ///
     /// Translate all `String` function id's to a [`FunctionId`] that is fast to
     /// lookup
-    pub fn link(module: Module) -> Self {
-        let symbol_table_len = module.functions().len();
-        let mut id_map = Self::symbol_table(&module);
-        let unresolved_symbols: BTreeSet<String> = module
-            .functions()
+    ///
+    /// `module` can't import anything. Use [`Self::link_modules`] for
+    /// multi-file workflows.
+    pub fn link(module: Module) -> Result<Self, LinkError> {
+        Self::link_modules([(String::new(), module)])
+    }
+
+    /// Link a workflow made up of several modules.
+    ///
+    /// The first module is the main module. Imports are resolved against the
+    /// other modules, by name. Functions from the other modules are named
+    /// `<module>.<function>`.
+    pub fn link_modules(
+        modules: impl IntoIterator<Item = (String, Module)>,
+    ) -> Result<Self, LinkError> {
+        let modules: Vec<(String, Module)> = modules.into_iter().collect();
+        let module_ids: HashMap<&str, usize> = modules
             .iter()
-            .flat_map(|f| f.unresolved_symbols(&id_map))
+            .enumerate()
+            .map(|(index, (name, _))| (name.as_str(), index))
             .collect();
 
-        assert!(symbol_table_len == id_map.len());
+        check_import_cycles(&modules, &module_ids)?;
+
+        let mut symbol_table_len = 0;
+        let symbol_tables = modules
+            .iter()
+            .map(|(_, module)| {
+                let symbol_table = Self::symbol_table(module, symbol_table_len)?;
+                symbol_table_len += symbol_table.len();
+                Ok(symbol_table)
+            })
+            .collect::<Result<Vec<IdMap>, LinkError>>()?;
+        let mut scopes = modules
+            .iter()
+            .zip(&symbol_tables)
+            .map(|((_, module), symbol_table)| {
+                Self::scope(module, symbol_table, &symbol_tables, &module_ids)
+            })
+            .collect::<Result<Vec<IdMap>, LinkError>>()?;
+        let mut unresolved_symbols = BTreeSet::new();
+
+        for ((_, module), scope) in modules.iter().zip(&scopes) {
+            for (span, name) in module
+                .functions()
+                .iter()
+                .flat_map(|f| f.unresolved_symbols(scope))
+            {
+                check_qualified_name(module, span, &name)?;
+                unresolved_symbols.insert(name);
+            }
+        }
 
         for (index, python_name) in unresolved_symbols.iter().enumerate() {
-            id_map.insert(python_name.clone(), FunctionId(index + symbol_table_len));
+            for scope in &mut scopes {
+                scope.insert(python_name.clone(), FunctionId(index + symbol_table_len));
+            }
         }
 
-        let main_id = id_map.get("main").copied();
-        let lookup_map = module
-            .functions()
+        let main_id = symbol_tables
+            .first()
+            .and_then(|main_symbols| main_symbols.get("main"))
+            .copied();
+        let lookup_map: Vec<LinkedFunction> = modules
             .iter()
-            .map(|f| f.translate_ids(&id_map))
+            .zip(&scopes)
+            .enumerate()
+            .flat_map(|(index, ((name, module), scope))| {
+                let qualifier = (index != 0).then_some(name.as_str());
+
+                module
+                    .functions()
+                    .iter()
+                    .map(move |f| f.translate_ids(qualifier, scope))
+            })
             .chain(unresolved_symbols.into_iter().map(LinkedFunction::python))
             .collect();
 
-        Self {
+        let mut library = Self {
             main_id,
             lookup_map,
-        }
+        };
+        library.include_callee_hashes();
+        Ok(library)
     }
 
     /// Lookup a function id
@@ -58,6 +117,24 @@ impl Library {
         &self.lookup_map[id.0]
     }
 
+    /// Find the id of a function by name
+    ///
+    /// Returns `None` if there's no function called `name`.
+    pub fn function_id(&self, name: &str) -> Option<FunctionId> {
+        self.lookup_map
+            .iter()
+            .position(|function| function.name() == name)
+            .map(FunctionId)
+    }
+
+    /// All the functions, including host functions.
+    pub fn functions(&self) -> impl Iterator<Item = (FunctionId, &LinkedFunction)> {
+        self.lookup_map
+            .iter()
+            .enumerate()
+            .map(|(index, function)| (FunctionId(index), function))
+    }
+
     /// Lookup a function called "main"
     ///
     /// Returns `None` if not found.
@@ -73,28 +150,218 @@ impl Library {
     }
 
     // TODO: Type for (CallStack, RunState)?
-    pub fn run(&self, call_states: &ThreadRunState) {
+    pub fn run(&self, call_states: &ThreadRunState) -> Result<(), RunError> {
         if let Some(main_id) = self.main_id() {
-            run_call(main_id, &[], self, call_states);
+            run_call(main_id, &[], self, call_states, &Scope::default())?;
+        }
+
+        Ok(())
+    }
+
+    /// Fold the source hashes of all the local functions each function can
+    /// reach into it's own, so changing a callee changes the caller's cache
+    /// keys.
+    fn include_callee_hashes(&mut self) {
+        let graph = CallGraph::new(self);
+        let mut callees = vec![Vec::new(); graph.functions().len()];
+
+        for call in graph.calls() {
+            callees[call.caller().index()].push(call.callee());
+        }
+
+        let reachable_hashes: Vec<_> = graph
+            .functions()
+            .iter()
+            .map(|function| {
+                let mut visited = vec![false; callees.len()];
+                let mut pending = callees[function.id().index()].clone();
+                let mut hashes = BTreeSet::new();
+
+                while let Some(id) = pending.pop() {
+                    if visited[id.index()] || graph.functions()[id.index()].is_external() {
+                        continue;
+                    }
+
+                    visited[id.index()] = true;
+                    hashes.insert(self.lookup(id).source_hash());
+                    pending.extend(&callees[id.index()]);
+                }
+
+                hashes
+            })
+            .collect();
+
+        for (function, hashes) in self.lookup_map.iter_mut().zip(&reachable_hashes) {
+            function.include_callee_hashes(hashes);
         }
     }
 
-    fn symbol_table(module: &Module) -> HashMap<String, FunctionId> {
+    /// Assign ids to the functions in `module`, starting at `first_id`.
+    fn symbol_table(module: &Module, first_id: usize) -> Result<IdMap, LinkError> {
         let mut id_map = IdMap::new();
 
         for function in module.functions() {
             let name = function.name();
-            let id = FunctionId(id_map.len());
+            let id = FunctionId(first_id + id_map.len());
 
             if id_map.insert(name.to_owned(), id).is_some() {
-                // TODO: Error
-                panic!("Duplicte symbol: {name}");
+                return Err(LinkError::DuplicateFunction {
+                    span: function.span(),
+                    name: name.to_owned(),
+                });
+            }
+        }
+
+        Ok(id_map)
+    }
+
+    /// The names visible in `module`: it's own functions, and anything it
+    /// imports.
+    fn scope(
+        module: &Module,
+        symbol_table: &IdMap,
+        symbol_tables: &[IdMap],
+        module_ids: &HashMap<&str, usize>,
+    ) -> Result<IdMap, LinkError> {
+        let mut scope = symbol_table.clone();
+
+        for import in module.imports() {
+            let imported = &symbol_tables[module_ids[import.module()]];
+
+            match import {
+                Import::Module { module, .. } => scope.extend(
+                    imported
+                        .iter()
+                        .map(|(name, id)| (format!("{module}.{name}"), *id)),
+                ),
+                Import::Names { module, names, .. } => {
+                    for (span, name) in names {
+                        let id = imported.get(name).ok_or_else(|| LinkError::UnknownName {
+                            span: *span,
+                            module: module.clone(),
+                            name: name.clone(),
+                        })?;
+                        scope.insert(name.clone(), *id);
+                    }
+                }
             }
         }
-        id_map
+
+        Ok(scope)
     }
 }
 
+/// Unresolved names are assumed to be Python functions, unless they're
+/// qualified with a module that `module` imports, in which case that module is
+/// missing the function.
+fn check_qualified_name(module: &Module, span: SrcSpan, name: &str) -> Result<(), LinkError> {
+    for import in module.imports() {
+        if let Import::Module { module, .. } = import {
+            let function = name
+                .strip_prefix(module.as_str())
+                .and_then(|name| name.strip_prefix('.'));
+
+            if let Some(function) = function {
+                return Err(LinkError::UnknownName {
+                    span,
+                    module: module.clone(),
+                    name: function.to_owned(),
+                });
+            }
+        }
+    }
+
+    Ok(())
+}
+
+/// Make sure all imported modules exist, and there are no import cycles.
+fn check_import_cycles(
+    modules: &[(String, Module)],
+    module_ids: &HashMap<&str, usize>,
+) -> Result<(), LinkError> {
+    #[derive(Copy, Clone, Eq, PartialEq)]
+    enum Visit {
+        NotVisited,
+        InProgress,
+        Done,
+    }
+
+    fn visit(
+        index: usize,
+        modules: &[(String, Module)],
+        module_ids: &HashMap<&str, usize>,
+        visits: &mut [Visit],
+        path: &mut Vec<usize>,
+    ) -> Result<(), LinkError> {
+        visits[index] = Visit::InProgress;
+        path.push(index);
+
+        for import in modules[index].1.imports() {
+            let Some(&imported) = module_ids.get(import.module()) else {
+                return Err(LinkError::UnknownModule {
+                    span: import.span(),
+                    module: import.module().to_owned(),
+                });
+            };
+
+            match visits[imported] {
+                Visit::NotVisited => visit(imported, modules, module_ids, visits, path)?,
+                Visit::InProgress => {
+                    let cycle_start = path.iter().position(|&index| index == imported).unwrap();
+                    let cycle = path[cycle_start..]
+                        .iter()
+                        .chain([&imported])
+                        .map(|&index| modules[index].0.clone())
+                        .collect();
+
+                    return Err(LinkError::ImportCycle(cycle));
+                }
+                Visit::Done => (),
+            }
+        }
+
+        path.pop();
+        visits[index] = Visit::Done;
+        Ok(())
+    }
+
+    let mut visits = vec![Visit::NotVisited; modules.len()];
+
+    for index in 0..modules.len() {
+        if visits[index] == Visit::NotVisited {
+            visit(index, modules, module_ids, &mut visits, &mut Vec::new())?;
+        }
+    }
+
+    Ok(())
+}
+
+#[derive(Error, Debug)]
+pub enum LinkError {
+    #[error("Unknown module '{module}' on line {}", span.line())]
+    UnknownModule { span: SrcSpan, module: String },
+    #[error("Module '{module}' has no function '{name}' (line {})", span.line())]
+    UnknownName {
+        span: SrcSpan,
+        module: String,
+        name: String,
+    },
+    #[error("Import cycle: {}", .0.join(" -> "))]
+    ImportCycle(Vec<String>),
+    #[error("Function '{name}' is defined more than once (line {})", span.line())]
+    DuplicateFunction { span: SrcSpan, name: String },
+}
+
 /// An id for a function that is fast to lookup.
 #[derive(Eq, PartialEq, Ord, PartialOrd, Hash, Copy, Clone, Serialize, Deserialize, Debug)]
 pub struct FunctionId(usize);
+
+impl FunctionId {
+    pub(crate) fn new(index: usize) -> Self {
+        Self(index)
+    }
+
+    pub(crate) fn index(self) -> usize {
+        self.0
+    }
+}
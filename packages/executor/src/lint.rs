@@ -0,0 +1,572 @@
+//! Static checks for workflows.
+//!
+//! A finding is suppressed by a `# noqa` comment on the line it starts on. Use
+//! `# noqa: <id>, ...` to only suppress some lints.
+use std::{
+    collections::{HashMap, HashSet},
+    fmt,
+};
+
+use crate::{
+    call_graph::CallGraph,
+    library::{FunctionId, Library},
+    sources::{SourceError, Sources},
+    syntax_tree::{
+        visit::{walk_call, walk_expression, walk_statement, Visit},
+        Body, Expression, Import, LinkedBody, Module, SrcSpan, Statement,
+    },
+};
+
+/// Host functions we know about, besides any the caller provides.
+pub const BUILT_INS: &[&str] = &["secret", "run_input"];
+
+/// Check a workflow.
+///
+/// Calls to host functions that aren't in `host_functions` or [`BUILT_INS`]
+/// are reported. Findings are in source order.
+pub fn lint(sources: &Sources, host_functions: &[&str]) -> Result<Vec<Lint>, SourceError> {
+    let modules = sources.parse()?;
+    let library = Library::link_modules(modules.clone())?;
+    let mut lints = Vec::new();
+
+    for (_name, module) in &modules {
+        shadowed_names(module, &mut lints);
+    }
+
+    unused_functions(&library, &mut lints);
+
+    let mut checks = Checks {
+        library: &library,
+        host_functions,
+        lints: &mut lints,
+    };
+
+    for (_id, function) in library.functions() {
+        if let LinkedBody::Local(body) = function.body() {
+            checks.visit_body(body);
+            unused_variables(body, checks.lints);
+        }
+    }
+
+    let suppressions: Vec<HashMap<usize, Suppression>> = modules
+        .iter()
+        .map(|(_name, module)| Suppression::find(module))
+        .collect();
+    lints.retain(|lint| {
+        !suppressions[lint.span.file().index()]
+            .get(&lint.span.line())
+            .is_some_and(|suppression| suppression.suppresses(lint.id))
+    });
+    lints.sort_by_key(|lint| (lint.span.file(), lint.span.start().offset()));
+
+    Ok(lints)
+}
+
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub struct Lint {
+    id: LintId,
+    span: SrcSpan,
+    message: String,
+}
+
+impl Lint {
+    pub fn id(&self) -> LintId {
+        self.id
+    }
+
+    pub fn span(&self) -> SrcSpan {
+        self.span
+    }
+
+    pub fn message(&self) -> &str {
+        &self.message
+    }
+}
+
+impl fmt::Display for Lint {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "{}:{}: {} [{}]",
+            self.span.line(),
+            self.span.column(),
+            self.message,
+            self.id
+        )
+    }
+}
+
+#[derive(Copy, Clone, Debug, Eq, PartialEq, Hash)]
+pub enum LintId {
+    /// An `if` condition that's a literal.
+    ConstantCondition,
+    /// A block that can't run because of a constant condition.
+    UnreachableBranch,
+    /// A function that can't be reached from `main`.
+    UnusedFunction,
+    /// An imported name that hides another function.
+    ShadowedName,
+    /// A call to a host function we don't know about.
+    UnknownHostCall,
+    /// A `with ... as` target or `lambda` parameter that's never used.
+    UnusedVariable,
+}
+
+impl LintId {
+    /// The id used in `# noqa` comments.
+    pub fn as_str(self) -> &'static str {
+        match self {
+            Self::ConstantCondition => "constant-condition",
+            Self::UnreachableBranch => "unreachable-branch",
+            Self::UnusedFunction => "unused-function",
+            Self::ShadowedName => "shadowed-name",
+            Self::UnknownHostCall => "unknown-host-call",
+            Self::UnusedVariable => "unused-variable",
+        }
+    }
+}
+
+impl fmt::Display for LintId {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(self.as_str())
+    }
+}
+
+fn report(lints: &mut Vec<Lint>, id: LintId, span: SrcSpan, message: String) {
+    lints.push(Lint { id, span, message });
+}
+
+/// Imported names take precedence over functions defined in the module, and
+/// later imports take precedence over earlier ones.
+fn shadowed_names(module: &Module, lints: &mut Vec<Lint>) {
+    let mut names: HashMap<&str, SrcSpan> = module
+        .functions()
+        .iter()
+        .map(|function| (function.name(), function.span()))
+        .collect();
+
+    for function in module.functions() {
+        if BUILT_INS.contains(&function.name()) {
+            report(
+                lints,
+                LintId::ShadowedName,
+                function.span(),
+                format!("Function '{}' shadows a built-in", function.name()),
+            );
+        }
+    }
+
+    for import in module.imports() {
+        let Import::Names {
+            names: imported, ..
+        } = import
+        else {
+            continue;
+        };
+
+        for (span, name) in imported {
+            if let Some(shadowed) = names.insert(name, *span) {
+                report(
+                    lints,
+                    LintId::ShadowedName,
+                    *span,
+                    format!("'{name}' shadows '{name}' from line {}", shadowed.line()),
+                );
+            }
+        }
+    }
+}
+
+fn unused_functions(library: &Library, lints: &mut Vec<Lint>) {
+    let graph = CallGraph::new(library);
+    let mut used = vec![false; graph.functions().len()];
+    let mut pending: Vec<_> = library.main_id().into_iter().collect();
+
+    while let Some(id) = pending.pop() {
+        if used[id.index()] {
+            continue;
+        }
+
+        used[id.index()] = true;
+        pending.extend(
+            graph
+                .calls()
+                .iter()
+                .filter(|call| call.caller() == id)
+                .map(|call| call.callee()),
+        );
+    }
+
+    for function in graph.functions() {
+        if let Some(span) = function.span() {
+            if !used[function.id().index()] {
+                report(
+                    lints,
+                    LintId::UnusedFunction,
+                    span,
+                    format!("Function '{}' is never called from 'main'", function.name()),
+                );
+            }
+        }
+    }
+}
+
+/// `with ... as` targets that aren't used anywhere in the function, and
+/// `lambda` parameters that aren't used in the lambda's body.
+fn unused_variables(body: &Body<FunctionId>, lints: &mut Vec<Lint>) {
+    let mut used = UsedVariables::default();
+    used.visit_body(body);
+    UnusedVariables {
+        used: used.0,
+        lints,
+    }
+    .visit_body(body);
+}
+
+#[derive(Default)]
+struct UsedVariables<'ast>(HashSet<&'ast str>);
+
+impl<'ast> Visit<'ast, FunctionId> for UsedVariables<'ast> {
+    fn visit_expression(&mut self, expression: &'ast Expression<FunctionId>) {
+        if let Expression::Variable { name } = expression {
+            self.0.insert(name);
+        }
+
+        walk_expression(self, expression);
+    }
+}
+
+struct UnusedVariables<'a, 'ast> {
+    used: HashSet<&'ast str>,
+    lints: &'a mut Vec<Lint>,
+}
+
+impl UnusedVariables<'_, '_> {
+    fn report(&mut self, span: SrcSpan, name: &str) {
+        report(
+            self.lints,
+            LintId::UnusedVariable,
+            span,
+            format!("Variable '{name}' is never used"),
+        );
+    }
+}
+
+impl<'ast> Visit<'ast, FunctionId> for UnusedVariables<'_, 'ast> {
+    fn visit_statement(&mut self, span: SrcSpan, statement: &'ast Statement<FunctionId>) {
+        if let Statement::With {
+            target: Some((target_span, target)),
+            ..
+        } = statement
+        {
+            if !self.used.contains(target.as_str()) {
+                self.report(*target_span, target);
+            }
+        }
+
+        walk_statement(self, span, statement);
+    }
+
+    fn visit_expression(&mut self, expression: &'ast Expression<FunctionId>) {
+        if let Expression::Lambda { params, body, .. } = expression {
+            let mut used = UsedVariables::default();
+            used.visit_expression(body);
+
+            for (param_span, param) in params {
+                if !used.0.contains(param.as_str()) {
+                    self.report(*param_span, param);
+                }
+            }
+        }
+
+        walk_expression(self, expression);
+    }
+}
+
+struct Checks<'a> {
+    library: &'a Library,
+    host_functions: &'a [&'a str],
+    lints: &'a mut Vec<Lint>,
+}
+
+impl<'ast> Visit<'ast, FunctionId> for Checks<'_> {
+    fn visit_statement(&mut self, span: SrcSpan, statement: &'ast Statement<FunctionId>) {
+        if let Statement::If {
+            if_span,
+            condition,
+            then_block,
+            else_block,
+        } = statement
+        {
+            if let Expression::Literal(literal) = condition.as_ref() {
+                let truthy = literal.is_truthy();
+                report(
+                    self.lints,
+                    LintId::ConstantCondition,
+                    *if_span,
+                    format!(
+                        "Condition is always {}",
+                        if truthy { "True" } else { "False" }
+                    ),
+                );
+
+                let unreachable = if truthy {
+                    else_block
+                        .as_ref()
+                        .map(|else_block| else_block.body().span())
+                } else {
+                    Some(then_block.span())
+                };
+
+                if let Some(unreachable) = unreachable {
+                    report(
+                        self.lints,
+                        LintId::UnreachableBranch,
+                        unreachable,
+                        "Branch is never run".to_owned(),
+                    );
+                }
+            }
+        }
+
+        walk_statement(self, span, statement);
+    }
+
+    fn visit_call(
+        &mut self,
+        span: SrcSpan,
+        name: &'ast FunctionId,
+        args: &'ast [Expression<FunctionId>],
+    ) {
+        let function = self.library.lookup(*name);
+
+        if matches!(function.body(), LinkedBody::Python)
+            && !self.host_functions.contains(&function.name())
+            && !BUILT_INS.contains(&function.name())
+        {
+            report(
+                self.lints,
+                LintId::UnknownHostCall,
+                span,
+                format!("Unknown host function '{}'", function.name()),
+            );
+        }
+
+        walk_call(self, span, name, args);
+    }
+}
+
+/// A `# noqa` comment.
+enum Suppression {
+    All,
+    Only(Vec<String>),
+}
+
+impl Suppression {
+    /// Find the `# noqa` comments in `module`, by line.
+    fn find(module: &Module) -> HashMap<usize, Self> {
+        module
+            .comments()
+            .iter()
+            .filter_map(|comment| Some((comment.span().line(), Self::parse(comment.text())?)))
+            .collect()
+    }
+
+    fn parse(comment: &str) -> Option<Self> {
+        let text = comment.trim_start_matches('#').trim();
+
+        if !text
+            .get(..NOQA.len())
+            .is_some_and(|prefix| prefix.eq_ignore_ascii_case(NOQA))
+        {
+            return None;
+        }
+
+        let ids = text[NOQA.len()..].trim();
+
+        if let Some(ids) = ids.strip_prefix(':') {
+            Some(Self::Only(
+                ids.split(',')
+                    .map(|id| id.trim().to_owned())
+                    .filter(|id| !id.is_empty())
+                    .collect(),
+            ))
+        } else if ids.is_empty() {
+            Some(Self::All)
+        } else {
+            None
+        }
+    }
+
+    fn suppresses(&self, id: LintId) -> bool {
+        match self {
+            Self::All => true,
+            Self::Only(ids) => ids.iter().any(|suppressed| suppressed == id.as_str()),
+        }
+    }
+}
+
+const NOQA: &str = "noqa";
+
+#[cfg(test)]
+mod tests {
+    use indoc::indoc;
+
+    use super::{lint, LintId, Suppression};
+    use crate::{sources::Sources, CODE};
+
+    #[test]
+    fn conditions() {
+        assert_lints(
+            indoc! {r#"
+                def main():
+                    if True:
+                        pass
+                    else:
+                        pass
+                    if False:
+                        pass
+                    if print():
+                        pass
+                    else:
+                        pass
+            "#},
+            &[
+                (LintId::ConstantCondition, 2, 5),
+                (LintId::UnreachableBranch, 5, 9),
+                (LintId::ConstantCondition, 6, 5),
+                (LintId::UnreachableBranch, 7, 9),
+            ],
+        );
+    }
+
+    #[test]
+    fn unused_functions() {
+        assert_lints(
+            indoc! {"
+                def main():
+                    a()
+
+                def a():
+                    a()
+
+                def b():
+                    c()
+
+                def c():
+                    b()
+            "},
+            &[
+                (LintId::UnusedFunction, 7, 5),
+                (LintId::UnusedFunction, 10, 5),
+            ],
+        );
+    }
+
+    #[test]
+    fn shadowed_names() {
+        let sources = Sources::load_with("main", |module| {
+            Ok(match module {
+                "main" => indoc! {"
+                    from other import a, b
+                    from other import b
+
+                    def main():
+                        a()
+                        b()
+
+                    def a():
+                        pass
+
+                    def secret():
+                        pass
+                "},
+                _ => "def a():\n    main()\n\ndef b():\n    pass\n\ndef main():\n    pass\n",
+            }
+            .to_owned())
+        })
+        .unwrap();
+        let lints: Vec<_> = lint(&sources, &[])
+            .unwrap()
+            .into_iter()
+            .filter(|lint| lint.id() == LintId::ShadowedName)
+            .map(|lint| (lint.span().line(), lint.span().column()))
+            .collect();
+
+        assert_eq!(lints, [(1, 19), (2, 19), (11, 5)]);
+    }
+
+    #[test]
+    fn unknown_host_calls() {
+        assert_lints(
+            indoc! {r#"
+                def main():
+                    print(secret("TOKEN"))
+                    deploy(print())
+            "#},
+            &[(LintId::UnknownHostCall, 3, 5)],
+        );
+    }
+
+    #[test]
+    fn unused_variables() {
+        assert_lints(
+            indoc! {"
+                def main():
+                    with lock() as held:
+                        print(lambda x, y: x)
+                    with lock() as used:
+                        print(lambda: used)
+            "},
+            &[
+                (LintId::UnknownHostCall, 2, 10),
+                (LintId::UnusedVariable, 2, 20),
+                (LintId::UnusedVariable, 3, 25),
+                (LintId::UnknownHostCall, 4, 10),
+            ],
+        );
+    }
+
+    #[test]
+    fn noqa() {
+        assert_lints(
+            indoc! {r#"
+                def main():
+                    if False:  # noqa
+                        pass
+                    if False:  # NOQA: constant-condition
+                        pass
+                    deploy()  # noqa: unused-function, unreachable-branch
+            "#},
+            &[
+                (LintId::UnreachableBranch, 3, 9),
+                (LintId::UnreachableBranch, 5, 9),
+                (LintId::UnknownHostCall, 6, 5),
+            ],
+        );
+        assert!(Suppression::parse("# noqanope").is_none());
+        assert!(Suppression::parse("# Not noqa").is_none());
+        assert!(Suppression::parse("# noqé").is_none());
+    }
+
+    #[test]
+    fn code() {
+        let lints = lint(&Sources::single("main", CODE), &["print"]).unwrap();
+
+        assert!(lints.iter().all(|lint| matches!(
+            lint.id(),
+            LintId::ConstantCondition | LintId::UnreachableBranch
+        )));
+        assert_eq!(lints.len(), 6);
+    }
+
+    fn assert_lints(source: &str, expected: &[(LintId, usize, usize)]) {
+        let lints: Vec<_> = lint(&Sources::single("main", source), &["print"])
+            .unwrap()
+            .into_iter()
+            .map(|lint| (lint.id(), lint.span().line(), lint.span().column()))
+            .collect();
+
+        assert_eq!(lints, expected);
+    }
+}
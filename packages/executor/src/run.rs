@@ -1,17 +1,36 @@
 use std::{
     cmp::Ordering,
-    collections::HashSet,
+    collections::{HashMap, HashSet},
+    fmt,
+    hash::{Hash, Hasher},
+    iter,
+    path::PathBuf,
     pin::pin,
-    sync::{Arc, RwLock, RwLockReadGuard, RwLockWriteGuard},
+    sync::{mpsc as std_mpsc, Arc, Condvar, Mutex, RwLock, RwLockReadGuard, RwLockWriteGuard},
+    thread::{self, sleep},
+    time::{Duration, Instant},
 };
 
-use clonelet::clone;
 use futures::{stream, Future, Stream};
 use serde::{Deserialize, Serialize};
-use tokio::sync::{broadcast, mpsc};
-use tokio_stream::{wrappers::BroadcastStream, StreamExt};
+use slotmap::{new_key_type, SecondaryMap, SlotMap};
+use thiserror::Error;
+use tokio::sync::{
+    broadcast::{self, error::RecvError},
+    mpsc,
+};
+use tokio_stream::StreamExt;
+
+use self::history::History;
+pub use self::history::{HistoryLimits, Summary};
+use crate::{
+    library::FunctionId,
+    secrets::{Secret, Secrets},
+    step_cache::{CacheEntry, CacheKey, StepCache},
+    syntax_tree::Value,
+};
 
-use crate::library::FunctionId;
+mod history;
 
 // The order of the enum variants is important, as we rely on later call stacks
 // to be greater than earlier ones.
@@ -21,6 +40,9 @@ pub enum StackFrame {
     Argument(usize),
     Call(FunctionId),
     NestedBlock(usize, NestedBlock),
+    Branch(usize),
+    /// An attempt at running a function with `@retry`.
+    Attempt(usize),
 }
 
 #[derive(Debug, Copy, Clone, Eq, PartialEq, Ord, PartialOrd, Hash, Serialize, Deserialize)]
@@ -29,20 +51,49 @@ pub enum NestedBlock {
     Body,
 }
 
-#[derive(Debug, Clone, Default, Eq, PartialEq, Hash, Serialize, Deserialize)]
-pub struct CallStack(Vec<StackFrame>);
+/// Why a node failed.
+///
+/// The error is logged on the innermost node that was running when it
+/// happened.
+#[derive(Error, Clone, Debug, Eq, PartialEq)]
+pub enum RunError {
+    #[error("Timed out")]
+    Timeout,
+    #[error("Unknown secret \"{0}\"")]
+    UnknownSecret(String),
+    #[error("secret() takes a single secret name")]
+    InvalidSecretArguments,
+    #[error("Name '{0}' is not defined")]
+    UnknownVariable(String),
+    #[error("'{0}' outside loop")]
+    OutsideLoop(&'static str),
+    #[error("No binding for nonlocal '{0}' found")]
+    NoNonlocalBinding(String),
+}
+
+/// A stack of [`StackFrame`]s.
+///
+/// Call stacks are persistent, so they share their prefix with the stack they
+/// were pushed onto. Cloning, pushing and finding the parent don't copy any
+/// frames.
+#[derive(Clone, Default, Serialize, Deserialize)]
+#[serde(from = "Vec<StackFrame>", into = "Vec<StackFrame>")]
+pub struct CallStack(Option<Arc<CallStackNode>>);
+
+struct CallStackNode {
+    parent: CallStack,
+    top: StackFrame,
+    len: usize,
+}
 
 impl Ord for CallStack {
     fn cmp(&self, other: &Self) -> Ordering {
-        for (i, j) in self.0.iter().zip(other.0.iter()) {
-            let cmp = i.cmp(j);
+        let len = self.len().min(other.len());
 
-            if cmp != Ordering::Equal {
-                return cmp;
-            }
+        match self.ancestor(len).cmp_same_len(other.ancestor(len)) {
+            Ordering::Equal => other.len().cmp(&self.len()),
+            cmp => cmp,
         }
-
-        other.0.len().cmp(&self.0.len())
     }
 }
 
@@ -52,39 +103,107 @@ impl PartialOrd for CallStack {
     }
 }
 
+impl PartialEq for CallStack {
+    fn eq(&self, other: &Self) -> bool {
+        self.len() == other.len() && self.cmp_same_len(other) == Ordering::Equal
+    }
+}
+
+impl Eq for CallStack {}
+
+impl Hash for CallStack {
+    fn hash<H: Hasher>(&self, state: &mut H) {
+        self.len().hash(state);
+
+        for frame in self.frames() {
+            frame.hash(state);
+        }
+    }
+}
+
+impl Drop for CallStack {
+    /// Unlink the nodes we own one at a time, so dropping a deep stack doesn't
+    /// recurse once per frame.
+    fn drop(&mut self) {
+        let mut current = self.0.take();
+
+        while let Some(node) = current {
+            current = match Arc::try_unwrap(node) {
+                Ok(mut node) => node.parent.0.take(),
+                // The rest of the stack is shared, so it's not ours to drop.
+                Err(_) => None,
+            };
+        }
+    }
+}
+
+impl fmt::Debug for CallStack {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_list().entries(Vec::from(self.clone())).finish()
+    }
+}
+
+impl From<Vec<StackFrame>> for CallStack {
+    fn from(frames: Vec<StackFrame>) -> Self {
+        let mut call_stack = Self::new();
+
+        for frame in frames {
+            call_stack.push(frame);
+        }
+
+        call_stack
+    }
+}
+
+impl From<CallStack> for Vec<StackFrame> {
+    fn from(call_stack: CallStack) -> Self {
+        let mut frames: Vec<StackFrame> = call_stack.frames().collect();
+        frames.reverse();
+        frames
+    }
+}
+
 impl CallStack {
     pub fn new() -> Self {
         Self::default()
     }
 
     pub fn starts_with(&self, other: &Self) -> bool {
-        self.0.starts_with(&other.0)
+        self.len() >= other.len() && self.ancestor(other.len()) == other
     }
 
     pub fn len(&self) -> usize {
-        self.0.len()
+        self.0.as_ref().map_or(0, |node| node.len)
     }
 
     #[must_use]
     pub fn is_empty(&self) -> bool {
-        self.0.is_empty()
+        self.0.is_none()
     }
 
-    // TODO: We need a "slice" for CallStacks
     pub fn parent(&self) -> Option<CallStack> {
-        let mut parent = Self(self.0.clone());
-
-        parent.0.pop()?;
+        let mut parent = &self.0.as_ref()?.parent;
 
         while !parent.is_node() {
-            parent.0.pop();
+            parent = parent.popped();
         }
 
-        Some(parent)
+        Some(parent.clone())
     }
 
     pub fn top(&self) -> Option<StackFrame> {
-        self.0.last().cloned()
+        self.0.as_ref().map(|node| node.top)
+    }
+
+    /// The frames, starting at the top of the stack.
+    pub fn frames(&self) -> impl Iterator<Item = StackFrame> + '_ {
+        let mut current = self;
+
+        iter::from_fn(move || {
+            let top = current.top()?;
+            current = current.popped();
+            Some(top)
+        })
     }
 
     pub fn is_node(&self) -> bool {
@@ -92,75 +211,556 @@ impl CallStack {
             self.top(),
             None | Some(StackFrame::Call(_))
                 | Some(StackFrame::NestedBlock(_, NestedBlock::Predicate))
+                | Some(StackFrame::Attempt(_))
         )
     }
 
     pub fn push(&mut self, item: StackFrame) {
-        self.0.push(item)
+        *self = self.push_cloned(item);
     }
 
     pub fn push_cloned(&self, item: StackFrame) -> Self {
-        let mut clone = self.clone();
-        clone.push(item);
-        clone
+        Self(Some(Arc::new(CallStackNode {
+            parent: self.clone(),
+            top: item,
+            len: self.len() + 1,
+        })))
     }
 
     pub fn pop(&mut self) {
-        self.0.pop();
+        *self = self.popped().clone();
+    }
+
+    /// The stack with the top frame removed, or an empty stack if it's already
+    /// empty.
+    fn popped(&self) -> &Self {
+        match &self.0 {
+            Some(node) => &node.parent,
+            None => self,
+        }
+    }
+
+    /// The prefix of this stack with length `len`.
+    fn ancestor(&self, len: usize) -> &Self {
+        let mut ancestor = self;
+
+        while ancestor.len() > len {
+            ancestor = ancestor.popped();
+        }
+
+        ancestor
+    }
+
+    /// Compare two stacks of the same length, frame by frame from the bottom.
+    ///
+    /// We walk down from the top, so the last difference we see is the one
+    /// nearest the bottom. We can stop as soon as the stacks share a prefix.
+    fn cmp_same_len(&self, other: &Self) -> Ordering {
+        let mut ordering = Ordering::Equal;
+        let (mut current, mut other) = (self, other);
+
+        while let (Some(node), Some(other_node)) = (&current.0, &other.0) {
+            if Arc::ptr_eq(node, other_node) {
+                break;
+            }
+
+            match node.top.cmp(&other_node.top) {
+                Ordering::Equal => (),
+                cmp => ordering = cmp,
+            }
+
+            current = &node.parent;
+            other = &other_node.parent;
+        }
+
+        ordering
     }
 }
 
 #[derive(Clone)]
-pub struct ThreadRunState(Arc<RwLock<SharedThreadRunState>>);
+pub struct ThreadRunState {
+    shared: Arc<RwLock<SharedThreadRunState>>,
+    paused: Pause,
+    thread: ThreadKey,
+    branch_depth: usize,
+}
 
 impl Default for ThreadRunState {
     fn default() -> Self {
-        let (update_sender, _update_receiver) = broadcast::channel(1000);
+        Self::new(None, None)
+    }
+}
 
-        Self(Arc::new(RwLock::new(SharedThreadRunState {
-            history: Vec::new(),
-            current: CallStack::new(),
-            update_sender,
-        })))
+new_key_type! {
+    struct ThreadKey;
+}
+
+/// Pause a run, in all it's threads.
+///
+/// A paused run stops before it's next statement or function call. Host
+/// functions that are already running aren't interrupted, and deadlines from
+/// [`ThreadRunState::with_timeout`] still pass while the run is paused.
+///
+/// This is separate from the rest of the run state, as we need a [`Mutex`] to
+/// wait on.
+#[derive(Clone, Default)]
+pub struct Pause(Arc<(Mutex<bool>, Condvar)>);
+
+impl Pause {
+    pub fn set(&self, paused: bool) {
+        let (is_paused, resumed) = &*self.0;
+        *is_paused.lock().unwrap() = paused;
+
+        if !paused {
+            resumed.notify_all();
+        }
+    }
+
+    pub fn is_paused(&self) -> bool {
+        *self.0 .0.lock().unwrap()
+    }
+
+    /// Block until the run is resumed, if it's paused.
+    pub fn wait(&self) {
+        let (is_paused, resumed) = &*self.0;
+        let _guard = resumed
+            .wait_while(is_paused.lock().unwrap(), |paused| *paused)
+            .unwrap();
     }
 }
 
 struct SharedThreadRunState {
-    history: Vec<(CallStack, RunState)>,
-    current: CallStack,
-    update_sender: broadcast::Sender<(CallStack, RunState)>,
+    history: History,
+    /// The number of clients that have each node open.
+    open_nodes: HashMap<CallStack, usize>,
+    logs: Vec<(CallStack, String)>,
+    running: SlotMap<ThreadKey, CallStack>,
+    /// When each thread has to finish by, set with
+    /// [`ThreadRunState::with_timeout`].
+    deadlines: SecondaryMap<ThreadKey, Instant>,
+    step_cache: Arc<StepCache>,
+    update_sender: broadcast::Sender<(u64, CallStack, RunState)>,
+    /// The number of run state updates so far. Each update is numbered with
+    /// the count before it.
+    update_count: u64,
+    forward_updates: Option<mpsc::UnboundedSender<ThreadUpdate>>,
+    secrets: Arc<Secrets>,
+    /// The input the run was started with, from [`ThreadRunState::with_input`].
+    input: Value,
+    /// Set with [`ThreadRunState::with_workflow_dir`].
+    workflow_dir: Option<PathBuf>,
+    /// Module level variables, keyed by module and name. The main module is
+    /// `None`.
+    globals: HashMap<(Option<String>, String), Value>,
+    /// How long each host function call takes, set with
+    /// [`ThreadRunState::with_host_call_time`].
+    host_call_time: Duration,
+    /// The number of clients currently subscribed to updates.
+    subscriptions: usize,
+    /// The number of times a subscribed client fell behind.
+    lag_events: u64,
 }
 
 impl SharedThreadRunState {
-    fn update(&self, call_stack: CallStack, run_state: RunState) {
+    fn update(&mut self, call_stack: CallStack, run_state: RunState) {
+        if let Some(forward_updates) = &self.forward_updates {
+            let _ = forward_updates.send(ThreadUpdate::RunState(call_stack.clone(), run_state));
+        }
+
+        let update_number = self.update_count;
+        self.update_count += 1;
+
         // TODO: I think we want to ignore errors. What happens to the queue?
-        let _ = self.update_sender.send((call_stack, run_state));
+        let _ = self
+            .update_sender
+            .send((update_number, call_stack, run_state));
+    }
+
+    fn pop_node(&mut self, call_stack: CallStack, run_state: RunState) {
+        self.history.insert(call_stack.clone(), run_state);
+        self.update(call_stack, run_state);
+    }
+
+    /// Pop everything in `thread` above `depth`, marking any nodes as failed.
+    fn fail_thread(&mut self, thread: ThreadKey, depth: usize) {
+        while self.running[thread].len() > depth {
+            let current = self.running[thread].clone();
+
+            if current.is_node() {
+                self.pop_node(current, RunState::Failed);
+            }
+
+            self.running[thread].pop();
+        }
+    }
+
+    fn is_running(&self, stack: &CallStack) -> bool {
+        self.running
+            .values()
+            .any(|running| running.starts_with(stack))
+    }
+
+    fn child_states(&self, call_stack: &CallStack) -> Vec<(CallStack, RunState)> {
+        let mut child_states = Vec::new();
+
+        for running in self.running.values() {
+            if running.len() > call_stack.len() && running.starts_with(call_stack) {
+                let mut current = innermost_node(running);
+
+                while current.len() > call_stack.len() {
+                    child_states.push((current.clone(), RunState::Running));
+                    current = current.parent().unwrap();
+                }
+            }
+        }
+
+        // Parallel branches share their ancestors
+        child_states.sort_by(|(stack0, _), (stack1, _)| stack0.cmp(stack1));
+        child_states.dedup_by(|(stack0, _), (stack1, _)| stack0 == stack1);
+
+        child_states.extend(self.history.children(call_stack));
+        child_states
+    }
+
+    /// The overall state of the run.
+    fn status(&self) -> RunState {
+        if self.running.values().any(|running| !running.is_empty()) {
+            return RunState::Running;
+        }
+
+        let root_states = self.history.children(&CallStack::new());
+
+        if root_states.is_empty() {
+            RunState::NotRun
+        } else if root_states
+            .iter()
+            .any(|(_, run_state)| *run_state == RunState::Failed)
+        {
+            RunState::Failed
+        } else {
+            RunState::Successful
+        }
+    }
+
+    fn open_node(&mut self, call_stack: CallStack) {
+        *self.open_nodes.entry(call_stack).or_default() += 1;
+    }
+
+    fn close_node(&mut self, call_stack: &CallStack) {
+        if let Some(count) = self.open_nodes.get_mut(call_stack) {
+            *count -= 1;
+
+            if *count == 0 {
+                self.open_nodes.remove(call_stack);
+            }
+        }
     }
 }
 
 impl ThreadRunState {
+    /// A [`ThreadRunState`] that also sends every update to `forward_updates`.
+    ///
+    /// Unlike [`Self::subscribe`], no updates are dropped, and logs are
+    /// included. This is used to mirror the state of a run in another process
+    /// with [`Self::apply`].
+    pub fn forward_updates(forward_updates: mpsc::UnboundedSender<ThreadUpdate>) -> Self {
+        Self::new(Some(forward_updates), None)
+    }
+
+    /// A [`ThreadRunState`] that pages history out to disk when there's too
+    /// much to keep in memory.
+    pub fn with_history_limits(limits: HistoryLimits) -> Self {
+        Self::new(None, Some(limits))
+    }
+
+    fn new(
+        forward_updates: Option<mpsc::UnboundedSender<ThreadUpdate>>,
+        history_limits: Option<HistoryLimits>,
+    ) -> Self {
+        let (update_sender, _update_receiver) = broadcast::channel(UPDATE_BUFFER_SIZE);
+        let mut running = SlotMap::with_key();
+        let thread = running.insert(CallStack::new());
+
+        Self {
+            shared: Arc::new(RwLock::new(SharedThreadRunState {
+                history: History::new(history_limits),
+                open_nodes: HashMap::new(),
+                logs: Vec::new(),
+                running,
+                deadlines: SecondaryMap::new(),
+                step_cache: Arc::default(),
+                update_sender,
+                update_count: 0,
+                forward_updates,
+                secrets: Arc::default(),
+                input: Value::None,
+                workflow_dir: None,
+                globals: HashMap::new(),
+                host_call_time: DEFAULT_HOST_CALL_TIME,
+                subscriptions: 0,
+                lag_events: 0,
+            })),
+            paused: Pause::default(),
+            thread,
+            branch_depth: 0,
+        }
+    }
+
+    /// Make `secrets` available to the run.
+    ///
+    /// Secret values are masked in the logs.
+    #[must_use]
+    pub fn with_secrets(self, secrets: Arc<Secrets>) -> Self {
+        self.write().secrets = secrets;
+        self
+    }
+
+    /// Make `input` available to the run, with the `run_input()` built-in.
+    ///
+    /// This is what the trigger, webhook or user that started the run passed
+    /// in.
+    #[must_use]
+    pub fn with_input(self, input: Value) -> Self {
+        self.write().input = input;
+        self
+    }
+
+    /// The directory the workflow was loaded from.
+    ///
+    /// Files named by `@cache(files=[...])` are relative to this. If it's
+    /// `None`, they're relative to the current directory.
+    #[must_use]
+    pub fn with_workflow_dir(self, workflow_dir: Option<PathBuf>) -> Self {
+        self.write().workflow_dir = workflow_dir;
+        self
+    }
+
+    /// How long each host function call takes.
+    ///
+    /// Host functions aren't really called yet, so this is how long we wait
+    /// instead.
+    #[must_use]
+    pub fn with_host_call_time(self, host_call_time: Duration) -> Self {
+        self.write().host_call_time = host_call_time;
+        self
+    }
+
+    /// Share results of functions with `@cache` through `step_cache`.
+    ///
+    /// Otherwise, results are only cached for the rest of the run.
+    #[must_use]
+    pub fn with_step_cache(self, step_cache: Arc<StepCache>) -> Self {
+        self.write().step_cache = step_cache;
+        self
+    }
+
+    /// Start a new branch that runs in parallel with this one.
+    ///
+    /// `frame` is pushed onto the current stack to identify the branch. The
+    /// branch should be finished with [`Self::end_branch`].
+    pub fn branch(&self, frame: StackFrame) -> Self {
+        let mut data = self.write();
+        let stack = data.running[self.thread].push_cloned(frame);
+        let branch_depth = stack.len();
+        let thread = data.running.insert(stack);
+
+        if let Some(deadline) = data.deadlines.get(self.thread).copied() {
+            data.deadlines.insert(thread, deadline);
+        }
+
+        Self {
+            shared: self.shared.clone(),
+            paused: self.paused.clone(),
+            thread,
+            branch_depth,
+        }
+    }
+
+    /// Finish a branch started with [`Self::branch`].
+    ///
+    /// If the branch `failed`, anything it was running is marked as failed.
+    pub fn end_branch(self, failed: bool) {
+        let mut data = self.write();
+
+        if failed {
+            data.fail_thread(self.thread, self.branch_depth);
+        }
+
+        data.running.remove(self.thread);
+        data.deadlines.remove(self.thread);
+    }
+
+    /// Apply an update from a [`ThreadRunState`] in another process.
+    ///
+    /// See [`Self::forward_updates`].
+    pub fn apply(&self, update: ThreadUpdate) {
+        let mut data = self.write();
+
+        match update {
+            ThreadUpdate::RunState(call_stack, RunState::Running) => {
+                data.running.insert(call_stack.clone());
+                data.update(call_stack, RunState::Running);
+            }
+            ThreadUpdate::RunState(call_stack, run_state) => {
+                data.running.retain(|_, running| *running != call_stack);
+                data.pop_node(call_stack, run_state);
+            }
+            ThreadUpdate::Log(call_stack, message) => {
+                let message = data.secrets.mask(&message);
+                data.logs.push((call_stack, message))
+            }
+        }
+
+        drop(data);
+        self.compact_history();
+    }
+
+    /// Mark everything that's currently running as failed.
+    ///
+    /// This is for when the thread has stopped without popping it's stack, for
+    /// example because the interpreter panicked or the process running it
+    /// crashed.
+    pub fn fail_running(&self) {
+        let mut data = self.write();
+        let threads: Vec<ThreadKey> = data.running.keys().collect();
+
+        for thread in threads {
+            data.fail_thread(thread, 0);
+        }
+    }
+
+    /// Add a log message to the currently running node.
+    ///
+    /// Any secret values in `message` are masked.
+    pub fn log(&self, message: String) {
+        let mut data = self.write();
+        let node = innermost_node(&data.running[self.thread]);
+        let message = data.secrets.mask(&message);
+
+        if let Some(forward_updates) = &data.forward_updates {
+            let _ = forward_updates.send(ThreadUpdate::Log(node.clone(), message.clone()));
+        }
+
+        data.logs.push((node, message));
+    }
+
+    /// The log messages for a node, in the order they were logged.
+    pub fn logs(&self, stack: &CallStack) -> Vec<String> {
+        self.read()
+            .logs
+            .iter()
+            .filter(|(call_stack, _)| call_stack == stack)
+            .map(|(_, message)| message.clone())
+            .collect()
+    }
+
+    /// The overall state of the run.
+    pub fn status(&self) -> RunState {
+        self.read().status()
+    }
+
+    /// The number of clients currently subscribed with [`Self::subscribe`].
+    pub fn subscription_count(&self) -> usize {
+        self.read().subscriptions
+    }
+
+    /// The number of times a subscribed client fell behind, and had to be
+    /// resynced.
+    pub fn lag_events(&self) -> u64 {
+        self.read().lag_events
+    }
+
+    pub fn secret(&self, name: &str) -> Option<Secret> {
+        self.read().secrets.get(name)
+    }
+
+    /// The input set with [`Self::with_input`].
+    pub fn input(&self) -> Value {
+        self.read().input.clone()
+    }
+
+    /// The directory set with [`Self::with_workflow_dir`].
+    pub fn workflow_dir(&self) -> Option<PathBuf> {
+        self.read().workflow_dir.clone()
+    }
+
+    /// The value of the module level variable `name` in `module`.
+    pub fn global(&self, module: Option<&str>, name: &str) -> Option<Value> {
+        self.read()
+            .globals
+            .get(&(module.map(str::to_owned), name.to_owned()))
+            .cloned()
+    }
+
+    /// Set a module level variable, for every thread in the run.
+    pub fn set_global(&self, module: Option<&str>, name: &str, value: Value) {
+        self.write()
+            .globals
+            .insert((module.map(str::to_owned), name.to_owned()), value);
+    }
+
     pub fn run_state(&self, stack: &CallStack) -> RunState {
         let data = self.read();
 
-        if data.current.starts_with(stack) {
+        if data.is_running(stack) {
             return RunState::Running;
         }
 
-        match data
-            .history
-            .binary_search_by_key(&stack, |(call_stack, _)| call_stack)
-        {
-            Ok(match_index) => data.history[match_index].1,
-            Err(_) => RunState::NotRun,
+        data.history.get(stack).unwrap_or(RunState::NotRun)
+    }
+
+    /// The run states of the child nodes of `call_stack`.
+    pub fn child_states(&self, call_stack: &CallStack) -> Vec<(CallStack, RunState)> {
+        self.read().child_states(call_stack)
+    }
+
+    /// Get the overall state of the run, what's currently running, and the
+    /// states of the children of each of `open_nodes`.
+    pub fn snapshot(&self, open_nodes: &[CallStack]) -> Snapshot {
+        let data = self.read();
+
+        Snapshot {
+            update_count: data.update_count,
+            status: data.status(),
+            paused: self.paused.is_paused(),
+            running: data
+                .running
+                .values()
+                .filter(|running| !running.is_empty())
+                .cloned()
+                .collect(),
+            child_states: open_nodes
+                .iter()
+                .flat_map(|call_stack| data.child_states(call_stack))
+                .collect(),
         }
     }
 
+    /// Find the children of `call_stack` by checking every finished node in
+    /// memory, without using the index.
+    ///
+    /// This ignores running nodes, and history that's been paged out. It's
+    /// only used to benchmark against [`Self::child_states`].
+    #[doc(hidden)]
+    pub fn scan_child_states(&self, call_stack: &CallStack) -> Vec<(CallStack, RunState)> {
+        self.read().history.scan_children(call_stack)
+    }
+
+    /// The summary of a completed subtree, if it's been paged out to disk.
+    ///
+    /// See [`HistoryLimits`].
+    pub fn summary(&self, root: &CallStack) -> Option<Summary> {
+        self.read().history.summary(root)
+    }
+
     pub fn push(&self, item: StackFrame) {
         let mut data = self.write();
-        data.current.push(item);
+        let current = &mut data.running[self.thread];
+        current.push(item);
 
-        if data.current.is_node() {
-            data.update(data.current.clone(), RunState::Running);
+        if current.is_node() {
+            let current = current.clone();
+            data.update(current, RunState::Running);
         }
     }
 
@@ -176,38 +776,249 @@ impl ThreadRunState {
         self.pop(RunState::PredicateSuccessful(result));
     }
 
-    fn pop(&self, run_state: RunState) {
-        let mut data = self.write();
-        clone!(data.current);
+    pub fn pop_cached(&self) {
+        self.pop(RunState::Cached);
+    }
+
+    /// Run `f` in a new node, identified by `frame`, unless there's already a
+    /// result for `key` in the step cache.
+    ///
+    /// On a cache hit, the logs from the original call are replayed onto the
+    /// new node, and `f` isn't run. Otherwise, the result of `f`, and
+    /// everything it logged, is cached. Errors aren't cached.
+    pub fn run_cached(
+        &self,
+        frame: StackFrame,
+        key: &CacheKey,
+        f: impl FnOnce() -> Result<Value, RunError>,
+    ) -> Result<Value, RunError> {
+        let step_cache = self.read().step_cache.clone();
+
+        if let Some(entry) = step_cache.get(key) {
+            self.push(frame);
+
+            for message in entry.logs {
+                self.log(message);
+            }
 
-        if let Some(last) = data.history.last() {
-            assert!(last.0 < current);
+            self.pop_cached();
+            return Ok(entry.value);
         }
 
-        if current.is_node() {
-            data.history.push((current.clone(), run_state));
-            data.update(current, run_state);
+        self.try_run(frame, || {
+            let (node, first_log) = {
+                let data = self.read();
+                (innermost_node(&data.running[self.thread]), data.logs.len())
+            };
+            let value = f()?;
+            let logs = self.read().logs[first_log..]
+                .iter()
+                .filter(|(call_stack, _)| call_stack.starts_with(&node))
+                .map(|(_, message)| message.clone())
+                .collect();
+            step_cache.insert(
+                *key,
+                CacheEntry {
+                    value: value.clone(),
+                    logs,
+                },
+            );
+
+            Ok(value)
+        })
+    }
+
+    /// Run `f` in a new node, identified by `frame`.
+    ///
+    /// If `f` fails, the node and anything still running inside it are marked
+    /// as failed.
+    pub fn try_run<T>(
+        &self,
+        frame: StackFrame,
+        f: impl FnOnce() -> Result<T, RunError>,
+    ) -> Result<T, RunError> {
+        let depth = self.read().running[self.thread].len();
+        self.push(frame);
+        let result = f();
+
+        if result.is_ok() {
+            self.pop_success();
+        } else {
+            self.write().fail_thread(self.thread, depth);
+        }
+
+        result
+    }
+
+    /// Run `f`, failing if it takes longer than `timeout`.
+    ///
+    /// The deadline is checked with [`Self::check_timeout`], and interrupts
+    /// host function calls made with [`Self::host_call`]. Parallel branches
+    /// inherit the deadline, and nested timeouts can only make it earlier.
+    pub fn with_timeout<T>(&self, timeout: Duration, f: impl FnOnce() -> T) -> T {
+        let deadline = Instant::now() + timeout;
+        let previous = {
+            let mut data = self.write();
+            let previous = data.deadlines.get(self.thread).copied();
+            data.deadlines.insert(
+                self.thread,
+                previous.map_or(deadline, |previous| previous.min(deadline)),
+            );
+            previous
+        };
+        let _restore = scopeguard::guard(previous, |previous| {
+            let mut data = self.write();
+
+            match previous {
+                Some(previous) => data.deadlines.insert(self.thread, previous),
+                None => data.deadlines.remove(self.thread),
+            };
+        });
+
+        f()
+    }
+
+    /// Check the deadline set by [`Self::with_timeout`].
+    pub fn check_timeout(&self) -> Result<(), RunError> {
+        let timed_out = self
+            .deadline()
+            .is_some_and(|deadline| Instant::now() >= deadline);
+
+        if timed_out {
+            self.fail(RunError::Timeout)
+        } else {
+            Ok(())
+        }
+    }
+
+    /// Call a host function, giving up if the deadline set by
+    /// [`Self::with_timeout`] passes first.
+    ///
+    /// The call runs on it's own thread, so the interpreter isn't blocked by
+    /// it. If we give up on it, it's left to finish in the background.
+    pub fn host_call(&self) -> Result<(), RunError> {
+        let host_call_time = self.read().host_call_time;
+        let Some(deadline) = self.deadline() else {
+            sleep(host_call_time);
+            return Ok(());
+        };
+        let (finished_sender, finished) = std_mpsc::channel();
+
+        // TODO: Call the host function.
+        thread::spawn(move || {
+            sleep(host_call_time);
+            let _ = finished_sender.send(());
+        });
+
+        let timeout = deadline.saturating_duration_since(Instant::now());
+
+        if finished.recv_timeout(timeout).is_ok() {
+            Ok(())
+        } else {
+            self.fail(RunError::Timeout)
+        }
+    }
+
+    /// Log `error` on the current node, and return it.
+    pub fn fail<T>(&self, error: RunError) -> Result<T, RunError> {
+        self.log(error.to_string());
+        Err(error)
+    }
+
+    /// Pause and resume the run.
+    pub fn pause(&self) -> &Pause {
+        &self.paused
+    }
+
+    fn deadline(&self) -> Option<Instant> {
+        self.read().deadlines.get(self.thread).copied()
+    }
+
+    fn pop(&self, run_state: RunState) {
+        {
+            let mut data = self.write();
+            let current = data.running[self.thread].clone();
+
+            if current.is_node() {
+                data.pop_node(current, run_state);
+            }
+
+            data.running[self.thread].pop();
         }
 
-        data.current.pop();
+        self.compact_history();
+    }
+
+    /// Page history out to disk, if there's too much in memory.
+    ///
+    /// We only hold the lock while choosing what to page out, and while
+    /// recording where it went, not while writing it.
+    fn compact_history(&self) {
+        let spill = {
+            let mut data = self.write();
+
+            if !data.history.needs_compaction() {
+                return;
+            }
+
+            let SharedThreadRunState {
+                history,
+                open_nodes,
+                running,
+                ..
+            } = &mut *data;
+
+            // Keep anything that's running or open in memory.
+            history.start_spill(|call_stack| {
+                open_nodes.keys().any(|open| open.starts_with(call_stack))
+                    || running
+                        .values()
+                        .any(|running| running.starts_with(call_stack))
+            })
+        };
+
+        if let Some(spill) = spill {
+            let written = spill.write();
+            self.write().history.finish_spill(written);
+        }
     }
 
+    /// Subscribe to updates to the children of the nodes from `open_nodes`.
+    ///
+    /// Each update is numbered, so a client can tell whether it's already
+    /// included in a [`Snapshot`]. When a node is opened, or the client has
+    /// to be resynced, the current states are sent, numbered with the update
+    /// count at the time.
     pub fn subscribe(
         &self,
         open_nodes: impl Stream<Item = CallStack> + Send + 'static,
     ) -> (
-        mpsc::Receiver<(CallStack, RunState)>,
+        mpsc::Receiver<(u64, CallStack, RunState)>,
         impl Future<Output = ()> + Send + 'static,
     ) {
-        let run_state_updates = BroadcastStream::new(self.read().update_sender.subscribe())
-            .map_while(Result::ok)
-            .map(|(call_stack, run_state)| UpdateClient::UpdateRunState(call_stack, run_state));
+        let update_receiver = self.read().update_sender.subscribe();
+        let run_state_updates = stream::unfold(update_receiver, |mut update_receiver| async {
+            let update = match update_receiver.recv().await {
+                Ok((update_number, call_stack, run_state)) => {
+                    UpdateClient::UpdateRunState(update_number, call_stack, run_state)
+                }
+                Err(RecvError::Lagged(_)) => {
+                    // Anything left in the queue is older than the snapshot we'll send
+                    // to resync the client, so skip to the latest update.
+                    update_receiver = update_receiver.resubscribe();
+                    UpdateClient::Lagged
+                }
+                Err(RecvError::Closed) => return None,
+            };
+
+            Some((update, update_receiver))
+        });
         let open_nodes = open_nodes.map(UpdateClient::OpenNode);
 
         let updates = stream::select(run_state_updates, open_nodes);
 
         // TODO: Channel bounds
-        let (run_state_sender, run_state_receiver) = mpsc::channel(1000);
+        let (run_state_sender, run_state_receiver) = mpsc::channel(UPDATE_BUFFER_SIZE);
 
         let update_client = {
             let thread_run_state = self.clone();
@@ -221,90 +1032,129 @@ impl ThreadRunState {
         (run_state_receiver, update_client)
     }
 
+    /// Send updates to a client until it disconnects.
     async fn update_client(
         &self,
-        send_run_state: mpsc::Sender<(CallStack, RunState)>,
+        send_run_state: mpsc::Sender<(u64, CallStack, RunState)>,
         updates: impl Stream<Item = UpdateClient>,
     ) {
-        let mut open_nodes = HashSet::new();
+        self.write().subscriptions += 1;
+
+        // Let the history know which nodes are open, so it can keep them in memory.
+        let mut open_nodes = scopeguard::guard(HashSet::new(), |open_nodes| {
+            let mut data = self.write();
+            data.subscriptions -= 1;
+
+            for call_stack in &open_nodes {
+                data.close_node(call_stack);
+            }
+        });
         let mut updates = pin!(updates);
 
         while let Some(update) = updates.next().await {
-            match update {
-                UpdateClient::UpdateRunState(call_stack, run_state) => {
+            let (update_number, states) = match update {
+                UpdateClient::UpdateRunState(update_number, call_stack, run_state) => {
                     assert!(call_stack.is_node());
 
-                    if let Some(parent) = call_stack.parent() {
-                        if open_nodes.contains(&parent) {
-                            send_run_state
-                                .send((call_stack.clone(), run_state))
-                                .await
-                                .unwrap();
+                    match call_stack.parent() {
+                        Some(parent) if open_nodes.contains(&parent) => {
+                            (update_number, vec![(call_stack, run_state)])
                         }
+                        _ => (update_number, Vec::new()),
                     }
                 }
                 UpdateClient::OpenNode(call_stack) => {
-                    println!("Opening node {call_stack:?}");
-
-                    let mut child_states = Vec::new();
+                    let mut data = self.write();
+                    let child_states = data.child_states(&call_stack);
 
-                    {
-                        let data = self.read();
-
-                        if data.current.is_node() && data.current.starts_with(&call_stack) {
-                            clone!(mut data.current);
+                    if open_nodes.insert(call_stack.clone()) {
+                        data.open_node(call_stack);
+                    }
 
-                            while current.len() > call_stack.len() {
-                                child_states.push((current.clone(), RunState::Running));
-                                current = current.parent().unwrap();
-                            }
-                        }
+                    (data.update_count, child_states)
+                }
+                UpdateClient::Lagged => {
+                    let mut data = self.write();
+                    data.lag_events += 1;
 
-                        let mut last_matching = match data
-                            .history
-                            .binary_search_by_key(&&call_stack, |(call_stack, _)| call_stack)
-                        {
-                            Ok(match_index) => match_index,
-                            Err(insert_index) => insert_index,
-                        };
-
-                        while last_matching > 0
-                            && data.history[last_matching - 1].0.starts_with(&call_stack)
-                        {
-                            last_matching -= 1;
-                            // TODO: This is pretty inefficient
-                            let child_stack = &data.history[last_matching];
-
-                            if child_stack.0.parent().as_ref() == Some(&call_stack) {
-                                child_states.push(child_stack.clone());
-                            }
-                        }
-                    }
+                    (
+                        data.update_count,
+                        open_nodes
+                            .iter()
+                            .flat_map(|call_stack| data.child_states(call_stack))
+                            .collect(),
+                    )
+                }
+            };
 
-                    for state in child_states {
-                        assert!(state.0.is_node());
-                        send_run_state.send(state).await.unwrap();
-                    }
+            for (call_stack, run_state) in states {
+                assert!(call_stack.is_node());
 
-                    open_nodes.insert(call_stack);
+                if send_run_state
+                    .send((update_number, call_stack, run_state))
+                    .await
+                    .is_err()
+                {
+                    return;
                 }
             }
         }
     }
 
     fn read(&self) -> RwLockReadGuard<'_, SharedThreadRunState> {
-        self.0.read().unwrap()
+        self.shared.read().unwrap()
     }
 
     fn write(&self) -> RwLockWriteGuard<'_, SharedThreadRunState> {
-        self.0.write().unwrap()
+        self.shared.write().unwrap()
+    }
+}
+
+fn innermost_node(stack: &CallStack) -> CallStack {
+    if stack.is_node() {
+        stack.clone()
+    } else {
+        stack.parent().unwrap_or_default()
     }
 }
 
+const UPDATE_BUFFER_SIZE: usize = 1000;
+const DEFAULT_HOST_CALL_TIME: Duration = Duration::from_secs(1);
+
 #[derive(Clone)]
 enum UpdateClient {
     OpenNode(CallStack),
-    UpdateRunState(CallStack, RunState),
+    /// A numbered update, from [`SharedThreadRunState::update`].
+    UpdateRunState(u64, CallStack, RunState),
+    /// The client fell behind, and missed some updates.
+    Lagged,
+}
+
+/// The state of a run at a point in time.
+///
+/// See [`ThreadRunState::snapshot`].
+#[derive(Clone, Debug, Eq, PartialEq, Serialize, Deserialize)]
+pub struct Snapshot {
+    /// The number of updates included in the snapshot.
+    ///
+    /// Updates from [`ThreadRunState::subscribe`] numbered lower than this
+    /// are already included, so they can be ignored.
+    pub update_count: u64,
+    pub status: RunState,
+    /// See [`ThreadRunState::pause`].
+    pub paused: bool,
+    /// The stack of each running thread.
+    pub running: Vec<CallStack>,
+    /// The states of the children of the requested nodes.
+    pub child_states: Vec<(CallStack, RunState)>,
+}
+
+/// An update from a [`ThreadRunState`] created with
+/// [`ThreadRunState::forward_updates`].
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub enum ThreadUpdate {
+    RunState(CallStack, RunState),
+    Log(CallStack, String),
 }
 
 #[derive(Copy, Clone, Eq, PartialEq, Debug, Serialize, Deserialize)]
@@ -314,4 +1164,416 @@ pub enum RunState {
     Successful,
     PredicateSuccessful(bool),
     Failed,
+    /// The result was reused from an earlier call, with `@cache`.
+    Cached,
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{
+        collections::{HashMap, HashSet},
+        env,
+        future::Future,
+        iter, process,
+        sync::Arc,
+        thread,
+        time::Duration,
+    };
+
+    use tokio::{spawn, sync::mpsc, task::yield_now, time::timeout};
+    use tokio_stream::wrappers::UnboundedReceiverStream;
+
+    use super::{
+        CallStack, HistoryLimits, NestedBlock, RunState, StackFrame, ThreadRunState, ThreadUpdate,
+        UPDATE_BUFFER_SIZE,
+    };
+    use crate::{
+        secrets::{Secrets, MASK},
+        syntax_tree::Value,
+    };
+
+    #[tokio::test]
+    async fn slow_consumer() {
+        let thread_run_state = ThreadRunState::default();
+        let (open_nodes, mut run_states, update_client) = subscribe(&thread_run_state);
+        open_nodes.send(CallStack::new()).unwrap();
+        spawn(update_client);
+        yield_now().await;
+
+        // We're not reading any updates yet, so this will overflow the buffers.
+        let node_count = UPDATE_BUFFER_SIZE * 3;
+
+        for index in 0..node_count {
+            run_node(&thread_run_state, index);
+        }
+
+        let received = receive_all(&mut run_states).await;
+        assert_eq!(received.len(), node_count);
+        assert!(received
+            .values()
+            .all(|run_state| *run_state == RunState::PredicateSuccessful(true)));
+
+        // Make sure we still get updates after lagging.
+        run_node(&thread_run_state, node_count);
+        let received = receive_all(&mut run_states).await;
+        assert_eq!(
+            received.get(&node(node_count)),
+            Some(&RunState::PredicateSuccessful(true))
+        );
+        assert!(thread_run_state.lag_events() > 0);
+    }
+
+    #[tokio::test]
+    async fn numbered_updates() {
+        let thread_run_state = ThreadRunState::default();
+        let (open_nodes, mut run_states, update_client) = subscribe(&thread_run_state);
+        open_nodes.send(CallStack::new()).unwrap();
+        spawn(update_client);
+        yield_now().await;
+
+        run_node(&thread_run_state, 0);
+        let snapshot = thread_run_state.snapshot(&[CallStack::new()]);
+        run_node(&thread_run_state, 1);
+
+        assert_eq!(
+            snapshot.child_states,
+            [(node(0), RunState::PredicateSuccessful(true))]
+        );
+
+        let mut new_updates = Vec::new();
+
+        while let Ok(Some((update_number, call_stack, run_state))) =
+            timeout(SHORT_TIMEOUT, run_states.recv()).await
+        {
+            if update_number >= snapshot.update_count {
+                new_updates.push((call_stack, run_state));
+            }
+        }
+
+        assert_eq!(
+            new_updates,
+            [
+                (node(1), RunState::Running),
+                (node(1), RunState::PredicateSuccessful(true))
+            ]
+        );
+    }
+
+    #[tokio::test]
+    async fn client_disconnects() {
+        let thread_run_state = ThreadRunState::default();
+        let (open_nodes, run_states, update_client) = subscribe(&thread_run_state);
+        open_nodes.send(CallStack::new()).unwrap();
+        let update_client = spawn(update_client);
+        yield_now().await;
+
+        assert_eq!(thread_run_state.subscription_count(), 1);
+
+        drop(run_states);
+        run_node(&thread_run_state, 0);
+
+        timeout(TIMEOUT, update_client).await.unwrap().unwrap();
+        assert_eq!(thread_run_state.subscription_count(), 0);
+    }
+
+    #[test]
+    fn parallel_branches() {
+        let thread_run_state = ThreadRunState::default();
+        thread_run_state.push(node_frame(0));
+
+        let branches: Vec<ThreadRunState> = (0..2)
+            .map(|index| thread_run_state.branch(StackFrame::Branch(index)))
+            .collect();
+
+        for branch in &branches {
+            branch.push(node_frame(0));
+        }
+
+        let branch_nodes: Vec<CallStack> = (0..2)
+            .map(|index| {
+                node(0)
+                    .push_cloned(StackFrame::Branch(index))
+                    .push_cloned(node_frame(0))
+            })
+            .collect();
+
+        for branch_node in &branch_nodes {
+            assert_eq!(thread_run_state.run_state(branch_node), RunState::Running);
+        }
+
+        let mut branches = branches.into_iter();
+        let failed_branch = branches.next().unwrap();
+        let successful_branch = branches.next().unwrap();
+
+        successful_branch.pop_predicate_success(true);
+        successful_branch.end_branch(false);
+        failed_branch.end_branch(true);
+
+        assert_eq!(
+            thread_run_state.run_state(&branch_nodes[0]),
+            RunState::Failed
+        );
+        assert_eq!(
+            thread_run_state.run_state(&branch_nodes[1]),
+            RunState::PredicateSuccessful(true)
+        );
+        assert_eq!(thread_run_state.run_state(&node(0)), RunState::Running);
+
+        thread_run_state.pop_failed();
+        assert_eq!(thread_run_state.run_state(&node(0)), RunState::Failed);
+    }
+
+    #[test]
+    fn call_stack_order() {
+        let parent = node(0);
+        let child = parent.push_cloned(node_frame(1));
+        let sibling = parent.push_cloned(node_frame(2));
+
+        assert!(parent > child);
+        assert!(child < sibling);
+        assert!(node(1) > sibling);
+        assert_eq!(child, CallStack::from(vec![node_frame(0), node_frame(1)]));
+        assert!(child.starts_with(&parent));
+        assert!(!parent.starts_with(&child));
+        assert_eq!(child.parent(), Some(parent));
+        assert_eq!(Vec::from(child), vec![node_frame(0), node_frame(1)]);
+    }
+
+    #[test]
+    fn deep_call_stack() {
+        let depth = 1_000_000;
+        let deep = CallStack::from(vec![node_frame(0); depth]);
+        let mut deeper = deep.clone();
+        deeper.push(node_frame(1));
+        let mut other = CallStack::from(vec![node_frame(0); depth]);
+        other.push(node_frame(2));
+
+        assert_eq!(deep, CallStack::from(Vec::from(deep.clone())));
+        assert!(deeper < other);
+        assert!(deep > deeper);
+        assert_eq!(deeper.parent().as_ref(), Some(&deep));
+
+        let mut hashes = HashSet::new();
+        hashes.insert(deep.clone());
+        assert!(hashes.contains(&other.parent().unwrap()));
+        assert!(!hashes.contains(&other));
+
+        drop(hashes);
+        drop(deep);
+        drop(deeper);
+        drop(other);
+    }
+
+    #[test]
+    fn child_states() {
+        let thread_run_state = ThreadRunState::default();
+
+        for index in 0..3 {
+            thread_run_state.push(node_frame(index));
+
+            for child_index in 0..2 {
+                run_node(&thread_run_state, child_index);
+            }
+
+            thread_run_state.pop_success();
+        }
+
+        thread_run_state.push(node_frame(3));
+        run_node(&thread_run_state, 0);
+
+        let mut expected: Vec<_> = (0..3)
+            .map(|index| (node(index), RunState::Successful))
+            .collect();
+        expected.push((node(3), RunState::Running));
+        assert_same_states(thread_run_state.child_states(&CallStack::new()), expected);
+
+        for index in 0..4 {
+            let child_count = if index == 3 { 1 } else { 2 };
+            let expected = (0..child_count)
+                .map(|child_index| {
+                    (
+                        node(index).push_cloned(node_frame(child_index)),
+                        RunState::PredicateSuccessful(true),
+                    )
+                })
+                .collect();
+
+            assert_same_states(thread_run_state.child_states(&node(index)), expected);
+        }
+
+        for index in 0..3 {
+            assert_same_states(
+                thread_run_state.scan_child_states(&node(index)),
+                thread_run_state.child_states(&node(index)),
+            );
+        }
+    }
+
+    #[test]
+    fn history_limits() {
+        let spill_file = env::temp_dir().join(format!(
+            "serpent-automation-{}-run-history-limits.history",
+            process::id()
+        ));
+        let thread_run_state =
+            ThreadRunState::with_history_limits(HistoryLimits::new(0, &spill_file));
+
+        for index in 0..3 {
+            thread_run_state.push(node_frame(index));
+
+            for child_index in 0..2 {
+                run_node(&thread_run_state, child_index);
+            }
+
+            thread_run_state.pop_success();
+        }
+
+        for index in 0..3 {
+            let summary = thread_run_state.summary(&node(index)).unwrap();
+            assert_eq!(summary.descendants(), 2);
+            assert_eq!(summary.failed(), 0);
+            assert!(thread_run_state.scan_child_states(&node(index)).is_empty());
+
+            let expected = (0..2)
+                .map(|child_index| {
+                    (
+                        node(index).push_cloned(node_frame(child_index)),
+                        RunState::PredicateSuccessful(true),
+                    )
+                })
+                .collect();
+            assert_same_states(thread_run_state.child_states(&node(index)), expected);
+        }
+
+        assert!(spill_file.exists());
+        drop(thread_run_state);
+        assert!(!spill_file.exists());
+    }
+
+    #[test]
+    fn snapshot() {
+        let thread_run_state = ThreadRunState::default();
+        assert_eq!(thread_run_state.snapshot(&[]).status, RunState::NotRun);
+
+        run_node(&thread_run_state, 0);
+        thread_run_state.push(node_frame(1));
+        run_node(&thread_run_state, 0);
+
+        let snapshot = thread_run_state.snapshot(&[CallStack::new(), node(1)]);
+        assert_eq!(snapshot.status, RunState::Running);
+        assert_eq!(snapshot.running, vec![node(1)]);
+        assert_same_states(
+            snapshot.child_states,
+            vec![
+                (node(0), RunState::PredicateSuccessful(true)),
+                (node(1), RunState::Running),
+                (
+                    node(1).push_cloned(node_frame(0)),
+                    RunState::PredicateSuccessful(true),
+                ),
+            ],
+        );
+
+        thread_run_state.pop_failed();
+        assert_eq!(thread_run_state.snapshot(&[]).status, RunState::Failed);
+    }
+
+    #[test]
+    fn mask_secrets() {
+        let secrets = Secrets::new([("TOKEN".to_owned(), "token-value".to_owned())]);
+        let (update_sender, mut updates) = mpsc::unbounded_channel();
+        let thread_run_state =
+            ThreadRunState::forward_updates(update_sender).with_secrets(Arc::new(secrets));
+        let token = Value::Secret(thread_run_state.secret("TOKEN").unwrap());
+
+        thread_run_state.push(node_frame(0));
+        thread_run_state.log(format!("deploy({token:?})"));
+        thread_run_state.log("Using token-value".to_owned());
+
+        let expected = vec![
+            r#"deploy(Secret(secret("TOKEN")))"#.to_owned(),
+            format!("Using {MASK}"),
+        ];
+        assert_eq!(thread_run_state.logs(&node(0)), expected);
+
+        let forwarded: Vec<String> = iter::from_fn(|| updates.try_recv().ok())
+            .filter_map(|update| match update {
+                ThreadUpdate::Log(_, message) => Some(message),
+                ThreadUpdate::RunState(..) => None,
+            })
+            .collect();
+        assert_eq!(forwarded, expected);
+    }
+
+    #[test]
+    fn pause() {
+        let thread_run_state = ThreadRunState::default();
+        let pause = thread_run_state.pause();
+        pause.set(true);
+        assert!(pause.is_paused());
+
+        let waiting = thread::spawn({
+            let thread_run_state = thread_run_state.branch(StackFrame::Statement(0));
+            move || thread_run_state.pause().wait()
+        });
+        thread::sleep(Duration::from_millis(100));
+        assert!(!waiting.is_finished());
+
+        pause.set(false);
+        waiting.join().unwrap();
+        assert!(!pause.is_paused());
+    }
+
+    fn assert_same_states(
+        mut actual: Vec<(CallStack, RunState)>,
+        mut expected: Vec<(CallStack, RunState)>,
+    ) {
+        actual.sort_by(|(stack0, _), (stack1, _)| stack0.cmp(stack1));
+        expected.sort_by(|(stack0, _), (stack1, _)| stack0.cmp(stack1));
+        assert_eq!(actual, expected);
+    }
+
+    fn subscribe(
+        thread_run_state: &ThreadRunState,
+    ) -> (
+        mpsc::UnboundedSender<CallStack>,
+        mpsc::Receiver<(u64, CallStack, RunState)>,
+        impl Future<Output = ()>,
+    ) {
+        let (open_nodes_sender, open_nodes) = mpsc::unbounded_channel();
+        let (run_states, update_client) =
+            thread_run_state.subscribe(UnboundedReceiverStream::new(open_nodes));
+
+        (open_nodes_sender, run_states, update_client)
+    }
+
+    async fn receive_all(
+        run_states: &mut mpsc::Receiver<(u64, CallStack, RunState)>,
+    ) -> HashMap<CallStack, RunState> {
+        let mut received = HashMap::new();
+
+        while let Ok(Some((_update_number, call_stack, run_state))) =
+            timeout(SHORT_TIMEOUT, run_states.recv()).await
+        {
+            received.insert(call_stack, run_state);
+        }
+
+        received
+    }
+
+    fn run_node(thread_run_state: &ThreadRunState, index: usize) {
+        thread_run_state.push(node_frame(index));
+        thread_run_state.pop_predicate_success(true);
+    }
+
+    fn node(index: usize) -> CallStack {
+        CallStack::new().push_cloned(node_frame(index))
+    }
+
+    fn node_frame(index: usize) -> StackFrame {
+        StackFrame::NestedBlock(index, NestedBlock::Predicate)
+    }
+
+    const TIMEOUT: Duration = Duration::from_secs(10);
+    const SHORT_TIMEOUT: Duration = Duration::from_millis(100);
 }
@@ -0,0 +1,512 @@
+use std::{
+    collections::{BTreeMap, HashMap},
+    fs::{self, File},
+    io::{self, Read, Seek, SeekFrom, Write},
+    path::PathBuf,
+    sync::{Arc, Mutex},
+};
+
+use super::{CallStack, RunState};
+
+/// Limit how much history is kept in memory.
+///
+/// When there are more than `max_in_memory` finished nodes in memory,
+/// completed subtrees that no client has open are summarised, and paged out to
+/// `spill_file`. They're read back from the file when a client opens a node in
+/// them.
+#[derive(Clone, Debug)]
+pub struct HistoryLimits {
+    max_in_memory: usize,
+    spill_file: PathBuf,
+}
+
+impl HistoryLimits {
+    /// Constructor
+    ///
+    /// `spill_file` is created when it's first needed, and removed when the
+    /// history is dropped.
+    pub fn new(max_in_memory: usize, spill_file: impl Into<PathBuf>) -> Self {
+        Self {
+            max_in_memory,
+            spill_file: spill_file.into(),
+        }
+    }
+}
+
+/// What's below the root of a subtree that's been paged out.
+#[derive(Copy, Clone, Default, Debug, Eq, PartialEq)]
+pub struct Summary {
+    descendants: usize,
+    failed: usize,
+}
+
+impl Summary {
+    /// The number of finished nodes below the root.
+    pub fn descendants(&self) -> usize {
+        self.descendants
+    }
+
+    /// The number of nodes below the root that failed.
+    pub fn failed(&self) -> usize {
+        self.failed
+    }
+
+    fn add(&mut self, nodes: &[(CallStack, RunState)]) {
+        self.descendants += nodes.len();
+        self.failed += nodes
+            .iter()
+            .filter(|(_, run_state)| *run_state == RunState::Failed)
+            .count();
+    }
+}
+
+/// The run states of finished nodes, indexed by their parent node.
+pub(super) struct History {
+    in_memory: BTreeMap<CallStack, BTreeMap<CallStack, RunState>>,
+    in_memory_len: usize,
+    /// Subtrees that are being written to the spill file, by their root.
+    writing: HashMap<CallStack, Subtree>,
+    /// Subtrees in the spill file, by their root.
+    spilled: HashMap<CallStack, Spilled>,
+    limits: Option<HistoryLimits>,
+    /// We compact when there are more than this many nodes in memory.
+    compact_at: usize,
+    spill_file: Arc<Mutex<Option<File>>>,
+}
+
+/// All the finished nodes below the root of a subtree.
+type Subtree = Arc<Vec<(CallStack, RunState)>>;
+
+#[derive(Default)]
+struct Spilled {
+    summary: Summary,
+    records: Vec<SpillRecord>,
+}
+
+pub(super) struct SpillRecord {
+    offset: u64,
+    len: usize,
+}
+
+impl History {
+    pub fn new(limits: Option<HistoryLimits>) -> Self {
+        Self {
+            in_memory: BTreeMap::new(),
+            in_memory_len: 0,
+            writing: HashMap::new(),
+            spilled: HashMap::new(),
+            compact_at: limits
+                .as_ref()
+                .map_or(usize::MAX, |limits| limits.max_in_memory),
+            limits,
+            spill_file: Arc::default(),
+        }
+    }
+
+    pub fn insert(&mut self, call_stack: CallStack, run_state: RunState) {
+        if let Some(parent) = call_stack.parent() {
+            let children = self.in_memory.entry(parent).or_default();
+
+            if children.insert(call_stack, run_state).is_none() {
+                self.in_memory_len += 1;
+            }
+        }
+    }
+
+    pub fn get(&self, call_stack: &CallStack) -> Option<RunState> {
+        let parent = call_stack.parent()?;
+
+        if let Some(run_state) = self
+            .in_memory
+            .get(&parent)
+            .and_then(|children| children.get(call_stack))
+        {
+            return Some(*run_state);
+        }
+
+        self.paged_out(&parent)
+            .into_iter()
+            .rev()
+            .find_map(|(child, run_state)| (child == *call_stack).then_some(run_state))
+    }
+
+    /// The run states of the children of `parent`.
+    pub fn children(&self, parent: &CallStack) -> Vec<(CallStack, RunState)> {
+        let mut children: BTreeMap<CallStack, RunState> = self
+            .paged_out(parent)
+            .into_iter()
+            .filter(|(child, _)| child.parent().as_ref() == Some(parent))
+            .collect();
+
+        if let Some(in_memory) = self.in_memory.get(parent) {
+            children.extend(
+                in_memory
+                    .iter()
+                    .map(|(child, run_state)| (child.clone(), *run_state)),
+            );
+        }
+
+        children.into_iter().collect()
+    }
+
+    /// Find the children of `parent` by checking every finished node in
+    /// memory, without using the index.
+    pub fn scan_children(&self, parent: &CallStack) -> Vec<(CallStack, RunState)> {
+        self.in_memory
+            .values()
+            .flatten()
+            .filter(|(child, _)| child.parent().as_ref() == Some(parent))
+            .map(|(child, run_state)| (child.clone(), *run_state))
+            .collect()
+    }
+
+    /// The summary of the subtree rooted at `root`, if it's been paged out.
+    pub fn summary(&self, root: &CallStack) -> Option<Summary> {
+        self.spilled.get(root).map(|spilled| spilled.summary)
+    }
+
+    /// Is there too much history in memory?
+    pub fn needs_compaction(&self) -> bool {
+        self.in_memory_len > self.compact_at && self.writing.is_empty()
+    }
+
+    /// Choose completed subtrees to page out, oldest first, until there are at
+    /// most half of `max_in_memory` nodes left in memory.
+    ///
+    /// `is_pinned(node)` should be `true` if anything at or below `node` is
+    /// running, or open in a client. Subtrees with anything pinned are kept in
+    /// memory, and so are the top level nodes.
+    ///
+    /// The subtrees are still readable until they've been written to disk with
+    /// [`Spill::write`], and the result passed to [`Self::finish_spill`].
+    pub fn start_spill(&mut self, is_pinned: impl Fn(&CallStack) -> bool) -> Option<Spill> {
+        let limits = self.limits.as_ref()?;
+        let target = limits.max_in_memory / 2;
+        let path = limits.spill_file.clone();
+
+        // Descendants sort immediately before their ancestors, so walking
+        // backwards we see each subtree's root before anything in it.
+        let mut roots = Vec::new();
+
+        for parent in self.in_memory.keys().rev() {
+            let in_last_root = roots
+                .last()
+                .is_some_and(|root: &CallStack| parent.starts_with(root));
+
+            if !parent.is_empty() && !in_last_root && !is_pinned(parent) {
+                roots.push(parent.clone());
+            }
+        }
+
+        let mut subtrees = Vec::new();
+
+        for root in roots.into_iter().rev() {
+            if self.in_memory_len <= target {
+                break;
+            }
+
+            let subtree = Arc::new(self.take_subtree(&root));
+            self.writing.insert(root.clone(), subtree.clone());
+            subtrees.push((root, subtree));
+        }
+
+        self.compact_at = self.next_compaction();
+
+        (!subtrees.is_empty()).then(|| Spill {
+            path,
+            file: self.spill_file.clone(),
+            subtrees,
+        })
+    }
+
+    /// Record where the subtrees from [`Self::start_spill`] were written.
+    ///
+    /// Any subtrees that couldn't be written are put back in memory.
+    pub fn finish_spill(&mut self, written: Vec<(CallStack, io::Result<SpillRecord>)>) {
+        for (root, record) in written {
+            let Some(subtree) = self.writing.remove(&root) else {
+                continue;
+            };
+
+            match record {
+                Ok(record) => {
+                    let spilled = self.spilled.entry(root).or_default();
+                    spilled.summary.add(&subtree);
+                    spilled.records.push(record);
+                }
+                Err(e) => {
+                    eprintln!("Unable to page out history: {e}");
+
+                    for (child, run_state) in subtree.iter() {
+                        self.insert(child.clone(), *run_state);
+                    }
+                }
+            }
+        }
+
+        self.compact_at = self.next_compaction();
+    }
+
+    /// Don't try to compact again until we've made some progress, if there's
+    /// still too much pinned history in memory.
+    fn next_compaction(&self) -> usize {
+        let max_in_memory = self
+            .limits
+            .as_ref()
+            .map_or(usize::MAX, |limits| limits.max_in_memory);
+
+        max_in_memory.max(self.in_memory_len.saturating_mul(2))
+    }
+
+    /// Remove everything below `root` from memory.
+    fn take_subtree(&mut self, root: &CallStack) -> Vec<(CallStack, RunState)> {
+        let parents: Vec<CallStack> = self
+            .in_memory
+            .range(..=root)
+            .rev()
+            .map(|(parent, _)| parent)
+            .take_while(|parent| parent.starts_with(root))
+            .cloned()
+            .collect();
+        let mut subtree = Vec::new();
+
+        for parent in parents {
+            subtree.extend(self.in_memory.remove(&parent).unwrap_or_default());
+        }
+
+        self.in_memory_len -= subtree.len();
+        subtree
+    }
+
+    /// The paged out nodes from the subtree that `parent` is in, including
+    /// any being written.
+    ///
+    /// Later records come last, as the node may have been run more than once.
+    fn paged_out(&self, parent: &CallStack) -> Vec<(CallStack, RunState)> {
+        let mut current = Some(parent.clone());
+
+        while let Some(node) = current {
+            let spilled = self.spilled.get(&node);
+            let writing = self.writing.get(&node);
+
+            if spilled.is_some() || writing.is_some() {
+                let mut nodes = Vec::new();
+
+                if let Some(spilled) = spilled {
+                    let mut file = self.spill_file.lock().unwrap();
+
+                    for record in &spilled.records {
+                        match file.as_mut().map_or_else(
+                            || Err(io::ErrorKind::NotFound.into()),
+                            |file| read_record(file, record),
+                        ) {
+                            Ok(record_nodes) => nodes.extend(record_nodes),
+                            Err(e) => eprintln!("Unable to read history: {e}"),
+                        }
+                    }
+                }
+
+                if let Some(writing) = writing {
+                    nodes.extend(writing.iter().cloned());
+                }
+
+                return nodes;
+            }
+
+            current = node.parent();
+        }
+
+        Vec::new()
+    }
+}
+
+impl Drop for History {
+    fn drop(&mut self) {
+        if self.spill_file.lock().unwrap().is_some() {
+            let path = &self.limits.as_ref().unwrap().spill_file;
+
+            if let Err(e) = fs::remove_file(path) {
+                eprintln!("Unable to remove '{}': {e}", path.display());
+            }
+        }
+    }
+}
+
+/// Subtrees chosen by [`History::start_spill`], to be written to disk.
+pub(super) struct Spill {
+    path: PathBuf,
+    file: Arc<Mutex<Option<File>>>,
+    subtrees: Vec<(CallStack, Subtree)>,
+}
+
+impl Spill {
+    /// Append each subtree to the spill file.
+    ///
+    /// This doesn't need access to the [`History`], so it can be done without
+    /// holding any locks on it.
+    pub fn write(self) -> Vec<(CallStack, io::Result<SpillRecord>)> {
+        let mut file = self.file.lock().unwrap();
+
+        self.subtrees
+            .into_iter()
+            .map(|(root, subtree)| {
+                let record =
+                    Self::open(&mut file, &self.path).and_then(|file| write_record(file, &subtree));
+                (root, record)
+            })
+            .collect()
+    }
+
+    fn open<'a>(file: &'a mut Option<File>, path: &PathBuf) -> io::Result<&'a mut File> {
+        if file.is_none() {
+            *file = Some(
+                File::options()
+                    .read(true)
+                    .write(true)
+                    .create(true)
+                    .truncate(true)
+                    .open(path)?,
+            );
+        }
+
+        Ok(file.as_mut().unwrap())
+    }
+}
+
+fn write_record(file: &mut File, nodes: &[(CallStack, RunState)]) -> io::Result<SpillRecord> {
+    let data = serde_json::to_vec(nodes)?;
+    let offset = file.seek(SeekFrom::End(0))?;
+    file.write_all(&data)?;
+
+    Ok(SpillRecord {
+        offset,
+        len: data.len(),
+    })
+}
+
+fn read_record(file: &mut File, record: &SpillRecord) -> io::Result<Vec<(CallStack, RunState)>> {
+    let mut data = vec![0; record.len];
+    file.seek(SeekFrom::Start(record.offset))?;
+    file.read_exact(&mut data)?;
+    Ok(serde_json::from_slice(&data)?)
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{env, process};
+
+    use super::{History, HistoryLimits, Summary};
+    use crate::run::{CallStack, NestedBlock, RunState, StackFrame};
+
+    #[test]
+    fn spill() {
+        let spill_file = spill_file("spill");
+        let mut history = History::new(Some(HistoryLimits::new(4, &spill_file)));
+
+        for index in 0..3 {
+            run_parent(&mut history, index);
+        }
+
+        compact(&mut history, |_| false);
+        assert!(history.in_memory_len <= 4);
+        assert!(spill_file.exists());
+
+        for index in 0..3 {
+            let parent = node(&CallStack::new(), index);
+            assert_eq!(history.children(&parent), children(&parent));
+            assert_eq!(
+                history.get(&node(&parent, 1)),
+                Some(RunState::PredicateSuccessful(true))
+            );
+        }
+
+        drop(history);
+        assert!(!spill_file.exists());
+    }
+
+    #[test]
+    fn pinned() {
+        let spill_file = spill_file("pinned");
+        let mut history = History::new(Some(HistoryLimits::new(0, &spill_file)));
+        let pinned = node(&CallStack::new(), 1);
+
+        for index in 0..3 {
+            run_parent(&mut history, index);
+        }
+
+        compact(&mut history, |node| pinned.starts_with(node));
+        assert_eq!(history.in_memory_len, 6);
+        assert!(history.in_memory.contains_key(&pinned));
+        assert_eq!(history.children(&pinned), children(&pinned));
+        assert_eq!(history.summary(&pinned), None);
+    }
+
+    #[test]
+    fn subtrees() {
+        let spill_file = spill_file("subtrees");
+        let mut history = History::new(Some(HistoryLimits::new(0, &spill_file)));
+        let root = node(&CallStack::new(), 0);
+        let child = node(&root, 0);
+
+        for (grandchild, _) in children(&child) {
+            history.insert(grandchild, RunState::Failed);
+        }
+
+        history.insert(child.clone(), RunState::Failed);
+        history.insert(node(&root, 1), RunState::Successful);
+        history.insert(root.clone(), RunState::Failed);
+
+        compact(&mut history, |_| false);
+        assert_eq!(history.in_memory_len, 1);
+        assert_eq!(
+            history.summary(&root),
+            Some(Summary {
+                descendants: 5,
+                failed: 4
+            })
+        );
+        assert_eq!(history.summary(&child), None);
+        assert_eq!(history.get(&root), Some(RunState::Failed));
+        assert_eq!(history.get(&child), Some(RunState::Failed));
+        assert_eq!(history.children(&root).len(), 2);
+        assert_eq!(
+            history.children(&child),
+            children(&child)
+                .into_iter()
+                .map(|(grandchild, _)| (grandchild, RunState::Failed))
+                .collect::<Vec<_>>()
+        );
+    }
+
+    fn compact(history: &mut History, is_pinned: impl Fn(&CallStack) -> bool) {
+        let spill = history.start_spill(is_pinned).unwrap();
+        history.finish_spill(spill.write());
+    }
+
+    fn run_parent(history: &mut History, index: usize) {
+        let parent = node(&CallStack::new(), index);
+
+        for (child, run_state) in children(&parent) {
+            history.insert(child, run_state);
+        }
+
+        history.insert(parent, RunState::Successful);
+    }
+
+    fn children(parent: &CallStack) -> Vec<(CallStack, RunState)> {
+        (0..3)
+            .map(|index| (node(parent, index), RunState::PredicateSuccessful(true)))
+            .collect()
+    }
+
+    fn node(parent: &CallStack, index: usize) -> CallStack {
+        parent.push_cloned(StackFrame::NestedBlock(index, NestedBlock::Predicate))
+    }
+
+    fn spill_file(name: &str) -> std::path::PathBuf {
+        env::temp_dir().join(format!(
+            "serpent-automation-{}-{name}.history",
+            process::id()
+        ))
+    }
+}
@@ -0,0 +1,236 @@
+//! Secrets, such as deployment credentials, for workflows to use.
+//!
+//! Workflows read secrets with the `secret("NAME")` built-in. Secrets are read
+//! from:
+//!
+//! - Environment variables called `SERPENT_AUTOMATION_SECRET_<NAME>`.
+//! - An encrypted secrets file, named by the `SERPENT_AUTOMATION_SECRETS_FILE`
+//!   environment variable. The hex encoded key is read from
+//!   `SERPENT_AUTOMATION_SECRETS_KEY`.
+//!
+//! Environment variables take precedence over the secrets file. The unencrypted
+//! secrets file is a TOML table of secret names to values:
+//!
+//! ```toml
+//! DEPLOY_TOKEN = "..."
+//! ```
+//!
+//! Secret values are masked in logs, and a [`Secret`]'s `Debug` output only
+//! shows it's name.
+use std::{
+    cmp::Reverse,
+    collections::{BTreeMap, HashMap},
+    env, fmt, fs, io,
+    path::PathBuf,
+};
+
+use chacha20poly1305::{
+    aead::{Aead, KeyInit},
+    ChaCha20Poly1305, Nonce,
+};
+use thiserror::Error;
+
+/// All the secrets available to a run, by name.
+#[derive(Clone, Default)]
+pub struct Secrets(HashMap<String, String>);
+
+impl Secrets {
+    /// Constructor
+    pub fn new(secrets: impl IntoIterator<Item = (String, String)>) -> Self {
+        Self(secrets.into_iter().collect())
+    }
+
+    /// Read secrets from environment variables, and the secrets file if there
+    /// is one.
+    pub fn from_env() -> Result<Self, SecretsError> {
+        let mut secrets = match env::var_os(SECRETS_FILE_VAR) {
+            Some(path) => {
+                let path = PathBuf::from(path);
+                let key = env::var(SECRETS_KEY_VAR).map_err(|_| SecretsError::MissingKey)?;
+                let key = hex::decode(key.trim()).map_err(|_| SecretsError::InvalidKey)?;
+                let data = fs::read(&path).map_err(|source| SecretsError::Read {
+                    path: path.clone(),
+                    source,
+                })?;
+
+                Self::decrypt(&data, &key)?
+            }
+            None => Self::default(),
+        };
+
+        secrets.0.extend(env::vars().filter_map(|(name, value)| {
+            name.strip_prefix(SECRET_VAR_PREFIX)
+                .map(|name| (name.to_owned(), value))
+        }));
+
+        Ok(secrets)
+    }
+
+    /// Encrypt the TOML source of a secrets file with `key`.
+    ///
+    /// `key` must be [`KEY_LEN`] bytes. `nonce` must be random, and never
+    /// reused with the same key.
+    pub fn encrypt(
+        source: &str,
+        key: &[u8],
+        nonce: &[u8; NONCE_LEN],
+    ) -> Result<Vec<u8>, SecretsError> {
+        Self::parse(source)?;
+        let cipher = ChaCha20Poly1305::new_from_slice(key).map_err(|_| SecretsError::InvalidKey)?;
+        let ciphertext = cipher
+            .encrypt(Nonce::from_slice(nonce), source.as_bytes())
+            .map_err(|_| SecretsError::Encrypt)?;
+
+        Ok(nonce.iter().copied().chain(ciphertext).collect())
+    }
+
+    /// Decrypt a secrets file created with [`Self::encrypt`].
+    pub fn decrypt(data: &[u8], key: &[u8]) -> Result<Self, SecretsError> {
+        let cipher = ChaCha20Poly1305::new_from_slice(key).map_err(|_| SecretsError::InvalidKey)?;
+
+        if data.len() < NONCE_LEN {
+            return Err(SecretsError::Decrypt);
+        }
+
+        let (nonce, ciphertext) = data.split_at(NONCE_LEN);
+        let source = cipher
+            .decrypt(Nonce::from_slice(nonce), ciphertext)
+            .map_err(|_| SecretsError::Decrypt)?;
+        let source = String::from_utf8(source).map_err(|_| SecretsError::Decrypt)?;
+
+        Self::parse(&source)
+    }
+
+    fn parse(source: &str) -> Result<Self, SecretsError> {
+        let secrets: BTreeMap<String, String> = toml::from_str(source)?;
+        Ok(Self::new(secrets))
+    }
+
+    pub fn get(&self, name: &str) -> Option<Secret> {
+        self.0.get(name).map(|value| Secret {
+            name: name.to_owned(),
+            value: value.clone(),
+        })
+    }
+
+    /// Replace any secret values in `text` with [`MASK`].
+    pub fn mask(&self, text: &str) -> String {
+        let mut values: Vec<&String> = self.0.values().filter(|value| !value.is_empty()).collect();
+        // Mask longer values first, in case one secret contains another.
+        values.sort_by_key(|value| Reverse(value.len()));
+
+        values.into_iter().fold(text.to_owned(), |text, value| {
+            text.replace(value.as_str(), MASK)
+        })
+    }
+}
+
+impl fmt::Debug for Secrets {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_set().entries(self.0.keys()).finish()
+    }
+}
+
+/// The value of a secret.
+///
+/// The `Debug` output only shows the name of the secret, not it's value.
+#[derive(Clone, PartialEq, Eq)]
+pub struct Secret {
+    name: String,
+    value: String,
+}
+
+impl Secret {
+    pub fn name(&self) -> &str {
+        &self.name
+    }
+
+    /// The secret value.
+    ///
+    /// Be careful not to log this.
+    pub fn expose(&self) -> &str {
+        &self.value
+    }
+}
+
+impl fmt::Debug for Secret {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "secret({:?})", self.name)
+    }
+}
+
+/// What secret values are replaced with in logs.
+pub const MASK: &str = "********";
+
+/// The environment variable naming the encrypted secrets file.
+pub const SECRETS_FILE_VAR: &str = "SERPENT_AUTOMATION_SECRETS_FILE";
+/// The environment variable containing the hex encoded secrets file key.
+pub const SECRETS_KEY_VAR: &str = "SERPENT_AUTOMATION_SECRETS_KEY";
+/// The prefix for environment variables containing individual secrets.
+pub const SECRET_VAR_PREFIX: &str = "SERPENT_AUTOMATION_SECRET_";
+
+/// The length of a secrets file key, in bytes.
+pub const KEY_LEN: usize = 32;
+/// The length of a secrets file nonce, in bytes.
+pub const NONCE_LEN: usize = 12;
+
+#[derive(Error, Debug)]
+pub enum SecretsError {
+    #[error("{SECRETS_FILE_VAR} is set, but {SECRETS_KEY_VAR} isn't")]
+    MissingKey,
+    #[error("The secrets key should be 32 hex encoded bytes")]
+    InvalidKey,
+    #[error("Unable to read secrets file '{}': {source}", path.display())]
+    Read { path: PathBuf, source: io::Error },
+    #[error("Unable to parse secrets: {0}")]
+    Parse(#[from] toml::de::Error),
+    #[error("Unable to encrypt secrets")]
+    Encrypt,
+    #[error("Unable to decrypt secrets. Is the key correct?")]
+    Decrypt,
+}
+
+#[cfg(test)]
+mod tests {
+    use indoc::indoc;
+
+    use super::{Secrets, SecretsError, KEY_LEN, MASK, NONCE_LEN};
+
+    const SOURCE: &str = indoc! {r#"
+        TOKEN = "token-value"
+        PASSWORD = "hunter2"
+    "#};
+
+    #[test]
+    fn encrypt_decrypt() {
+        let key = [1; KEY_LEN];
+        let data = Secrets::encrypt(SOURCE, &key, &[2; NONCE_LEN]).unwrap();
+        assert!(!String::from_utf8_lossy(&data).contains("hunter2"));
+
+        let secrets = Secrets::decrypt(&data, &key).unwrap();
+        assert_eq!(secrets.get("PASSWORD").unwrap().expose(), "hunter2");
+        assert!(secrets.get("MISSING").is_none());
+
+        assert!(matches!(
+            Secrets::decrypt(&data, &[3; KEY_LEN]),
+            Err(SecretsError::Decrypt)
+        ));
+    }
+
+    #[test]
+    fn mask() {
+        let secrets = Secrets::new([
+            ("TOKEN".to_owned(), "token".to_owned()),
+            ("LONG_TOKEN".to_owned(), "token-value".to_owned()),
+        ]);
+
+        assert_eq!(
+            secrets.mask("token-value and token"),
+            format!("{MASK} and {MASK}")
+        );
+        assert_eq!(
+            format!("{:?}", secrets.get("TOKEN").unwrap()),
+            r#"secret("TOKEN")"#
+        );
+    }
+}
@@ -0,0 +1,305 @@
+//! The source files that make up a workflow.
+//!
+//! A workflow is a main module, and any modules it imports. Module `<name>` is
+//! read from `<name>.py` in the workflow directory.
+use std::{
+    collections::{HashSet, VecDeque},
+    fs, io,
+    path::{Path, PathBuf},
+};
+
+use serde::{Deserialize, Serialize};
+use thiserror::Error;
+
+use crate::{
+    library::{Library, LinkError},
+    syntax_tree::{parse_file, FileId, Module, ParseError},
+};
+
+/// The source files of a workflow, indexed by [`FileId`].
+///
+/// The main module is always first.
+#[derive(Clone, Debug, Eq, PartialEq, Serialize, Deserialize)]
+pub struct Sources(Vec<SourceFile>);
+
+#[derive(Clone, Debug, Eq, PartialEq, Serialize, Deserialize)]
+pub struct SourceFile {
+    module: String,
+    source: String,
+}
+
+impl SourceFile {
+    pub fn module(&self) -> &str {
+        &self.module
+    }
+
+    pub fn source(&self) -> &str {
+        &self.source
+    }
+
+    fn parse(&self, file: FileId) -> Result<Module, SourceError> {
+        parse_file(&self.source, file).map_err(|source| SourceError::Parse {
+            module: self.module.clone(),
+            source,
+        })
+    }
+}
+
+impl Sources {
+    /// A workflow with a single source file.
+    pub fn single(module: &str, source: &str) -> Self {
+        Self(vec![SourceFile {
+            module: module.to_owned(),
+            source: source.to_owned(),
+        }])
+    }
+
+    /// Load module `main` from `dir`, along with any modules it imports.
+    pub fn load(dir: &Path, main: &str) -> Result<Self, SourceError> {
+        Self::load_with(main, |module| {
+            let path = dir.join(format!("{module}.py"));
+            fs::read_to_string(&path).map_err(|source| SourceError::Read { path, source })
+        })
+    }
+
+    /// Load module `main`, along with any modules it imports, using `read` to
+    /// get the source of each module.
+    ///
+    /// This lets us use sources that aren't on disk yet, like files that are
+    /// open in an editor.
+    pub fn load_with(
+        main: &str,
+        mut read: impl FnMut(&str) -> Result<String, SourceError>,
+    ) -> Result<Self, SourceError> {
+        let mut files = Vec::new();
+        let mut seen = HashSet::from([main.to_owned()]);
+        let mut to_load = VecDeque::from([main.to_owned()]);
+
+        while let Some(module) = to_load.pop_front() {
+            let source = read(&module)?;
+            let file = SourceFile { module, source };
+
+            for import in file.parse(FileId::new(files.len()))?.imports() {
+                if seen.insert(import.module().to_owned()) {
+                    to_load.push_back(import.module().to_owned());
+                }
+            }
+
+            files.push(file);
+        }
+
+        Ok(Self(files))
+    }
+
+    pub fn main(&self) -> &SourceFile {
+        &self.0[0]
+    }
+
+    pub fn file(&self, file: FileId) -> Option<&SourceFile> {
+        self.0.get(file.index())
+    }
+
+    pub fn iter(&self) -> impl Iterator<Item = &SourceFile> {
+        self.0.iter()
+    }
+
+    /// Parse each file, returning the modules with their names.
+    pub fn parse(&self) -> Result<Vec<(String, Module)>, SourceError> {
+        self.0
+            .iter()
+            .enumerate()
+            .map(|(index, file)| Ok((file.module.clone(), file.parse(FileId::new(index))?)))
+            .collect()
+    }
+
+    /// Parse and link all the files.
+    pub fn link(&self) -> Result<Library, SourceError> {
+        Ok(Library::link_modules(self.parse()?)?)
+    }
+}
+
+#[derive(Error, Debug)]
+pub enum SourceError {
+    #[error("Unable to read '{}': {source}", path.display())]
+    Read { path: PathBuf, source: io::Error },
+    #[error("Unable to parse module '{module}': {source}")]
+    Parse { module: String, source: ParseError },
+    #[error("Unable to link: {0}")]
+    Link(#[from] LinkError),
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{env, fs, process};
+
+    use indoc::indoc;
+
+    use super::{SourceError, Sources};
+    use crate::library::LinkError;
+
+    #[test]
+    fn load() {
+        let dir = workflow_dir(
+            "load",
+            &[
+                (
+                    "main",
+                    indoc! {"
+                        import helpers
+                        from build import compile
+
+                        def main():
+                            helpers.setup()
+                            compile()
+                    "},
+                ),
+                (
+                    "helpers",
+                    indoc! {"
+                        def setup():
+                            pass
+                    "},
+                ),
+                (
+                    "build",
+                    indoc! {"
+                        import helpers
+
+                        def compile():
+                            helpers.setup()
+                    "},
+                ),
+            ],
+        );
+        let sources = Sources::load(&dir, "main").unwrap();
+        let modules: Vec<&str> = sources.iter().map(|file| file.module()).collect();
+        assert_eq!(modules, ["main", "helpers", "build"]);
+
+        let library = sources.link().unwrap();
+        let main = library.main().unwrap();
+        assert_eq!(main.name(), "main");
+        assert!(library.function_id("helpers.setup").is_some());
+        assert!(library.function_id("build.compile").is_some());
+        assert!(library.function_id("compile").is_none());
+
+        fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    fn import_cycle() {
+        let dir = workflow_dir(
+            "cycle",
+            &[
+                ("main", "import a\n"),
+                ("a", "import b\n"),
+                ("b", "import a\n"),
+            ],
+        );
+        let sources = Sources::load(&dir, "main").unwrap();
+
+        let Err(SourceError::Link(LinkError::ImportCycle(cycle))) = sources.link() else {
+            panic!("Expected an import cycle");
+        };
+        assert_eq!(cycle, ["a", "b", "a"]);
+
+        fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    fn load_with() {
+        let sources = Sources::load_with("main", |module| match module {
+            "main" => Ok("import helpers\n".to_owned()),
+            "helpers" => Ok("def setup():\n    pass\n".to_owned()),
+            _ => panic!("Unexpected module {module}"),
+        })
+        .unwrap();
+
+        assert!(sources
+            .link()
+            .unwrap()
+            .function_id("helpers.setup")
+            .is_some());
+    }
+
+    #[test]
+    fn duplicate_function() {
+        let sources = Sources::single(
+            "main",
+            indoc! {"
+                def main():
+                    pass
+
+                def main():
+                    pass
+            "},
+        );
+
+        let Err(SourceError::Link(LinkError::DuplicateFunction { span, name })) = sources.link()
+        else {
+            panic!("Expected a duplicate function");
+        };
+        assert_eq!(name, "main");
+        assert_eq!(span.line(), 4);
+    }
+
+    #[test]
+    fn unknown_module() {
+        let sources = Sources::single("main", "import missing\n");
+
+        assert!(matches!(
+            sources.link(),
+            Err(SourceError::Link(LinkError::UnknownModule { .. }))
+        ));
+    }
+
+    #[test]
+    fn unknown_name() {
+        let dir = workflow_dir(
+            "unknown-name",
+            &[("main", "from a import missing\n"), ("a", "")],
+        );
+        let sources = Sources::load(&dir, "main").unwrap();
+
+        assert!(matches!(
+            sources.link(),
+            Err(SourceError::Link(LinkError::UnknownName { .. }))
+        ));
+
+        fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    fn unknown_qualified_name() {
+        let sources = Sources::load_with("main", |module| match module {
+            "main" => Ok(indoc! {"
+                import helpers
+
+                def main():
+                    helpers.missing()
+            "}
+            .to_owned()),
+            "helpers" => Ok("def setup():\n    pass\n".to_owned()),
+            _ => panic!("Unexpected module {module}"),
+        })
+        .unwrap();
+
+        let Err(SourceError::Link(LinkError::UnknownName { span, module, name })) = sources.link()
+        else {
+            panic!("Expected an unknown name");
+        };
+        assert_eq!(module, "helpers");
+        assert_eq!(name, "missing");
+        assert_eq!(span.line(), 4);
+    }
+
+    fn workflow_dir(name: &str, modules: &[(&str, &str)]) -> std::path::PathBuf {
+        let dir = env::temp_dir().join(format!("serpent-automation-{}-{name}", process::id()));
+        fs::create_dir_all(&dir).unwrap();
+
+        for (module, source) in modules {
+            fs::write(dir.join(format!("{module}.py")), source).unwrap();
+        }
+
+        dir
+    }
+}
@@ -0,0 +1,527 @@
+//! A content-addressed cache of results, for functions with `@cache`.
+//!
+//! Results are keyed on a SHA-256 hash of the function's source, the source of
+//! every local function it can call, it's arguments, the `key` from
+//! `@cache(key=...)` and the contents of the files it declares with
+//! `@cache(files=[...])`. Changing any of these means the function is run
+//! again. Files are relative to the workflow's directory. Calls with secret or
+//! lambda arguments aren't cached.
+//!
+//! Entries are kept in memory, up to a limit, dropping the least recently used
+//! first. A cache opened on a directory also writes each entry to a file named
+//! after it's key, so results are shared between runs and worker processes.
+//! Files that haven't been used for a while are removed.
+use std::{
+    collections::HashMap,
+    env, fmt,
+    fs::{self, File},
+    io::{self, Read},
+    path::{Path, PathBuf},
+    process,
+    sync::Mutex,
+    time::{Duration, Instant, SystemTime},
+};
+
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+
+use crate::syntax_tree::Value;
+
+/// The environment variable [`StepCache::from_env`] reads the cache
+/// directory from.
+pub const STEP_CACHE_DIR_VAR: &str = "SERPENT_AUTOMATION_STEP_CACHE_DIR";
+
+/// The default number of entries to keep in memory.
+pub const DEFAULT_MAX_IN_MEMORY: usize = 1000;
+
+/// By default, entry files are removed if they haven't been used for 30 days.
+pub const DEFAULT_MAX_AGE: Duration = Duration::from_secs(30 * 24 * 60 * 60);
+
+/// How often [`StepCache::insert`] removes old entry files.
+const EVICTION_INTERVAL: Duration = Duration::from_secs(60 * 60);
+
+pub struct StepCache {
+    dir: Option<PathBuf>,
+    max_in_memory: usize,
+    max_age: Duration,
+    in_memory: Mutex<InMemory>,
+    last_eviction: Mutex<Instant>,
+}
+
+impl Default for StepCache {
+    fn default() -> Self {
+        Self {
+            dir: None,
+            max_in_memory: DEFAULT_MAX_IN_MEMORY,
+            max_age: DEFAULT_MAX_AGE,
+            in_memory: Mutex::default(),
+            last_eviction: Mutex::new(Instant::now()),
+        }
+    }
+}
+
+impl StepCache {
+    /// Open a cache that stores entries in `dir`, creating it if it doesn't
+    /// exist.
+    ///
+    /// Entry files older than [`DEFAULT_MAX_AGE`] are removed.
+    pub fn open(dir: impl Into<PathBuf>) -> io::Result<Self> {
+        let dir = dir.into();
+        fs::create_dir_all(&dir)?;
+        let step_cache = Self {
+            dir: Some(dir),
+            ..Self::default()
+        };
+        step_cache.evict();
+
+        Ok(step_cache)
+    }
+
+    /// Open the cache directory named by [`STEP_CACHE_DIR_VAR`], or use an
+    /// in-memory cache if it's not set.
+    pub fn from_env() -> io::Result<Self> {
+        match env::var_os(STEP_CACHE_DIR_VAR) {
+            Some(dir) => Self::open(dir),
+            None => Ok(Self::default()),
+        }
+    }
+
+    /// Keep at most `max_in_memory` entries in memory, and remove entry files
+    /// that haven't been used for `max_age`.
+    #[must_use]
+    pub fn with_limits(mut self, max_in_memory: usize, max_age: Duration) -> Self {
+        self.max_in_memory = max_in_memory;
+        self.max_age = max_age;
+        self
+    }
+
+    /// The directory entries are stored in, if there is one.
+    pub fn dir(&self) -> Option<&Path> {
+        self.dir.as_deref()
+    }
+
+    /// Look up `key`, in memory first, and then in the cache directory.
+    ///
+    /// Entries that can't be read are treated as missing. Using an entry
+    /// updates the modified time of it's file, so it's not removed while it's
+    /// still in use.
+    pub fn get(&self, key: &CacheKey) -> Option<CacheEntry> {
+        let path = self.entry_path(key);
+
+        if let Some(path) = &path {
+            touch(path);
+        }
+
+        if let Some(entry) = self.in_memory.lock().unwrap().get(key) {
+            return Some(entry);
+        }
+
+        let data = fs::read(path?).ok()?;
+        let entry: CacheEntry = serde_json::from_slice(&data).ok()?;
+        self.in_memory
+            .lock()
+            .unwrap()
+            .insert(*key, entry.clone(), self.max_in_memory);
+        Some(entry)
+    }
+
+    /// Store `entry` under `key`.
+    ///
+    /// Entries are written to a temporary file first, so other processes never
+    /// see a partial entry. If the entry can't be written, it's only kept in
+    /// memory. Old entry files are removed every [`EVICTION_INTERVAL`].
+    pub fn insert(&self, key: CacheKey, entry: CacheEntry) {
+        if let Some(path) = self.entry_path(&key) {
+            let write = serde_json::to_vec(&entry)
+                .map_err(io::Error::from)
+                .and_then(|data| {
+                    let temp_path = path.with_extension(format!("{}.tmp", process::id()));
+                    fs::write(&temp_path, data)?;
+                    fs::rename(&temp_path, &path)
+                });
+
+            if let Err(e) = write {
+                eprintln!("Unable to write cache entry '{}': {e}", path.display());
+            }
+
+            let evict = {
+                let mut last_eviction = self.last_eviction.lock().unwrap();
+                let evict = last_eviction.elapsed() >= EVICTION_INTERVAL;
+
+                if evict {
+                    *last_eviction = Instant::now();
+                }
+
+                evict
+            };
+
+            if evict {
+                self.evict();
+            }
+        }
+
+        self.in_memory
+            .lock()
+            .unwrap()
+            .insert(key, entry, self.max_in_memory);
+    }
+
+    /// Remove files from the cache directory that haven't been modified for
+    /// longer than the maximum age.
+    ///
+    /// This includes temporary files left behind by processes that were
+    /// killed while writing an entry. Errors are logged, and otherwise
+    /// ignored.
+    pub fn evict(&self) {
+        let Some(dir) = &self.dir else {
+            return;
+        };
+        let entries = match fs::read_dir(dir) {
+            Ok(entries) => entries,
+            Err(e) => {
+                eprintln!("Unable to read cache directory '{}': {e}", dir.display());
+                return;
+            }
+        };
+
+        for entry in entries.flatten() {
+            let expired = entry
+                .metadata()
+                .and_then(|metadata| metadata.modified())
+                .ok()
+                .and_then(|modified| modified.elapsed().ok())
+                .is_some_and(|age| age > self.max_age);
+
+            if expired {
+                if let Err(e) = fs::remove_file(entry.path()) {
+                    eprintln!(
+                        "Unable to remove cache entry '{}': {e}",
+                        entry.path().display()
+                    );
+                }
+            }
+        }
+    }
+
+    fn entry_path(&self, key: &CacheKey) -> Option<PathBuf> {
+        Some(self.dir.as_ref()?.join(format!("{key}.json")))
+    }
+}
+
+/// The entries kept in memory, with when they were last used.
+#[derive(Default)]
+struct InMemory {
+    entries: HashMap<CacheKey, (CacheEntry, u64)>,
+    /// Counts up each time an entry is used.
+    clock: u64,
+}
+
+impl InMemory {
+    fn get(&mut self, key: &CacheKey) -> Option<CacheEntry> {
+        let (entry, last_used) = self.entries.get_mut(key)?;
+        self.clock += 1;
+        *last_used = self.clock;
+        Some(entry.clone())
+    }
+
+    /// Insert an entry, dropping the least recently used one if there are
+    /// more than `max_len`.
+    fn insert(&mut self, key: CacheKey, entry: CacheEntry, max_len: usize) {
+        self.clock += 1;
+        self.entries.insert(key, (entry, self.clock));
+
+        if self.entries.len() > max_len {
+            let least_recently_used = self
+                .entries
+                .iter()
+                .min_by_key(|(_key, (_entry, last_used))| *last_used)
+                .map(|(key, _)| *key);
+
+            if let Some(key) = least_recently_used {
+                self.entries.remove(&key);
+            }
+        }
+    }
+}
+
+/// Update the modified time of `path`, ignoring any errors.
+fn touch(path: &Path) {
+    if let Ok(file) = File::options().write(true).open(path) {
+        let _ = file.set_modified(SystemTime::now());
+    }
+}
+
+/// Identifies a cached result.
+#[derive(Copy, Clone, Eq, PartialEq, Hash, Debug)]
+pub struct CacheKey([u8; 32]);
+
+impl CacheKey {
+    /// Constructor
+    ///
+    /// `files` are relative to `dir`, or the current directory if there isn't
+    /// one. A directory's key covers all the files in it, and their names.
+    ///
+    /// Returns `None` if any of the `args` can't be cached. Secrets would be
+    /// stale after they're rotated, and lambdas have no stable encoding.
+    pub fn new(
+        source_hash: &[u8],
+        args: &[Value],
+        key: Option<&str>,
+        files: &[String],
+        dir: Option<&Path>,
+    ) -> Option<Self> {
+        let mut hasher = Sha256::new();
+        field(&mut hasher, source_hash);
+
+        for arg in args {
+            hash_value(&mut hasher, arg)?;
+        }
+
+        field(&mut hasher, key.unwrap_or_default().as_bytes());
+
+        for file in files {
+            let path = match dir {
+                Some(dir) => dir.join(file),
+                None => PathBuf::from(file),
+            };
+            field(&mut hasher, file.as_bytes());
+            field(&mut hasher, &hash_path(&path));
+        }
+
+        Some(Self(hasher.finalize().into()))
+    }
+}
+
+impl fmt::Display for CacheKey {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(&hex::encode(self.0))
+    }
+}
+
+/// The result of a cached call.
+#[derive(Clone, Debug, Eq, PartialEq, Serialize, Deserialize)]
+pub struct CacheEntry {
+    pub value: Value,
+    /// The messages logged by the call, and anything it called.
+    pub logs: Vec<String>,
+}
+
+/// Add a length prefixed field to `hasher`, so fields can't run into each
+/// other.
+fn field(hasher: &mut Sha256, data: &[u8]) {
+    hasher.update((data.len() as u64).to_le_bytes());
+    hasher.update(data);
+}
+
+/// Add `value` to `hasher`, tagged with it's type.
+///
+/// Returns `None` for values that can't be part of a key.
+fn hash_value(hasher: &mut Sha256, value: &Value) -> Option<()> {
+    match value {
+        Value::String(string) => {
+            field(hasher, b"string");
+            field(hasher, string.as_bytes());
+        }
+        Value::Bool(b) => {
+            field(hasher, b"bool");
+            field(hasher, &[u8::from(*b)]);
+        }
+        Value::None => field(hasher, b"none"),
+        Value::Secret(_) | Value::Lambda { .. } => return None,
+    }
+
+    Some(())
+}
+
+/// Hash the contents of a file, or the names and contents of everything in a
+/// directory.
+///
+/// Paths that can't be read hash to a fixed value, so a missing file still
+/// gives a consistent key. Symlinks to directories aren't followed, so a
+/// symlink loop can't recurse forever. The link's target path is hashed
+/// instead.
+fn hash_path(path: &Path) -> Vec<u8> {
+    let mut hasher = Sha256::new();
+    let metadata = fs::symlink_metadata(path);
+
+    if metadata
+        .as_ref()
+        .is_ok_and(|metadata| metadata.is_symlink())
+        && path.is_dir()
+    {
+        let target = fs::read_link(path).unwrap_or_default();
+        field(&mut hasher, SYMLINK);
+        field(&mut hasher, target.to_string_lossy().as_bytes());
+    } else if metadata.is_ok_and(|metadata| metadata.is_dir()) {
+        let mut entries: Vec<PathBuf> = fs::read_dir(path)
+            .into_iter()
+            .flatten()
+            .filter_map(|entry| Some(entry.ok()?.path()))
+            .collect();
+        entries.sort();
+
+        for entry in entries {
+            let name = entry.file_name().unwrap_or_default();
+            field(&mut hasher, name.to_string_lossy().as_bytes());
+            field(&mut hasher, &hash_path(&entry));
+        }
+    } else if let Ok(mut file) = File::open(path) {
+        let mut buffer = [0; 8192];
+
+        loop {
+            match file.read(&mut buffer) {
+                Ok(0) | Err(_) => break,
+                Ok(len) => hasher.update(&buffer[..len]),
+            }
+        }
+    } else {
+        hasher.update(MISSING);
+    }
+
+    hasher.finalize().to_vec()
+}
+
+const MISSING: &[u8] = b"\0missing";
+const SYMLINK: &[u8] = b"\0symlink";
+
+#[cfg(test)]
+mod tests {
+    use std::{
+        env,
+        fs::{self, File},
+        process,
+        time::{Duration, SystemTime},
+    };
+
+    use super::{CacheEntry, CacheKey, StepCache, DEFAULT_MAX_AGE, DEFAULT_MAX_IN_MEMORY};
+    use crate::{secrets::Secrets, syntax_tree::Value};
+
+    #[test]
+    fn keys() {
+        let dir = env::temp_dir().join(format!(
+            "serpent-automation-{}-step-cache-keys",
+            process::id()
+        ));
+        let file = dir.join("input.txt");
+        fs::create_dir_all(&dir).unwrap();
+        fs::write(&file, "a").unwrap();
+        let files = ["input.txt".to_owned()];
+        let key =
+            |args: &[Value], key| CacheKey::new(b"source", args, key, &files, Some(&dir)).unwrap();
+
+        let original = key(&[], None);
+        assert_eq!(original, key(&[], None));
+        assert_ne!(original, key(&[Value::None], None));
+        assert_ne!(
+            key(&[Value::None], None),
+            key(&[Value::String("None".to_owned())], None)
+        );
+        assert_ne!(original, key(&[], Some("key")));
+        assert_ne!(
+            original,
+            CacheKey::new(b"changed source", &[], None, &files, Some(&dir)).unwrap()
+        );
+
+        fs::write(&file, "b").unwrap();
+        assert_ne!(original, key(&[], None));
+
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn uncachable_args() {
+        let secrets = Secrets::new([("TOKEN".to_owned(), "token-value".to_owned())]);
+        let secret = Value::Secret(secrets.get("TOKEN").unwrap());
+
+        assert_eq!(CacheKey::new(b"source", &[secret], None, &[], None), None);
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn symlink_loop() {
+        let dir = env::temp_dir().join(format!(
+            "serpent-automation-{}-step-cache-symlink",
+            process::id()
+        ));
+        fs::create_dir_all(&dir).unwrap();
+        std::os::unix::fs::symlink(&dir, dir.join("loop")).unwrap();
+        let files = [".".to_owned()];
+
+        assert_eq!(
+            CacheKey::new(b"source", &[], None, &files, Some(&dir)).unwrap(),
+            CacheKey::new(b"source", &[], None, &files, Some(&dir)).unwrap()
+        );
+
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn persist() {
+        let dir = env::temp_dir().join(format!("serpent-automation-{}-step-cache", process::id()));
+        let key = CacheKey::new(b"source", &[], None, &[], None).unwrap();
+        let entry = CacheEntry {
+            value: Value::String("built".to_owned()),
+            logs: vec!["Building".to_owned()],
+        };
+
+        StepCache::open(&dir).unwrap().insert(key, entry.clone());
+        assert_eq!(StepCache::open(&dir).unwrap().get(&key), Some(entry));
+        assert_eq!(StepCache::default().get(&key), None);
+
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn evict_in_memory() {
+        let step_cache = StepCache::default().with_limits(2, DEFAULT_MAX_AGE);
+        let [a, b, c] =
+            [b"a", b"b", b"c"].map(|source| CacheKey::new(source, &[], None, &[], None).unwrap());
+        let entry = CacheEntry {
+            value: Value::None,
+            logs: Vec::new(),
+        };
+
+        step_cache.insert(a, entry.clone());
+        step_cache.insert(b, entry.clone());
+        assert!(step_cache.get(&a).is_some());
+        step_cache.insert(c, entry);
+
+        assert!(step_cache.get(&a).is_some());
+        assert!(step_cache.get(&b).is_none());
+        assert!(step_cache.get(&c).is_some());
+    }
+
+    #[test]
+    fn evict_files() {
+        let dir = env::temp_dir().join(format!(
+            "serpent-automation-{}-step-cache-evict",
+            process::id()
+        ));
+        let key = CacheKey::new(b"source", &[], None, &[], None).unwrap();
+        let entry = CacheEntry {
+            value: Value::None,
+            logs: Vec::new(),
+        };
+        let day = Duration::from_secs(24 * 60 * 60);
+
+        StepCache::open(&dir).unwrap().insert(key, entry.clone());
+        let path = dir.join(format!("{key}.json"));
+        File::options()
+            .write(true)
+            .open(&path)
+            .unwrap()
+            .set_modified(SystemTime::now() - 2 * day)
+            .unwrap();
+
+        StepCache::open(&dir).unwrap().evict();
+        assert!(path.exists());
+
+        StepCache::open(&dir)
+            .unwrap()
+            .with_limits(DEFAULT_MAX_IN_MEMORY, day)
+            .evict();
+        assert!(!path.exists());
+
+        fs::remove_dir_all(&dir).unwrap();
+    }
+}
@@ -1,35 +1,77 @@
-use std::{collections::HashMap, sync::Arc, thread::sleep, time::Duration};
+use std::{
+    cmp::{max_by_key, min_by_key},
+    collections::{BTreeSet, HashMap, HashSet},
+    ops::Range,
+    panic::{self, AssertUnwindSafe},
+    path::Path,
+    sync::Arc,
+    thread::{self, sleep},
+    time::Duration,
+};
 
 use nom::{
     branch::alt,
     bytes::complete::{is_not, tag},
-    character::complete::{alpha1, alphanumeric1, line_ending, multispace0, space0, space1},
-    combinator::{all_consuming, eof, map, opt, recognize},
+    character::complete::{
+        alpha1, alphanumeric1, line_ending, multispace0, multispace1, space0, space1,
+    },
+    combinator::{all_consuming, consumed, eof, map, not, opt, recognize, verify},
     error::{context, ErrorKind},
     multi::{many0, many_till, separated_list0, separated_list1},
-    sequence::{delimited, pair, preceded, separated_pair, tuple},
-    Finish, IResult, Parser as _,
+    sequence::{delimited, pair, preceded, separated_pair, terminated, tuple},
+    Finish, IResult, InputTake, Parser as _,
 };
 use nom_greedyerror::{convert_error, GreedyError};
 use nom_locate::LocatedSpan;
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
 use thiserror::Error;
 
+pub use self::decorator::Decorator;
+use self::visit::{walk_call, walk_statement, Fold, Visit};
 use crate::{
     library::{FunctionId, Library},
-    run::{NestedBlock, StackFrame, ThreadRunState},
+    run::{NestedBlock, RunError, StackFrame, ThreadRunState},
+    secrets::Secret,
+    step_cache::CacheKey,
 };
 
+mod decorator;
+mod indentation;
+pub mod visit;
+
+/// Parse a module with no file id.
+///
+/// See [`parse_file`].
 pub fn parse(input: &str) -> Result<Module, ParseError> {
+    parse_file(input, FileId::default())
+}
+
+/// Parse a module, tagging spans with `file`.
+///
+/// Indentation is checked before anything else, so indentation mistakes are
+/// reported as [`ParseError::Indentation`] or [`ParseError::Tab`].
+pub fn parse_file(input: &str, file: FileId) -> Result<Module, ParseError> {
+    indentation::check(input, file)?;
+
     match all_consuming(Module::parse())
-        .parse(Span::new(input))
+        .parse(Span::new_extra(input, file))
         .finish()
     {
-        Ok((_, module)) => Ok(module),
-        Err(e) => Err(ParseError(convert_error(input, e))),
+        Ok((_, module)) => {
+            MisplacedStatements::check(&module)?;
+            check_duplicate_decorators(&module)?;
+
+            Ok(Module {
+                comments: Comment::scan(input, file),
+                ..module
+            })
+        }
+        Err(e) => Err(ParseError::new(input, file, e)),
     }
 }
 
-type Span<'a> = LocatedSpan<&'a str>;
+type Span<'a> = LocatedSpan<&'a str, FileId>;
 
 type ParseResult<'a, T> = IResult<Span<'a>, T, GreedyError<Span<'a>, ErrorKind>>;
 
@@ -37,29 +79,334 @@ trait Parser<'a, O>: nom::Parser<Span<'a>, O, GreedyError<Span<'a>, ErrorKind>>
 
 impl<'a, O, P: nom::Parser<Span<'a>, O, GreedyError<Span<'a>, ErrorKind>>> Parser<'a, O> for P {}
 
-#[derive(PartialEq, Eq, Debug)]
+#[derive(Clone, PartialEq, Eq, Debug)]
 pub struct Module {
+    imports: Vec<Import>,
     functions: Vec<Function>,
+    triggers: Vec<Trigger>,
+    triggers_span: Option<SrcSpan>,
+    comments: Vec<Comment>,
 }
 
 impl Module {
     fn parse<'a>() -> impl Parser<'a, Self> {
         context(
             "module",
-            many_till(multiline_ws(Function::parse(None)), eof),
+            preceded(
+                trivia(),
+                many_till(
+                    terminated(
+                        alt((
+                            Import::parse().map(ModuleItem::Import),
+                            Function::parse(None).map(ModuleItem::Function),
+                            consumed(Trigger::parse_list()).map(|(span, trigger_list)| {
+                                ModuleItem::Triggers(SrcSpan::from_span(&span), trigger_list)
+                            }),
+                        )),
+                        trivia(),
+                    ),
+                    eof,
+                ),
+            ),
         )
-        .map(|(functions, _)| Module { functions })
+        .map(|(items, _)| {
+            let mut imports = Vec::new();
+            let mut functions = Vec::new();
+            let mut triggers = Vec::new();
+            let mut triggers_span: Option<SrcSpan> = None;
+
+            for item in items {
+                match item {
+                    ModuleItem::Import(import) => imports.push(import),
+                    ModuleItem::Function(function) => functions.push(function),
+                    ModuleItem::Triggers(span, trigger_list) => {
+                        triggers_span =
+                            Some(triggers_span.map_or(span, |existing| existing.merge(&span)));
+                        triggers.extend(trigger_list);
+                    }
+                }
+            }
+
+            Module {
+                imports,
+                functions,
+                triggers,
+                triggers_span,
+                comments: Vec::new(),
+            }
+        })
+    }
+
+    /// The `import` and `from ... import` statements.
+    pub fn imports(&self) -> &[Import] {
+        &self.imports
     }
 
     pub fn functions(&self) -> &[Function] {
         &self.functions
     }
+
+    /// The triggers declared with a module level `triggers = [...]`
+    pub fn triggers(&self) -> &[Trigger] {
+        &self.triggers
+    }
+
+    /// The span covering all the `triggers = [...]` declarations, if there
+    /// are any.
+    pub fn triggers_span(&self) -> Option<SrcSpan> {
+        self.triggers_span
+    }
+
+    /// All the comments in the module, in source order.
+    pub fn comments(&self) -> &[Comment] {
+        &self.comments
+    }
+}
+
+/// Find statements that can't be used where they are.
+///
+/// There are no loops yet, so any `break` or `continue` is an error. There are
+/// no nested functions either, so a `nonlocal` name never has a binding.
+#[derive(Default)]
+struct MisplacedStatements(Option<(SrcSpan, String)>);
+
+impl MisplacedStatements {
+    fn check(module: &Module) -> Result<(), ParseError> {
+        let mut misplaced = Self::default();
+
+        for function in module.functions() {
+            misplaced.visit_body(function.body());
+        }
+
+        match misplaced.0 {
+            Some((span, text)) => Err(ParseError::Syntax { span, text }),
+            None => Ok(()),
+        }
+    }
+}
+
+impl<'ast> Visit<'ast, String> for MisplacedStatements {
+    fn visit_statement(&mut self, span: SrcSpan, statement: &'ast Statement<String>) {
+        match statement {
+            Statement::Break => {
+                self.0
+                    .get_or_insert_with(|| (span, "'break' outside loop".to_owned()));
+            }
+            Statement::Continue => {
+                self.0
+                    .get_or_insert_with(|| (span, "'continue' outside loop".to_owned()));
+            }
+            Statement::Nonlocal(names) => {
+                self.0.get_or_insert_with(|| {
+                    (
+                        span,
+                        format!("no binding for nonlocal '{}' found", names[0]),
+                    )
+                });
+            }
+            _ => walk_statement(self, span, statement),
+        }
+    }
+}
+
+/// Each kind of decorator can only be used once per function.
+fn check_duplicate_decorators(module: &Module) -> Result<(), ParseError> {
+    for function in module.functions() {
+        let mut names = HashSet::new();
+
+        for decorator in function.decorators() {
+            if !names.insert(decorator.name()) {
+                return Err(ParseError::Syntax {
+                    span: decorator.span(),
+                    text: format!("Duplicate decorator '@{}'", decorator.name()),
+                });
+            }
+        }
+    }
+
+    Ok(())
+}
+
+enum ModuleItem {
+    Import(Import),
+    Function(Function),
+    Triggers(SrcSpan, Vec<Trigger>),
+}
+
+/// A `# ...` comment.
+///
+/// Comments don't affect how a workflow runs, but we keep them so tools like
+/// the formatter can reproduce them.
+#[derive(Clone, PartialEq, Eq, Debug)]
+pub struct Comment {
+    span: SrcSpan,
+    text: String,
+}
+
+impl Comment {
+    pub fn span(&self) -> SrcSpan {
+        self.span
+    }
+
+    /// The comment text, including the leading `#`.
+    pub fn text(&self) -> &str {
+        &self.text
+    }
+
+    /// Find all the comments in `input`.
+    ///
+    /// The grammar only allows comments where whitespace is allowed, so once
+    /// `input` has parsed, any `#` outside a string literal starts a comment.
+    fn scan(input: &str, file: FileId) -> Vec<Self> {
+        let mut comments = Vec::new();
+        let mut pos = SrcPos {
+            line: 1,
+            column: 1,
+            offset: 0,
+        };
+        let mut in_string = false;
+
+        for (offset, c) in input.char_indices() {
+            if offset < pos.offset {
+                continue;
+            }
+
+            match c {
+                '"' => in_string = !in_string,
+                '#' if !in_string => {
+                    let len = input[offset..]
+                        .find(['\r', '\n'])
+                        .unwrap_or(input.len() - offset);
+                    let text = &input[offset..offset + len];
+                    let start = pos.advance(&input[pos.offset..offset]);
+                    let end = start.advance(text);
+
+                    comments.push(Self {
+                        span: SrcSpan { file, start, end },
+                        text: text.to_owned(),
+                    });
+                    pos = end;
+                }
+                _ => (),
+            }
+        }
+
+        comments
+    }
+}
+
+/// Import another module from the workflow directory.
+#[derive(Clone, PartialEq, Eq, Debug)]
+pub enum Import {
+    /// `import <module>`
+    ///
+    /// The module's functions are called with `<module>.<function>()`.
+    Module { span: SrcSpan, module: String },
+    /// `from <module> import <name>, ...`
+    Names {
+        span: SrcSpan,
+        module: String,
+        names: Vec<(SrcSpan, String)>,
+    },
+}
+
+impl Import {
+    /// The span of the module name.
+    pub fn span(&self) -> SrcSpan {
+        match self {
+            Self::Module { span, .. } | Self::Names { span, .. } => *span,
+        }
+    }
+
+    pub fn module(&self) -> &str {
+        match self {
+            Self::Module { module, .. } | Self::Names { module, .. } => module,
+        }
+    }
+
+    fn parse<'a>() -> impl Parser<'a, Self> {
+        context(
+            "import",
+            alt((
+                preceded(pair(import, space1), identifier()).map(|module| Self::Module {
+                    span: SrcSpan::from_span(&module),
+                    module: module.fragment().to_string(),
+                }),
+                tuple((
+                    preceded(pair(from, space1), identifier()),
+                    preceded(tuple((space1, import, space1)), spanned_names()),
+                ))
+                .map(|(module, names)| Self::Names {
+                    span: SrcSpan::from_span(&module),
+                    module: module.fragment().to_string(),
+                    names,
+                }),
+            )),
+        )
+    }
+}
+
+/// Something that should start a new run of the workflow.
+#[derive(Clone, PartialEq, Eq, Debug)]
+pub enum Trigger {
+    /// `cron("<schedule>")`
+    Cron { span: SrcSpan, schedule: String },
+    /// `watch("<path>")`
+    Watch { span: SrcSpan, path: String },
+}
+
+impl Trigger {
+    pub fn span(&self) -> SrcSpan {
+        match self {
+            Self::Cron { span, .. } | Self::Watch { span, .. } => *span,
+        }
+    }
+
+    fn parse_list<'a>() -> impl Parser<'a, Vec<Self>> {
+        context(
+            "triggers",
+            preceded(
+                pair(triggers, equals),
+                delimited(
+                    tag("["),
+                    multiline_ws(terminated(
+                        separated_list0(tag(","), multiline_ws(Self::parse())),
+                        opt(tag(",")),
+                    )),
+                    tag("]"),
+                ),
+            ),
+        )
+    }
+
+    fn parse<'a>() -> impl Parser<'a, Self> {
+        context(
+            "trigger",
+            alt((
+                Self::parse_call("cron").map(|(span, schedule)| Self::Cron { span, schedule }),
+                Self::parse_call("watch").map(|(span, path)| Self::Watch { span, path }),
+            )),
+        )
+    }
+
+    fn parse_call<'a>(name: &'static str) -> impl Parser<'a, (SrcSpan, String)> {
+        separated_pair(
+            tag(name),
+            space0,
+            delimited(tag("("), multiline_ws(string_literal()), tag(")")),
+        )
+        .map(|(name, contents)| (SrcSpan::from_span(&name), contents.fragment().to_string()))
+    }
 }
 
-#[derive(PartialEq, Eq, Debug)]
+#[derive(Clone, PartialEq, Eq, Debug)]
 pub struct Function {
     name: String,
     span: SrcSpan,
+    whole_span: SrcSpan,
+    decorators: Vec<Decorator>,
+    /// The SHA-256 hash of the function's source, including decorators.
+    source_hash: [u8; 32],
     body: Body<String>,
 }
 
@@ -68,42 +415,102 @@ impl Function {
         &self.name
     }
 
-    fn parse(current_indent: Option<&str>) -> impl Parser<Self> {
+    /// The span of the function name.
+    pub fn span(&self) -> SrcSpan {
+        self.span
+    }
+
+    /// The span of the whole function, from the first decorator, or `def` if
+    /// there are no decorators, to the end of the body.
+    pub fn whole_span(&self) -> SrcSpan {
+        self.whole_span
+    }
+
+    /// The decorators, in source order.
+    pub fn decorators(&self) -> &[Decorator] {
+        &self.decorators
+    }
+
+    pub fn body(&self) -> &Body<String> {
+        &self.body
+    }
+
+    fn parse<'a>(current_indent: Option<usize>) -> impl Parser<'a, Self> {
         context(
             "function",
-            tuple((
+            consumed(tuple((
+                many0(terminated(Decorator::parse(), trivia())),
                 def,
                 space1,
                 identifier(),
                 ws(tag("()")),
                 colon,
                 Body::parse(current_indent),
-            )),
+            ))),
+        )
+        .map(
+            |(whole, (decorators, _def, _, name, _params, _colon, body))| Function {
+                name: name.fragment().to_string(),
+                span: SrcSpan::from_span(&name),
+                whole_span: SrcSpan::from_span(&whole),
+                decorators,
+                source_hash: Sha256::digest(whole.fragment().as_bytes()).into(),
+                body,
+            },
         )
-        .map(|(_def, _, name, _params, _colon, body)| Function {
-            name: name.fragment().to_string(),
-            span: SrcSpan::from_span(&name),
-            body,
-        })
     }
 
-    pub fn translate_ids(&self, id_map: &IdMap) -> LinkedFunction {
+    /// Link the function, resolving calls with `id_map`.
+    ///
+    /// If `module` is `Some`, the linked function's name is qualified with it.
+    pub fn translate_ids(&self, module: Option<&str>, id_map: &IdMap) -> LinkedFunction {
+        let name = match module {
+            Some(module) => format!("{module}.{}", self.name),
+            None => self.name.clone(),
+        };
+
         LinkedFunction::local(
-            &self.name,
+            &name,
+            module,
             self.span,
-            self.body
-                .iter()
-                .map(|statement| statement.translate_ids(id_map)),
+            self.decorators.clone(),
+            self.source_hash,
+            TranslateIds(id_map).fold_body(&self.body),
         )
     }
 
-    pub fn unresolved_symbols<'a>(
-        &'a self,
-        id_map: &'a HashMap<String, FunctionId>,
-    ) -> impl Iterator<Item = String> + 'a {
-        self.body
-            .iter()
-            .flat_map(|stmt| stmt.unresolved_symbols(id_map))
+    /// The names of functions called from this function that aren't in
+    /// `id_map`, with the span of each call.
+    pub fn unresolved_symbols(&self, id_map: &IdMap) -> Vec<(SrcSpan, String)> {
+        let mut unresolved = UnresolvedSymbols {
+            id_map,
+            unresolved: Vec::new(),
+        };
+        unresolved.visit_body(&self.body);
+        unresolved.unresolved
+    }
+}
+
+struct TranslateIds<'a>(&'a IdMap);
+
+impl Fold<String, FunctionId> for TranslateIds<'_> {
+    fn fold_fn_id(&mut self, _span: SrcSpan, name: &String) -> FunctionId {
+        *self.0.get(name).unwrap()
+    }
+}
+
+struct UnresolvedSymbols<'a> {
+    id_map: &'a IdMap,
+    unresolved: Vec<(SrcSpan, String)>,
+}
+
+impl<'ast> Visit<'ast, String> for UnresolvedSymbols<'_> {
+    fn visit_call(&mut self, span: SrcSpan, name: &'ast String, args: &'ast [Expression<String>]) {
+        if !self.id_map.contains_key(name) {
+            self.unresolved.push((span, name.clone()));
+        }
+
+        walk_call(self, span, name, args);
     }
 }
 
@@ -111,27 +518,43 @@ impl Function {
 #[derive(Debug)]
 pub struct LinkedFunction {
     name: String,
+    /// The module the function is defined in, or `None` for the main module
+    /// and host functions.
+    module: Option<String>,
     span: Option<SrcSpan>,
+    decorators: Vec<Decorator>,
+    /// The hash of the function's source, combined with the source hashes of
+    /// all the local functions it can call. See [`Self::cache_key`].
+    source_hash: [u8; 32],
     body: LinkedBody,
 }
 
 impl LinkedFunction {
     pub fn local(
         name: &str,
+        module: Option<&str>,
         span: SrcSpan,
-        body: impl IntoIterator<Item = Statement<FunctionId>>,
+        decorators: Vec<Decorator>,
+        source_hash: [u8; 32],
+        body: Body<FunctionId>,
     ) -> Self {
         Self {
             name: name.to_owned(),
+            module: module.map(str::to_owned),
             span: Some(span),
-            body: LinkedBody::Local(Arc::new(Body::new(body))),
+            decorators,
+            source_hash,
+            body: LinkedBody::Local(Arc::new(body)),
         }
     }
 
     pub fn python(name: String) -> Self {
         Self {
             name,
+            module: None,
             span: None,
+            decorators: Vec::new(),
+            source_hash: [0; 32],
             body: LinkedBody::Python,
         }
     }
@@ -140,25 +563,242 @@ impl LinkedFunction {
         &self.name
     }
 
+    /// The name to show in the call tree.
+    ///
+    /// This is the name from `@step`, if there is one.
+    pub fn display_name(&self) -> &str {
+        self.decorators
+            .iter()
+            .find_map(|decorator| match decorator {
+                Decorator::Step { name, .. } => Some(name.as_str()),
+                _ => None,
+            })
+            .unwrap_or(&self.name)
+    }
+
     pub fn span(&self) -> Option<SrcSpan> {
         self.span
     }
 
+    pub fn decorators(&self) -> &[Decorator] {
+        &self.decorators
+    }
+
+    /// The number of attempts, and the wait before the first retry, from
+    /// `@retry`.
+    pub fn retry(&self) -> Option<(usize, Duration)> {
+        self.decorators
+            .iter()
+            .find_map(|decorator| match decorator {
+                Decorator::Retry {
+                    attempts, backoff, ..
+                } => Some((*attempts as usize, Duration::from_secs(*backoff))),
+                _ => None,
+            })
+    }
+
+    /// The time limit from `@timeout`.
+    pub fn timeout(&self) -> Option<Duration> {
+        self.decorators
+            .iter()
+            .find_map(|decorator| match decorator {
+                Decorator::Timeout { seconds, .. } => Some(Duration::from_secs(*seconds)),
+                _ => None,
+            })
+    }
+
     pub fn body(&self) -> &LinkedBody {
         &self.body
     }
 
-    pub fn run(&self, args: &[Value], lib: &Library, call_states: &ThreadRunState) {
+    pub(crate) fn source_hash(&self) -> [u8; 32] {
+        self.source_hash
+    }
+
+    /// Combine the source hashes of the local functions this function can
+    /// call into it's own.
+    ///
+    /// Host functions have nothing to combine, so they're left alone.
+    pub(crate) fn include_callee_hashes(&mut self, callee_hashes: &BTreeSet<[u8; 32]>) {
+        if matches!(self.body, LinkedBody::Python) {
+            return;
+        }
+
+        let mut hasher = Sha256::new();
+        hasher.update(self.source_hash);
+
+        for callee_hash in callee_hashes {
+            hasher.update(callee_hash);
+        }
+
+        self.source_hash = hasher.finalize().into();
+    }
+
+    pub fn run(
+        &self,
+        args: &[Value],
+        lib: &Library,
+        call_states: &ThreadRunState,
+    ) -> Result<Value, RunError> {
         println!("Running function '{}'", self.name());
-        sleep(Duration::from_secs(1));
 
         match &self.body {
-            LinkedBody::Local(local) => local.run(lib, call_states),
+            LinkedBody::Local(local) => {
+                sleep(Duration::from_secs(1));
+                self.run_attempts(local, lib, call_states)?;
+                Ok(Value::None)
+            }
             LinkedBody::Python => {
-                // TODO
-                println!("{}({:?})", self.name(), args)
+                call_states.host_call()?;
+
+                match self.name() {
+                    "secret" => secret(args, call_states),
+                    "run_input" => Ok(call_states.input()),
+                    _ => {
+                        // TODO
+                        call_states.log(format!("{}({:?})", self.name(), args));
+                        Ok(Value::None)
+                    }
+                }
+            }
+        }
+    }
+
+    /// The key for a call with `args`, if the function has `@cache`.
+    ///
+    /// Files from `@cache(files=[...])` are relative to `workflow_dir`, or the
+    /// current directory if there isn't one. Calls with arguments that can't
+    /// be cached, like secrets, have no key.
+    pub fn cache_key(&self, args: &[Value], workflow_dir: Option<&Path>) -> Option<CacheKey> {
+        let (key, files) = self
+            .decorators
+            .iter()
+            .find_map(|decorator| match decorator {
+                Decorator::Cache { key, files, .. } => Some((key, files)),
+                _ => None,
+            })?;
+
+        CacheKey::new(&self.source_hash, args, key.as_deref(), files, workflow_dir)
+    }
+
+    /// Run a local function with `@retry` and `@timeout` applied.
+    ///
+    /// Each attempt gets it's own timeout.
+    fn run_attempts(
+        &self,
+        body: &Body<FunctionId>,
+        lib: &Library,
+        call_states: &ThreadRunState,
+    ) -> Result<(), RunError> {
+        let Some((attempts, mut backoff)) = self.retry() else {
+            return self.run_with_timeout(body, lib, call_states);
+        };
+
+        for attempt in 0..attempts {
+            let result = call_states.try_run(StackFrame::Attempt(attempt), || {
+                self.run_with_timeout(body, lib, call_states)
+            });
+
+            match result {
+                Ok(()) => return Ok(()),
+                Err(error) if attempt + 1 == attempts => return Err(error),
+                Err(_) => {
+                    call_states.log(format!("Attempt {} of {attempts} failed", attempt + 1));
+                    sleep(backoff);
+                    backoff = backoff.saturating_mul(2);
+                }
+            }
+        }
+
+        Ok(())
+    }
+
+    fn run_with_timeout(
+        &self,
+        body: &Body<FunctionId>,
+        lib: &Library,
+        call_states: &ThreadRunState,
+    ) -> Result<(), RunError> {
+        let mut scope = Scope::new(self.module.as_deref(), body);
+
+        match self.timeout() {
+            Some(timeout) => {
+                call_states.with_timeout(timeout, || body.run(lib, call_states, &mut scope))
             }
+            None => body.run(lib, call_states, &mut scope),
+        }
+    }
+}
+
+/// The variables visible to a call to a local function.
+///
+/// Names declared `global` anywhere in the function refer to module level
+/// variables. Other names are local, but fall back to module level variables
+/// when they haven't been assigned to.
+#[derive(Default)]
+pub struct Scope {
+    module: Option<String>,
+    global_names: HashSet<String>,
+    locals: HashMap<String, Value>,
+}
+
+impl Scope {
+    fn new(module: Option<&str>, body: &Body<FunctionId>) -> Self {
+        let mut global_names = GlobalNames::default();
+        global_names.visit_body(body);
+
+        Self {
+            module: module.map(str::to_owned),
+            global_names: global_names.0,
+            locals: HashMap::new(),
+        }
+    }
+
+    fn get(&self, name: &str, call_states: &ThreadRunState) -> Result<Value, RunError> {
+        let local = if self.global_names.contains(name) {
+            None
+        } else {
+            self.locals.get(name).cloned()
+        };
+
+        match local.or_else(|| call_states.global(self.module.as_deref(), name)) {
+            Some(value) => Ok(value),
+            None => call_states.fail(RunError::UnknownVariable(name.to_owned())),
+        }
+    }
+
+    fn set(&mut self, name: &str, value: Value, call_states: &ThreadRunState) {
+        if self.global_names.contains(name) {
+            call_states.set_global(self.module.as_deref(), name, value);
+        } else {
+            self.locals.insert(name.to_owned(), value);
+        }
+    }
+}
+
+/// The names declared with `global` in a function.
+#[derive(Default)]
+struct GlobalNames(HashSet<String>);
+
+impl<'ast> Visit<'ast, FunctionId> for GlobalNames {
+    fn visit_statement(&mut self, span: SrcSpan, statement: &'ast Statement<FunctionId>) {
+        if let Statement::Global(names) = statement {
+            self.0.extend(names.iter().cloned());
         }
+
+        walk_statement(self, span, statement);
+    }
+}
+
+/// The `secret("NAME")` built-in.
+fn secret(args: &[Value], call_states: &ThreadRunState) -> Result<Value, RunError> {
+    let [Value::String(name)] = args else {
+        return call_states.fail(RunError::InvalidSecretArguments);
+    };
+
+    match call_states.secret(name) {
+        Some(secret) => Ok(Value::Secret(secret)),
+        None => call_states.fail(RunError::UnknownSecret(name.clone())),
     }
 }
 
@@ -168,68 +808,106 @@ pub enum LinkedBody {
     Python,
 }
 
-#[derive(Debug, Eq, PartialEq)]
-pub struct Body<T>(Vec<Statement<T>>);
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub struct Body<T> {
+    span: SrcSpan,
+    statements: Vec<(SrcSpan, Statement<T>)>,
+}
 
 impl<T> Body<T> {
-    pub fn new(stmts: impl IntoIterator<Item = Statement<T>>) -> Self {
-        Self(stmts.into_iter().collect())
+    /// Constructor
+    ///
+    /// `span` should cover all the `statements`.
+    pub fn new(
+        span: SrcSpan,
+        statements: impl IntoIterator<Item = (SrcSpan, Statement<T>)>,
+    ) -> Self {
+        Self {
+            span,
+            statements: statements.into_iter().collect(),
+        }
     }
 
-    pub fn empty() -> Self {
-        Self(Vec::new())
+    /// The span from the start of the first statement to the end of the last.
+    pub fn span(&self) -> SrcSpan {
+        self.span
     }
 
     pub fn is_empty(&self) -> bool {
-        self.0.is_empty()
+        self.statements.is_empty()
     }
 
     pub fn iter(&self) -> impl Iterator<Item = &Statement<T>> {
-        self.0.iter()
+        self.statements.iter().map(|(_span, statement)| statement)
+    }
+
+    /// The statements, with their spans.
+    pub fn spanned(&self) -> impl Iterator<Item = (SrcSpan, &Statement<T>)> {
+        self.statements
+            .iter()
+            .map(|(span, statement)| (*span, statement))
     }
 }
 
 impl Body<String> {
-    fn parse(current_indent: Option<&str>) -> impl Parser<Self> {
-        alt((Self::parse_inline(), Self::parse_block(current_indent))).map(Self::new)
+    fn parse<'a>(current_indent: Option<usize>) -> impl Parser<'a, Self> {
+        alt((Self::parse_inline(), Self::parse_block(current_indent)))
     }
 
-    fn parse_inline<'a>() -> impl Parser<'a, Vec<Statement<String>>> {
-        context("inline body", Statement::parse(None)).map(|statement| vec![statement])
+    fn parse_inline<'a>() -> impl Parser<'a, Self> {
+        context("inline body", Self::parse_statement(None)).map(|(span, statement)| Self {
+            span,
+            statements: vec![(span, statement)],
+        })
     }
 
-    fn parse_block(current_indent: Option<&str>) -> impl Parser<Vec<Statement<String>>> {
+    fn parse_block<'a>(current_indent: Option<usize>) -> impl Parser<'a, Self> {
         move |input| {
-            let (input, prefix) = preceded(
+            let (input, indent) = preceded(
                 pair(eol(), blank_lines()),
-                recognize(pair(discard_indent(current_indent), space1)),
+                verify(
+                    space1.map(|indent: Span| indentation::width(indent.fragment())),
+                    |&indent| indent > current_indent.unwrap_or(0),
+                ),
             )
-            .map(|prefix: Span| Some(*(prefix.fragment())))
             .parse(input)?;
+            let prefix = Some(indent);
 
             // TODO: Is error reporting friendly enough?
-            separated_list1(discard_newline_indent(prefix), Statement::parse(prefix))(input)
+            consumed(separated_list1(
+                discard_newline_indent(prefix),
+                Self::parse_statement(prefix),
+            ))
+            .map(|(block, statements)| Self {
+                span: SrcSpan::from_span(&block),
+                statements,
+            })
+            .parse(input)
         }
     }
 
-    fn translate_ids(&self, id_map: &IdMap) -> Body<FunctionId> {
-        Body(self.iter().map(|stmt| stmt.translate_ids(id_map)).collect())
-    }
-
-    fn unresolved_symbols(&self, id_map: &HashMap<String, FunctionId>) -> Vec<String> {
-        self.iter()
-            .flat_map(|stmt| stmt.unresolved_symbols(id_map))
-            .collect()
+    fn parse_statement<'a>(prefix: Option<usize>) -> impl Parser<'a, (SrcSpan, Statement<String>)> {
+        consumed(Statement::parse(prefix))
+            .map(|(statement_span, statement)| (SrcSpan::from_span(&statement_span), statement))
     }
 }
 
 impl Body<FunctionId> {
-    pub fn run(&self, lib: &Library, call_states: &ThreadRunState) {
+    pub fn run(
+        &self,
+        lib: &Library,
+        call_states: &ThreadRunState,
+        scope: &mut Scope,
+    ) -> Result<(), RunError> {
         for (index, stmt) in self.iter().enumerate() {
+            call_states.pause().wait();
+            call_states.check_timeout()?;
             call_states.push(StackFrame::Statement(index));
-            stmt.run(lib, call_states);
+            stmt.run(lib, call_states, scope)?;
             call_states.pop_success();
         }
+
+        Ok(())
     }
 }
 
@@ -240,14 +918,19 @@ fn blank_lines<'a>() -> impl Parser<'a, ()> {
 }
 
 fn eol<'a>() -> impl Parser<'a, ()> {
-    discard(tuple((
-        space0,
-        opt(pair(tag("#"), is_not("\r\n"))),
-        line_ending,
-    )))
+    discard(tuple((space0, opt(comment()), line_ending)))
+}
+
+fn comment<'a>() -> impl Parser<'a, Span<'a>> {
+    recognize(pair(tag("#"), opt(is_not("\r\n"))))
+}
+
+/// Whitespace and comments between module level items.
+fn trivia<'a>() -> impl Parser<'a, ()> {
+    discard(many0(alt((multispace1, comment()))))
 }
 
-#[derive(Eq, PartialEq, Debug)]
+#[derive(Clone, Eq, PartialEq, Debug)]
 pub enum Statement<FnId> {
     Pass,
     Expression(Expression<FnId>),
@@ -257,21 +940,47 @@ pub enum Statement<FnId> {
         then_block: Arc<Body<FnId>>,
         else_block: Option<ElseClause<FnId>>,
     },
+    /// `with <context_manager> [as <target>]:`
+    ///
+    /// The context manager is evaluated and entered in the predicate block,
+    /// and the value it returns is bound to `target`. It's exited after the
+    /// body, even if the body fails.
+    With {
+        with_span: SrcSpan,
+        context_manager: Arc<Expression<FnId>>,
+        target: Option<(SrcSpan, String)>,
+        body: Arc<Body<FnId>>,
+    },
+    /// `global <name>, ...`
+    Global(Vec<String>),
+    /// `nonlocal <name>, ...`
+    ///
+    /// There are no nested functions yet, so the parser rejects `nonlocal`.
+    Nonlocal(Vec<String>),
+    /// There are no loops yet, so the parser rejects `break`.
+    Break,
+    /// There are no loops yet, so the parser rejects `continue`.
+    Continue,
 }
 
 impl Statement<String> {
-    fn parse(prefix: Option<&str>) -> impl Parser<Self> {
+    fn parse<'a>(prefix: Option<usize>) -> impl Parser<'a, Self> {
         context(
             "statement",
             alt((
                 pass.map(|_| Statement::Pass),
+                terminated(r#break, keyword_end()).map(|_| Statement::Break),
+                terminated(r#continue, keyword_end()).map(|_| Statement::Continue),
+                preceded(pair(global, space1), names()).map(Statement::Global),
+                preceded(pair(nonlocal, space1), names()).map(Statement::Nonlocal),
                 move |input| Self::parse_if(prefix, input),
+                move |input| Self::parse_with(prefix, input),
                 map(Expression::parse(), Statement::Expression),
             )),
         )
     }
 
-    fn parse_if<'a>(current_indent: Option<&'a str>, input: Span<'a>) -> ParseResult<'a, Self> {
+    fn parse_if(current_indent: Option<usize>, input: Span) -> ParseResult<Self> {
         // TODO: elif
         let (input, (if_keyword, condition, _colon, then_block, else_block)) = context(
             "if",
@@ -294,54 +1003,41 @@ impl Statement<String> {
         Ok((input, statement))
     }
 
-    fn translate_ids(&self, id_map: &IdMap) -> Statement<FunctionId> {
-        match self {
-            Self::Pass => Statement::Pass,
-            Self::Expression(expression) => Statement::Expression(expression.translate_ids(id_map)),
-            Self::If {
-                if_span,
-                condition,
-                then_block,
-                else_block,
-            } => Statement::If {
-                if_span: *if_span,
-                condition: Arc::new(condition.translate_ids(id_map)),
-                then_block: Arc::new(then_block.translate_ids(id_map)),
-                else_block: else_block.as_ref().map(|e| e.translate_ids(id_map)),
-            },
-        }
-    }
-
-    fn unresolved_symbols(&self, id_map: &HashMap<String, FunctionId>) -> Vec<String> {
-        match self {
-            Self::Pass => vec![],
-            Self::Expression(expr) => expr.unresolved_symbols(id_map),
-            Self::If {
-                condition,
-                then_block,
-                else_block,
-                ..
-            } => {
-                let mut unresolved = condition.unresolved_symbols(id_map);
-
-                unresolved.extend(then_block.unresolved_symbols(id_map));
+    fn parse_with(current_indent: Option<usize>, input: Span) -> ParseResult<Self> {
+        // TODO: Multiple context managers
+        let (input, (with_keyword, context_manager, target, _colon, body)) = context(
+            "with",
+            tuple((
+                terminated(with, keyword_end()),
+                ws(Expression::parse()),
+                opt(preceded(pair(r#as, space1), spanned_identifier())),
+                ws(colon),
+                Body::parse(current_indent),
+            )),
+        )(input)?;
 
-                if let Some(else_block) = else_block {
-                    unresolved.extend(else_block.unresolved_symbols(id_map));
-                }
+        let statement = Self::With {
+            with_span: SrcSpan::from_span(&with_keyword),
+            context_manager: Arc::new(context_manager),
+            target,
+            body: Arc::new(body),
+        };
 
-                unresolved
-            }
-        }
+        Ok((input, statement))
     }
 }
 
 impl Statement<FunctionId> {
-    pub fn run(&self, lib: &Library, call_states: &ThreadRunState) {
+    pub fn run(
+        &self,
+        lib: &Library,
+        call_states: &ThreadRunState,
+        scope: &mut Scope,
+    ) -> Result<(), RunError> {
         match self {
             Self::Pass => (),
             Self::Expression(expr) => {
-                expr.run(lib, call_states);
+                expr.run(lib, call_states, scope)?;
             }
             Self::If {
                 condition,
@@ -353,13 +1049,13 @@ impl Statement<FunctionId> {
 
                 // TODO: Tidy this
                 call_states.push(StackFrame::NestedBlock(0, NestedBlock::Predicate));
-                let truthy = condition.run(lib, call_states).truthy();
+                let truthy = condition.run(lib, call_states, scope)?.truthy();
                 call_states.pop_predicate_success(truthy);
 
                 if truthy {
                     drop_through = false;
                     call_states.push(StackFrame::NestedBlock(0, NestedBlock::Body));
-                    then_block.run(lib, call_states);
+                    then_block.run(lib, call_states, scope)?;
                     call_states.pop_success();
                 }
 
@@ -371,23 +1067,76 @@ impl Statement<FunctionId> {
                     if drop_through {
                         call_states.push(StackFrame::NestedBlock(block_index, NestedBlock::Body));
 
-                        else_block.run(lib, call_states);
+                        else_block.run(lib, call_states, scope)?;
                         call_states.pop_success();
                     }
                 }
             }
+            Self::With {
+                context_manager,
+                target,
+                body,
+                ..
+            } => {
+                call_states.push(StackFrame::NestedBlock(0, NestedBlock::Predicate));
+                let context_manager = context_manager.run(lib, call_states, scope)?;
+                let value = enter_context(&context_manager, call_states)?;
+                call_states.pop_success();
+
+                if let Some((_span, target)) = target {
+                    scope.set(target, value, call_states);
+                }
+
+                let result = call_states
+                    .try_run(StackFrame::NestedBlock(0, NestedBlock::Body), || {
+                        body.run(lib, call_states, scope)
+                    });
+                let exited = exit_context(&context_manager, call_states);
+                result.and(exited)?;
+            }
+            // `global` applies to the whole function, so it's handled by
+            // `Scope`.
+            Self::Global(_) => (),
+            Self::Nonlocal(names) => {
+                return call_states.fail(RunError::NoNonlocalBinding(names[0].clone()))
+            }
+            Self::Break => return call_states.fail(RunError::OutsideLoop("break")),
+            Self::Continue => return call_states.fail(RunError::OutsideLoop("continue")),
         }
+
+        Ok(())
     }
 }
 
-#[derive(Eq, PartialEq, Debug)]
+/// Enter the context manager for a `with` statement.
+///
+/// Context managers are host objects, so this is a host call. Host functions
+/// aren't really called yet, so the context manager is returned as the value
+/// to bind.
+fn enter_context(context_manager: &Value, call_states: &ThreadRunState) -> Result<Value, RunError> {
+    call_states.host_call()?;
+    call_states.log(format!("__enter__({context_manager:?})"));
+    Ok(context_manager.clone())
+}
+
+/// Exit the context manager for a `with` statement.
+///
+/// This happens whether or not the body succeeded, and is logged on the node
+/// containing the `with` statement.
+fn exit_context(context_manager: &Value, call_states: &ThreadRunState) -> Result<(), RunError> {
+    call_states.host_call()?;
+    call_states.log(format!("__exit__({context_manager:?})"));
+    Ok(())
+}
+
+#[derive(Clone, Eq, PartialEq, Debug)]
 pub struct ElseClause<FnId> {
     else_span: SrcSpan,
     body: Arc<Body<FnId>>,
 }
 
 impl ElseClause<String> {
-    fn parse(current_indent: Option<&str>) -> impl Parser<Self> {
+    fn parse<'a>(current_indent: Option<usize>) -> impl Parser<'a, Self> {
         context(
             "else",
             tuple((
@@ -402,34 +1151,30 @@ impl ElseClause<String> {
             body: Arc::new(body),
         })
     }
-
-    fn translate_ids(&self, id_map: &IdMap) -> ElseClause<FunctionId> {
-        ElseClause {
-            else_span: self.else_span,
-            body: Arc::new(self.body.translate_ids(id_map)),
-        }
-    }
-
-    fn unresolved_symbols(&self, id_map: &HashMap<String, FunctionId>) -> Vec<String> {
-        self.body.unresolved_symbols(id_map)
-    }
 }
 
-impl ElseClause<FunctionId> {
-    pub fn run(&self, lib: &Library, call_states: &ThreadRunState) {
-        self.body.run(lib, call_states)
-    }
-
+impl<FnId> ElseClause<FnId> {
     pub fn span(&self) -> SrcSpan {
         self.else_span
     }
 
-    pub fn body(&self) -> &Arc<Body<FunctionId>> {
+    pub fn body(&self) -> &Arc<Body<FnId>> {
         &self.body
     }
 }
 
-#[derive(PartialEq, Eq, Debug)]
+impl ElseClause<FunctionId> {
+    pub fn run(
+        &self,
+        lib: &Library,
+        call_states: &ThreadRunState,
+        scope: &mut Scope,
+    ) -> Result<(), RunError> {
+        self.body.run(lib, call_states, scope)
+    }
+}
+
+#[derive(Clone, PartialEq, Eq, Debug)]
 pub enum Expression<FnId> {
     Literal(Literal),
     Variable {
@@ -440,44 +1185,130 @@ pub enum Expression<FnId> {
         name: FnId,
         args: Vec<Expression<FnId>>,
     },
+    /// `parallel(f1, f2, ...)`
+    ///
+    /// Each branch is a call to one of the functions, with no arguments.
+    Parallel {
+        span: SrcSpan,
+        branches: Vec<Expression<FnId>>,
+    },
+    /// `lambda <param>, ...: <body>`
+    ///
+    /// Running a lambda gives a [`Value::Lambda`]. The body is only run by the
+    /// host function it's passed to.
+    Lambda {
+        span: SrcSpan,
+        params: Vec<(SrcSpan, String)>,
+        body: Box<Expression<FnId>>,
+    },
 }
 
 impl Expression<FunctionId> {
-    pub fn run(&self, lib: &Library, call_states: &ThreadRunState) -> Value {
+    pub fn run(
+        &self,
+        lib: &Library,
+        call_states: &ThreadRunState,
+        scope: &Scope,
+    ) -> Result<Value, RunError> {
         match self {
-            Expression::Variable { name } => todo!("Variable {name}"),
-            Expression::Call { name, args, .. } => run_call(*name, args, lib, call_states),
-            Expression::Literal(literal) => literal.run(),
+            Expression::Variable { name } => scope.get(name, call_states),
+            Expression::Call { name, args, .. } => run_call(*name, args, lib, call_states, scope),
+            Expression::Literal(literal) => Ok(literal.run()),
+            Expression::Parallel { branches, .. } => {
+                run_parallel(branches, lib, call_states, scope)?;
+                Ok(Value::None)
+            }
+            Expression::Lambda { params, body, .. } => Ok(Value::Lambda {
+                params: params.iter().map(|(_span, name)| name.clone()).collect(),
+                body: Arc::new(body.as_ref().clone()),
+            }),
         }
     }
 }
 
+/// Call the function `name`, evaluating `args` in `scope`.
 pub(crate) fn run_call(
     name: FunctionId,
     args: &[Expression<FunctionId>],
     lib: &Library,
     call_states: &ThreadRunState,
-) -> Value {
-    let args: Vec<_> = args
+    scope: &Scope,
+) -> Result<Value, RunError> {
+    let args = args
         .iter()
         .enumerate()
         .map(|(index, arg)| {
             call_states.push(StackFrame::Argument(index));
-            let value = arg.run(lib, call_states);
+            let value = arg.run(lib, call_states, scope)?;
             call_states.pop_success();
-            value
+            Ok(value)
         })
-        .collect();
-    call_states.push(StackFrame::Call(name));
-    lib.lookup(name).run(&args, lib, call_states);
-    call_states.pop_success();
-    Value::None
+        .collect::<Result<Vec<_>, RunError>>()?;
+    call_states.pause().wait();
+    call_states.check_timeout()?;
+    let function = lib.lookup(name);
+    let frame = StackFrame::Call(name);
+    let run = || function.run(&args, lib, call_states);
+
+    match function.cache_key(&args, call_states.workflow_dir().as_deref()) {
+        Some(key) => call_states.run_cached(frame, &key, run),
+        None => call_states.try_run(frame, run),
+    }
+}
+
+/// Run each branch on it's own thread.
+///
+/// We wait for all the branches to finish, even if some of them fail.
+fn run_parallel(
+    branches: &[Expression<FunctionId>],
+    lib: &Library,
+    call_states: &ThreadRunState,
+    scope: &Scope,
+) -> Result<(), RunError> {
+    let results: Vec<thread::Result<Result<Value, RunError>>> = thread::scope(|threads| {
+        let branch_threads: Vec<_> = branches
+            .iter()
+            .enumerate()
+            .map(|(index, branch)| {
+                let branch_states = call_states.branch(StackFrame::Branch(index));
+
+                threads.spawn(move || {
+                    let result = panic::catch_unwind(AssertUnwindSafe(|| {
+                        branch.run(lib, &branch_states, scope)
+                    }));
+                    branch_states.end_branch(!matches!(result, Ok(Ok(_))));
+                    result
+                })
+            })
+            .collect();
+
+        branch_threads
+            .into_iter()
+            .map(|branch_thread| branch_thread.join().unwrap())
+            .collect()
+    });
+
+    let mut first_error = None;
+
+    for result in results {
+        match result {
+            Ok(Ok(_)) => (),
+            Ok(Err(error)) => {
+                first_error.get_or_insert(error);
+            }
+            Err(panic) => panic::resume_unwind(panic),
+        }
+    }
+
+    first_error.map_or(Ok(()), Err)
 }
 
 impl Expression<String> {
     fn parse<'a>() -> impl Parser<'a, Self> {
         alt((
             Self::literal(),
+            Self::lambda(),
+            Self::parallel(),
             Self::call(),
             Self::variable(),
             Self::parenthasized(),
@@ -499,7 +1330,7 @@ impl Expression<String> {
             context(
                 "call",
                 separated_pair(
-                    identifier(),
+                    qualified_identifier(),
                     space0,
                     delimited(
                         tag("("),
@@ -517,52 +1348,82 @@ impl Expression<String> {
         }
     }
 
-    fn parenthasized<'a>() -> impl Parser<'a, Self> {
+    fn parallel<'a>() -> impl Parser<'a, Self> {
+        context(
+            "parallel",
+            separated_pair(
+                parallel,
+                space0,
+                delimited(
+                    tag("("),
+                    separated_list1(tag(","), multiline_ws(qualified_identifier())),
+                    tag(")"),
+                ),
+            ),
+        )
+        .map(|(keyword, functions)| Self::Parallel {
+            span: SrcSpan::from_span(&keyword),
+            branches: functions
+                .iter()
+                .map(|name| Self::Call {
+                    span: SrcSpan::from_span(name),
+                    name: name.fragment().to_string(),
+                    args: Vec::new(),
+                })
+                .collect(),
+        })
+    }
+
+    fn lambda<'a>() -> impl Parser<'a, Self> {
         move |input| {
             context(
-                "parenthesized",
-                delimited(tag("("), multiline_ws(Expression::parse()), tag(")")),
+                "lambda",
+                tuple((
+                    terminated(lambda, keyword_end()),
+                    opt(preceded(space1, spanned_names())),
+                    colon,
+                    Expression::parse(),
+                )),
             )
+            .map(|(keyword, params, _colon, body)| Self::Lambda {
+                span: SrcSpan::from_span(&keyword),
+                params: params.unwrap_or_default(),
+                body: Box::new(body),
+            })
             .parse(input)
         }
     }
 
-    fn translate_ids(&self, id_map: &IdMap) -> Expression<FunctionId> {
-        match self {
-            Self::Literal(literal) => Expression::Literal(literal.clone()),
-            Self::Variable { name } => Expression::Variable { name: name.clone() },
-            Self::Call { name, args, span } => Expression::Call {
-                name: *id_map.get(name).unwrap(),
-                args: args.iter().map(|arg| arg.translate_ids(id_map)).collect(),
-                span: *span,
-            },
-        }
-    }
-
-    fn unresolved_symbols(&self, id_map: &HashMap<String, FunctionId>) -> Vec<String> {
-        match self {
-            Expression::Literal(_) | Expression::Variable { .. } => vec![],
-            Expression::Call { name, args, .. } => {
-                let args_unresolved = args.iter().flat_map(|arg| arg.unresolved_symbols(id_map));
-
-                if id_map.contains_key(name) {
-                    None
-                } else {
-                    Some(name.to_owned())
-                }
-                .into_iter()
-                .chain(args_unresolved)
-                .collect()
-            }
+    fn parenthasized<'a>() -> impl Parser<'a, Self> {
+        move |input| {
+            context(
+                "parenthesized",
+                delimited(tag("("), multiline_ws(Expression::parse()), tag(")")),
+            )
+            .parse(input)
         }
     }
 }
 
-#[derive(Debug)]
+#[derive(Clone, PartialEq, Eq, Debug, Serialize, Deserialize)]
 pub enum Value {
     String(String),
     Bool(bool),
     None,
+    /// The result of `secret("NAME")`.
+    ///
+    /// The `Debug` output only shows the secret's name, and secrets can't be
+    /// serialized, so they never leave the interpreter.
+    #[serde(skip)]
+    Secret(Secret),
+    /// The result of a `lambda` expression.
+    ///
+    /// Lambdas can be passed to host functions, but they can't be serialized.
+    #[serde(skip)]
+    Lambda {
+        params: Vec<String>,
+        body: Arc<Expression<FunctionId>>,
+    },
 }
 impl Value {
     fn truthy(&self) -> bool {
@@ -570,6 +1431,8 @@ impl Value {
             Value::String(s) => !s.is_empty(),
             Value::Bool(b) => *b,
             Value::None => false,
+            Value::Secret(secret) => !secret.expose().is_empty(),
+            Value::Lambda { .. } => true,
         }
     }
 }
@@ -581,6 +1444,10 @@ pub enum Literal {
 }
 
 impl Literal {
+    pub fn is_truthy(&self) -> bool {
+        self.run().truthy()
+    }
+
     fn run(&self) -> Value {
         match self {
             Self::String(string) => Value::String(string.clone()),
@@ -594,8 +1461,7 @@ impl Literal {
     }
 
     fn parse_string<'a>() -> impl Parser<'a, Self> {
-        delimited(tag("\""), is_not("\""), tag("\""))
-            .map(|contents: Span| Self::String(contents.fragment().to_string()))
+        string_literal().map(|contents: Span| Self::String(contents.fragment().to_string()))
     }
 
     fn parse_bool<'a>() -> impl Parser<'a, Self> {
@@ -610,13 +1476,62 @@ impl Literal {
 }
 
 #[derive(Error, Debug)]
-#[error("Parse error:\n{0}")]
-pub struct ParseError(String);
+pub enum ParseError {
+    #[error("Parse error:\n{text}")]
+    Syntax { span: SrcSpan, text: String },
+    /// Indentation that doesn't follow Python's rules.
+    #[error("IndentationError: {message} (line {})", span.line())]
+    Indentation { span: SrcSpan, message: String },
+    /// Indentation that mixes tabs and spaces ambiguously.
+    #[error("TabError: {TAB_ERROR} (line {})", span.line())]
+    Tab { span: SrcSpan },
+}
 
 impl ParseError {
+    fn new(input: &str, file: FileId, error: GreedyError<Span, ErrorKind>) -> Self {
+        // The error furthest into the input is the most useful.
+        let location = error
+            .errors
+            .iter()
+            .map(|(location, _kind)| *location)
+            .max_by_key(|location| location.location_offset())
+            .unwrap_or_else(|| Span::new_extra(input, file));
+        let line_len = location
+            .fragment()
+            .find(['\r', '\n'])
+            .unwrap_or(location.fragment().len());
+
+        Self::Syntax {
+            span: SrcSpan::from_span(&location.take(line_len)),
+            text: convert_error(input, error),
+        }
+    }
+
+    /// A description of the error, without the location.
     pub fn text(&self) -> &str {
-        &self.0
+        match self {
+            Self::Syntax { text, .. } => text,
+            Self::Indentation { message, .. } => message,
+            Self::Tab { .. } => TAB_ERROR,
+        }
     }
+
+    /// Where parsing failed.
+    ///
+    /// For syntax errors, this goes up to the end of the line.
+    pub fn span(&self) -> SrcSpan {
+        match self {
+            Self::Syntax { span, .. } | Self::Indentation { span, .. } | Self::Tab { span } => {
+                *span
+            }
+        }
+    }
+}
+
+const TAB_ERROR: &str = "inconsistent use of tabs and spaces in indentation";
+
+fn string_literal<'a>() -> impl Parser<'a, Span<'a>> {
+    delimited(tag("\""), is_not("\""), tag("\""))
 }
 
 fn identifier<'a>() -> impl Parser<'a, Span<'a>> {
@@ -629,20 +1544,50 @@ fn identifier<'a>() -> impl Parser<'a, Span<'a>> {
     )
 }
 
-fn discard_newline_indent(prefix: Option<&str>) -> impl Parser<()> {
-    move |input| {
-        if let Some(prefix) = prefix {
-            discard(tuple((eol(), blank_lines(), tag(prefix)))).parse(input)
-        } else {
-            Ok((input, ()))
-        }
-    }
+/// A comma separated list of identifiers.
+fn names<'a>() -> impl Parser<'a, Vec<String>> {
+    separated_list1(ws(tag(",")), identifier()).map(|names| {
+        names
+            .iter()
+            .map(|name| name.fragment().to_string())
+            .collect()
+    })
 }
 
-fn discard_indent(prefix: Option<&str>) -> impl Parser<()> {
+/// A comma separated list of identifiers, with their spans.
+fn spanned_names<'a>() -> impl Parser<'a, Vec<(SrcSpan, String)>> {
+    separated_list1(ws(tag(",")), spanned_identifier())
+}
+
+fn spanned_identifier<'a>() -> impl Parser<'a, (SrcSpan, String)> {
+    identifier().map(|name| (SrcSpan::from_span(&name), name.fragment().to_string()))
+}
+
+/// Make sure a keyword isn't the start of a longer identifier.
+fn keyword_end<'a>() -> impl Parser<'a, ()> {
+    not(alt((alphanumeric1, tag("_"))))
+}
+
+/// An identifier, optionally qualified with a module name, like `module.name`.
+fn qualified_identifier<'a>() -> impl Parser<'a, Span<'a>> {
+    context(
+        "qualified identifier",
+        recognize(pair(identifier(), opt(pair(tag("."), identifier())))),
+    )
+}
+
+/// Discard a newline, then indentation that's `width` columns wide.
+fn discard_newline_indent<'a>(width: Option<usize>) -> impl Parser<'a, ()> {
     move |input| {
-        if let Some(prefix) = prefix {
-            discard(tag(prefix)).parse(input)
+        if let Some(width) = width {
+            discard(tuple((
+                eol(),
+                blank_lines(),
+                verify(space0, |indent: &Span| {
+                    indentation::width(indent.fragment()) == width
+                }),
+            )))
+            .parse(input)
         } else {
             Ok((input, ()))
         }
@@ -691,7 +1636,23 @@ macro_rules! keyword {
     };
 }
 
-keywords!(def, pass, r#if("if"), r#else("else"));
+keywords!(
+    def,
+    pass,
+    r#if("if"),
+    r#else("else"),
+    triggers,
+    parallel,
+    import,
+    from,
+    with,
+    r#as("as"),
+    global,
+    nonlocal,
+    r#break("break"),
+    r#continue("continue"),
+    lambda
+);
 
 macro_rules! operators {
     ($(($name:ident, $op:expr)),*) => {
@@ -703,34 +1664,154 @@ macro_rules! operators {
     }
 }
 
-operators!((colon, ":"));
+operators!((colon, ":"), (equals, "="));
 
-#[derive(Copy, Clone, Debug, Eq, PartialEq)]
-pub struct SrcSpan {
+/// Identifies a source file in a multi-file workflow.
+#[derive(
+    Copy, Clone, Default, Eq, PartialEq, Ord, PartialOrd, Hash, Debug, Serialize, Deserialize,
+)]
+pub struct FileId(usize);
+
+impl FileId {
+    pub const fn new(index: usize) -> Self {
+        Self(index)
+    }
+
+    pub const fn index(self) -> usize {
+        self.0
+    }
+}
+
+/// A position in a source file.
+#[derive(Copy, Clone, Debug, Eq, PartialEq, Serialize)]
+pub struct SrcPos {
     line: usize,
     column: usize,
-    len: usize,
+    offset: usize,
+}
+
+impl SrcPos {
+    /// The 1 based line number.
+    pub fn line(&self) -> usize {
+        self.line
+    }
+
+    /// The 1 based column, in characters.
+    pub fn column(&self) -> usize {
+        self.column
+    }
+
+    /// The 0 based byte offset from the start of the file.
+    pub fn offset(&self) -> usize {
+        self.offset
+    }
+
+    /// The position after `text`, if `text` starts at `self`.
+    fn advance(self, text: &str) -> Self {
+        let offset = self.offset + text.len();
+
+        match text.rfind('\n') {
+            Some(last_newline) => Self {
+                line: self.line + text.matches('\n').count(),
+                column: text[last_newline + 1..].chars().count() + 1,
+                offset,
+            },
+            None => Self {
+                line: self.line,
+                column: self.column + text.chars().count(),
+                offset,
+            },
+        }
+    }
+}
+
+/// A range of source code in a file.
+///
+/// The end position is exclusive.
+#[derive(Copy, Clone, Debug, Eq, PartialEq, Serialize)]
+pub struct SrcSpan {
+    file: FileId,
+    start: SrcPos,
+    end: SrcPos,
 }
 
 impl SrcSpan {
     pub fn from_span(span: &Span) -> Self {
-        Self {
+        let start = SrcPos {
             line: span.location_line() as usize,
             column: span.get_utf8_column(),
-            len: span.fragment().len(),
+            offset: span.location_offset(),
+        };
+
+        Self {
+            file: span.extra,
+            start,
+            end: start.advance(span.fragment()),
         }
     }
 
+    /// The file this span is in.
+    pub fn file(&self) -> FileId {
+        self.file
+    }
+
+    pub fn start(&self) -> SrcPos {
+        self.start
+    }
+
+    pub fn end(&self) -> SrcPos {
+        self.end
+    }
+
+    /// The start line.
     pub fn line(&self) -> usize {
-        self.line
+        self.start.line
     }
 
+    /// The start column.
     pub fn column(&self) -> usize {
-        self.column
+        self.start.column
     }
 
+    /// The length in bytes.
     pub fn len(&self) -> usize {
-        self.len
+        self.end.offset - self.start.offset
+    }
+
+    /// The byte offsets of the span within it's file.
+    pub fn byte_range(&self) -> Range<usize> {
+        self.start.offset..self.end.offset
+    }
+
+    /// The smallest span that covers `self` and `other`.
+    ///
+    /// # Panic
+    ///
+    /// If the spans are from different files.
+    #[must_use]
+    pub fn merge(&self, other: &Self) -> Self {
+        assert_eq!(
+            self.file, other.file,
+            "Can't merge spans from different files"
+        );
+
+        Self {
+            file: self.file,
+            start: min_by_key(self.start, other.start, |pos| pos.offset),
+            end: max_by_key(self.end, other.end, |pos| pos.offset),
+        }
+    }
+
+    /// Is `other` entirely within `self`?
+    pub fn contains(&self, other: &Self) -> bool {
+        self.file == other.file
+            && self.start.offset <= other.start.offset
+            && other.end.offset <= self.end.offset
+    }
+
+    /// Is the byte `offset` within `self`?
+    pub fn contains_offset(&self, file: FileId, offset: usize) -> bool {
+        self.file == file && self.byte_range().contains(&offset)
     }
 
     #[must_use]
@@ -741,10 +1822,18 @@ impl SrcSpan {
 
 #[cfg(test)]
 mod tests {
-    use indoc::indoc;
+    use std::time::Duration;
 
-    use super::{parse, Expression, Function, Literal, Module, SrcSpan, Statement};
-    use crate::syntax_tree::Body;
+    use indoc::{formatdoc, indoc};
+
+    use super::{
+        parse, parse_file, Decorator, Expression, FileId, Import, Literal, Module, SrcPos, SrcSpan,
+        Statement, Trigger, Value,
+    };
+    use crate::{
+        library::Library,
+        run::{CallStack, NestedBlock, RunState, StackFrame, ThreadRunState},
+    };
 
     #[test]
     fn empty_fn() {
@@ -793,6 +1882,52 @@ mod tests {
         );
     }
 
+    #[test]
+    fn parse_error() {
+        let input = indoc! {"
+            def test():
+                pass
+            import 1x
+        "};
+        let error = parse_file(input, FileId::new(1)).unwrap_err();
+
+        assert_eq!(
+            error.span(),
+            SrcSpan {
+                file: FileId::new(1),
+                ..src_span(input, 3, 8, 2)
+            }
+        );
+    }
+
+    #[test]
+    fn module_comments() {
+        let input = indoc! {r##"
+            # Module comment
+            import helpers  # Trailing comment
+
+            def test():  # Header comment
+                print("# Not a comment")
+            #
+        "##};
+        let module = parse(input).unwrap();
+        let comments: Vec<_> = module
+            .comments()
+            .iter()
+            .map(|comment| (comment.span(), comment.text()))
+            .collect();
+
+        assert_eq!(
+            comments,
+            [
+                (src_span(input, 1, 1, 16), "# Module comment"),
+                (src_span(input, 2, 17, 18), "# Trailing comment"),
+                (src_span(input, 4, 14, 16), "# Header comment"),
+                (src_span(input, 6, 1, 1), "#"),
+            ]
+        );
+    }
+
     #[test]
     fn variable_expression() {
         parse_expression(
@@ -808,43 +1943,46 @@ mod tests {
 
     #[test]
     fn call0_expression() {
+        let input = indoc! {"
+            def test():
+                x()
+        "};
         parse_expression(
-            indoc! {"
-                def test():
-                    x()
-            "},
+            input,
             Expression::Call {
                 name: "x".to_string(),
                 args: Vec::new(),
-                span: src_span(2, 5, 1),
+                span: src_span(input, 2, 5, 1),
             },
         );
     }
 
     #[test]
     fn call1_expression() {
+        let input = indoc! {"
+            def test():
+                x(y)
+        "};
         parse_expression(
-            indoc! {"
-                def test():
-                    x(y)
-            "},
+            input,
             Expression::Call {
                 name: "x".to_string(),
                 args: vec![Expression::Variable {
                     name: "y".to_string(),
                 }],
-                span: src_span(2, 5, 1),
+                span: src_span(input, 2, 5, 1),
             },
         );
     }
 
     #[test]
     fn call2_expression() {
+        let input = indoc! {"
+            def test():
+                x(y, z)
+        "};
         parse_expression(
-            indoc! {"
-                def test():
-                    x(y, z)
-            "},
+            input,
             Expression::Call {
                 name: "x".to_string(),
                 args: vec![
@@ -855,21 +1993,22 @@ mod tests {
                         name: "z".to_string(),
                     },
                 ],
-                span: src_span(2, 5, 1),
+                span: src_span(input, 2, 5, 1),
             },
         );
     }
 
     #[test]
     fn call2_multiline_expression() {
+        let input = indoc! {"
+            def test():
+                x(
+                    y,
+                    z
+                )
+        "};
         parse_expression(
-            indoc! {"
-                def test():
-                    x(
-                        y,
-                        z
-                    )
-            "},
+            input,
             Expression::Call {
                 name: "x".to_string(),
                 args: vec![
@@ -880,46 +2019,695 @@ mod tests {
                         name: "z".to_string(),
                     },
                 ],
-                span: src_span(2, 5, 1),
+                span: src_span(input, 2, 5, 1),
             },
         );
     }
 
     #[test]
     fn string_literal() {
+        let input = indoc! {"
+            def test():
+                print(\"Hello, world!\")
+        "};
         parse_expression(
-            indoc! {"
-                def test():
-                    print(\"Hello, world!\")
-            "},
+            input,
             Expression::Call {
                 name: "print".to_string(),
                 args: vec![Expression::Literal(Literal::String(
                     "Hello, world!".to_string(),
                 ))],
-                span: src_span(2, 5, 5),
+                span: src_span(input, 2, 5, 5),
+            },
+        );
+    }
+
+    #[test]
+    fn triggers() {
+        let input = indoc! {r#"
+            triggers = [
+                cron("0 * * * *"),
+                watch("src"),
+            ]
+        "#};
+        assert_eq!(
+            parse(input).unwrap(),
+            Module {
+                imports: Vec::new(),
+                functions: Vec::new(),
+                triggers: vec![
+                    Trigger::Cron {
+                        span: src_span(input, 2, 5, 4),
+                        schedule: "0 * * * *".to_string()
+                    },
+                    Trigger::Watch {
+                        span: src_span(input, 3, 5, 5),
+                        path: "src".to_string()
+                    },
+                ],
+                triggers_span: Some(src_span(input, 1, 1, 8).merge(&src_span(input, 4, 1, 1))),
+                comments: Vec::new(),
+            }
+        );
+    }
+
+    #[test]
+    fn parallel() {
+        let input = indoc! {"
+            def test():
+                parallel(x, y)
+        "};
+        parse_expression(
+            input,
+            Expression::Parallel {
+                span: src_span(input, 2, 5, 8),
+                branches: vec![
+                    Expression::Call {
+                        name: "x".to_string(),
+                        args: Vec::new(),
+                        span: src_span(input, 2, 14, 1),
+                    },
+                    Expression::Call {
+                        name: "y".to_string(),
+                        args: Vec::new(),
+                        span: src_span(input, 2, 17, 1),
+                    },
+                ],
+            },
+        );
+    }
+
+    #[test]
+    fn imports() {
+        let input = indoc! {"
+            import helpers
+            from build import compile, test
+        "};
+        let module = parse_file(input, FileId::new(1)).unwrap();
+        let span = |line, column, len| SrcSpan {
+            file: FileId::new(1),
+            ..src_span(input, line, column, len)
+        };
+
+        assert_eq!(
+            module.imports(),
+            [
+                Import::Module {
+                    span: span(1, 8, 7),
+                    module: "helpers".to_string()
+                },
+                Import::Names {
+                    span: span(2, 6, 5),
+                    module: "build".to_string(),
+                    names: vec![
+                        (span(2, 19, 7), "compile".to_string()),
+                        (span(2, 28, 4), "test".to_string())
+                    ],
+                },
+            ]
+        );
+    }
+
+    #[test]
+    fn qualified_call() {
+        let input = indoc! {"
+            def test():
+                helpers.build()
+        "};
+        parse_expression(
+            input,
+            Expression::Call {
+                name: "helpers.build".to_string(),
+                args: Vec::new(),
+                span: src_span(input, 2, 5, 13),
+            },
+        );
+    }
+
+    #[test]
+    fn spans() {
+        let input = indoc! {"
+            def test():
+                if x():
+                    y()
+                    z()
+                else:
+                    pass
+        "};
+        let module = parse(input).unwrap();
+        let function = &module.functions()[0];
+        let whole_span = function.whole_span();
+        assert_eq!(whole_span.line(), 1);
+        assert_eq!(whole_span.column(), 1);
+        assert_eq!(whole_span.end().line(), 6);
+        assert_eq!(whole_span.end().column(), 13);
+        assert_eq!(&input[whole_span.byte_range()], input.trim_end());
+
+        let (if_span, if_statement) = function.body().spanned().next().unwrap();
+        assert_eq!(if_span.start(), src_span(input, 2, 5, 2).start());
+        assert_eq!(if_span.end(), whole_span.end());
+
+        let Statement::If {
+            if_span: keyword_span,
+            then_block,
+            else_block: Some(else_block),
+            ..
+        } = if_statement
+        else {
+            panic!("Expected an if statement");
+        };
+        assert_eq!(*keyword_span, src_span(input, 2, 5, 2));
+        assert!(if_span.contains(keyword_span));
+        assert!(if_span.contains(&then_block.span()));
+        assert!(!then_block.span().contains(&if_span));
+        assert_eq!(
+            then_block.span(),
+            src_span(input, 3, 9, 3).merge(&src_span(input, 4, 9, 3))
+        );
+        assert_eq!(
+            keyword_span.merge(&else_block.body.span()).end(),
+            if_span.end()
+        );
+        assert!(if_span.contains_offset(FileId::default(), then_block.span().start().offset()));
+        assert!(!if_span.contains_offset(FileId::new(1), then_block.span().start().offset()));
+    }
+
+    #[test]
+    fn with() {
+        let input = indoc! {"
+            def test():
+                with lock(\"a\") as held:
+                    pass
+                with(lock()):
+                    pass
+        "};
+        let module = parse(input).unwrap();
+        let statements: Vec<_> = module.functions()[0].body().iter().collect();
+
+        let Statement::With {
+            with_span,
+            context_manager,
+            target,
+            body,
+        } = statements[0]
+        else {
+            panic!("Expected a with statement");
+        };
+        assert_eq!(*with_span, src_span(input, 2, 5, 4));
+        assert!(matches!(
+            context_manager.as_ref(),
+            Expression::Call { name, .. } if name == "lock"
+        ));
+        assert_eq!(
+            target.as_ref(),
+            Some(&(src_span(input, 2, 23, 4), "held".to_string()))
+        );
+        assert_eq!(body.iter().collect::<Vec<_>>(), [&Statement::Pass]);
+
+        assert!(matches!(
+            statements[1],
+            Statement::With { target: None, .. }
+        ));
+    }
+
+    #[test]
+    fn global() {
+        parse_function_body(
+            indoc! {"
+                def test():
+                    global x, y
+            "},
+            [Statement::Global(vec!["x".to_string(), "y".to_string()])],
+        );
+    }
+
+    #[test]
+    fn nonlocal() {
+        let input = indoc! {"
+            def test():
+                nonlocal y, z
+        "};
+        let error = parse(input).unwrap_err();
+
+        assert_eq!(error.text(), "no binding for nonlocal 'y' found");
+        assert_eq!(error.span(), src_span(input, 2, 5, 13));
+    }
+
+    #[test]
+    fn lambda() {
+        let input = indoc! {"
+            def test():
+                sorted(lambda x, y: x, lambda: lambdas)
+        "};
+        parse_expression(
+            input,
+            Expression::Call {
+                name: "sorted".to_string(),
+                args: vec![
+                    Expression::Lambda {
+                        span: src_span(input, 2, 12, 6),
+                        params: vec![
+                            (src_span(input, 2, 19, 1), "x".to_string()),
+                            (src_span(input, 2, 22, 1), "y".to_string()),
+                        ],
+                        body: Box::new(Expression::Variable {
+                            name: "x".to_string(),
+                        }),
+                    },
+                    Expression::Lambda {
+                        span: src_span(input, 2, 28, 6),
+                        params: Vec::new(),
+                        body: Box::new(Expression::Variable {
+                            name: "lambdas".to_string(),
+                        }),
+                    },
+                ],
+                span: src_span(input, 2, 5, 6),
+            },
+        );
+    }
+
+    #[test]
+    fn break_continue() {
+        let input = indoc! {"
+            def test():
+                if True:
+                    continue
+                break
+        "};
+        let error = parse(input).unwrap_err();
+
+        assert_eq!(error.text(), "'continue' outside loop");
+        assert_eq!(error.span(), src_span(input, 3, 9, 8));
+
+        parse_expression(
+            indoc! {"
+                def test():
+                    breakfast
+            "},
+            Expression::Variable {
+                name: "breakfast".to_string(),
             },
         );
     }
 
+    #[test]
+    fn decorators() {
+        let input = indoc! {r#"
+            @retry(3, backoff=2)
+            @timeout(seconds=60)  # Comment
+            @step(name="Build")
+            @cache(key="a", files=["src"])
+            def build():
+                pass
+
+            @retry(0)
+            def never():
+                pass
+        "#};
+        let error = parse(input).unwrap_err();
+        assert_eq!(error.span().line(), 8);
+        assert_eq!(error.span().column(), 8);
+
+        let module = parse(input.split("\n\n").next().unwrap()).unwrap();
+        let function = &module.functions()[0];
+
+        assert_eq!(
+            function.decorators(),
+            [
+                Decorator::Retry {
+                    span: src_span(input, 1, 1, 20),
+                    attempts: 3,
+                    backoff: 2
+                },
+                Decorator::Timeout {
+                    span: src_span(input, 2, 1, 20),
+                    seconds: 60
+                },
+                Decorator::Step {
+                    span: src_span(input, 3, 1, 19),
+                    name: "Build".to_string()
+                },
+                Decorator::Cache {
+                    span: src_span(input, 4, 1, 30),
+                    key: Some("a".to_string()),
+                    files: vec!["src".to_string()]
+                },
+            ]
+        );
+        assert_eq!(function.whole_span().line(), 1);
+        assert_eq!(function.span(), src_span(input, 5, 5, 5));
+
+        let library = Library::link(module).unwrap();
+        let build = library.lookup(library.function_id("build").unwrap());
+        assert_eq!(build.display_name(), "Build");
+        assert_eq!(build.retry(), Some((3, Duration::from_secs(2))));
+        assert_eq!(build.timeout(), Some(Duration::from_secs(60)));
+    }
+
+    #[test]
+    fn duplicate_decorators() {
+        let input = indoc! {"
+            @timeout(60)
+            @cache
+            @timeout(30)
+            def build():
+                pass
+        "};
+        let error = parse(input).unwrap_err();
+
+        assert_eq!(error.text(), "Duplicate decorator '@timeout'");
+        assert_eq!(error.span(), src_span(input, 3, 1, 12));
+    }
+
+    #[test]
+    fn retry() {
+        let (_library, call_states, call) = run_function_with(
+            indoc! {"
+            @retry(2)
+            @timeout(1)
+            def test():
+                print()
+        "},
+            ThreadRunState::default().with_host_call_time(Duration::from_secs(2)),
+            false,
+        );
+
+        assert_eq!(call_states.run_state(&call), RunState::Failed);
+
+        for attempt in 0..2 {
+            let attempt = call.push_cloned(StackFrame::Attempt(attempt));
+            assert_eq!(call_states.run_state(&attempt), RunState::Failed);
+        }
+
+        assert_eq!(call_states.logs(&call), ["Attempt 1 of 2 failed"]);
+    }
+
+    #[test]
+    fn timeout() {
+        let (library, call_states, call) = run_function_with(
+            indoc! {"
+            @timeout(1)
+            def test():
+                print()
+                print()
+        "},
+            ThreadRunState::default().with_host_call_time(Duration::from_millis(600)),
+            false,
+        );
+
+        let print = |index| {
+            call.push_cloned(StackFrame::Statement(index))
+                .push_cloned(StackFrame::Call(library.function_id("print").unwrap()))
+        };
+
+        assert_eq!(call_states.run_state(&call), RunState::Failed);
+        assert_eq!(call_states.run_state(&print(0)), RunState::Successful);
+        assert_eq!(call_states.run_state(&print(1)), RunState::Failed);
+        assert_eq!(call_states.logs(&print(1)), ["Timed out"]);
+    }
+
+    #[test]
+    fn unknown_secret() {
+        let (library, call_states, call) = run_function(
+            indoc! {r#"
+            def test():
+                secret("MISSING")
+                secret()
+        "#},
+            false,
+        );
+        let secret = |index| {
+            call.push_cloned(StackFrame::Statement(index))
+                .push_cloned(StackFrame::Call(library.function_id("secret").unwrap()))
+        };
+
+        assert_eq!(call_states.run_state(&secret(0)), RunState::Failed);
+        assert_eq!(
+            call_states.logs(&secret(0)),
+            [r#"Unknown secret "MISSING""#]
+        );
+        assert_eq!(call_states.run_state(&secret(1)), RunState::NotRun);
+    }
+
+    #[test]
+    fn invalid_secret_arguments() {
+        let (library, call_states, call) = run_function(
+            indoc! {r#"
+            def test():
+                secret("A", "B")
+        "#},
+            false,
+        );
+        let secret = call
+            .push_cloned(StackFrame::Statement(0))
+            .push_cloned(StackFrame::Call(library.function_id("secret").unwrap()));
+
+        assert_eq!(call_states.run_state(&secret), RunState::Failed);
+        assert_eq!(
+            call_states.logs(&secret),
+            ["secret() takes a single secret name"]
+        );
+    }
+
+    #[test]
+    fn with_statement() {
+        let (library, call_states, call) = run_function_with(
+            indoc! {"
+            def test():
+                with run_input() as db:
+                    migrate(db)
+        "},
+            ThreadRunState::default()
+                .with_host_call_time(Duration::ZERO)
+                .with_input(Value::String("db".to_owned())),
+            true,
+        );
+        let with = call.push_cloned(StackFrame::Statement(0));
+        let migrate = with
+            .push_cloned(StackFrame::NestedBlock(0, NestedBlock::Body))
+            .push_cloned(StackFrame::Statement(0))
+            .push_cloned(StackFrame::Call(library.function_id("migrate").unwrap()));
+
+        assert_eq!(
+            call_states.logs(&with.push_cloned(StackFrame::NestedBlock(0, NestedBlock::Predicate))),
+            [r#"__enter__(String("db"))"#]
+        );
+        assert_eq!(call_states.logs(&migrate), [r#"migrate([String("db")])"#]);
+        assert_eq!(call_states.logs(&call), [r#"__exit__(String("db"))"#]);
+    }
+
+    #[test]
+    fn with_statement_failure() {
+        let (_library, call_states, call) = run_function_with(
+            indoc! {r#"
+            def test():
+                with lock():
+                    secret("MISSING")
+        "#},
+            ThreadRunState::default().with_host_call_time(Duration::ZERO),
+            false,
+        );
+        let body = call
+            .push_cloned(StackFrame::Statement(0))
+            .push_cloned(StackFrame::NestedBlock(0, NestedBlock::Body));
+
+        assert_eq!(call_states.run_state(&body), RunState::NotRun);
+        assert_eq!(call_states.logs(&call), ["__exit__(None)"]);
+    }
+
+    #[test]
+    fn global_variables() {
+        let (library, call_states, call) = run_function_with(
+            indoc! {"
+            def test():
+                global current
+                with run_input() as current:
+                    pass
+                show()
+
+            def show():
+                print(current)
+        "},
+            ThreadRunState::default()
+                .with_host_call_time(Duration::ZERO)
+                .with_input(Value::String("x".to_owned())),
+            true,
+        );
+        let print = call
+            .push_cloned(StackFrame::Statement(2))
+            .push_cloned(StackFrame::Call(library.function_id("show").unwrap()))
+            .push_cloned(StackFrame::Statement(0))
+            .push_cloned(StackFrame::Call(library.function_id("print").unwrap()));
+
+        assert_eq!(call_states.logs(&print), [r#"print([String("x")])"#]);
+    }
+
+    #[test]
+    fn unknown_variable() {
+        let (library, call_states, call) = run_function_with(
+            indoc! {"
+            def test():
+                with run_input() as local:
+                    pass
+                show()
+
+            def show():
+                print(local)
+        "},
+            ThreadRunState::default().with_host_call_time(Duration::ZERO),
+            false,
+        );
+        let show = call
+            .push_cloned(StackFrame::Statement(1))
+            .push_cloned(StackFrame::Call(library.function_id("show").unwrap()));
+
+        assert_eq!(call_states.run_state(&show), RunState::Failed);
+        assert_eq!(call_states.logs(&show), ["Name 'local' is not defined"]);
+    }
+
+    #[test]
+    fn lambda_value() {
+        let (library, call_states, call) = run_function_with(
+            indoc! {"
+            def test():
+                sorted(lambda item: item)
+        "},
+            ThreadRunState::default().with_host_call_time(Duration::ZERO),
+            true,
+        );
+        let sorted = call
+            .push_cloned(StackFrame::Statement(0))
+            .push_cloned(StackFrame::Call(library.function_id("sorted").unwrap()));
+
+        assert_eq!(
+            call_states.logs(&sorted),
+            [r#"sorted([Lambda { params: ["item"], body: Variable { name: "item" } }])"#]
+        );
+    }
+
+    #[test]
+    fn cache() {
+        let (library, call_states, call) = run_function(
+            indoc! {r#"
+                def test():
+                    build("a")
+                    build("a")
+                    build("b")
+
+                @cache
+                def build():
+                    print()
+            "#},
+            true,
+        );
+        let build = |index| {
+            call.push_cloned(StackFrame::Statement(index))
+                .push_cloned(StackFrame::Call(library.function_id("build").unwrap()))
+        };
+
+        assert_eq!(call_states.run_state(&build(0)), RunState::Successful);
+        assert_eq!(call_states.run_state(&build(1)), RunState::Cached);
+        assert_eq!(call_states.run_state(&build(2)), RunState::Successful);
+        assert!(call_states.logs(&build(0)).is_empty());
+        assert_eq!(call_states.logs(&build(1)), ["print([])"]);
+    }
+
+    #[test]
+    fn cache_key_callees() {
+        let cache_key = |helper_body, unrelated_body| {
+            let input = formatdoc! {"
+                @cache
+                def build():
+                    compile()
+
+                def compile():
+                    helper()
+
+                def helper():
+                    {helper_body}
+
+                def unrelated():
+                    {unrelated_body}
+            "};
+            let library = Library::link(parse(&input).unwrap()).unwrap();
+            let build = library.lookup(library.function_id("build").unwrap());
+            build.cache_key(&[], None).unwrap()
+        };
+
+        let original = cache_key("pass", "pass");
+        assert_eq!(original, cache_key("pass", "print()"));
+        assert_ne!(original, cache_key("print()", "pass"));
+    }
+
+    /// Run the function `test`, returning it's call stack.
+    fn run_function(input: &str, succeeds: bool) -> (Library, ThreadRunState, CallStack) {
+        run_function_with(input, ThreadRunState::default(), succeeds)
+    }
+
+    /// Run the function `test` with `call_states`, returning it's call stack.
+    fn run_function_with(
+        input: &str,
+        call_states: ThreadRunState,
+        succeeds: bool,
+    ) -> (Library, ThreadRunState, CallStack) {
+        let library = Library::link(parse(input).unwrap()).unwrap();
+        let id = library.function_id("test").unwrap();
+        let result = call_states.try_run(StackFrame::Call(id), || {
+            library.lookup(id).run(&[], &library, &call_states)
+        });
+
+        assert_eq!(result.is_ok(), succeeds);
+        (
+            library,
+            call_states,
+            CallStack::new().push_cloned(StackFrame::Call(id)),
+        )
+    }
+
     fn parse_expression(input: &str, expression: Expression<String>) {
         parse_function_body(input, [Statement::Expression(expression)])
     }
 
     fn parse_function_body<const COUNT: usize>(input: &str, body: [Statement<String>; COUNT]) {
+        let module = parse(input).unwrap();
+        assert!(module.imports().is_empty());
+        assert!(module.triggers().is_empty());
+
+        let [function] = module.functions() else {
+            panic!("Expected a single function");
+        };
+        assert_eq!(function.name(), "test");
+        assert_eq!(function.span(), src_span(input, 1, 5, 4));
         assert_eq!(
-            parse(input).unwrap(),
-            Module {
-                functions: vec![Function {
-                    name: "test".to_owned(),
-                    span: src_span(1, 5, 4),
-                    body: Body::new(body),
-                }],
-            }
+            function.body().iter().collect::<Vec<_>>(),
+            body.iter().collect::<Vec<_>>()
         );
     }
 
-    fn src_span(line: usize, column: usize, len: usize) -> SrcSpan {
-        SrcSpan { line, column, len }
+    /// A span on a single line of `input`.
+    fn src_span(input: &str, line: usize, column: usize, len: usize) -> SrcSpan {
+        let offset = input
+            .split_inclusive('\n')
+            .take(line - 1)
+            .map(str::len)
+            .sum::<usize>()
+            + column
+            - 1;
+        let start = SrcPos {
+            line,
+            column,
+            offset,
+        };
+
+        SrcSpan {
+            file: FileId::default(),
+            start,
+            end: SrcPos {
+                line,
+                column: column + len,
+                offset: offset + len,
+            },
+        }
     }
 }
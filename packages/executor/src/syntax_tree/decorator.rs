@@ -0,0 +1,161 @@
+//! Decorators on function definitions, like `@retry(3)`.
+//!
+//! Like Python, arguments can be passed by position or by keyword, so
+//! `@step("Build")` and `@step(name="Build")` are the same.
+use nom::{
+    branch::alt,
+    bytes::complete::tag,
+    character::complete::{space0, u32 as unsigned32, u64 as unsigned64},
+    combinator::{consumed, cut, opt, success, verify},
+    error::context,
+    multi::separated_list0,
+    sequence::{delimited, pair, preceded, separated_pair, terminated},
+    Parser as _,
+};
+
+use super::{equals, keyword_end, multiline_ws, string_literal, Parser, Span, SrcSpan};
+
+#[derive(Clone, PartialEq, Eq, Debug)]
+pub enum Decorator {
+    /// `@retry(<attempts>, backoff=<seconds>)`
+    ///
+    /// Run the function up to `attempts` times, until it succeeds. We wait
+    /// `backoff` seconds before the first retry, doubling the wait for each
+    /// retry after that.
+    Retry {
+        span: SrcSpan,
+        attempts: u32,
+        backoff: u64,
+    },
+    /// `@timeout(<seconds>)`
+    ///
+    /// Fail the function if it runs for longer than `seconds`.
+    ///
+    /// The deadline is checked before each statement and call, and host
+    /// function calls are abandoned if it passes while they're running.
+    Timeout { span: SrcSpan, seconds: u64 },
+    /// `@step(name="<name>")`
+    ///
+    /// The name to show for the function in the call tree.
+    Step { span: SrcSpan, name: String },
+    /// `@cache`, or `@cache(key="<key>", files=["<path>", ...])`
+    ///
+    /// Reuse the result of an earlier call with the same arguments, `key` and
+    /// file contents. See [`crate::step_cache`].
+    Cache {
+        span: SrcSpan,
+        key: Option<String>,
+        files: Vec<String>,
+    },
+}
+
+impl Decorator {
+    /// The span of the whole decorator, from the `@` to the closing bracket.
+    pub fn span(&self) -> SrcSpan {
+        match self {
+            Self::Retry { span, .. }
+            | Self::Timeout { span, .. }
+            | Self::Step { span, .. }
+            | Self::Cache { span, .. } => *span,
+        }
+    }
+
+    /// The name used after the `@`.
+    pub fn name(&self) -> &'static str {
+        match self {
+            Self::Retry { .. } => "retry",
+            Self::Timeout { .. } => "timeout",
+            Self::Step { .. } => "step",
+            Self::Cache { .. } => "cache",
+        }
+    }
+
+    pub(super) fn parse<'a>() -> impl Parser<'a, Self> {
+        context(
+            "decorator",
+            alt((
+                call(
+                    "retry",
+                    arguments(pair(
+                        argument("attempts", verify(unsigned32, |attempts| *attempts > 0)),
+                        opt(preceded(
+                            multiline_ws(tag(",")),
+                            argument("backoff", unsigned64),
+                        )),
+                    )),
+                )
+                .map(|(span, (attempts, backoff))| Self::Retry {
+                    span,
+                    attempts,
+                    backoff: backoff.unwrap_or(0),
+                }),
+                call("timeout", arguments(argument("seconds", unsigned64)))
+                    .map(|(span, seconds)| Self::Timeout { span, seconds }),
+                call("step", arguments(argument("name", string_literal()))).map(|(span, name)| {
+                    Self::Step {
+                        span,
+                        name: name.fragment().to_string(),
+                    }
+                }),
+                call("cache", opt(arguments(cache_arguments()))).map(|(span, args)| {
+                    let (key, files) = args.unwrap_or_default();
+                    Self::Cache { span, key, files }
+                }),
+            )),
+        )
+    }
+}
+
+/// `@<name><args>`
+///
+/// Once we've seen the name, any errors in `args` are reported where they
+/// happen, rather than at the `@`.
+fn call<'a, O>(name: &'static str, args: impl Parser<'a, O>) -> impl Parser<'a, (SrcSpan, O)> {
+    consumed(preceded(
+        pair(tag("@"), terminated(tag(name), keyword_end())),
+        cut(args),
+    ))
+    .map(|(span, args): (Span, O)| (SrcSpan::from_span(&span), args))
+}
+
+/// `(<args>)`
+fn arguments<'a, O>(args: impl Parser<'a, O>) -> impl Parser<'a, O> {
+    delimited(pair(space0, tag("(")), multiline_ws(args), tag(")"))
+}
+
+/// `key="<key>", files=["<path>", ...]`, where both are optional.
+fn cache_arguments<'a>() -> impl Parser<'a, (Option<String>, Vec<String>)> {
+    let key = || argument("key", string_literal()).map(|key| key.fragment().to_string());
+    let files = || {
+        argument(
+            "files",
+            delimited(
+                tag("["),
+                multiline_ws(terminated(
+                    separated_list0(tag(","), multiline_ws(string_literal())),
+                    opt(tag(",")),
+                )),
+                tag("]"),
+            ),
+        )
+        .map(|files| {
+            files
+                .iter()
+                .map(|file| file.fragment().to_string())
+                .collect()
+        })
+    };
+
+    alt((
+        separated_pair(key(), multiline_ws(tag(",")), files())
+            .map(|(key, files)| (Some(key), files)),
+        files().map(|files| (None, files)),
+        key().map(|key| (Some(key), Vec::new())),
+        success((None, Vec::new())),
+    ))
+}
+
+/// An argument that can be passed by position, or as `<name>=<value>`.
+fn argument<'a, O>(name: &'static str, value: impl Parser<'a, O>) -> impl Parser<'a, O> {
+    preceded(opt(pair(tag(name), equals)), value)
+}
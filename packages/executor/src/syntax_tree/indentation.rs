@@ -0,0 +1,242 @@
+//! Python's indentation rules.
+//!
+//! Indentation is checked before parsing, so mistakes get a specific error
+//! rather than a generic parse error. Like Python, a tab moves to the next
+//! multiple of 8 columns. Indentation that compares differently when tabs are
+//! 1 column wide is ambiguous, and is a [`ParseError::Tab`].
+use super::{FileId, ParseError, SrcPos, SrcSpan};
+
+/// The width of `indent`, in columns.
+pub(super) fn width(indent: &str) -> usize {
+    Indent::new(indent).width
+}
+
+pub(super) fn check(input: &str, file: FileId) -> Result<(), ParseError> {
+    let mut levels = vec![Indent::default()];
+    let mut expect_block = false;
+    let mut brackets = 0_usize;
+    let mut in_string = false;
+    let mut last_code_char = None;
+    let mut pos = SrcPos {
+        line: 1,
+        column: 1,
+        offset: 0,
+    };
+
+    for line in input.split_inclusive('\n') {
+        let line_start = pos;
+        pos = pos.advance(line);
+        let content = line.trim_start_matches([' ', '\t']);
+        let indent = &line[..line.len() - content.len()];
+        let content = content.trim_end();
+
+        if brackets == 0 && !in_string {
+            // Blank lines and comments don't affect indentation
+            if content.is_empty() || content.starts_with('#') {
+                continue;
+            }
+
+            let current = *levels.last().unwrap();
+            let new = Indent::new(indent);
+            let indent_span = span(file, line_start, indent);
+
+            if new.width > current.width {
+                if new.alt_width <= current.alt_width {
+                    return Err(ParseError::Tab { span: indent_span });
+                }
+
+                if !expect_block {
+                    return Err(indentation_error(indent_span, "unexpected indent"));
+                }
+
+                levels.push(new);
+            } else if expect_block {
+                let content_span = span(file, line_start.advance(indent), content);
+                return Err(indentation_error(
+                    content_span,
+                    "expected an indented block",
+                ));
+            } else {
+                while new.width < levels.last().unwrap().width {
+                    levels.pop();
+                }
+
+                let current = levels.last().unwrap();
+
+                if new.width != current.width {
+                    return Err(indentation_error(
+                        indent_span,
+                        "unindent does not match any outer indentation level",
+                    ));
+                }
+
+                if new.alt_width != current.alt_width {
+                    return Err(ParseError::Tab { span: indent_span });
+                }
+            }
+
+            last_code_char = None;
+        }
+
+        for c in content.chars() {
+            if in_string {
+                in_string = c != '"';
+            } else {
+                match c {
+                    '#' => break,
+                    '"' => in_string = true,
+                    '(' | '[' | '{' => brackets += 1,
+                    ')' | ']' | '}' => brackets = brackets.saturating_sub(1),
+                    _ => (),
+                }
+            }
+
+            if !c.is_whitespace() {
+                last_code_char = Some(c);
+            }
+        }
+
+        if brackets == 0 && !in_string {
+            expect_block = last_code_char == Some(':');
+        }
+    }
+
+    if expect_block {
+        return Err(indentation_error(
+            span(file, pos, ""),
+            "expected an indented block",
+        ));
+    }
+
+    Ok(())
+}
+
+#[derive(Copy, Clone, Default, Debug)]
+struct Indent {
+    /// With tabs to the next multiple of 8 columns.
+    width: usize,
+    /// With tabs 1 column wide.
+    alt_width: usize,
+}
+
+impl Indent {
+    fn new(indent: &str) -> Self {
+        let width = indent.chars().fold(0, |width, c| match c {
+            '\t' => (width / TAB_SIZE + 1) * TAB_SIZE,
+            _ => width + 1,
+        });
+
+        Self {
+            width,
+            alt_width: indent.chars().count(),
+        }
+    }
+}
+
+fn span(file: FileId, start: SrcPos, text: &str) -> SrcSpan {
+    SrcSpan {
+        file,
+        start,
+        end: start.advance(text),
+    }
+}
+
+fn indentation_error(span: SrcSpan, message: &str) -> ParseError {
+    ParseError::Indentation {
+        span,
+        message: message.to_owned(),
+    }
+}
+
+const TAB_SIZE: usize = 8;
+
+#[cfg(test)]
+mod tests {
+    use crate::syntax_tree::{parse, ParseError};
+
+    #[test]
+    fn tabs() {
+        let module = parse(concat!(
+            "def main():\n",
+            "\tif True:\n",
+            "\t\tpass\n",
+            "\telse:\n",
+            "\t        pass\n",
+            "\tpass\n",
+        ))
+        .unwrap();
+
+        assert_eq!(module.functions()[0].body().iter().count(), 2);
+    }
+
+    #[test]
+    fn continuation_lines() {
+        parse(concat!(
+            "def main():\n",
+            "    print(\n",
+            "  \"a:\",\n",
+            "        x())\n",
+            "    pass\n",
+            "triggers = [\n",
+            "  cron(\"0 * * * *\"),\n",
+            "]\n",
+        ))
+        .unwrap();
+    }
+
+    #[test]
+    fn tab_error() {
+        assert_error("def main():\n    if True:\n\tpass\n", "TabError", (3, 1, 1));
+        assert_error("def main():\n        pass\n\tpass\n", "TabError", (3, 1, 1));
+    }
+
+    #[test]
+    fn unexpected_indent() {
+        assert_error(
+            "def main():\n    pass\n      pass\n",
+            "unexpected indent",
+            (3, 1, 6),
+        );
+    }
+
+    #[test]
+    fn unindent_mismatch() {
+        assert_error(
+            "def main():\n    if True:\n        pass\n  pass\n",
+            "unindent does not match any outer indentation level",
+            (4, 1, 2),
+        );
+    }
+
+    #[test]
+    fn expected_block() {
+        assert_error(
+            "def main():\n# Comment\npass\n",
+            "expected an indented block",
+            (3, 1, 4),
+        );
+        assert_error(
+            "def main():\n    if True:  # Comment\n",
+            "expected an indented block",
+            (3, 1, 0),
+        );
+    }
+
+    fn assert_error(source: &str, message: &str, (line, column, len): (usize, usize, usize)) {
+        let error = parse(source).unwrap_err();
+        let span = error.span();
+
+        assert!(
+            matches!(
+                error,
+                ParseError::Indentation { .. } | ParseError::Tab { .. }
+            ),
+            "{error}"
+        );
+        assert!(error.to_string().contains(message), "{error}");
+        assert_eq!(
+            (span.line(), span.column(), span.len()),
+            (line, column, len)
+        );
+    }
+}
@@ -0,0 +1,469 @@
+//! Generic traversals of the syntax tree.
+//!
+//! - [`Visit`] walks a tree by reference.
+//! - [`VisitMut`] walks a tree by mutable reference, so nodes can be edited in
+//!   place.
+//! - [`Fold`] builds a new tree, possibly with a different function id type.
+//!
+//! Each trait method defaults to calling the free function with the same name
+//! (with `walk_` in place of `visit_`), which visits the node's children.
+//! Override the methods for the nodes you're interested in, and call the free
+//! function from the override if you want to carry on into the children.
+use std::sync::Arc;
+
+use super::{Body, ElseClause, Expression, SrcSpan, Statement};
+
+pub trait Visit<'ast, FnId> {
+    fn visit_body(&mut self, body: &'ast Body<FnId>) {
+        walk_body(self, body)
+    }
+
+    fn visit_statement(&mut self, span: SrcSpan, statement: &'ast Statement<FnId>) {
+        walk_statement(self, span, statement)
+    }
+
+    fn visit_else_clause(&mut self, else_clause: &'ast ElseClause<FnId>) {
+        walk_else_clause(self, else_clause)
+    }
+
+    fn visit_expression(&mut self, expression: &'ast Expression<FnId>) {
+        walk_expression(self, expression)
+    }
+
+    /// Visit a call, including each branch of a `parallel`.
+    fn visit_call(&mut self, span: SrcSpan, name: &'ast FnId, args: &'ast [Expression<FnId>]) {
+        walk_call(self, span, name, args)
+    }
+}
+
+pub fn walk_body<'ast, FnId, V>(visitor: &mut V, body: &'ast Body<FnId>)
+where
+    V: Visit<'ast, FnId> + ?Sized,
+{
+    for (span, statement) in &body.statements {
+        visitor.visit_statement(*span, statement);
+    }
+}
+
+pub fn walk_statement<'ast, FnId, V>(
+    visitor: &mut V,
+    _span: SrcSpan,
+    statement: &'ast Statement<FnId>,
+) where
+    V: Visit<'ast, FnId> + ?Sized,
+{
+    match statement {
+        Statement::Pass
+        | Statement::Global(_)
+        | Statement::Nonlocal(_)
+        | Statement::Break
+        | Statement::Continue => (),
+        Statement::Expression(expression) => visitor.visit_expression(expression),
+        Statement::If {
+            condition,
+            then_block,
+            else_block,
+            ..
+        } => {
+            visitor.visit_expression(condition);
+            visitor.visit_body(then_block);
+
+            if let Some(else_block) = else_block {
+                visitor.visit_else_clause(else_block);
+            }
+        }
+        Statement::With {
+            context_manager,
+            body,
+            ..
+        } => {
+            visitor.visit_expression(context_manager);
+            visitor.visit_body(body);
+        }
+    }
+}
+
+pub fn walk_else_clause<'ast, FnId, V>(visitor: &mut V, else_clause: &'ast ElseClause<FnId>)
+where
+    V: Visit<'ast, FnId> + ?Sized,
+{
+    visitor.visit_body(&else_clause.body);
+}
+
+pub fn walk_expression<'ast, FnId, V>(visitor: &mut V, expression: &'ast Expression<FnId>)
+where
+    V: Visit<'ast, FnId> + ?Sized,
+{
+    match expression {
+        Expression::Literal(_) | Expression::Variable { .. } => (),
+        Expression::Call { span, name, args } => visitor.visit_call(*span, name, args),
+        Expression::Parallel { branches, .. } => {
+            for branch in branches {
+                visitor.visit_expression(branch);
+            }
+        }
+        Expression::Lambda { body, .. } => visitor.visit_expression(body),
+    }
+}
+
+pub fn walk_call<'ast, FnId, V>(
+    visitor: &mut V,
+    _span: SrcSpan,
+    _name: &'ast FnId,
+    args: &'ast [Expression<FnId>],
+) where
+    V: Visit<'ast, FnId> + ?Sized,
+{
+    for arg in args {
+        visitor.visit_expression(arg);
+    }
+}
+
+/// Like [`Visit`], but with mutable access to the tree.
+///
+/// Nodes that are shared with another tree, through an [`Arc`], are cloned
+/// before they're visited.
+pub trait VisitMut<FnId: Clone> {
+    fn visit_body_mut(&mut self, body: &mut Body<FnId>) {
+        walk_body_mut(self, body)
+    }
+
+    fn visit_statement_mut(&mut self, span: SrcSpan, statement: &mut Statement<FnId>) {
+        walk_statement_mut(self, span, statement)
+    }
+
+    fn visit_else_clause_mut(&mut self, else_clause: &mut ElseClause<FnId>) {
+        walk_else_clause_mut(self, else_clause)
+    }
+
+    fn visit_expression_mut(&mut self, expression: &mut Expression<FnId>) {
+        walk_expression_mut(self, expression)
+    }
+
+    /// Visit a call, including each branch of a `parallel`.
+    fn visit_call_mut(&mut self, span: SrcSpan, name: &mut FnId, args: &mut Vec<Expression<FnId>>) {
+        walk_call_mut(self, span, name, args)
+    }
+}
+
+pub fn walk_body_mut<FnId, V>(visitor: &mut V, body: &mut Body<FnId>)
+where
+    FnId: Clone,
+    V: VisitMut<FnId> + ?Sized,
+{
+    for (span, statement) in &mut body.statements {
+        visitor.visit_statement_mut(*span, statement);
+    }
+}
+
+pub fn walk_statement_mut<FnId, V>(visitor: &mut V, _span: SrcSpan, statement: &mut Statement<FnId>)
+where
+    FnId: Clone,
+    V: VisitMut<FnId> + ?Sized,
+{
+    match statement {
+        Statement::Pass
+        | Statement::Global(_)
+        | Statement::Nonlocal(_)
+        | Statement::Break
+        | Statement::Continue => (),
+        Statement::Expression(expression) => visitor.visit_expression_mut(expression),
+        Statement::If {
+            condition,
+            then_block,
+            else_block,
+            ..
+        } => {
+            visitor.visit_expression_mut(Arc::make_mut(condition));
+            visitor.visit_body_mut(Arc::make_mut(then_block));
+
+            if let Some(else_block) = else_block {
+                visitor.visit_else_clause_mut(else_block);
+            }
+        }
+        Statement::With {
+            context_manager,
+            body,
+            ..
+        } => {
+            visitor.visit_expression_mut(Arc::make_mut(context_manager));
+            visitor.visit_body_mut(Arc::make_mut(body));
+        }
+    }
+}
+
+pub fn walk_else_clause_mut<FnId, V>(visitor: &mut V, else_clause: &mut ElseClause<FnId>)
+where
+    FnId: Clone,
+    V: VisitMut<FnId> + ?Sized,
+{
+    visitor.visit_body_mut(Arc::make_mut(&mut else_clause.body));
+}
+
+pub fn walk_expression_mut<FnId, V>(visitor: &mut V, expression: &mut Expression<FnId>)
+where
+    FnId: Clone,
+    V: VisitMut<FnId> + ?Sized,
+{
+    match expression {
+        Expression::Literal(_) | Expression::Variable { .. } => (),
+        Expression::Call { span, name, args } => visitor.visit_call_mut(*span, name, args),
+        Expression::Parallel { branches, .. } => {
+            for branch in branches {
+                visitor.visit_expression_mut(branch);
+            }
+        }
+        Expression::Lambda { body, .. } => visitor.visit_expression_mut(body),
+    }
+}
+
+pub fn walk_call_mut<FnId, V>(
+    visitor: &mut V,
+    _span: SrcSpan,
+    _name: &mut FnId,
+    args: &mut Vec<Expression<FnId>>,
+) where
+    FnId: Clone,
+    V: VisitMut<FnId> + ?Sized,
+{
+    for arg in args {
+        visitor.visit_expression_mut(arg);
+    }
+}
+
+/// Build a new tree with function ids of type `To`, from a tree with ids of
+/// type `From`.
+///
+/// Spans are copied from the original tree.
+pub trait Fold<From, To> {
+    /// Map the function id of a call.
+    fn fold_fn_id(&mut self, span: SrcSpan, id: &From) -> To;
+
+    fn fold_body(&mut self, body: &Body<From>) -> Body<To> {
+        fold_body(self, body)
+    }
+
+    fn fold_statement(&mut self, span: SrcSpan, statement: &Statement<From>) -> Statement<To> {
+        fold_statement(self, span, statement)
+    }
+
+    fn fold_else_clause(&mut self, else_clause: &ElseClause<From>) -> ElseClause<To> {
+        fold_else_clause(self, else_clause)
+    }
+
+    fn fold_expression(&mut self, expression: &Expression<From>) -> Expression<To> {
+        fold_expression(self, expression)
+    }
+}
+
+pub fn fold_body<From, To, F>(folder: &mut F, body: &Body<From>) -> Body<To>
+where
+    F: Fold<From, To> + ?Sized,
+{
+    Body {
+        span: body.span,
+        statements: body
+            .statements
+            .iter()
+            .map(|(span, statement)| (*span, folder.fold_statement(*span, statement)))
+            .collect(),
+    }
+}
+
+pub fn fold_statement<From, To, F>(
+    folder: &mut F,
+    _span: SrcSpan,
+    statement: &Statement<From>,
+) -> Statement<To>
+where
+    F: Fold<From, To> + ?Sized,
+{
+    match statement {
+        Statement::Pass => Statement::Pass,
+        Statement::Expression(expression) => {
+            Statement::Expression(folder.fold_expression(expression))
+        }
+        Statement::If {
+            if_span,
+            condition,
+            then_block,
+            else_block,
+        } => Statement::If {
+            if_span: *if_span,
+            condition: Arc::new(folder.fold_expression(condition)),
+            then_block: Arc::new(folder.fold_body(then_block)),
+            else_block: else_block
+                .as_ref()
+                .map(|else_block| folder.fold_else_clause(else_block)),
+        },
+        Statement::With {
+            with_span,
+            context_manager,
+            target,
+            body,
+        } => Statement::With {
+            with_span: *with_span,
+            context_manager: Arc::new(folder.fold_expression(context_manager)),
+            target: target.clone(),
+            body: Arc::new(folder.fold_body(body)),
+        },
+        Statement::Global(names) => Statement::Global(names.clone()),
+        Statement::Nonlocal(names) => Statement::Nonlocal(names.clone()),
+        Statement::Break => Statement::Break,
+        Statement::Continue => Statement::Continue,
+    }
+}
+
+pub fn fold_else_clause<From, To, F>(
+    folder: &mut F,
+    else_clause: &ElseClause<From>,
+) -> ElseClause<To>
+where
+    F: Fold<From, To> + ?Sized,
+{
+    ElseClause {
+        else_span: else_clause.else_span,
+        body: Arc::new(folder.fold_body(&else_clause.body)),
+    }
+}
+
+pub fn fold_expression<From, To, F>(folder: &mut F, expression: &Expression<From>) -> Expression<To>
+where
+    F: Fold<From, To> + ?Sized,
+{
+    match expression {
+        Expression::Literal(literal) => Expression::Literal(literal.clone()),
+        Expression::Variable { name } => Expression::Variable { name: name.clone() },
+        Expression::Call { span, name, args } => Expression::Call {
+            span: *span,
+            name: folder.fold_fn_id(*span, name),
+            args: args.iter().map(|arg| folder.fold_expression(arg)).collect(),
+        },
+        Expression::Parallel { span, branches } => Expression::Parallel {
+            span: *span,
+            branches: branches
+                .iter()
+                .map(|branch| folder.fold_expression(branch))
+                .collect(),
+        },
+        Expression::Lambda { span, params, body } => Expression::Lambda {
+            span: *span,
+            params: params.clone(),
+            body: Box::new(folder.fold_expression(body)),
+        },
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use indoc::indoc;
+
+    use super::{walk_call, walk_expression_mut, Fold, Visit, VisitMut};
+    use crate::syntax_tree::{parse, Body, Expression, Literal, SrcSpan};
+
+    const SOURCE: &str = indoc! {r#"
+        def test():
+            if first(second()):
+                parallel(third, fourth)
+            else:
+                print("done")
+    "#};
+
+    #[derive(Default)]
+    struct CallNames(Vec<String>);
+
+    impl<'ast> Visit<'ast, String> for CallNames {
+        fn visit_call(
+            &mut self,
+            span: SrcSpan,
+            name: &'ast String,
+            args: &'ast [Expression<String>],
+        ) {
+            self.0.push(name.clone());
+            walk_call(self, span, name, args);
+        }
+    }
+
+    fn call_names(body: &Body<String>) -> Vec<String> {
+        let mut names = CallNames::default();
+        names.visit_body(body);
+        names.0
+    }
+
+    #[test]
+    fn visit() {
+        let module = parse(SOURCE).unwrap();
+
+        assert_eq!(
+            call_names(module.functions()[0].body()),
+            ["first", "second", "third", "fourth", "print"]
+        );
+    }
+
+    #[test]
+    fn visit_mut() {
+        struct Rename;
+
+        impl VisitMut<String> for Rename {
+            fn visit_call_mut(
+                &mut self,
+                _span: SrcSpan,
+                name: &mut String,
+                args: &mut Vec<Expression<String>>,
+            ) {
+                *name = name.to_uppercase();
+                args.clear();
+            }
+
+            fn visit_expression_mut(&mut self, expression: &mut Expression<String>) {
+                if let Expression::Literal(Literal::String(string)) = expression {
+                    string.clear();
+                }
+
+                walk_expression_mut(self, expression);
+            }
+        }
+
+        let module = parse(SOURCE).unwrap();
+        let mut body = module.functions()[0].body().clone();
+        Rename.visit_body_mut(&mut body);
+
+        assert_eq!(call_names(&body), ["FIRST", "THIRD", "FOURTH", "PRINT"]);
+        // The original is unchanged.
+        assert_eq!(call_names(module.functions()[0].body()).len(), 5);
+    }
+
+    #[test]
+    fn fold() {
+        struct NameLengths;
+
+        impl Fold<String, usize> for NameLengths {
+            fn fold_fn_id(&mut self, _span: SrcSpan, id: &String) -> usize {
+                id.len()
+            }
+        }
+
+        struct Lengths(Vec<usize>);
+
+        impl<'ast> Visit<'ast, usize> for Lengths {
+            fn visit_call(
+                &mut self,
+                span: SrcSpan,
+                name: &'ast usize,
+                args: &'ast [Expression<usize>],
+            ) {
+                self.0.push(*name);
+                walk_call(self, span, name, args);
+            }
+        }
+
+        let module = parse(SOURCE).unwrap();
+        let body = module.functions()[0].body();
+        let folded = NameLengths.fold_body(body);
+        let mut lengths = Lengths(Vec::new());
+        lengths.visit_body(&folded);
+
+        assert_eq!(lengths.0, [5, 6, 5, 6, 5]);
+        assert_eq!(folded.span(), body.span());
+    }
+}
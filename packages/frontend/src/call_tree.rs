@@ -1,13 +1,16 @@
-use std::{cell::RefCell, collections::BTreeMap, pin::pin, rc::Rc};
+use std::{
+    cell::RefCell,
+    collections::{btree_map::Entry, BTreeMap},
+    rc::Rc,
+};
 
 use clonelet::clone;
-use futures::{Future, Stream, StreamExt};
+use futures::{Future, Stream};
 use futures_signals::signal::{Mutable, ReadOnlyMutable};
-use gloo_console::info;
 use serpent_automation_executor::{
     library::{FunctionId, Library},
     run::{CallStack, NestedBlock, RunState, StackFrame},
-    syntax_tree::{self, ElseClause, LinkedBody, SrcSpan},
+    syntax_tree::{self, ElseClause, LinkedBody, LinkedFunction, SrcSpan},
 };
 use tokio::sync::mpsc;
 
@@ -25,6 +28,11 @@ pub struct CallTree {
     run_state_map: RunStateMap,
 }
 
+/// The run state of each node, by call stack.
+///
+/// The tree is built lazily, as nodes are expanded, so updates can arrive for
+/// nodes that haven't been built yet. We keep their state, ready for when they
+/// are.
 #[derive(Clone)]
 struct RunStateMap {
     run_state_map: Rc<RefCell<BTreeMap<CallStack, Mutable<RunState>>>>,
@@ -38,18 +46,23 @@ impl RunStateMap {
     }
 
     pub fn update_run_state(&self, call_stack: CallStack, new_run_state: RunState) {
-        self.run_state_map
-            .borrow_mut()
-            .entry(call_stack)
-            .and_modify(|run_state| run_state.set(new_run_state));
+        match self.run_state_map.borrow_mut().entry(call_stack) {
+            Entry::Occupied(entry) => entry.get().set(new_run_state),
+            Entry::Vacant(entry) => {
+                entry.insert(Mutable::new(new_run_state));
+            }
+        }
     }
 
+    /// The run state for a node that's being built.
+    ///
+    /// This is `NotRun`, unless we've already had an update for it.
     pub fn insert(&self, call_stack: CallStack) -> Mutable<RunState> {
-        let run_state = Mutable::new(RunState::NotRun);
         self.run_state_map
             .borrow_mut()
-            .insert(call_stack, run_state.clone());
-        run_state
+            .entry(call_stack)
+            .or_insert_with(|| Mutable::new(RunState::NotRun))
+            .clone()
     }
 }
 
@@ -61,6 +74,16 @@ struct Builder {
     run_state_map: RunStateMap,
 }
 
+impl Builder {
+    /// Ask for updates to the children of `call_stack`.
+    ///
+    /// The receiver is only dropped once we've stopped updating the run state,
+    /// so there's nobody left to tell if sending fails.
+    fn open_node(&self, call_stack: CallStack) {
+        let _ = self.opened_nodes.send(call_stack);
+    }
+}
+
 impl CallTree {
     pub fn root(
         fn_id: FunctionId,
@@ -69,22 +92,22 @@ impl CallTree {
     ) -> Self {
         let f = library.lookup(fn_id);
 
-        let mut call_stack = CallStack::new();
-        opened_nodes.send(call_stack.clone()).unwrap();
-        call_stack.push(StackFrame::Call(fn_id));
         let run_state_map = RunStateMap::new();
-        let run_state = run_state_map.insert(call_stack.clone());
         let builder = Builder {
             library: library.clone(),
             opened_nodes,
             run_state_map: run_state_map.clone(),
         };
+        let mut call_stack = CallStack::new();
+        builder.open_node(call_stack.clone());
+        call_stack.push(StackFrame::Call(fn_id));
+        let run_state = run_state_map.insert(call_stack.clone());
 
         Self {
             span: f.span(),
-            name: f.name().to_string(),
+            name: f.display_name().to_string(),
             run_state,
-            body: Body::from_linked_body(call_stack, &builder, f.body()),
+            body: Body::from_function(call_stack, &builder, f),
             run_state_map,
         }
     }
@@ -105,16 +128,11 @@ impl CallTree {
         clone!(self.run_state_map);
 
         async move {
-            info!("Subscribing to thread state updates");
-            let run_state_updates = server_connection.subscribe(opened_nodes).await;
-            let mut run_state_updates = pin!(run_state_updates);
-
-            while let Some((call_stack, new_run_state)) = run_state_updates.next().await {
-                info!(format!("Updating node"));
-                run_state_map.update_run_state(call_stack, new_run_state);
-            }
-
-            info!("Finished subscribing to thread state updates");
+            server_connection
+                .subscribe(opened_nodes, |call_stack, new_run_state| {
+                    run_state_map.update_run_state(call_stack, new_run_state)
+                })
+                .await
         }
     }
 
@@ -131,6 +149,44 @@ impl CallTree {
 pub struct Body(Rc<Vec<Statement>>);
 
 impl Body {
+    /// The body of a call to `function`.
+    ///
+    /// If the function has `@retry`, each attempt is a child node, with the
+    /// function body inside it.
+    fn from_function(
+        call_stack: CallStack,
+        builder: &Builder,
+        function: &LinkedFunction,
+    ) -> TreeNode<Expandable<Self>> {
+        match (function.retry(), function.span()) {
+            (Some((attempts, _backoff)), Some(span)) => {
+                let body = function.body().clone();
+
+                TreeNode::Internal(Expandable::new({
+                    clone!(builder);
+
+                    move || {
+                        builder.open_node(call_stack.clone());
+                        Self(Rc::new(
+                            (0..attempts)
+                                .map(|index| {
+                                    Statement::Attempt(Attempt::new(
+                                        index,
+                                        &call_stack,
+                                        &builder,
+                                        span,
+                                        &body,
+                                    ))
+                                })
+                                .collect(),
+                        ))
+                    }
+                }))
+            }
+            _ => Self::from_linked_body(call_stack, builder, function.body()),
+        }
+    }
+
     fn from_linked_body(
         call_stack: CallStack,
         builder: &Builder,
@@ -144,11 +200,7 @@ impl Body {
                     clone!(builder);
 
                     move || {
-                        // TODO: `send`s fail if there's no-one listening, which happens if we're
-                        // not connected to the server. We need a queue that buffers sends even when
-                        // there's no listeners, or make sure the server connection end re-uses the
-                        // queue on reconnect.
-                        builder.opened_nodes.send(call_stack.clone()).unwrap();
+                        builder.open_node(call_stack.clone());
                         Self::from_body(call_stack, &builder, &body)
                     }
                 }))
@@ -168,7 +220,17 @@ impl Body {
             let call_stack = call_stack.push_cloned(StackFrame::Statement(index));
 
             match stmt {
-                syntax_tree::Statement::Pass => (),
+                syntax_tree::Statement::Pass
+                | syntax_tree::Statement::Global(_)
+                | syntax_tree::Statement::Nonlocal(_)
+                | syntax_tree::Statement::Break
+                | syntax_tree::Statement::Continue => (),
+                syntax_tree::Statement::Expression(syntax_tree::Expression::Parallel {
+                    span,
+                    branches,
+                }) => stmts.push(Statement::Parallel(Parallel::new(
+                    call_stack, builder, *span, branches,
+                ))),
                 syntax_tree::Statement::Expression(expr) => stmts.extend(
                     Call::from_expression(call_stack, builder, expr)
                         .into_iter()
@@ -182,6 +244,18 @@ impl Body {
                 } => stmts.push(Statement::If(If::new(
                     call_stack, builder, *if_span, condition, then_block, else_block,
                 ))),
+                syntax_tree::Statement::With {
+                    with_span,
+                    context_manager,
+                    body,
+                    ..
+                } => stmts.push(Statement::With(With::new(
+                    call_stack,
+                    builder,
+                    *with_span,
+                    context_manager,
+                    body,
+                ))),
             }
         }
 
@@ -200,6 +274,9 @@ impl Body {
 pub enum Statement {
     Call(Call),
     If(If),
+    With(With),
+    Parallel(Parallel),
+    Attempt(Attempt),
 }
 
 #[derive(Clone)]
@@ -216,9 +293,9 @@ impl Call {
 
         Self {
             span,
-            name: function.name().to_string(),
+            name: function.display_name().to_string(),
             run_state: builder.run_state_map.insert(call_stack.clone()),
-            body: Body::from_linked_body(call_stack, builder, function.body()),
+            body: Body::from_function(call_stack, builder, function),
         }
     }
 
@@ -228,9 +305,10 @@ impl Call {
         expr: &syntax_tree::Expression<FunctionId>,
     ) -> Vec<Call> {
         match expr {
-            syntax_tree::Expression::Literal(_) | syntax_tree::Expression::Variable { .. } => {
-                Vec::new()
-            }
+            // Calls in a lambda don't run when the lambda is created.
+            syntax_tree::Expression::Literal(_)
+            | syntax_tree::Expression::Variable { .. }
+            | syntax_tree::Expression::Lambda { .. } => Vec::new(),
             syntax_tree::Expression::Call { span, name, args } => {
                 let mut calls = Vec::new();
 
@@ -250,6 +328,9 @@ impl Call {
                 ));
                 calls
             }
+            syntax_tree::Expression::Parallel { branches, .. } => {
+                Parallel::branch_calls(&call_stack, builder, branches)
+            }
         }
     }
 
@@ -270,6 +351,51 @@ impl Call {
     }
 }
 
+pub struct Parallel {
+    span: SrcSpan,
+    branches: Vec<Call>,
+}
+
+impl Parallel {
+    fn new(
+        call_stack: CallStack,
+        builder: &Builder,
+        span: SrcSpan,
+        branches: &[syntax_tree::Expression<FunctionId>],
+    ) -> Self {
+        Self {
+            span,
+            branches: Self::branch_calls(&call_stack, builder, branches),
+        }
+    }
+
+    fn branch_calls(
+        call_stack: &CallStack,
+        builder: &Builder,
+        branches: &[syntax_tree::Expression<FunctionId>],
+    ) -> Vec<Call> {
+        branches
+            .iter()
+            .enumerate()
+            .flat_map(|(index, branch)| {
+                Call::from_expression(
+                    call_stack.push_cloned(StackFrame::Branch(index)),
+                    builder,
+                    branch,
+                )
+            })
+            .collect()
+    }
+
+    pub fn span(&self) -> SrcSpan {
+        self.span
+    }
+
+    pub fn branches(&self) -> &[Call] {
+        &self.branches
+    }
+}
+
 pub struct If {
     span: SrcSpan,
     run_state: Mutable<RunState>,
@@ -287,12 +413,7 @@ impl If {
         then_block: &syntax_tree::Body<FunctionId>,
         else_block: &Option<syntax_tree::ElseClause<FunctionId>>,
     ) -> Self {
-        // TODO: Tidy this
-        let condition_call_stack =
-            call_stack.push_cloned(StackFrame::NestedBlock(0, NestedBlock::Predicate));
-
-        let calls = Call::from_expression(condition_call_stack.clone(), builder, condition);
-        let run_state = builder.run_state_map.insert(condition_call_stack.clone());
+        let (run_state, condition) = predicate(&call_stack, builder, condition);
         let then_block = Body::from_body(
             call_stack.push_cloned(StackFrame::NestedBlock(0, NestedBlock::Body)),
             builder,
@@ -302,16 +423,7 @@ impl If {
         Self {
             span,
             run_state,
-            condition: if calls.is_empty() {
-                TreeNode::Leaf
-            } else {
-                clone!(builder);
-
-                TreeNode::Internal(Expandable::new(move || {
-                    builder.opened_nodes.send(condition_call_stack).unwrap();
-                    calls
-                }))
-            },
+            condition,
             then_block,
             else_block: else_block
                 .as_ref()
@@ -379,3 +491,119 @@ impl Else {
         &self.body
     }
 }
+
+/// A `with` block.
+pub struct With {
+    span: SrcSpan,
+    run_state: Mutable<RunState>,
+    context_manager: TreeNode<Expandable<Vec<Call>>>,
+    body: Body,
+}
+
+impl With {
+    fn new(
+        call_stack: CallStack,
+        builder: &Builder,
+        span: SrcSpan,
+        context_manager: &syntax_tree::Expression<FunctionId>,
+        body: &syntax_tree::Body<FunctionId>,
+    ) -> Self {
+        let (run_state, context_manager) = predicate(&call_stack, builder, context_manager);
+
+        Self {
+            span,
+            run_state,
+            context_manager,
+            body: Body::from_body(
+                call_stack.push_cloned(StackFrame::NestedBlock(0, NestedBlock::Body)),
+                builder,
+                body,
+            ),
+        }
+    }
+
+    pub fn span(&self) -> SrcSpan {
+        self.span
+    }
+
+    pub fn run_state(&self) -> ReadOnlyMutable<RunState> {
+        self.run_state.read_only()
+    }
+
+    pub fn context_manager(&self) -> &TreeNode<Expandable<Vec<Call>>> {
+        &self.context_manager
+    }
+
+    pub fn body(&self) -> &Body {
+        &self.body
+    }
+}
+
+/// An attempt at running a function with `@retry`.
+pub struct Attempt {
+    span: SrcSpan,
+    name: String,
+    run_state: Mutable<RunState>,
+    body: TreeNode<Expandable<Body>>,
+}
+
+impl Attempt {
+    fn new(
+        index: usize,
+        call_stack: &CallStack,
+        builder: &Builder,
+        span: SrcSpan,
+        body: &LinkedBody,
+    ) -> Self {
+        let call_stack = call_stack.push_cloned(StackFrame::Attempt(index));
+
+        Self {
+            span,
+            name: format!("attempt {}", index + 1),
+            run_state: builder.run_state_map.insert(call_stack.clone()),
+            body: Body::from_linked_body(call_stack, builder, body),
+        }
+    }
+
+    /// The span of the function name.
+    pub fn span(&self) -> SrcSpan {
+        self.span
+    }
+
+    pub fn name(&self) -> &str {
+        &self.name
+    }
+
+    pub fn run_state(&self) -> ReadOnlyMutable<RunState> {
+        self.run_state.read_only()
+    }
+
+    pub fn body(&self) -> &TreeNode<Expandable<Body>> {
+        &self.body
+    }
+}
+
+/// The run state and calls for the expression that starts the first nested
+/// block of a statement, like an `if` condition.
+fn predicate(
+    call_stack: &CallStack,
+    builder: &Builder,
+    expression: &syntax_tree::Expression<FunctionId>,
+) -> (Mutable<RunState>, TreeNode<Expandable<Vec<Call>>>) {
+    let predicate_call_stack =
+        call_stack.push_cloned(StackFrame::NestedBlock(0, NestedBlock::Predicate));
+    let calls = Call::from_expression(predicate_call_stack.clone(), builder, expression);
+    let run_state = builder.run_state_map.insert(predicate_call_stack.clone());
+    let calls = if calls.is_empty() {
+        TreeNode::Leaf
+    } else {
+        clone!(builder);
+
+        TreeNode::Internal(Expandable::new(move || {
+            builder.open_node(predicate_call_stack);
+            calls
+        }))
+    };
+
+    (run_state, calls)
+}
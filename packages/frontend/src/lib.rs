@@ -1,62 +1,335 @@
+use std::{
+    cell::RefCell,
+    collections::BTreeSet,
+    pin::{pin, Pin},
+    rc::Rc,
+    time::Duration,
+};
+
 use arpy::ConcurrentRpcClient;
 use arpy_reqwasm::websocket;
-use futures::Stream;
-use gloo_net::websocket::futures::WebSocket;
+use futures::{stream, Stream};
+use futures_signals::signal::{Mutable, ReadOnlyMutable};
+use gloo_console::info;
+use gloo_net::{http::Request, websocket::futures::WebSocket};
+use gloo_timers::future::sleep;
 use serpent_automation_executor::{
     library::FunctionId,
-    run::{CallStack, RunState},
-    syntax_tree::{Body, Expression, Statement},
+    run::{CallStack, RunState, Snapshot},
+    sources::Sources,
+    syntax_tree::{
+        visit::{walk_expression, walk_statement, Visit},
+        Body, Expression, SrcSpan, Statement,
+    },
 };
-use serpent_automation_server_api::ThreadSubscription;
-use tokio_stream::StreamExt;
+use serpent_automation_server_api::{
+    PauseRun, RunId, RunSnapshot, ThreadSubscription, WorkflowSources,
+};
+use tokio::sync::mpsc;
+use tokio_stream::{wrappers::UnboundedReceiverStream, StreamExt};
 
 pub mod call_tree;
 pub mod tree;
 
-pub fn expression_is_expandable(expression: &Expression<FunctionId>) -> bool {
-    match expression {
-        Expression::Variable { .. } | Expression::Literal(_) => false,
-        Expression::Call { .. } => true,
-    }
+/// Does `body` have any children in the call tree?
+///
+/// That's any `if` or `with` statement, or any call outside a `lambda`.
+pub fn is_expandable(body: &Body<FunctionId>) -> bool {
+    let mut expandable = Expandable(false);
+    expandable.visit_body(body);
+    expandable.0
 }
 
-pub fn statement_is_expandable(stmt: &Statement<FunctionId>) -> bool {
-    match stmt {
-        Statement::Pass => false,
-        Statement::Expression(e) => expression_is_expandable(e),
-        Statement::If { .. } => true,
+struct Expandable(bool);
+
+impl<'ast> Visit<'ast, FunctionId> for Expandable {
+    fn visit_statement(&mut self, span: SrcSpan, statement: &'ast Statement<FunctionId>) {
+        if let Statement::If { .. } | Statement::With { .. } = statement {
+            self.0 = true;
+        } else {
+            walk_statement(self, span, statement);
+        }
     }
-}
 
-// TODO: Make this a method on `Body`
-pub fn is_expandable(body: &Body<FunctionId>) -> bool {
-    body.iter().any(statement_is_expandable)
+    fn visit_expression(&mut self, expression: &'ast Expression<FunctionId>) {
+        if !matches!(expression, Expression::Lambda { .. }) {
+            walk_expression(self, expression);
+        }
+    }
+
+    fn visit_call(
+        &mut self,
+        _span: SrcSpan,
+        _name: &'ast FunctionId,
+        _args: &'ast [Expression<FunctionId>],
+    ) {
+        self.0 = true;
+    }
 }
 
+/// A connection to the server, that reconnects when it's lost.
+#[derive(Clone)]
 pub struct ServerConnection {
-    ws: websocket::Connection,
+    url: Rc<str>,
+    ws: Rc<RefCell<Option<Rc<websocket::Connection>>>>,
+    status: Mutable<ConnectionStatus>,
+    run_id: Mutable<Option<RunId>>,
+    paused: Mutable<bool>,
 }
 
 impl Default for ServerConnection {
     fn default() -> Self {
-        let ws = websocket::Connection::new(WebSocket::open("ws://127.0.0.1:9090/api").unwrap());
-
-        Self { ws }
+        Self::new(DEFAULT_URL)
     }
 }
 
 impl ServerConnection {
+    pub fn new(url: &str) -> Self {
+        Self {
+            url: url.into(),
+            ws: Rc::new(RefCell::new(None)),
+            status: Mutable::new(ConnectionStatus::Connecting),
+            run_id: Mutable::new(None),
+            paused: Mutable::new(false),
+        }
+    }
+
+    pub fn status(&self) -> ReadOnlyMutable<ConnectionStatus> {
+        self.status.read_only()
+    }
+
+    /// The run we're subscribed to, if there is one.
+    pub fn run_id(&self) -> ReadOnlyMutable<Option<RunId>> {
+        self.run_id.read_only()
+    }
+
+    /// Whether the run we're subscribed to is paused.
+    ///
+    /// This is from the snapshot we get when we connect, and any changes made
+    /// with [`Self::pause_run`].
+    pub fn paused(&self) -> ReadOnlyMutable<bool> {
+        self.paused.read_only()
+    }
+
+    /// Pause or resume the run we're subscribed to.
+    ///
+    /// This uses the operator API, so the token in our URL must belong to an
+    /// operator.
+    pub async fn pause_run(&self, paused: bool) -> Result<(), String> {
+        let run_id = self.run_id.get().ok_or_else(|| "No run".to_owned())?;
+        let body =
+            serde_json::to_string(&PauseRun { run_id, paused }).map_err(|e| e.to_string())?;
+        let response = Request::post(&self.operator_url("pause"))
+            .header("Content-Type", "application/json")
+            .body(body)
+            .send()
+            .await
+            .map_err(|e| e.to_string())?;
+
+        if !response.ok() {
+            return Err(format!("Unable to pause run: {}", response.status_text()));
+        }
+
+        if response.json().await.map_err(|e| e.to_string())? {
+            self.paused.set_neq(paused);
+            Ok(())
+        } else {
+            Err(format!("Run {run_id} has finished"))
+        }
+    }
+
+    /// The URL of an operator API endpoint, on the same server as our API.
+    ///
+    /// The query string is kept, as it can contain an access token.
+    fn operator_url(&self, action: &str) -> String {
+        let (api_url, query) = match self.url.split_once('?') {
+            Some((api_url, query)) => (api_url, format!("?{query}")),
+            None => (self.url.as_ref(), String::new()),
+        };
+        // This turns `ws` into `http` and `wss` into `https`.
+        let api_url = api_url.replacen("ws", "http", 1);
+
+        format!("{api_url}/operator/{action}{query}")
+    }
+
+    /// Subscribe to run state updates, reconnecting whenever the connection
+    /// is lost.
+    ///
+    /// Each time we connect, we start with a snapshot of the latest run, so
+    /// we're up to date even if we joined late or missed updates while we were
+    /// disconnected. Nodes from `opened_nodes` are buffered while we're
+    /// disconnected, and all the nodes opened so far are sent again when we
+    /// reconnect. This never finishes.
     pub async fn subscribe(
         &self,
-        opened_nodes: impl Stream<Item = CallStack> + 'static,
-    ) -> impl Stream<Item = (CallStack, RunState)> {
-        // TODO: Error handling
-        let ((), subscription) = self
-            .ws
-            .subscribe(ThreadSubscription, opened_nodes)
+        opened_nodes: impl Stream<Item = CallStack>,
+        mut update_run_state: impl FnMut(CallStack, RunState),
+    ) {
+        let mut opened_nodes = pin!(opened_nodes);
+        let mut all_opened_nodes = BTreeSet::new();
+        let mut backoff = MIN_BACKOFF;
+
+        loop {
+            self.status.set_neq(ConnectionStatus::Connecting);
+
+            match self
+                .subscribe_once(
+                    &mut all_opened_nodes,
+                    opened_nodes.as_mut(),
+                    &mut update_run_state,
+                )
+                .await
+            {
+                Ok(()) => {
+                    info!("Disconnected from server");
+                    backoff = MIN_BACKOFF;
+                }
+                Err(e) => info!(format!("Unable to connect to server: {e}")),
+            }
+
+            self.ws.replace(None);
+            self.status.set_neq(ConnectionStatus::Disconnected);
+            sleep(backoff).await;
+            backoff = (backoff * 2).min(MAX_BACKOFF);
+        }
+    }
+
+    /// Subscribe until the connection is lost.
+    async fn subscribe_once(
+        &self,
+        all_opened_nodes: &mut BTreeSet<CallStack>,
+        opened_nodes: Pin<&mut impl Stream<Item = CallStack>>,
+        update_run_state: &mut impl FnMut(CallStack, RunState),
+    ) -> Result<(), String> {
+        let ws = Rc::new(websocket::Connection::new(
+            WebSocket::open(&self.url).map_err(|e| e.to_string())?,
+        ));
+        self.ws.replace(Some(ws.clone()));
+
+        let (opened_sender, opened_receiver) = mpsc::unbounded_channel();
+        let resent_nodes = stream::iter(all_opened_nodes.clone());
+
+        // Subscribe before we get the snapshot, so we don't miss any updates in
+        // between. Updates are buffered until we've applied the snapshot.
+        info!("Subscribing to thread state updates");
+        let (run_id, subscription) = ws
+            .subscribe(
+                ThreadSubscription,
+                resent_nodes.chain(UnboundedReceiverStream::new(opened_receiver)),
+            )
             .await
-            .unwrap();
+            .map_err(|e| e.to_string())?;
+
+        // Updates numbered lower than this are already in the snapshot.
+        let mut snapshot_update_count = 0;
+
+        if let Some(run_id) = run_id {
+            let (snapshot_run_id, snapshot) = self
+                .run_snapshot(Some(run_id), all_opened_nodes.iter().cloned().collect())
+                .await?
+                .ok_or_else(|| format!("No snapshot for run {run_id}"))?;
 
-        subscription.map_while(Result::ok)
+            if snapshot_run_id != run_id {
+                return Err(format!(
+                    "Subscribed to run {run_id}, but got a snapshot of run {snapshot_run_id}"
+                ));
+            }
+
+            info!(format!("Syncing with run {run_id}"));
+            self.run_id.set_neq(Some(run_id));
+            self.paused.set_neq(snapshot.paused);
+            snapshot_update_count = snapshot.update_count;
+
+            for (call_stack, run_state) in snapshot.child_states {
+                update_run_state(call_stack, run_state);
+            }
+        }
+
+        self.status.set_neq(ConnectionStatus::Connected);
+
+        let events = stream::select(
+            opened_nodes.map(Event::OpenNode),
+            subscription
+                .map(Event::Update)
+                .chain(stream::once(async { Event::Disconnected })),
+        );
+        let mut events = pin!(events);
+
+        while let Some(event) = events.next().await {
+            match event {
+                Event::OpenNode(call_stack) => {
+                    // If the receiver's gone, we've lost the connection. The
+                    // node is resent when we reconnect.
+                    if all_opened_nodes.insert(call_stack.clone())
+                        && opened_sender.send(call_stack).is_err()
+                    {
+                        break;
+                    }
+                }
+                Event::Update(Ok((update_number, call_stack, run_state))) => {
+                    if update_number >= snapshot_update_count {
+                        update_run_state(call_stack, run_state)
+                    }
+                }
+                Event::Update(Err(e)) => info!(format!("Subscription error: {e}")),
+                Event::Disconnected => break,
+            }
+        }
+
+        Ok(())
     }
+
+    /// Get the source files of the latest run's workflow.
+    ///
+    /// This uses it's own connection, so it can be called before
+    /// [`Self::subscribe`]. Returns `None` if there are no runs.
+    pub async fn workflow_sources(&self) -> Result<Option<Sources>, String> {
+        let ws = websocket::Connection::new(WebSocket::open(&self.url).map_err(|e| e.to_string())?);
+
+        ws.begin_call(WorkflowSources { run_id: None })
+            .await
+            .map_err(|e| e.to_string())?
+            .await
+            .map_err(|e| e.to_string())
+    }
+
+    /// Get a snapshot of a run, including the children of each of
+    /// `open_nodes`.
+    ///
+    /// If `run_id` is `None`, the latest run is used. Returns `None` if
+    /// there's no such run.
+    pub async fn run_snapshot(
+        &self,
+        run_id: Option<RunId>,
+        open_nodes: Vec<CallStack>,
+    ) -> Result<Option<(RunId, Snapshot)>, String> {
+        let ws = self
+            .ws
+            .borrow()
+            .clone()
+            .ok_or_else(|| "Not connected".to_owned())?;
+
+        ws.begin_call(RunSnapshot { run_id, open_nodes })
+            .await
+            .map_err(|e| e.to_string())?
+            .await
+            .map_err(|e| e.to_string())
+    }
+}
+
+#[derive(Copy, Clone, Eq, PartialEq, Debug)]
+pub enum ConnectionStatus {
+    Connecting,
+    Connected,
+    Disconnected,
 }
+
+enum Event<T> {
+    OpenNode(CallStack),
+    Update(T),
+    Disconnected,
+}
+
+const DEFAULT_URL: &str = "ws://127.0.0.1:9090/api";
+const MIN_BACKOFF: Duration = Duration::from_millis(500);
+const MAX_BACKOFF: Duration = Duration::from_secs(30);
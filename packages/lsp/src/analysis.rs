@@ -0,0 +1,466 @@
+//! What the language server knows about a workflow module.
+//!
+//! Offsets are byte offsets into the module's source.
+use std::path::{Path, PathBuf};
+
+use serpent_automation_executor::{
+    library::{FunctionId, Library, LinkError},
+    sources::{SourceError, Sources},
+    syntax_tree::{
+        parse,
+        visit::{walk_call, Visit},
+        Expression, FileId, Import, LinkedBody, Module, SrcSpan,
+    },
+};
+
+/// The analysis of a module that's open in the editor.
+///
+/// The module is analysed as if it was the main module of a workflow, so
+/// modules it imports are analysed too.
+#[derive(Default)]
+pub struct Analysis {
+    main: Option<Module>,
+    sources: Option<Sources>,
+    modules: Vec<(String, Module)>,
+    library: Option<Library>,
+    call_sites: Vec<(SrcSpan, FunctionId)>,
+    diagnostics: Vec<Diagnostic>,
+}
+
+/// A problem with the module.
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub struct Diagnostic {
+    /// Where the problem is, or `None` if it's the whole module.
+    pub span: Option<SrcSpan>,
+    pub message: String,
+}
+
+/// A function defined in the module.
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub struct Symbol {
+    pub name: String,
+    /// The span of the function name.
+    pub span: SrcSpan,
+    /// The span of the whole function.
+    pub whole_span: SrcSpan,
+}
+
+impl Analysis {
+    /// Analyse module `main`, with the source `source`.
+    ///
+    /// `read` is used to get the source of any modules that `main` imports.
+    pub fn new(
+        main: &str,
+        source: &str,
+        mut read: impl FnMut(&str) -> Result<String, SourceError>,
+    ) -> Self {
+        let mut analysis = Self::default();
+
+        match parse(source) {
+            Ok(module) => analysis.main = Some(module),
+            Err(e) => {
+                analysis.error(Some(e.span()), e.text());
+                return analysis;
+            }
+        }
+
+        let sources = Sources::load_with(main, |module| {
+            if module == main {
+                Ok(source.to_owned())
+            } else {
+                read(module)
+            }
+        });
+
+        match sources {
+            Ok(sources) => analysis.link(sources),
+            Err(e) => {
+                let module = match &e {
+                    SourceError::Read { path, .. } => {
+                        path.file_stem().and_then(|stem| stem.to_str())
+                    }
+                    SourceError::Parse { module, .. } => Some(module.as_str()),
+                    SourceError::Link(_) => None,
+                };
+                let span = module.and_then(|module| analysis.import_span(module));
+                analysis.error(span, e.to_string());
+            }
+        }
+
+        analysis
+    }
+
+    fn link(&mut self, sources: Sources) {
+        // All the modules have already been parsed successfully by
+        // `Sources::load_with`.
+        let Ok(modules) = sources.parse() else {
+            return;
+        };
+        self.modules = modules.clone();
+
+        match Library::link_modules(modules) {
+            Ok(library) => {
+                let mut call_sites = CallSites::default();
+
+                for (_id, function) in library.functions() {
+                    if let LinkedBody::Local(body) = function.body() {
+                        call_sites.visit_body(body);
+                    }
+                }
+
+                self.call_sites = call_sites.0;
+                self.library = Some(library);
+            }
+            Err(e) => {
+                let span = match &e {
+                    LinkError::UnknownModule { span, .. }
+                    | LinkError::UnknownName { span, .. }
+                    | LinkError::DuplicateFunction { span, .. } => {
+                        Self::main_span(&sources, &self.main, *span)
+                    }
+                    LinkError::ImportCycle(cycle) => {
+                        cycle.iter().find_map(|module| self.import_span(module))
+                    }
+                };
+                self.error(span, e.to_string());
+            }
+        }
+
+        self.sources = Some(sources);
+    }
+
+    pub fn diagnostics(&self) -> &[Diagnostic] {
+        &self.diagnostics
+    }
+
+    /// The source of `file`, if the module and it's imports loaded.
+    pub fn source(&self, file: FileId) -> Option<&str> {
+        Some(self.sources.as_ref()?.file(file)?.source())
+    }
+
+    /// The path of `file`, if it's in `dir`.
+    pub fn path(&self, dir: &Path, file: FileId) -> Option<PathBuf> {
+        let module = self.sources.as_ref()?.file(file)?.module();
+        Some(dir.join(format!("{module}.py")))
+    }
+
+    /// Where the function called at `offset` is defined.
+    pub fn definition(&self, offset: usize) -> Option<SrcSpan> {
+        let id = self.call_at(offset)?;
+        self.library.as_ref()?.lookup(id).span()
+    }
+
+    /// All the calls to the function called, or defined, at `offset`.
+    ///
+    /// These can be in any module of the workflow.
+    pub fn references(&self, offset: usize, include_declaration: bool) -> Vec<SrcSpan> {
+        let Some(id) = self.function_at(offset) else {
+            return Vec::new();
+        };
+
+        let declaration = include_declaration
+            .then(|| self.library.as_ref()?.lookup(id).span())
+            .flatten();
+
+        declaration
+            .into_iter()
+            .chain(
+                self.call_sites
+                    .iter()
+                    .filter(|(_span, call)| *call == id)
+                    .map(|(span, _call)| *span),
+            )
+            .collect()
+    }
+
+    /// A description of the function called, or defined, at `offset`, as
+    /// Markdown.
+    pub fn hover(&self, offset: usize) -> Option<(SrcSpan, String)> {
+        let (span, id) = self.function_span_at(offset)?;
+        let function = self.library.as_ref()?.lookup(id);
+        let name = function.name();
+
+        let description = match function.body() {
+            LinkedBody::Local(_) => match name.split_once('.') {
+                Some((module, name)) => {
+                    format!("```python\ndef {name}()\n```\n\nFrom module `{module}`")
+                }
+                None => format!("```python\ndef {name}()\n```"),
+            },
+            LinkedBody::Python => format!("```python\n{name}(...)\n```\n\nHost function"),
+        };
+
+        Some((span, description))
+    }
+
+    /// The functions defined in the module.
+    pub fn symbols(&self) -> Vec<Symbol> {
+        let Some(main) = &self.main else {
+            return Vec::new();
+        };
+
+        main.functions()
+            .iter()
+            .map(|function| Symbol {
+                name: function.name().to_owned(),
+                span: function.span(),
+                whole_span: function.whole_span(),
+            })
+            .collect()
+    }
+
+    /// The names of functions that the module can call.
+    pub fn completions(&self) -> Vec<String> {
+        let Some(main) = &self.main else {
+            return Vec::new();
+        };
+
+        let mut names: Vec<String> = main
+            .functions()
+            .iter()
+            .map(|function| function.name().to_owned())
+            .collect();
+
+        for import in main.imports() {
+            match import {
+                Import::Module { module, .. } => {
+                    if let Some((_, imported)) =
+                        self.modules.iter().find(|(name, _)| name == module)
+                    {
+                        names.extend(
+                            imported
+                                .functions()
+                                .iter()
+                                .map(|function| format!("{module}.{}", function.name())),
+                        );
+                    }
+                }
+                Import::Names {
+                    names: imported, ..
+                } => names.extend(imported.iter().map(|(_span, name)| name.clone())),
+            }
+        }
+
+        if let Some(library) = &self.library {
+            names.extend(
+                library
+                    .functions()
+                    .filter(|(_id, function)| matches!(function.body(), LinkedBody::Python))
+                    .map(|(_id, function)| function.name().to_owned()),
+            );
+        }
+
+        names.sort();
+        names.dedup();
+        names
+    }
+
+    /// The function called at `offset`, in the main module.
+    fn call_at(&self, offset: usize) -> Option<FunctionId> {
+        self.call_sites
+            .iter()
+            .find(|(span, _id)| touches(*span, offset))
+            .map(|(_span, id)| *id)
+    }
+
+    fn function_at(&self, offset: usize) -> Option<FunctionId> {
+        self.function_span_at(offset).map(|(_span, id)| id)
+    }
+
+    /// The function called, or defined, at `offset` in the main module.
+    fn function_span_at(&self, offset: usize) -> Option<(SrcSpan, FunctionId)> {
+        let call = self
+            .call_sites
+            .iter()
+            .find(|(span, _id)| touches(*span, offset))
+            .copied();
+
+        call.or_else(|| {
+            self.library
+                .as_ref()?
+                .functions()
+                .find_map(|(id, function)| {
+                    let span = function.span()?;
+                    touches(span, offset).then_some((span, id))
+                })
+        })
+    }
+
+    /// The span of the import of `module` in the main module.
+    fn import_span(&self, module: &str) -> Option<SrcSpan> {
+        self.main
+            .as_ref()?
+            .imports()
+            .iter()
+            .find(|import| import.module() == module)
+            .map(Import::span)
+    }
+
+    /// Map a span in any module to a span in the main module.
+    ///
+    /// Spans in other modules are mapped to the span of the import.
+    fn main_span(sources: &Sources, main: &Option<Module>, span: SrcSpan) -> Option<SrcSpan> {
+        if span.file() == MAIN_FILE {
+            return Some(span);
+        }
+
+        let module = sources.file(span.file())?.module();
+
+        main.as_ref()?
+            .imports()
+            .iter()
+            .find(|import| import.module() == module)
+            .map(Import::span)
+    }
+
+    fn error(&mut self, span: Option<SrcSpan>, message: impl Into<String>) {
+        self.diagnostics.push(Diagnostic {
+            span,
+            message: message.into(),
+        });
+    }
+}
+
+/// Is `offset` within `span` in the main module, or just after it?
+///
+/// Editors usually put the cursor just after the word it's on.
+fn touches(span: SrcSpan, offset: usize) -> bool {
+    span.file() == MAIN_FILE
+        && (span.byte_range().contains(&offset) || span.end().offset() == offset)
+}
+
+/// The open module is always the first file.
+pub(crate) const MAIN_FILE: FileId = FileId::new(0);
+
+#[derive(Default)]
+struct CallSites(Vec<(SrcSpan, FunctionId)>);
+
+impl<'ast> Visit<'ast, FunctionId> for CallSites {
+    fn visit_call(
+        &mut self,
+        span: SrcSpan,
+        name: &'ast FunctionId,
+        args: &'ast [Expression<FunctionId>],
+    ) {
+        self.0.push((span, *name));
+        walk_call(self, span, name, args);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use indoc::indoc;
+    use serpent_automation_executor::sources::SourceError;
+
+    use super::{Analysis, MAIN_FILE};
+
+    const MAIN: &str = indoc! {"
+        import helpers
+
+        def main():
+            build()
+            helpers.setup()
+
+        def build():
+            build_step()
+    "};
+
+    const HELPERS: &str = indoc! {"
+        def setup():
+            pass
+    "};
+
+    fn analyse(source: &str) -> Analysis {
+        Analysis::new("main", source, |module| match module {
+            "helpers" => Ok(HELPERS.to_owned()),
+            _ => Err(SourceError::Read {
+                path: format!("{module}.py").into(),
+                source: std::io::ErrorKind::NotFound.into(),
+            }),
+        })
+    }
+
+    fn offset(source: &str, text: &str) -> usize {
+        source.find(text).unwrap()
+    }
+
+    #[test]
+    fn definition() {
+        let analysis = analyse(MAIN);
+        assert!(analysis.diagnostics().is_empty());
+
+        let build = analysis.definition(offset(MAIN, "build()")).unwrap();
+        assert_eq!(build.file(), MAIN_FILE);
+        assert_eq!(build.line(), 7);
+
+        let setup = analysis.definition(offset(MAIN, "setup()") + 2).unwrap();
+        assert_ne!(setup.file(), MAIN_FILE);
+        assert_eq!(setup.line(), 1);
+        assert_eq!(analysis.source(setup.file()), Some(HELPERS));
+
+        assert!(analysis.definition(offset(MAIN, "def main")).is_none());
+    }
+
+    #[test]
+    fn references() {
+        let analysis = analyse(MAIN);
+        let references = analysis.references(offset(MAIN, "build():"), true);
+        let lines: Vec<usize> = references.iter().map(|span| span.line()).collect();
+
+        assert_eq!(lines, [7, 4]);
+        assert_eq!(analysis.references(offset(MAIN, "build()"), false).len(), 1);
+    }
+
+    #[test]
+    fn hover() {
+        let analysis = analyse(MAIN);
+
+        let (_span, setup) = analysis.hover(offset(MAIN, "helpers.setup")).unwrap();
+        assert_eq!(
+            setup,
+            "```python\ndef setup()\n```\n\nFrom module `helpers`"
+        );
+
+        let (span, build_step) = analysis.hover(offset(MAIN, "build_step")).unwrap();
+        assert_eq!(span.line(), 8);
+        assert!(build_step.ends_with("Host function"));
+    }
+
+    #[test]
+    fn symbols_and_completions() {
+        let analysis = analyse(MAIN);
+        let symbols: Vec<String> = analysis
+            .symbols()
+            .into_iter()
+            .map(|symbol| symbol.name)
+            .collect();
+
+        assert_eq!(symbols, ["main", "build"]);
+        assert_eq!(
+            analysis.completions(),
+            ["build", "build_step", "helpers.setup", "main"]
+        );
+    }
+
+    #[test]
+    fn diagnostics() {
+        let parse_error = analyse("def main(:\n");
+        let [diagnostic] = parse_error.diagnostics() else {
+            panic!("Expected a diagnostic");
+        };
+        assert_eq!(diagnostic.span.unwrap().line(), 1);
+
+        let missing_module = analyse("import missing\n");
+        let [diagnostic] = missing_module.diagnostics() else {
+            panic!("Expected a diagnostic");
+        };
+        assert_eq!(diagnostic.span.unwrap().column(), 8);
+        assert!(diagnostic.message.contains("missing.py"));
+
+        let unknown_name = analyse("from helpers import missing\n");
+        let [diagnostic] = unknown_name.diagnostics() else {
+            panic!("Expected a diagnostic");
+        };
+        assert_eq!(diagnostic.span.unwrap().column(), 21);
+    }
+}
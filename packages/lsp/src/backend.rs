@@ -0,0 +1,361 @@
+use std::{collections::HashMap, fs, path::PathBuf, sync::Mutex};
+
+use serpent_automation_executor::{
+    sources::SourceError,
+    syntax_tree::{SrcPos, SrcSpan},
+};
+use tower_lsp::{
+    jsonrpc::Result,
+    lsp_types::{
+        CompletionItem, CompletionItemKind, CompletionOptions, CompletionParams,
+        CompletionResponse, Diagnostic, DiagnosticSeverity, DidChangeTextDocumentParams,
+        DidCloseTextDocumentParams, DidOpenTextDocumentParams, DocumentSymbol,
+        DocumentSymbolParams, DocumentSymbolResponse, GotoDefinitionParams, GotoDefinitionResponse,
+        Hover, HoverContents, HoverParams, HoverProviderCapability, InitializeParams,
+        InitializeResult, Location, MarkupContent, MarkupKind, OneOf, Position, Range,
+        ReferenceParams, ServerCapabilities, ServerInfo, SymbolKind, TextDocumentSyncCapability,
+        TextDocumentSyncKind, Url,
+    },
+    Client, LanguageServer,
+};
+
+use crate::analysis::{Analysis, MAIN_FILE};
+
+/// The language server.
+///
+/// Each request analyses the document from scratch. Workflows are small, so
+/// this is fast enough.
+pub struct Backend {
+    client: Client,
+    documents: Mutex<HashMap<Url, String>>,
+}
+
+impl Backend {
+    /// Constructor
+    pub fn new(client: Client) -> Self {
+        Self {
+            client,
+            documents: Mutex::new(HashMap::new()),
+        }
+    }
+
+    /// Analyse the open document at `url`.
+    ///
+    /// Imported modules are read from the document's directory, unless
+    /// they're open in the editor.
+    fn analyse(&self, url: &Url) -> Option<Document> {
+        let documents = self.documents.lock().unwrap();
+        let source = documents.get(url)?.clone();
+        let path = url.to_file_path().ok()?;
+        let dir = path.parent()?.to_owned();
+        let main = path.file_stem()?.to_str()?;
+
+        let analysis = Analysis::new(main, &source, |module| {
+            let path = dir.join(format!("{module}.py"));
+            let open_document = Url::from_file_path(&path)
+                .ok()
+                .and_then(|url| documents.get(&url));
+
+            match open_document {
+                Some(source) => Ok(source.clone()),
+                None => {
+                    fs::read_to_string(&path).map_err(|source| SourceError::Read { path, source })
+                }
+            }
+        });
+
+        Some(Document {
+            url: url.clone(),
+            dir,
+            source,
+            analysis,
+        })
+    }
+
+    async fn publish_diagnostics(&self, url: Url) {
+        let Some(document) = self.analyse(&url) else {
+            return;
+        };
+
+        let diagnostics = document
+            .analysis
+            .diagnostics()
+            .iter()
+            .map(|diagnostic| Diagnostic {
+                range: diagnostic
+                    .span
+                    .map_or_else(Range::default, |span| range(&document.source, span)),
+                severity: Some(DiagnosticSeverity::ERROR),
+                source: Some(SOURCE_NAME.to_owned()),
+                message: diagnostic.message.clone(),
+                ..Diagnostic::default()
+            })
+            .collect();
+
+        self.client
+            .publish_diagnostics(url, diagnostics, None)
+            .await;
+    }
+}
+
+#[tower_lsp::async_trait]
+impl LanguageServer for Backend {
+    async fn initialize(&self, _params: InitializeParams) -> Result<InitializeResult> {
+        Ok(InitializeResult {
+            capabilities: ServerCapabilities {
+                text_document_sync: Some(TextDocumentSyncCapability::Kind(
+                    TextDocumentSyncKind::FULL,
+                )),
+                definition_provider: Some(OneOf::Left(true)),
+                references_provider: Some(OneOf::Left(true)),
+                hover_provider: Some(HoverProviderCapability::Simple(true)),
+                document_symbol_provider: Some(OneOf::Left(true)),
+                completion_provider: Some(CompletionOptions::default()),
+                ..ServerCapabilities::default()
+            },
+            server_info: Some(ServerInfo {
+                name: env!("CARGO_PKG_NAME").to_owned(),
+                version: Some(env!("CARGO_PKG_VERSION").to_owned()),
+            }),
+        })
+    }
+
+    async fn shutdown(&self) -> Result<()> {
+        Ok(())
+    }
+
+    async fn did_open(&self, params: DidOpenTextDocumentParams) {
+        let url = params.text_document.uri;
+        self.documents
+            .lock()
+            .unwrap()
+            .insert(url.clone(), params.text_document.text);
+        self.publish_diagnostics(url).await;
+    }
+
+    async fn did_change(&self, params: DidChangeTextDocumentParams) {
+        let url = params.text_document.uri;
+
+        // We only support full document sync, so the last change has all the
+        // text.
+        if let Some(change) = params.content_changes.into_iter().last() {
+            self.documents
+                .lock()
+                .unwrap()
+                .insert(url.clone(), change.text);
+        }
+
+        self.publish_diagnostics(url).await;
+    }
+
+    async fn did_close(&self, params: DidCloseTextDocumentParams) {
+        let url = params.text_document.uri;
+        self.documents.lock().unwrap().remove(&url);
+        self.client.publish_diagnostics(url, Vec::new(), None).await;
+    }
+
+    async fn goto_definition(
+        &self,
+        params: GotoDefinitionParams,
+    ) -> Result<Option<GotoDefinitionResponse>> {
+        let position = params.text_document_position_params;
+        let Some(document) = self.analyse(&position.text_document.uri) else {
+            return Ok(None);
+        };
+        let offset = offset(&document.source, position.position);
+
+        Ok(document
+            .analysis
+            .definition(offset)
+            .and_then(|span| document.location(span))
+            .map(GotoDefinitionResponse::Scalar))
+    }
+
+    async fn references(&self, params: ReferenceParams) -> Result<Option<Vec<Location>>> {
+        let position = params.text_document_position;
+        let Some(document) = self.analyse(&position.text_document.uri) else {
+            return Ok(None);
+        };
+        let offset = offset(&document.source, position.position);
+
+        Ok(Some(
+            document
+                .analysis
+                .references(offset, params.context.include_declaration)
+                .into_iter()
+                .filter_map(|span| document.location(span))
+                .collect(),
+        ))
+    }
+
+    async fn hover(&self, params: HoverParams) -> Result<Option<Hover>> {
+        let position = params.text_document_position_params;
+        let Some(document) = self.analyse(&position.text_document.uri) else {
+            return Ok(None);
+        };
+        let offset = offset(&document.source, position.position);
+
+        Ok(document
+            .analysis
+            .hover(offset)
+            .map(|(span, description)| Hover {
+                contents: HoverContents::Markup(MarkupContent {
+                    kind: MarkupKind::Markdown,
+                    value: description,
+                }),
+                range: Some(range(&document.source, span)),
+            }))
+    }
+
+    async fn document_symbol(
+        &self,
+        params: DocumentSymbolParams,
+    ) -> Result<Option<DocumentSymbolResponse>> {
+        let Some(document) = self.analyse(&params.text_document.uri) else {
+            return Ok(None);
+        };
+
+        let symbols = document
+            .analysis
+            .symbols()
+            .into_iter()
+            .map(|symbol| {
+                // `deprecated` is deprecated in favour of `tags`, but we still
+                // have to set it.
+                #[allow(deprecated)]
+                DocumentSymbol {
+                    name: symbol.name,
+                    detail: None,
+                    kind: SymbolKind::FUNCTION,
+                    tags: None,
+                    deprecated: None,
+                    range: range(&document.source, symbol.whole_span),
+                    selection_range: range(&document.source, symbol.span),
+                    children: None,
+                }
+            })
+            .collect();
+
+        Ok(Some(DocumentSymbolResponse::Nested(symbols)))
+    }
+
+    async fn completion(&self, params: CompletionParams) -> Result<Option<CompletionResponse>> {
+        let Some(document) = self.analyse(&params.text_document_position.text_document.uri) else {
+            return Ok(None);
+        };
+
+        let items = document
+            .analysis
+            .completions()
+            .into_iter()
+            .map(|name| CompletionItem {
+                label: name,
+                kind: Some(CompletionItemKind::FUNCTION),
+                ..CompletionItem::default()
+            })
+            .collect();
+
+        Ok(Some(CompletionResponse::Array(items)))
+    }
+}
+
+/// An open document, and it's analysis.
+struct Document {
+    url: Url,
+    dir: PathBuf,
+    source: String,
+    analysis: Analysis,
+}
+
+impl Document {
+    /// The location of a span in any module of the workflow.
+    fn location(&self, span: SrcSpan) -> Option<Location> {
+        let source = self.analysis.source(span.file())?;
+        let url = if span.file() == MAIN_FILE {
+            self.url.clone()
+        } else {
+            Url::from_file_path(self.analysis.path(&self.dir, span.file())?).ok()?
+        };
+
+        Some(Location {
+            uri: url,
+            range: range(source, span),
+        })
+    }
+}
+
+fn range(source: &str, span: SrcSpan) -> Range {
+    Range {
+        start: position(source, span.start()),
+        end: position(source, span.end()),
+    }
+}
+
+/// Convert a source position to an LSP position, which counts UTF-16 code
+/// units.
+fn position(source: &str, pos: SrcPos) -> Position {
+    let before = &source[..pos.offset()];
+    let line_start = before.rfind('\n').map_or(0, |newline| newline + 1);
+
+    Position {
+        line: (pos.line() - 1) as u32,
+        character: before[line_start..].encode_utf16().count() as u32,
+    }
+}
+
+/// Convert an LSP position to a byte offset into `source`.
+///
+/// Positions past the end of a line are clamped to the end of the line.
+fn offset(source: &str, position: Position) -> usize {
+    let line_start: usize = source
+        .split_inclusive('\n')
+        .take(position.line as usize)
+        .map(str::len)
+        .sum();
+    let line = source[line_start..].lines().next().unwrap_or("");
+    let mut utf16_len = 0;
+
+    for (index, c) in line.char_indices() {
+        if utf16_len >= position.character as usize {
+            return line_start + index;
+        }
+
+        utf16_len += c.len_utf16();
+    }
+
+    line_start + line.len()
+}
+
+const SOURCE_NAME: &str = "serpent-automation";
+
+#[cfg(test)]
+mod tests {
+    use serpent_automation_executor::syntax_tree::parse;
+    use tower_lsp::lsp_types::Position;
+
+    use super::{offset, position};
+
+    #[test]
+    fn positions() {
+        let source = "def main():\n    print(\"é😀\", x())\n";
+        let module = parse(source).unwrap();
+        let body_span = module.functions()[0].body().span();
+        let end = Position {
+            line: 1,
+            character: 21,
+        };
+
+        assert_eq!(position(source, body_span.end()), end);
+        assert_eq!(offset(source, end), body_span.end().offset());
+        assert_eq!(
+            offset(
+                source,
+                Position {
+                    line: 0,
+                    character: 100
+                }
+            ),
+            11
+        );
+    }
+}
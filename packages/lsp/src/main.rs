@@ -0,0 +1,17 @@
+//! A language server for workflow files.
+//!
+//! It talks LSP over stdin and stdout, so point your editor at the
+//! `serpent-automation-lsp` executable for `.py` files in your workflow
+//! directory.
+use backend::Backend;
+use tokio::io::{stdin, stdout};
+use tower_lsp::{LspService, Server};
+
+mod analysis;
+mod backend;
+
+#[tokio::main]
+async fn main() {
+    let (service, socket) = LspService::new(Backend::new);
+    Server::new(stdin(), stdout(), socket).serve(service).await;
+}
@@ -1,12 +1,168 @@
-use arpy::{FnSubscription, MsgId};
+use std::{fmt, num::ParseIntError, path::PathBuf, str::FromStr};
+
+use arpy::{FnRemote, FnSubscription, MsgId};
 use serde::{Deserialize, Serialize};
-use serpent_automation_executor::run::{CallStack, RunState};
+use serpent_automation_executor::{
+    run::{CallStack, RunState, Snapshot, ThreadUpdate},
+    sources::Sources,
+    syntax_tree::Value,
+};
 
+/// Subscribe to run state updates for the latest run.
+///
+/// The initial reply is the run we're subscribed to, if there is one. Items
+/// are numbered, so updates already included in a [`RunSnapshot`] can be
+/// ignored. See [`Snapshot::update_count`].
 #[derive(MsgId, Serialize, Deserialize, Debug)]
 pub struct ThreadSubscription;
 
 impl FnSubscription for ThreadSubscription {
-    type InitialReply = ();
-    type Item = (CallStack, RunState);
+    type InitialReply = Option<RunId>;
+    type Item = (u64, CallStack, RunState);
     type Update = CallStack;
 }
+
+/// Identifies a single run of a workflow.
+#[derive(
+    Copy, Clone, Default, Eq, PartialEq, Ord, PartialOrd, Hash, Debug, Serialize, Deserialize,
+)]
+pub struct RunId(u64);
+
+impl RunId {
+    #[must_use]
+    pub fn next(self) -> Self {
+        Self(self.0 + 1)
+    }
+}
+
+impl fmt::Display for RunId {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        self.0.fmt(f)
+    }
+}
+
+impl FromStr for RunId {
+    type Err = ParseIntError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        Ok(Self(s.parse()?))
+    }
+}
+
+/// Get the runs that are queued, but haven't started yet.
+#[derive(MsgId, Serialize, Deserialize, Debug)]
+pub struct PendingRuns;
+
+impl FnRemote for PendingRuns {
+    type Output = Vec<PendingRun>;
+}
+
+#[derive(Clone, Eq, PartialEq, Serialize, Deserialize, Debug)]
+pub struct PendingRun {
+    pub run_id: RunId,
+    pub workflow: String,
+}
+
+/// Start a new run of `workflow`.
+///
+/// This is the JSON body for `POST /api/operator/start`. The response is the
+/// new run's [`RunId`], or `null` if there's no such workflow.
+#[derive(Serialize, Deserialize, Debug)]
+pub struct StartRun {
+    pub workflow: String,
+    pub input: Value,
+}
+
+/// Remove a run from the queue.
+///
+/// This is the JSON body for `POST /api/operator/cancel`. The response is
+/// `false` if the run wasn't queued.
+#[derive(Serialize, Deserialize, Debug)]
+pub struct CancelRun {
+    pub run_id: RunId,
+}
+
+/// Pause or resume a run.
+///
+/// This is the JSON body for `POST /api/operator/pause`. The response is
+/// `false` if there's no such run, or it's already finished.
+#[derive(Serialize, Deserialize, Debug)]
+pub struct PauseRun {
+    pub run_id: RunId,
+    pub paused: bool,
+}
+
+/// Get a snapshot of a run, so a client can render it straight away.
+///
+/// If `run_id` is `None`, the latest run is used. The snapshot includes the
+/// states of the children of each of `open_nodes`.
+#[derive(MsgId, Serialize, Deserialize, Debug)]
+pub struct RunSnapshot {
+    pub run_id: Option<RunId>,
+    pub open_nodes: Vec<CallStack>,
+}
+
+impl FnRemote for RunSnapshot {
+    type Output = Option<(RunId, Snapshot)>;
+}
+
+/// Get the source files of a run's workflow, so a client can show it's call
+/// tree.
+///
+/// If `run_id` is `None`, the latest run is used.
+#[derive(MsgId, Serialize, Deserialize, Debug)]
+pub struct WorkflowSources {
+    pub run_id: Option<RunId>,
+}
+
+impl FnRemote for WorkflowSources {
+    type Output = Option<Sources>;
+}
+
+/// The response body from the webhook endpoint.
+#[derive(Serialize, Deserialize, Debug)]
+pub struct WebhookResponse {
+    pub run_id: RunId,
+}
+
+/// Get the job for a run.
+///
+/// This is used by worker processes to find out what they should run. `token`
+/// is the secret the server gave the worker for this run. If it's wrong, there
+/// is no job.
+#[derive(MsgId, Serialize, Deserialize, Debug)]
+pub struct WorkerJob {
+    pub run_id: RunId,
+    pub token: String,
+}
+
+impl FnRemote for WorkerJob {
+    type Output = Option<Job>;
+}
+
+#[derive(Serialize, Deserialize, Debug)]
+pub struct Job {
+    pub workflow: String,
+    /// The directory the workflow was loaded from, if it came from one.
+    pub dir: Option<PathBuf>,
+    pub sources: Sources,
+    pub input: Value,
+}
+
+/// Report progress from a worker process.
+///
+/// Updates with the wrong `token` are ignored. See [`WorkerJob`].
+///
+/// Returns whether the run is paused, so the worker can pause it's
+/// interpreter. Idle workers send empty updates to find out when they've been
+/// resumed.
+#[derive(MsgId, Serialize, Deserialize, Debug)]
+pub struct WorkerUpdates {
+    pub run_id: RunId,
+    pub token: String,
+    pub updates: Vec<ThreadUpdate>,
+}
+
+impl FnRemote for WorkerUpdates {
+    type Output = bool;
+}
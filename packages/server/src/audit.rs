@@ -0,0 +1,98 @@
+//! A record of who started, cancelled, paused or resumed each run.
+//!
+//! Each entry is a line of JSON.
+use std::{
+    fs::{File, OpenOptions},
+    io::{self, Write},
+    path::Path,
+    sync::{Arc, Mutex},
+};
+
+use chrono::Utc;
+use serde::Serialize;
+use serpent_automation_server_api::RunId;
+
+/// An append only audit log.
+///
+/// The default audit log doesn't record anything.
+#[derive(Clone, Default)]
+pub struct AuditLog(Option<Arc<Mutex<File>>>);
+
+impl AuditLog {
+    /// Append to the log at `path`, creating it if it doesn't exist.
+    pub fn open(path: &Path) -> io::Result<Self> {
+        let file = OpenOptions::new().create(true).append(true).open(path)?;
+        Ok(Self(Some(Arc::new(Mutex::new(file)))))
+    }
+
+    pub fn record(&self, user: &str, action: Action, run_id: RunId, workflow: &str) {
+        let Some(file) = &self.0 else {
+            return;
+        };
+
+        let entry = Entry {
+            time: Utc::now().to_rfc3339(),
+            user,
+            action,
+            run_id,
+            workflow,
+        };
+        let mut line = serde_json::to_string(&entry).unwrap();
+        line.push('\n');
+
+        if let Err(e) = file.lock().unwrap().write_all(line.as_bytes()) {
+            eprintln!("Unable to write to audit log: {e}");
+        }
+    }
+}
+
+#[derive(Copy, Clone, Eq, PartialEq, Debug, Serialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum Action {
+    Start,
+    Cancel,
+    Pause,
+    Resume,
+}
+
+#[derive(Serialize)]
+struct Entry<'a> {
+    time: String,
+    user: &'a str,
+    action: Action,
+    run_id: RunId,
+    workflow: &'a str,
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{env, fs, process};
+
+    use serpent_automation_server_api::RunId;
+
+    use super::{Action, AuditLog};
+
+    #[test]
+    fn record() {
+        let path = env::temp_dir().join(format!("serpent-automation-{}-audit.log", process::id()));
+        let audit_log = AuditLog::open(&path).unwrap();
+        let run_id = RunId::default();
+
+        audit_log.record("alice", Action::Start, run_id, "build");
+        audit_log.record("bob", Action::Cancel, run_id, "build");
+
+        let log = fs::read_to_string(&path).unwrap();
+        fs::remove_file(&path).unwrap();
+        let entries: Vec<serde_json::Value> = log
+            .lines()
+            .map(|line| serde_json::from_str(line).unwrap())
+            .collect();
+
+        assert_eq!(entries.len(), 2);
+        assert_eq!(entries[0]["user"], "alice");
+        assert_eq!(entries[0]["action"], "start");
+        assert_eq!(entries[1]["user"], "bob");
+        assert_eq!(entries[1]["action"], "cancel");
+        assert_eq!(entries[1]["workflow"], "build");
+    }
+}
@@ -0,0 +1,339 @@
+//! Token authentication and role based permissions for the API.
+//!
+//! Users are listed in a TOML file:
+//!
+//! ```toml
+//! [[users]]
+//! name = "alice"
+//! role = "operator"
+//! token-sha256 = "<hex SHA-256 hash of alice's token>"
+//! ```
+//!
+//! Clients send their token in an `Authorization: Bearer <token>` header, or in
+//! a `token` query parameter, as browsers can't set headers on websocket
+//! requests. Handlers behind [`require`] can get the authenticated [`User`]
+//! from the request extensions.
+use std::{
+    fs, io,
+    path::{Path, PathBuf},
+    sync::Arc,
+};
+
+use axum::{
+    extract::{Query, State},
+    headers::{authorization::Bearer, Authorization},
+    http::{Request, StatusCode},
+    middleware::{self, Next},
+    response::{IntoResponse, Response},
+    Router, TypedHeader,
+};
+use serde::Deserialize;
+use sha2::{Digest, Sha256};
+use thiserror::Error;
+
+/// What a user is allowed to do.
+///
+/// Each role can do everything the roles before it can.
+#[derive(Copy, Clone, Eq, PartialEq, Ord, PartialOrd, Debug, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum Role {
+    /// View runs
+    Viewer,
+    /// Start, cancel, pause and resume runs
+    Operator,
+}
+
+#[derive(Clone, Debug)]
+pub struct User {
+    name: String,
+    role: Role,
+}
+
+impl User {
+    pub fn name(&self) -> &str {
+        &self.name
+    }
+
+    pub fn role(&self) -> Role {
+        self.role
+    }
+}
+
+/// The users that can access the API.
+#[derive(Clone)]
+pub struct Users(Option<Arc<[UserEntry]>>);
+
+#[derive(Deserialize)]
+#[serde(rename_all = "kebab-case", deny_unknown_fields)]
+struct UserEntry {
+    name: String,
+    role: Role,
+    #[serde(with = "hex")]
+    token_sha256: Vec<u8>,
+}
+
+#[derive(Deserialize)]
+#[serde(deny_unknown_fields)]
+struct UsersFile {
+    users: Vec<UserEntry>,
+}
+
+impl Users {
+    /// Read users from a TOML file.
+    pub fn load(path: &Path) -> Result<Self, AuthError> {
+        let source = fs::read_to_string(path).map_err(|source| AuthError::Read {
+            path: path.to_owned(),
+            source,
+        })?;
+
+        Self::parse(&source).map_err(|source| AuthError::Parse {
+            path: path.to_owned(),
+            source,
+        })
+    }
+
+    pub(crate) fn parse(source: &str) -> Result<Self, toml::de::Error> {
+        let users_file: UsersFile = toml::from_str(source)?;
+        Ok(Self(Some(users_file.users.into())))
+    }
+
+    /// Don't check tokens.
+    ///
+    /// Everyone is the [`ANONYMOUS`] operator.
+    pub fn unauthenticated() -> Self {
+        Self(None)
+    }
+
+    /// Are tokens checked?
+    ///
+    /// This is `false` for [`Users::unauthenticated`].
+    pub fn checks_tokens(&self) -> bool {
+        self.0.is_some()
+    }
+
+    /// Find the user with `token`.
+    pub fn authenticate(&self, token: Option<&str>) -> Option<User> {
+        let Some(users) = &self.0 else {
+            return Some(User {
+                name: ANONYMOUS.to_owned(),
+                role: Role::Operator,
+            });
+        };
+
+        let token_sha256 = Sha256::digest(token?.as_bytes());
+
+        users
+            .iter()
+            .find(|user| user.token_sha256 == token_sha256.as_slice())
+            .map(|user| User {
+                name: user.name.clone(),
+                role: user.role,
+            })
+    }
+}
+
+/// Only allow requests to `router` from users with at least `role`.
+///
+/// The [`User`] is added to the request extensions.
+pub fn require(router: Router, users: Users, role: Role) -> Router {
+    router.route_layer(middleware::from_fn_with_state(
+        Access { users, role },
+        check_access,
+    ))
+}
+
+#[derive(Clone)]
+struct Access {
+    users: Users,
+    role: Role,
+}
+
+#[derive(Deserialize)]
+struct TokenQuery {
+    token: Option<String>,
+}
+
+async fn check_access<B>(
+    State(access): State<Access>,
+    Query(query): Query<TokenQuery>,
+    authorization: Option<TypedHeader<Authorization<Bearer>>>,
+    mut request: Request<B>,
+    next: Next<B>,
+) -> Response {
+    let token = authorization
+        .as_ref()
+        .map(|TypedHeader(authorization)| authorization.token())
+        .or(query.token.as_deref());
+
+    let Some(user) = access.users.authenticate(token) else {
+        return StatusCode::UNAUTHORIZED.into_response();
+    };
+
+    if user.role >= access.role {
+        request.extensions_mut().insert(user);
+        next.run(request).await
+    } else {
+        StatusCode::FORBIDDEN.into_response()
+    }
+}
+
+/// The user name when authentication is turned off.
+pub const ANONYMOUS: &str = "anonymous";
+
+#[derive(Error, Debug)]
+pub enum AuthError {
+    #[error("Unable to read users file '{}': {source}", path.display())]
+    Read { path: PathBuf, source: io::Error },
+    #[error("Unable to parse users file '{}': {source}", path.display())]
+    Parse {
+        path: PathBuf,
+        source: toml::de::Error,
+    },
+}
+
+#[cfg(test)]
+mod tests {
+    use std::net::{SocketAddr, TcpListener};
+
+    use axum::{routing::get, Extension, Router, Server};
+    use hyper::{Body, Client, Request, StatusCode};
+    use indoc::{formatdoc, indoc};
+    use sha2::{Digest, Sha256};
+
+    use super::{require, Role, User, Users, ANONYMOUS};
+
+    const VIEWER_TOKEN: &str = "viewer-token";
+    const OPERATOR_TOKEN: &str = "operator-token";
+
+    #[test]
+    fn authenticate() {
+        let users = users();
+
+        let viewer = users.authenticate(Some(VIEWER_TOKEN)).unwrap();
+        assert_eq!(viewer.name(), "viewer");
+        assert_eq!(viewer.role(), Role::Viewer);
+        assert!(users.authenticate(Some("wrong")).is_none());
+        assert!(users.authenticate(None).is_none());
+    }
+
+    #[test]
+    fn unauthenticated() {
+        let user = Users::unauthenticated().authenticate(None).unwrap();
+        assert_eq!(user.name(), ANONYMOUS);
+        assert_eq!(user.role(), Role::Operator);
+    }
+
+    #[test]
+    fn invalid_users_file() {
+        assert!(Users::parse(indoc! {r#"
+            [[users]]
+            name = "viewer"
+            role = "admin"
+            token-sha256 = "00"
+        "#})
+        .is_err());
+    }
+
+    #[tokio::test]
+    async fn viewer_route() {
+        let address = serve(Role::Viewer);
+
+        assert_eq!(get_status(address, None).await, StatusCode::UNAUTHORIZED);
+        assert_eq!(
+            get_status(address, Some("wrong")).await,
+            StatusCode::UNAUTHORIZED
+        );
+        assert_eq!(
+            get_status(address, Some(VIEWER_TOKEN)).await,
+            StatusCode::OK
+        );
+        assert_eq!(
+            get_status(address, Some(OPERATOR_TOKEN)).await,
+            StatusCode::OK
+        );
+
+        let query_uri = format!("http://{address}/test?token={VIEWER_TOKEN}");
+        let response = Client::new().get(query_uri.parse().unwrap()).await.unwrap();
+        assert_eq!(response.status(), StatusCode::OK);
+    }
+
+    #[tokio::test]
+    async fn operator_route() {
+        let address = serve(Role::Operator);
+
+        assert_eq!(
+            get_status(address, Some(VIEWER_TOKEN)).await,
+            StatusCode::FORBIDDEN
+        );
+        assert_eq!(
+            get_status(address, Some(OPERATOR_TOKEN)).await,
+            StatusCode::OK
+        );
+    }
+
+    #[tokio::test]
+    async fn user_extension() {
+        let address = serve(Role::Viewer);
+        let request = Request::get(format!("http://{address}/user"))
+            .header("Authorization", format!("Bearer {OPERATOR_TOKEN}"))
+            .body(Body::empty())
+            .unwrap();
+        let response = Client::new().request(request).await.unwrap();
+        let body = hyper::body::to_bytes(response.into_body()).await.unwrap();
+
+        assert_eq!(body.as_ref(), b"operator");
+    }
+
+    fn users() -> Users {
+        Users::parse(&formatdoc! {r#"
+            [[users]]
+            name = "viewer"
+            role = "viewer"
+            token-sha256 = "{}"
+
+            [[users]]
+            name = "operator"
+            role = "operator"
+            token-sha256 = "{}"
+        "#,
+            hex::encode(Sha256::digest(VIEWER_TOKEN)),
+            hex::encode(Sha256::digest(OPERATOR_TOKEN)),
+        })
+        .unwrap()
+    }
+
+    fn serve(role: Role) -> SocketAddr {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let address = listener.local_addr().unwrap();
+        let app = require(
+            Router::new().route("/test", get(|| async { "ok" })).route(
+                "/user",
+                get(|Extension(user): Extension<User>| async move { user.name }),
+            ),
+            users(),
+            role,
+        );
+
+        tokio::spawn(
+            Server::from_tcp(listener)
+                .unwrap()
+                .serve(app.into_make_service()),
+        );
+
+        address
+    }
+
+    async fn get_status(address: SocketAddr, token: Option<&str>) -> StatusCode {
+        let mut request = Request::get(format!("http://{address}/test"));
+
+        if let Some(token) = token {
+            request = request.header("Authorization", format!("Bearer {token}"));
+        }
+
+        Client::new()
+            .request(request.body(Body::empty()).unwrap())
+            .await
+            .unwrap()
+            .status()
+    }
+}
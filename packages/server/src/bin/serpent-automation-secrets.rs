@@ -0,0 +1,53 @@
+use std::{
+    env, fs,
+    path::{Path, PathBuf},
+    process::ExitCode,
+};
+
+use chacha20poly1305::{
+    aead::{AeadCore, KeyInit, OsRng},
+    ChaCha20Poly1305,
+};
+use clap::Parser;
+use serpent_automation_executor::secrets::{Secrets, SECRETS_KEY_VAR};
+
+/// Create encrypted secrets files for workflows.
+#[derive(Parser, Debug)]
+#[command(about = "Manage Serpent Automation secrets files")]
+enum Command {
+    /// Print a new random hex encoded key
+    GenerateKey,
+    /// Encrypt a TOML secrets file with the key in
+    /// `SERPENT_AUTOMATION_SECRETS_KEY`
+    Encrypt { input: PathBuf, output: PathBuf },
+}
+
+fn main() -> ExitCode {
+    match Command::parse() {
+        Command::GenerateKey => {
+            println!(
+                "{}",
+                hex::encode(ChaCha20Poly1305::generate_key(&mut OsRng))
+            );
+            ExitCode::SUCCESS
+        }
+        Command::Encrypt { input, output } => match encrypt(&input, &output) {
+            Ok(()) => ExitCode::SUCCESS,
+            Err(e) => {
+                eprintln!("{e}");
+                ExitCode::FAILURE
+            }
+        },
+    }
+}
+
+fn encrypt(input: &Path, output: &Path) -> Result<(), String> {
+    let key = env::var(SECRETS_KEY_VAR).map_err(|_| format!("{SECRETS_KEY_VAR} isn't set"))?;
+    let key = hex::decode(key.trim()).map_err(|e| format!("Invalid key: {e}"))?;
+    let source = fs::read_to_string(input)
+        .map_err(|e| format!("Unable to read '{}': {e}", input.display()))?;
+    let nonce = ChaCha20Poly1305::generate_nonce(&mut OsRng);
+    let data = Secrets::encrypt(&source, &key, &nonce.into()).map_err(|e| e.to_string())?;
+
+    fs::write(output, data).map_err(|e| format!("Unable to write '{}': {e}", output.display()))
+}
@@ -0,0 +1,30 @@
+use std::{env, process::ExitCode};
+
+use serpent_automation_server::remote::work;
+use serpent_automation_server_api::RunId;
+
+#[tokio::main]
+async fn main() -> ExitCode {
+    let args: Vec<String> = env::args().skip(1).collect();
+
+    let [server_url, run_id] = args.as_slice() else {
+        eprintln!("Usage: serpent-automation-worker <server url> <run id>");
+        return ExitCode::FAILURE;
+    };
+
+    let run_id: RunId = match run_id.parse() {
+        Ok(run_id) => run_id,
+        Err(e) => {
+            eprintln!("Invalid run id \"{run_id}\": {e}");
+            return ExitCode::FAILURE;
+        }
+    };
+
+    match work(server_url, run_id).await {
+        Ok(()) => ExitCode::SUCCESS,
+        Err(e) => {
+            eprintln!("{e}");
+            ExitCode::FAILURE
+        }
+    }
+}
@@ -0,0 +1,166 @@
+use std::{
+    fs,
+    path::{Path, PathBuf},
+    process::ExitCode,
+};
+
+use clap::{Parser, ValueEnum};
+use serpent_automation_executor::{
+    call_graph::CallGraph, format::format, lint::lint, sources::Sources,
+};
+
+/// Tools for working with workflow source files.
+#[derive(Parser, Debug)]
+#[command(about = "Serpent Automation workflow tools")]
+enum Command {
+    /// Format workflow files in place
+    Fmt {
+        /// Don't write any files. Fail if any of them aren't formatted.
+        #[arg(long)]
+        check: bool,
+        #[arg(required = true)]
+        files: Vec<PathBuf>,
+    },
+    /// Print the static call graph of a workflow
+    Graph {
+        #[arg(long, value_enum, default_value_t = GraphFormat::Dot)]
+        format: GraphFormat,
+        /// The workflow's main module. Imported modules are loaded from the
+        /// same directory.
+        file: PathBuf,
+    },
+    /// Check a workflow for common mistakes
+    ///
+    /// Suppress a finding with a `# noqa` or `# noqa: <id>, ...` comment on
+    /// the line it's reported on.
+    Lint {
+        /// A host function the workflow may call. Can be given more than once.
+        #[arg(long = "host-function", value_name = "NAME")]
+        host_functions: Vec<String>,
+        /// The workflow's main module. Imported modules are loaded from the
+        /// same directory.
+        file: PathBuf,
+    },
+}
+
+#[derive(ValueEnum, Copy, Clone, Debug)]
+enum GraphFormat {
+    Dot,
+    Json,
+}
+
+fn main() -> ExitCode {
+    match Command::parse() {
+        Command::Fmt { check, files } => fmt(check, &files),
+        Command::Graph { format, file } => match graph(format, &file) {
+            Ok(graph) => {
+                print!("{graph}");
+                ExitCode::SUCCESS
+            }
+            Err(e) => {
+                eprintln!("{e}");
+                ExitCode::FAILURE
+            }
+        },
+        Command::Lint {
+            host_functions,
+            file,
+        } => lint_workflow(&host_functions, &file),
+    }
+}
+
+fn fmt(check: bool, files: &[PathBuf]) -> ExitCode {
+    let mut success = true;
+
+    for file in files {
+        match fmt_file(check, file) {
+            Ok(true) => (),
+            Ok(false) => {
+                println!("'{}' isn't formatted", file.display());
+                success = false;
+            }
+            Err(e) => {
+                eprintln!("{e}");
+                success = false;
+            }
+        }
+    }
+
+    if success {
+        ExitCode::SUCCESS
+    } else {
+        ExitCode::FAILURE
+    }
+}
+
+/// Format `file`, or just check it's formatted if `check` is set.
+///
+/// Returns whether `file` was already formatted, or `true` if it's been
+/// formatted.
+fn fmt_file(check: bool, file: &PathBuf) -> Result<bool, String> {
+    let source = fs::read_to_string(file)
+        .map_err(|e| format!("Unable to read '{}': {e}", file.display()))?;
+    let formatted =
+        format(&source).map_err(|e| format!("Unable to format '{}': {e}", file.display()))?;
+
+    if formatted == source {
+        return Ok(true);
+    }
+
+    if check {
+        return Ok(false);
+    }
+
+    fs::write(file, formatted).map_err(|e| format!("Unable to write '{}': {e}", file.display()))?;
+
+    Ok(true)
+}
+
+fn graph(format: GraphFormat, file: &Path) -> Result<String, String> {
+    let library = load(file)?.link().map_err(|e| e.to_string())?;
+    let graph = CallGraph::new(&library);
+
+    Ok(match format {
+        GraphFormat::Dot => graph.to_dot(),
+        GraphFormat::Json => graph.to_json() + "\n",
+    })
+}
+
+fn lint_workflow(host_functions: &[String], file: &Path) -> ExitCode {
+    let host_functions: Vec<&str> = host_functions.iter().map(String::as_str).collect();
+    let lints = load(file).and_then(|sources| {
+        let lints = lint(&sources, &host_functions).map_err(|e| e.to_string())?;
+        Ok((sources, lints))
+    });
+
+    match lints {
+        Ok((sources, lints)) => {
+            for lint in &lints {
+                let module = sources.file(lint.span().file()).unwrap().module();
+                println!("{module}.py:{lint}");
+            }
+
+            if lints.is_empty() {
+                ExitCode::SUCCESS
+            } else {
+                ExitCode::FAILURE
+            }
+        }
+        Err(e) => {
+            eprintln!("{e}");
+            ExitCode::FAILURE
+        }
+    }
+}
+
+/// Load the workflow with main module `file`.
+fn load(file: &Path) -> Result<Sources, String> {
+    let invalid_path = || format!("Invalid workflow path '{}'", file.display());
+    let dir = file.parent().ok_or_else(invalid_path)?;
+    let main = file
+        .file_stem()
+        .and_then(|stem| stem.to_str())
+        .ok_or_else(invalid_path)?;
+
+    Sources::load(dir, main).map_err(|e| e.to_string())
+}
@@ -0,0 +1,176 @@
+use std::{
+    fs, io,
+    net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr},
+    path::{Path, PathBuf},
+};
+
+use clap::Parser;
+use serde::Deserialize;
+use thiserror::Error;
+
+/// Command line arguments for the server.
+///
+/// These override any settings in the config file.
+#[derive(Parser, Debug)]
+#[command(about = "Run the Serpent Automation server")]
+pub struct Args {
+    /// A TOML config file
+    #[arg(long)]
+    config: Option<PathBuf>,
+    /// The address to listen on
+    #[arg(long)]
+    bind_address: Option<IpAddr>,
+    /// The port to listen on
+    #[arg(long)]
+    port: Option<u16>,
+    /// A directory of workflows to load. Each `.py` file is a workflow, named
+    /// after the file.
+    #[arg(long)]
+    workflow_dir: Option<PathBuf>,
+    /// Where to store run data
+    #[arg(long)]
+    data_dir: Option<PathBuf>,
+    /// A directory containing the built UI to serve
+    #[arg(long)]
+    ui_dir: Option<PathBuf>,
+    /// A TOML file listing the users that can access the API. If there's no
+    /// users file, anyone can access the API.
+    #[arg(long)]
+    users_file: Option<PathBuf>,
+}
+
+#[derive(Deserialize, Clone, Debug, Eq, PartialEq)]
+#[serde(default, rename_all = "kebab-case", deny_unknown_fields)]
+pub struct Config {
+    pub bind_address: IpAddr,
+    pub port: u16,
+    pub workflow_dir: Option<PathBuf>,
+    pub data_dir: Option<PathBuf>,
+    pub ui_dir: Option<PathBuf>,
+    pub users_file: Option<PathBuf>,
+    /// The maximum number of finished nodes to keep in memory for each run.
+    ///
+    /// This only applies if there's a `data_dir` to page history out to.
+    pub max_history_in_memory: usize,
+}
+
+impl Default for Config {
+    fn default() -> Self {
+        Self {
+            bind_address: IpAddr::V4(Ipv4Addr::UNSPECIFIED),
+            port: 9090,
+            workflow_dir: None,
+            data_dir: None,
+            ui_dir: None,
+            users_file: None,
+            max_history_in_memory: 100_000,
+        }
+    }
+}
+
+impl Config {
+    /// Read the config file, if there is one, and apply the command line
+    /// arguments.
+    pub fn load(args: Args) -> Result<Self, ConfigError> {
+        let config = match &args.config {
+            Some(path) => Self::read(path)?,
+            None => Self::default(),
+        };
+
+        Ok(config.with_args(args))
+    }
+
+    fn read(path: &Path) -> Result<Self, ConfigError> {
+        let source = fs::read_to_string(path).map_err(|source| ConfigError::Read {
+            path: path.to_owned(),
+            source,
+        })?;
+
+        toml::from_str(&source).map_err(|source| ConfigError::Parse {
+            path: path.to_owned(),
+            source,
+        })
+    }
+
+    fn with_args(self, args: Args) -> Self {
+        Self {
+            bind_address: args.bind_address.unwrap_or(self.bind_address),
+            port: args.port.unwrap_or(self.port),
+            workflow_dir: args.workflow_dir.or(self.workflow_dir),
+            data_dir: args.data_dir.or(self.data_dir),
+            ui_dir: args.ui_dir.or(self.ui_dir),
+            users_file: args.users_file.or(self.users_file),
+            max_history_in_memory: self.max_history_in_memory,
+        }
+    }
+
+    pub fn socket_addr(&self) -> SocketAddr {
+        SocketAddr::new(self.bind_address, self.port)
+    }
+
+    /// A URL that processes on this machine can use to reach the server.
+    pub fn local_url(&self) -> String {
+        let address = match self.bind_address {
+            IpAddr::V4(address) if address.is_unspecified() => IpAddr::V4(Ipv4Addr::LOCALHOST),
+            IpAddr::V6(address) if address.is_unspecified() => IpAddr::V6(Ipv6Addr::LOCALHOST),
+            address => address,
+        };
+
+        format!("http://{}", SocketAddr::new(address, self.port))
+    }
+}
+
+#[derive(Error, Debug)]
+pub enum ConfigError {
+    #[error("Unable to read config file '{}': {source}", path.display())]
+    Read { path: PathBuf, source: io::Error },
+    #[error("Unable to parse config file '{}': {source}", path.display())]
+    Parse {
+        path: PathBuf,
+        source: toml::de::Error,
+    },
+}
+
+#[cfg(test)]
+mod tests {
+    use std::net::{IpAddr, Ipv4Addr};
+
+    use clap::Parser;
+    use indoc::indoc;
+
+    use super::{Args, Config};
+
+    #[test]
+    fn config_file() {
+        let config: Config = toml::from_str(indoc! {r#"
+            bind-address = "127.0.0.1"
+            workflow-dir = "workflows"
+        "#})
+        .unwrap();
+
+        assert_eq!(config.bind_address, IpAddr::V4(Ipv4Addr::LOCALHOST));
+        assert_eq!(config.port, 9090);
+        assert_eq!(config.workflow_dir, Some("workflows".into()));
+        assert_eq!(config.data_dir, None);
+    }
+
+    #[test]
+    fn unknown_field() {
+        assert!(toml::from_str::<Config>("prot = 8080").is_err());
+    }
+
+    #[test]
+    fn args_override_config() {
+        let config = Config {
+            port: 8080,
+            data_dir: Some("data".into()),
+            ..Config::default()
+        };
+        let args = Args::parse_from(["serpent-automation-server", "--port", "8081"]);
+        let config = config.with_args(args);
+
+        assert_eq!(config.port, 8081);
+        assert_eq!(config.data_dir, Some("data".into()));
+        assert_eq!(config.local_url(), "http://127.0.0.1:8081");
+    }
+}
@@ -0,0 +1,10 @@
+pub mod audit;
+pub mod auth;
+pub mod config;
+pub mod metrics;
+pub mod operator;
+pub mod remote;
+pub mod runs;
+pub mod scheduler;
+pub mod triggers;
+pub mod webhook;
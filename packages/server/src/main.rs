@@ -1,44 +1,243 @@
-use std::thread;
+use std::{env, fs, io, path::Path, process::ExitCode};
 
 use arpy_axum::RpcRoute;
 use arpy_server::WebSocketRouter;
 use axum::{Router, Server};
+use clap::Parser;
 use clonelet::clone;
 use futures::stream::BoxStream;
 use serpent_automation_executor::{
-    library::Library,
-    run::{CallStack, ThreadRunState},
-    syntax_tree::parse,
-    CODE,
+    run::CallStack, secrets::Secrets, step_cache::StepCache, syntax_tree::Value, CODE,
+};
+use serpent_automation_server::{
+    audit::AuditLog,
+    auth::{self, Role, Users},
+    config::{Args, Config},
+    metrics, operator,
+    remote::{self, RemoteWorker},
+    runs::{Runs, Workflow, Workflows, DEFAULT_WORKER_COUNT},
+    triggers::Triggers,
+    webhook,
+};
+use serpent_automation_server_api::{
+    PendingRuns, RunSnapshot, ThreadSubscription, WorkflowSources,
 };
-use serpent_automation_server_api::ThreadSubscription;
 use tokio::spawn;
 use tokio_stream::wrappers::ReceiverStream;
+use tower_http::services::{ServeDir, ServeFile};
 
 #[tokio::main]
-async fn main() {
-    let thread_run_state = ThreadRunState::default();
+async fn main() -> ExitCode {
+    let config = match Config::load(Args::parse()) {
+        Ok(config) => config,
+        Err(e) => {
+            eprintln!("{e}");
+            return ExitCode::FAILURE;
+        }
+    };
+
+    let worker_executable = env::current_exe().unwrap().with_file_name(format!(
+        "serpent-automation-worker{}",
+        env::consts::EXE_SUFFIX
+    ));
+    let runs = if worker_executable.exists() {
+        Runs::remote(
+            DEFAULT_WORKER_COUNT,
+            RemoteWorker::new(worker_executable, config.local_url()),
+        )
+    } else {
+        println!("Worker executable not found. Running workflows in the server process.");
+        Runs::default()
+    };
+    let runs = match Secrets::from_env() {
+        Ok(secrets) => runs.with_secrets(secrets),
+        Err(e) => {
+            eprintln!("{e}");
+            return ExitCode::FAILURE;
+        }
+    };
+    let runs = match &config.data_dir {
+        Some(data_dir) => {
+            let history_dir = data_dir.join("history");
+
+            // Run ids start again from 0, so history paged out by an earlier server is
+            // no use. It's only removed when a run is dropped, which doesn't happen
+            // if the server is killed.
+            if let Err(e) = remove_dir_if_exists(&history_dir) {
+                eprintln!("Unable to remove '{}': {e}", history_dir.display());
+                return ExitCode::FAILURE;
+            }
 
-    thread::spawn({
-        clone!(thread_run_state);
+            if let Err(e) = fs::create_dir_all(&history_dir) {
+                eprintln!("Unable to create '{}': {e}", history_dir.display());
+                return ExitCode::FAILURE;
+            }
 
-        move || {
-            let lib = Library::link(parse(CODE).unwrap());
-            lib.run(&thread_run_state);
+            let audit_log_file = data_dir.join("audit.log");
+            let audit_log = match AuditLog::open(&audit_log_file) {
+                Ok(audit_log) => audit_log,
+                Err(e) => {
+                    eprintln!("Unable to open '{}': {e}", audit_log_file.display());
+                    return ExitCode::FAILURE;
+                }
+            };
+
+            let step_cache_dir = data_dir.join("cache");
+            let step_cache = match StepCache::open(&step_cache_dir) {
+                Ok(step_cache) => step_cache,
+                Err(e) => {
+                    eprintln!("Unable to open '{}': {e}", step_cache_dir.display());
+                    return ExitCode::FAILURE;
+                }
+            };
+
+            runs.with_history_limits(config.max_history_in_memory, history_dir)
+                .with_audit_log(audit_log)
+                .with_step_cache(step_cache)
         }
-    });
+        None => runs,
+    };
+    let users = match &config.users_file {
+        Some(users_file) => match Users::load(users_file) {
+            Ok(users) => users,
+            Err(e) => {
+                eprintln!("{e}");
+                return ExitCode::FAILURE;
+            }
+        },
+        None => {
+            println!("No users file. Anyone can access the API.");
+            Users::unauthenticated()
+        }
+    };
+    let workflows = match &config.workflow_dir {
+        Some(workflow_dir) => match Workflows::load(workflow_dir) {
+            Ok(workflows) => workflows,
+            Err(e) => {
+                eprintln!("{e}");
+                return ExitCode::FAILURE;
+            }
+        },
+        None => Workflows::from_iter([Workflow::parse("main", CODE).unwrap()]),
+    };
+    let _triggers: Vec<Triggers> = match workflows
+        .iter()
+        .map(|workflow| {
+            Triggers::spawn(workflow, &runs)
+                .map_err(|e| format!("Workflow \"{}\": {e}", workflow.name()))
+        })
+        .collect()
+    {
+        Ok(triggers) => triggers,
+        Err(e) => {
+            eprintln!("{e}");
+            return ExitCode::FAILURE;
+        }
+    };
+
+    if let Some(main) = workflows.get("main") {
+        runs.start(main, Value::None, "server");
+    }
+
+    let webhook_secret = env::var("SERPENT_AUTOMATION_WEBHOOK_SECRET").ok();
+    // Without a secret, anyone could start runs with the webhook, so require an
+    // operator token instead.
+    let webhook_requires_token = webhook_secret.is_none() && users.checks_tokens();
 
     let ws = WebSocketRouter::new().handle_subscription({
+        clone!(runs);
+
         move |updates: BoxStream<'static, CallStack>, _subscription: ThreadSubscription| {
+            // TODO: Let the client choose which run to subscribe to
+            let latest = runs.latest();
+            let run_id = latest.as_ref().map(|run| run.id());
+            let thread_run_state = latest
+                .map(|run| run.thread_run_state().clone())
+                .unwrap_or_default();
             let (run_state_receiver, update_client) = thread_run_state.subscribe(updates);
             spawn(update_client);
-            ((), ReceiverStream::new(run_state_receiver))
+            (run_id, ReceiverStream::new(run_state_receiver))
         }
     });
 
-    let app = Router::new().ws_rpc_route("/api", ws, 10000);
-    Server::bind(&"0.0.0.0:9090".parse().unwrap())
-        .serve(app.into_make_service())
-        .await
-        .unwrap();
+    let ws = ws
+        .handle({
+            clone!(runs);
+
+            move |_: PendingRuns| {
+                let pending = runs.pending();
+                async move { pending }
+            }
+        })
+        .handle({
+            clone!(runs);
+
+            move |RunSnapshot { run_id, open_nodes }| {
+                let run = match run_id {
+                    Some(run_id) => runs.get(run_id),
+                    None => runs.latest(),
+                };
+                let snapshot =
+                    run.map(|run| (run.id(), run.thread_run_state().snapshot(&open_nodes)));
+                async move { snapshot }
+            }
+        })
+        .handle({
+            clone!(runs);
+
+            move |WorkflowSources { run_id }| {
+                let run = match run_id {
+                    Some(run_id) => runs.get(run_id),
+                    None => runs.latest(),
+                };
+                let sources = run.map(|run| run.workflow().sources().clone());
+                async move { sources }
+            }
+        });
+
+    let app = auth::require(
+        Router::new().ws_rpc_route("/api", ws, 10000),
+        users.clone(),
+        Role::Viewer,
+    )
+    .merge(auth::require(
+        operator::router(workflows.clone(), runs.clone()),
+        users.clone(),
+        Role::Operator,
+    ))
+    .merge(auth::require(
+        metrics::router(runs.clone()),
+        users.clone(),
+        Role::Viewer,
+    ))
+    .merge(remote::router(runs.clone()));
+    let webhook = webhook::router(workflows, runs, webhook_secret);
+    let app = if webhook_requires_token {
+        app.merge(auth::require(webhook, users, Role::Operator))
+    } else {
+        app.merge(webhook)
+    };
+    let app = match &config.ui_dir {
+        Some(ui_dir) => app.fallback_service(
+            ServeDir::new(ui_dir).fallback(ServeFile::new(ui_dir.join("index.html"))),
+        ),
+        None => app,
+    };
+
+    let addr = config.socket_addr();
+    println!("Listening on {addr}");
+
+    if let Err(e) = Server::bind(&addr).serve(app.into_make_service()).await {
+        eprintln!("{e}");
+        return ExitCode::FAILURE;
+    }
+
+    ExitCode::SUCCESS
+}
+
+fn remove_dir_if_exists(dir: &Path) -> io::Result<()> {
+    match fs::remove_dir_all(dir) {
+        Err(e) if e.kind() == io::ErrorKind::NotFound => Ok(()),
+        result => result,
+    }
 }
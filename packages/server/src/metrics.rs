@@ -0,0 +1,208 @@
+//! A `/metrics` endpoint in the Prometheus text format.
+//!
+//! Run counts and durations are labelled by workflow, so we can alert on
+//! failing workflows.
+use std::{
+    collections::BTreeMap,
+    fmt::{self, Write},
+};
+
+use axum::{
+    extract::State, http::header::CONTENT_TYPE, response::IntoResponse, routing::get, Router,
+};
+
+use crate::runs::{Run, RunStatus, Runs};
+
+/// A router with the metrics endpoint at `/metrics`.
+pub fn router(runs: Runs) -> Router {
+    Router::new()
+        .route("/metrics", get(metrics))
+        .with_state(runs)
+}
+
+async fn metrics(State(runs): State<Runs>) -> impl IntoResponse {
+    ([(CONTENT_TYPE, "text/plain; version=0.0.4")], render(&runs))
+}
+
+fn render(runs: &Runs) -> String {
+    let mut text = String::new();
+    write_metrics(&mut text, &runs.all(), runs.pending().len()).unwrap();
+    text
+}
+
+fn write_metrics(out: &mut impl Write, runs: &[Run], queue_depth: usize) -> fmt::Result {
+    let mut workflows: BTreeMap<&str, Vec<&Run>> = BTreeMap::new();
+
+    for run in runs {
+        workflows
+            .entry(run.workflow().name())
+            .or_default()
+            .push(run);
+    }
+
+    header(out, "runs", "gauge", "The number of runs in each state.")?;
+
+    for (workflow, runs) in &workflows {
+        let workflow = escape(workflow);
+
+        for status in RunStatus::ALL {
+            let count = runs.iter().filter(|run| run.status() == status).count();
+            writeln!(
+                out,
+                "{PREFIX}_runs{{workflow=\"{workflow}\",state=\"{}\"}} {count}",
+                status.name()
+            )?;
+        }
+    }
+
+    header(
+        out,
+        "run_duration_seconds",
+        "histogram",
+        "How long finished runs took.",
+    )?;
+
+    for (workflow, runs) in &workflows {
+        let workflow = escape(workflow);
+        let durations: Vec<f64> = runs
+            .iter()
+            .filter_map(|run| run.duration())
+            .map(|duration| duration.as_secs_f64())
+            .collect();
+
+        for bucket in DURATION_BUCKETS {
+            let count = durations
+                .iter()
+                .filter(|duration| **duration <= bucket)
+                .count();
+            writeln!(
+                out,
+                "{PREFIX}_run_duration_seconds_bucket{{workflow=\"{workflow}\",le=\"{bucket}\"}} \
+                 {count}"
+            )?;
+        }
+
+        let count = durations.len();
+        let sum: f64 = durations.iter().sum();
+        writeln!(
+            out,
+            "{PREFIX}_run_duration_seconds_bucket{{workflow=\"{workflow}\",le=\"+Inf\"}} {count}"
+        )?;
+        writeln!(
+            out,
+            "{PREFIX}_run_duration_seconds_sum{{workflow=\"{workflow}\"}} {sum}"
+        )?;
+        writeln!(
+            out,
+            "{PREFIX}_run_duration_seconds_count{{workflow=\"{workflow}\"}} {count}"
+        )?;
+    }
+
+    header(
+        out,
+        "queue_depth",
+        "gauge",
+        "The number of runs waiting for a worker.",
+    )?;
+    writeln!(out, "{PREFIX}_queue_depth {queue_depth}")?;
+
+    let subscriptions: usize = runs
+        .iter()
+        .map(|run| run.thread_run_state().subscription_count())
+        .sum();
+    header(
+        out,
+        "subscriptions",
+        "gauge",
+        "The number of clients subscribed to run updates.",
+    )?;
+    writeln!(out, "{PREFIX}_subscriptions {subscriptions}")?;
+
+    let lag_events: u64 = runs
+        .iter()
+        .map(|run| run.thread_run_state().lag_events())
+        .sum();
+    header(
+        out,
+        "subscription_lag_events_total",
+        "counter",
+        "The number of times a subscribed client fell behind, and had to be resynced.",
+    )?;
+    writeln!(out, "{PREFIX}_subscription_lag_events_total {lag_events}")
+}
+
+fn header(out: &mut impl Write, name: &str, metric_type: &str, help: &str) -> fmt::Result {
+    writeln!(out, "# HELP {PREFIX}_{name} {help}")?;
+    writeln!(out, "# TYPE {PREFIX}_{name} {metric_type}")
+}
+
+/// Escape a label value.
+fn escape(value: &str) -> String {
+    value
+        .replace('\\', "\\\\")
+        .replace('"', "\\\"")
+        .replace('\n', "\\n")
+}
+
+const PREFIX: &str = "serpent_automation";
+
+/// Upper bounds of the run duration histogram buckets, in seconds.
+const DURATION_BUCKETS: [f64; 8] = [1.0, 5.0, 10.0, 30.0, 60.0, 300.0, 900.0, 3600.0];
+
+#[cfg(test)]
+mod tests {
+    use std::{sync::Arc, time::Duration};
+
+    use indoc::indoc;
+    use serpent_automation_executor::syntax_tree::Value;
+    use tokio::time::sleep;
+
+    use super::{escape, render};
+    use crate::runs::{RunStatus, Runs, Workflow};
+
+    #[tokio::test]
+    async fn metrics() {
+        let workflow = Arc::new(
+            Workflow::parse(
+                "test",
+                indoc! {"
+                    def main():
+                        pass
+                "},
+            )
+            .unwrap(),
+        );
+        let runs = Runs::new(1);
+        let finished = runs.start(&workflow, Value::None, "test");
+        runs.start(&workflow, Value::None, "test");
+        let cancelled = runs.start(&workflow, Value::None, "test");
+        assert!(runs.cancel(cancelled, "test"));
+
+        while runs.get(finished).unwrap().status() != RunStatus::Successful {
+            sleep(Duration::from_millis(10)).await;
+        }
+
+        let text = render(&runs);
+        let lines: Vec<&str> = text.lines().collect();
+
+        for expected in [
+            r#"serpent_automation_runs{workflow="test",state="successful"} 1"#,
+            r#"serpent_automation_runs{workflow="test",state="cancelled"} 1"#,
+            r#"serpent_automation_runs{workflow="test",state="failed"} 0"#,
+            r#"serpent_automation_run_duration_seconds_count{workflow="test"} 1"#,
+            "# TYPE serpent_automation_queue_depth gauge",
+            "serpent_automation_subscriptions 0",
+            "serpent_automation_subscription_lag_events_total 0",
+        ] {
+            assert!(
+                lines.contains(&expected),
+                "Missing {expected:?} in:\n{text}"
+            );
+        }
+    }
+
+    #[test]
+    fn escape_label() {
+        assert_eq!(escape("a\"b\\c\nd"), r#"a\"b\\c\nd"#);
+    }
+}
@@ -0,0 +1,244 @@
+//! HTTP endpoints for operators to control runs.
+//!
+//! Each endpoint takes a JSON body and responds with JSON:
+//!
+//! - `POST /api/operator/start` takes a [`StartRun`].
+//! - `POST /api/operator/cancel` takes a [`CancelRun`].
+//! - `POST /api/operator/pause` takes a [`PauseRun`].
+//!
+//! The router should be wrapped in [`auth::require`](crate::auth::require)
+//! with [`Role::Operator`](crate::auth::Role::Operator). The authenticated user
+//! is recorded in the audit log.
+use axum::{extract::State, routing::post, Extension, Json, Router};
+use serpent_automation_server_api::{CancelRun, PauseRun, RunId, StartRun};
+
+use crate::{
+    auth::User,
+    runs::{Runs, Workflows},
+};
+
+/// A router with the operator endpoints under `/api/operator`.
+pub fn router(workflows: Workflows, runs: Runs) -> Router {
+    Router::new()
+        .route("/api/operator/start", post(start_run))
+        .route("/api/operator/cancel", post(cancel_run))
+        .route("/api/operator/pause", post(pause_run))
+        .with_state(Operator { workflows, runs })
+}
+
+#[derive(Clone)]
+struct Operator {
+    workflows: Workflows,
+    runs: Runs,
+}
+
+async fn start_run(
+    State(operator): State<Operator>,
+    Extension(user): Extension<User>,
+    Json(StartRun { workflow, input }): Json<StartRun>,
+) -> Json<Option<RunId>> {
+    let run_id = operator
+        .workflows
+        .get(&workflow)
+        .map(|workflow| operator.runs.start(workflow, input, user.name()));
+    Json(run_id)
+}
+
+async fn cancel_run(
+    State(operator): State<Operator>,
+    Extension(user): Extension<User>,
+    Json(CancelRun { run_id }): Json<CancelRun>,
+) -> Json<bool> {
+    Json(operator.runs.cancel(run_id, user.name()))
+}
+
+async fn pause_run(
+    State(operator): State<Operator>,
+    Extension(user): Extension<User>,
+    Json(PauseRun { run_id, paused }): Json<PauseRun>,
+) -> Json<bool> {
+    Json(operator.runs.pause(run_id, paused, user.name()))
+}
+
+#[cfg(test)]
+mod tests {
+    use std::net::{SocketAddr, TcpListener};
+
+    use axum::Server;
+    use hyper::{Body, Client, Request, Response, StatusCode};
+    use indoc::{formatdoc, indoc};
+    use serde::{de::DeserializeOwned, Serialize};
+    use serpent_automation_executor::syntax_tree::Value;
+    use serpent_automation_server_api::{CancelRun, PauseRun, RunId, StartRun};
+    use sha2::{Digest, Sha256};
+
+    use super::router;
+    use crate::{
+        auth::{require, Role, Users, ANONYMOUS},
+        runs::{RunStatus, Runs, Workflow, Workflows},
+    };
+
+    const VIEWER_TOKEN: &str = "viewer-token";
+    const OPERATOR_TOKEN: &str = "operator-token";
+
+    #[tokio::test]
+    async fn pause() {
+        let runs = Runs::new(1);
+        let address = serve(&runs, Users::unauthenticated());
+
+        let run_id = start_run(address, None).await.unwrap();
+        let run = runs.get(run_id).unwrap();
+        assert_eq!(run.started_by(), ANONYMOUS);
+
+        assert!(pause_run(address, run_id, true).await);
+        assert!(run.thread_run_state().pause().is_paused());
+        assert!(pause_run(address, run_id, false).await);
+        assert!(!run.thread_run_state().pause().is_paused());
+        assert!(!pause_run(address, run_id.next(), true).await);
+    }
+
+    #[tokio::test]
+    async fn operator_access() {
+        let runs = Runs::new(1);
+        let address = serve(&runs, users());
+
+        let running = start_run(address, Some(OPERATOR_TOKEN)).await.unwrap();
+        assert_eq!(runs.get(running).unwrap().started_by(), "operator");
+
+        // There's only 1 worker, so this stays queued while the first run prints.
+        let queued = start_run(address, Some(OPERATOR_TOKEN)).await.unwrap();
+        assert!(cancel_run(address, queued, OPERATOR_TOKEN).await);
+        assert_eq!(runs.get(queued).unwrap().status(), RunStatus::Cancelled);
+    }
+
+    #[tokio::test]
+    async fn viewer_access() {
+        let runs = Runs::new(1);
+        let address = serve(&runs, users());
+
+        let response = request(
+            address,
+            "start",
+            Some(VIEWER_TOKEN),
+            &StartRun {
+                workflow: "main".to_owned(),
+                input: Value::None,
+            },
+        )
+        .await;
+        assert_eq!(response.status(), StatusCode::FORBIDDEN);
+        assert!(runs.latest().is_none());
+
+        start_run(address, Some(OPERATOR_TOKEN)).await.unwrap();
+        let queued = start_run(address, Some(OPERATOR_TOKEN)).await.unwrap();
+        let response = request(
+            address,
+            "cancel",
+            Some(VIEWER_TOKEN),
+            &CancelRun { run_id: queued },
+        )
+        .await;
+        assert_eq!(response.status(), StatusCode::FORBIDDEN);
+        assert_eq!(runs.get(queued).unwrap().status(), RunStatus::Queued);
+    }
+
+    async fn start_run(address: SocketAddr, token: Option<&str>) -> Option<RunId> {
+        post(
+            address,
+            "start",
+            token,
+            &StartRun {
+                workflow: "main".to_owned(),
+                input: Value::None,
+            },
+        )
+        .await
+    }
+
+    async fn cancel_run(address: SocketAddr, run_id: RunId, token: &str) -> bool {
+        post(address, "cancel", Some(token), &CancelRun { run_id }).await
+    }
+
+    async fn pause_run(address: SocketAddr, run_id: RunId, paused: bool) -> bool {
+        post(address, "pause", None, &PauseRun { run_id, paused }).await
+    }
+
+    fn users() -> Users {
+        Users::parse(&formatdoc! {r#"
+            [[users]]
+            name = "viewer"
+            role = "viewer"
+            token-sha256 = "{}"
+
+            [[users]]
+            name = "operator"
+            role = "operator"
+            token-sha256 = "{}"
+        "#,
+            hex::encode(Sha256::digest(VIEWER_TOKEN)),
+            hex::encode(Sha256::digest(OPERATOR_TOKEN)),
+        })
+        .unwrap()
+    }
+
+    fn serve(runs: &Runs, users: Users) -> SocketAddr {
+        let workflow = Workflow::parse(
+            "main",
+            indoc! {r#"
+                def main():
+                    print("Hello")
+            "#},
+        )
+        .unwrap();
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let address = listener.local_addr().unwrap();
+        let app = require(
+            router(Workflows::from_iter([workflow]), runs.clone()),
+            users,
+            Role::Operator,
+        );
+
+        tokio::spawn(
+            Server::from_tcp(listener)
+                .unwrap()
+                .serve(app.into_make_service()),
+        );
+
+        address
+    }
+
+    async fn post<T: DeserializeOwned>(
+        address: SocketAddr,
+        action: &str,
+        token: Option<&str>,
+        body: &impl Serialize,
+    ) -> T {
+        let response = request(address, action, token, body).await;
+        assert_eq!(response.status(), StatusCode::OK);
+        let body = hyper::body::to_bytes(response.into_body()).await.unwrap();
+        serde_json::from_slice(&body).unwrap()
+    }
+
+    async fn request(
+        address: SocketAddr,
+        action: &str,
+        token: Option<&str>,
+        body: &impl Serialize,
+    ) -> Response<Body> {
+        let mut request = Request::post(format!("http://{address}/api/operator/{action}"))
+            .header("Content-Type", "application/json");
+
+        if let Some(token) = token {
+            request = request.header("Authorization", format!("Bearer {token}"));
+        }
+
+        Client::new()
+            .request(
+                request
+                    .body(Body::from(serde_json::to_vec(body).unwrap()))
+                    .unwrap(),
+            )
+            .await
+            .unwrap()
+    }
+}
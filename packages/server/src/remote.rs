@@ -0,0 +1,241 @@
+//! Run workflows in separate worker processes.
+//!
+//! The server starts a worker process for each run. The worker fetches it's
+//! job with [`WorkerJob`], and reports progress back with [`WorkerUpdates`].
+//! This means an interpreter crash only takes down the worker, and the run is
+//! marked as failed.
+//!
+//! The worker routes aren't behind user authentication. Instead, each worker
+//! is given a [`WorkerToken`] for it's run in the [`WORKER_TOKEN_VAR`]
+//! environment variable, and has to send it with every request.
+use std::{
+    env, io,
+    path::{Path, PathBuf},
+    process::Command,
+    sync::Arc,
+    thread,
+    time::Duration,
+};
+
+use arpy::FnRemote;
+use arpy_axum::RpcRoute;
+use arpy_reqwest::Connection;
+use axum::Router;
+use chacha20poly1305::aead::{rand_core::RngCore, OsRng};
+use clonelet::clone;
+use reqwest::Client;
+use serpent_automation_executor::{
+    run::ThreadRunState,
+    secrets::{Secrets, SecretsError},
+    sources::SourceError,
+    step_cache::{StepCache, STEP_CACHE_DIR_VAR},
+};
+use serpent_automation_server_api::{Job, RunId, WorkerJob, WorkerUpdates};
+use sha2::{Digest, Sha256};
+use thiserror::Error;
+use tokio::{sync::mpsc, time};
+
+use crate::runs::Runs;
+
+/// How to start a worker process.
+#[derive(Clone)]
+pub struct RemoteWorker {
+    executable: PathBuf,
+    server_url: String,
+}
+
+impl RemoteWorker {
+    /// `server_url` is the base URL that workers should use to connect back to
+    /// the server. [`router`] must be served from there.
+    pub fn new(executable: impl Into<PathBuf>, server_url: impl Into<String>) -> Self {
+        Self {
+            executable: executable.into(),
+            server_url: server_url.into(),
+        }
+    }
+
+    /// Run `run_id` in a new worker process, and wait for it to finish.
+    ///
+    /// If `step_cache` is stored in a directory, the worker uses it too.
+    /// Returns `false` if the worker couldn't be started, or failed.
+    pub(crate) fn run(
+        &self,
+        run_id: RunId,
+        token: &WorkerToken,
+        thread_run_state: &ThreadRunState,
+        step_cache: &StepCache,
+    ) -> bool {
+        let mut command = Command::new(&self.executable);
+        command
+            .arg(&self.server_url)
+            .arg(run_id.to_string())
+            .env(WORKER_TOKEN_VAR, token.0.as_ref());
+
+        if let Some(dir) = step_cache.dir() {
+            command.env(STEP_CACHE_DIR_VAR, dir);
+        }
+
+        let status = command.status();
+
+        match status {
+            Ok(status) if status.success() => return true,
+            Ok(status) => eprintln!("Worker for run {run_id} failed: {status}"),
+            Err(e) => eprintln!("Unable to start worker for run {run_id}: {e}"),
+        }
+
+        thread_run_state.fail_running();
+        false
+    }
+}
+
+/// A random secret that only the worker for a run is given.
+#[derive(Clone)]
+pub(crate) struct WorkerToken(Arc<str>);
+
+impl WorkerToken {
+    pub(crate) fn generate() -> Self {
+        let mut token = [0; 32];
+        OsRng.fill_bytes(&mut token);
+        Self(hex::encode(token).into())
+    }
+
+    /// Compare hashes, so the time taken doesn't depend on how much of `token`
+    /// is correct.
+    pub(crate) fn matches(&self, token: &str) -> bool {
+        Sha256::digest(self.0.as_bytes()) == Sha256::digest(token.as_bytes())
+    }
+}
+
+/// The routes that worker processes use to talk to the server.
+pub fn router(runs: Runs) -> Router {
+    Router::new()
+        .http_rpc_route(WORKER_API_PATH, {
+            clone!(runs);
+
+            move |WorkerJob { run_id, token }| {
+                let job = runs.worker_run(run_id, &token).map(|run| Job {
+                    workflow: run.workflow().name().to_owned(),
+                    dir: run.workflow().dir().map(Path::to_owned),
+                    sources: run.workflow().sources().clone(),
+                    input: run.input().clone(),
+                });
+
+                async move { job }
+            }
+        })
+        .http_rpc_route(WORKER_API_PATH, move |worker_updates: WorkerUpdates| {
+            let paused = runs
+                .worker_run(worker_updates.run_id, &worker_updates.token)
+                .is_some_and(|run| {
+                    let thread_run_state = run.thread_run_state();
+
+                    for update in worker_updates.updates {
+                        thread_run_state.apply(update);
+                    }
+
+                    thread_run_state.pause().is_paused()
+                });
+
+            async move { paused }
+        })
+}
+
+/// The worker process side.
+///
+/// Fetch the job for `run_id` from the server, run it and report back. The
+/// interpreter runs on it's own thread, so we can report any panics as
+/// errors. The token for the run is read from [`WORKER_TOKEN_VAR`], secrets
+/// with [`Secrets::from_env`], and the step cache with [`StepCache::from_env`].
+pub async fn work(server_url: &str, run_id: RunId) -> Result<(), WorkerError> {
+    let token = env::var(WORKER_TOKEN_VAR).map_err(WorkerError::Token)?;
+    let secrets = Arc::new(Secrets::from_env()?);
+    let step_cache = Arc::new(StepCache::from_env().map_err(WorkerError::StepCache)?);
+    let connection = Connection::new(&Client::new(), format!("{server_url}{WORKER_API_PATH}"));
+    let job = WorkerJob {
+        run_id,
+        token: token.clone(),
+    }
+    .call(&connection)
+    .await
+    .map_err(|e| WorkerError::Rpc(e.to_string()))?
+    .ok_or(WorkerError::UnknownRun(run_id))?;
+    println!("Worker running '{}'", job.workflow);
+    let library = job.sources.link()?;
+    let input = job.input;
+    let (update_sender, mut update_receiver) = mpsc::unbounded_channel();
+    let thread_run_state = ThreadRunState::forward_updates(update_sender)
+        .with_secrets(secrets)
+        .with_step_cache(step_cache)
+        .with_input(input)
+        .with_workflow_dir(job.dir);
+    let pause = thread_run_state.pause().clone();
+    let interpreter = thread::spawn(move || library.run(&thread_run_state));
+
+    loop {
+        // If there are no updates, send an empty batch anyway so we find out
+        // when we've been paused or resumed.
+        let mut updates = match time::timeout(PAUSE_POLL_INTERVAL, update_receiver.recv()).await {
+            Ok(Some(update)) => vec![update],
+            Ok(None) => break,
+            Err(_) => Vec::new(),
+        };
+
+        while let Ok(update) = update_receiver.try_recv() {
+            updates.push(update);
+        }
+
+        let paused = WorkerUpdates {
+            run_id,
+            token: token.clone(),
+            updates,
+        }
+        .call(&connection)
+        .await
+        .map_err(|e| WorkerError::Rpc(e.to_string()))?;
+        pause.set(paused);
+    }
+
+    // A failed run is already recorded in the updates we've sent, so we only
+    // need to report a panic.
+    let _run_result = interpreter.join().map_err(|_| WorkerError::Panicked)?;
+    Ok(())
+}
+
+const WORKER_API_PATH: &str = "/worker";
+
+const PAUSE_POLL_INTERVAL: Duration = Duration::from_secs(1);
+
+/// The environment variable a worker reads it's [`WorkerToken`] from.
+pub const WORKER_TOKEN_VAR: &str = "SERPENT_AUTOMATION_WORKER_TOKEN";
+
+#[derive(Error, Debug)]
+pub enum WorkerError {
+    #[error("Error talking to server: {0}")]
+    Rpc(String),
+    #[error("Unknown run {0}")]
+    UnknownRun(RunId),
+    #[error("Unable to read {WORKER_TOKEN_VAR}: {0}")]
+    Token(#[source] env::VarError),
+    #[error(transparent)]
+    Source(#[from] SourceError),
+    #[error(transparent)]
+    Secrets(#[from] SecretsError),
+    #[error("Unable to open the step cache: {0}")]
+    StepCache(#[source] io::Error),
+    #[error("The interpreter panicked")]
+    Panicked,
+}
+
+#[cfg(test)]
+mod tests {
+    use super::WorkerToken;
+
+    #[test]
+    fn worker_token() {
+        let token = WorkerToken::generate();
+
+        assert!(token.matches(&token.0));
+        assert!(!token.matches(""));
+        assert!(!WorkerToken::generate().matches(&token.0));
+    }
+}
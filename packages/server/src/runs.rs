@@ -0,0 +1,609 @@
+use std::{
+    collections::{BTreeMap, HashSet},
+    ffi::OsStr,
+    fs, io,
+    panic::{self, AssertUnwindSafe},
+    path::{Path, PathBuf},
+    sync::{
+        atomic::{self, AtomicBool},
+        Arc, OnceLock, RwLock, RwLockReadGuard, RwLockWriteGuard,
+    },
+    time::{Duration, Instant},
+};
+
+use clonelet::clone;
+use serpent_automation_executor::{
+    library::Library,
+    run::{HistoryLimits, RunState, ThreadRunState},
+    secrets::Secrets,
+    sources::{SourceError, Sources},
+    step_cache::StepCache,
+    syntax_tree::{Trigger, Value},
+};
+use serpent_automation_server_api::{PendingRun, RunId};
+use thiserror::Error;
+
+use crate::{
+    audit::{Action, AuditLog},
+    remote::{RemoteWorker, WorkerToken},
+    scheduler::Scheduler,
+};
+
+/// A parsed and linked workflow, ready to run.
+pub struct Workflow {
+    name: String,
+    dir: Option<PathBuf>,
+    sources: Sources,
+    library: Library,
+    triggers: Vec<Trigger>,
+    max_concurrent_runs: Option<usize>,
+}
+
+impl Workflow {
+    /// A workflow with a single source file.
+    pub fn parse(name: &str, source: &str) -> Result<Self, SourceError> {
+        Self::new(name, None, Sources::single(name, source))
+    }
+
+    /// Load the workflow `name` from `dir`, along with any modules it imports.
+    pub fn load(dir: &Path, name: &str) -> Result<Self, SourceError> {
+        Self::new(name, Some(dir.to_owned()), Sources::load(dir, name)?)
+    }
+
+    fn new(name: &str, dir: Option<PathBuf>, sources: Sources) -> Result<Self, SourceError> {
+        let modules = sources.parse()?;
+        let triggers = modules[0].1.triggers().to_vec();
+
+        Ok(Self {
+            name: name.to_owned(),
+            dir,
+            library: Library::link_modules(modules)?,
+            sources,
+            triggers,
+            max_concurrent_runs: None,
+        })
+    }
+
+    /// Limit how many runs of this workflow can run at once.
+    ///
+    /// Any more will wait in the queue.
+    #[must_use]
+    pub fn with_max_concurrent_runs(mut self, max_concurrent_runs: usize) -> Self {
+        self.max_concurrent_runs = Some(max_concurrent_runs);
+        self
+    }
+
+    pub fn name(&self) -> &str {
+        &self.name
+    }
+
+    /// The directory the workflow was loaded from, if it was loaded from a
+    /// file.
+    ///
+    /// Relative paths in the workflow, like `watch` triggers, are relative to
+    /// this.
+    pub fn dir(&self) -> Option<&Path> {
+        self.dir.as_deref()
+    }
+
+    pub fn sources(&self) -> &Sources {
+        &self.sources
+    }
+
+    pub fn library(&self) -> &Library {
+        &self.library
+    }
+
+    pub fn triggers(&self) -> &[Trigger] {
+        &self.triggers
+    }
+}
+
+/// The workflows the server can run, by name.
+#[derive(Clone, Default)]
+pub struct Workflows(Arc<BTreeMap<String, Arc<Workflow>>>);
+
+impl Workflows {
+    /// Load each `.py` file in `dir` as a workflow, named after the file.
+    ///
+    /// Workflows can import other modules from `dir`. Modules that another
+    /// module imports are helpers, not workflows, so they're skipped.
+    pub fn load(dir: &Path) -> Result<Self, LoadWorkflowError> {
+        let io_error = |source| LoadWorkflowError::Io {
+            path: dir.to_owned(),
+            source,
+        };
+        let mut workflows = Vec::new();
+
+        for entry in fs::read_dir(dir).map_err(io_error)? {
+            let path = entry.map_err(io_error)?.path();
+
+            if path.extension() != Some(OsStr::new("py")) {
+                continue;
+            }
+
+            let Some(name) = path.file_stem().and_then(OsStr::to_str) else {
+                continue;
+            };
+
+            let workflow =
+                Workflow::load(dir, name).map_err(|source| LoadWorkflowError::Source {
+                    workflow: name.to_owned(),
+                    source: Box::new(source),
+                })?;
+            workflows.push(workflow);
+        }
+
+        let imported: HashSet<String> = workflows
+            .iter()
+            .flat_map(|workflow| workflow.sources().iter().skip(1))
+            .map(|file| file.module().to_owned())
+            .collect();
+
+        Ok(workflows
+            .into_iter()
+            .filter(|workflow| !imported.contains(workflow.name()))
+            .collect())
+    }
+
+    pub fn get(&self, name: &str) -> Option<&Arc<Workflow>> {
+        self.0.get(name)
+    }
+
+    pub fn iter(&self) -> impl Iterator<Item = &Arc<Workflow>> {
+        self.0.values()
+    }
+}
+
+impl FromIterator<Workflow> for Workflows {
+    fn from_iter<T: IntoIterator<Item = Workflow>>(iter: T) -> Self {
+        Self(Arc::new(
+            iter.into_iter()
+                .map(|workflow| (workflow.name().to_owned(), Arc::new(workflow)))
+                .collect(),
+        ))
+    }
+}
+
+/// All the runs the server knows about.
+///
+/// Runs are queued, and executed on a bounded pool of worker threads.
+#[derive(Clone)]
+pub struct Runs {
+    runs: Arc<RwLock<SharedRuns>>,
+    scheduler: Arc<Scheduler>,
+    executor: Executor,
+    history_limits: Option<(usize, PathBuf)>,
+    audit_log: AuditLog,
+    secrets: Arc<Secrets>,
+    step_cache: Arc<StepCache>,
+}
+
+#[derive(Clone)]
+enum Executor {
+    InProcess,
+    Remote(RemoteWorker),
+}
+
+impl Default for Runs {
+    fn default() -> Self {
+        Self::new(DEFAULT_WORKER_COUNT)
+    }
+}
+
+#[derive(Default)]
+struct SharedRuns {
+    next_id: RunId,
+    runs: BTreeMap<RunId, Run>,
+}
+
+impl Runs {
+    /// Run at most `worker_count` workflows at once, on threads in this
+    /// process.
+    pub fn new(worker_count: usize) -> Self {
+        Self::with_executor(worker_count, Executor::InProcess)
+    }
+
+    /// Run at most `worker_count` workflows at once, each in it's own worker
+    /// process.
+    pub fn remote(worker_count: usize, worker: RemoteWorker) -> Self {
+        Self::with_executor(worker_count, Executor::Remote(worker))
+    }
+
+    fn with_executor(worker_count: usize, executor: Executor) -> Self {
+        Self {
+            runs: Arc::default(),
+            scheduler: Arc::new(Scheduler::new(worker_count)),
+            executor,
+            history_limits: None,
+            audit_log: AuditLog::default(),
+            secrets: Arc::default(),
+            step_cache: Arc::default(),
+        }
+    }
+
+    /// Record who starts, cancels, pauses and resumes runs in `audit_log`.
+    #[must_use]
+    pub fn with_audit_log(mut self, audit_log: AuditLog) -> Self {
+        self.audit_log = audit_log;
+        self
+    }
+
+    /// Make `secrets` available to runs in this process.
+    ///
+    /// Worker processes read their own secrets, but these are still used to
+    /// mask the logs they send back.
+    #[must_use]
+    pub fn with_secrets(mut self, secrets: Secrets) -> Self {
+        self.secrets = Arc::new(secrets);
+        self
+    }
+
+    /// Share results of functions with `@cache` between runs through
+    /// `step_cache`.
+    ///
+    /// Worker processes open the same cache directory, so the cache should be
+    /// opened on a directory if runs are executed remotely. By default,
+    /// results are only shared between runs in this process.
+    #[must_use]
+    pub fn with_step_cache(mut self, step_cache: StepCache) -> Self {
+        self.step_cache = Arc::new(step_cache);
+        self
+    }
+
+    /// Keep at most `max_in_memory` finished nodes in memory for each run.
+    ///
+    /// Older history is paged out to files in `spill_dir`.
+    #[must_use]
+    pub fn with_history_limits(mut self, max_in_memory: usize, spill_dir: PathBuf) -> Self {
+        self.history_limits = Some((max_in_memory, spill_dir));
+        self
+    }
+
+    /// Queue a new run of `workflow`.
+    ///
+    /// `started_by` is the user or trigger that started the run.
+    pub fn start(&self, workflow: &Arc<Workflow>, input: Value, started_by: &str) -> RunId {
+        let run = {
+            let mut data = self.write();
+            let run_id = data.next_id;
+            data.next_id = run_id.next();
+            let thread_run_state = match &self.history_limits {
+                Some((max_in_memory, spill_dir)) => ThreadRunState::with_history_limits(
+                    HistoryLimits::new(*max_in_memory, spill_dir.join(format!("{run_id}.history"))),
+                ),
+                None => ThreadRunState::default(),
+            }
+            .with_secrets(self.secrets.clone())
+            .with_step_cache(self.step_cache.clone())
+            .with_input(input.clone())
+            .with_workflow_dir(workflow.dir().map(Path::to_owned));
+            let run = Run {
+                id: run_id,
+                worker_token: WorkerToken::generate(),
+                workflow: workflow.clone(),
+                input: input.clone(),
+                started_by: started_by.to_owned(),
+                thread_run_state,
+                lifecycle: Arc::default(),
+            };
+            data.runs.insert(run_id, run.clone());
+            run
+        };
+        let run_id = run.id;
+
+        println!("Queueing run {run_id:?} of '{}'", workflow.name());
+        self.audit_log
+            .record(started_by, Action::Start, run_id, workflow.name());
+
+        self.scheduler
+            .enqueue(run_id, workflow.name(), workflow.max_concurrent_runs, {
+                clone!(workflow);
+                clone!(self.executor);
+                clone!(self.step_cache);
+
+                move || {
+                    let thread_run_state = &run.thread_run_state;
+                    let _ = run.lifecycle.started.set(Instant::now());
+
+                    let succeeded = match executor {
+                        Executor::InProcess => {
+                            // Don't let a panic in the interpreter take down the worker thread. A
+                            // failed run is recorded in `thread_run_state`, so it doesn't count as
+                            // an executor failure.
+                            let result = panic::catch_unwind(AssertUnwindSafe(|| {
+                                let _ = workflow.library().run(thread_run_state);
+                            }));
+
+                            if result.is_err() {
+                                thread_run_state.fail_running();
+                            }
+
+                            result.is_ok()
+                        }
+                        Executor::Remote(worker) => {
+                            worker.run(run_id, &run.worker_token, thread_run_state, &step_cache)
+                        }
+                    };
+
+                    // Set this before `finished`, so the status is never successful when the
+                    // executor failed.
+                    if !succeeded {
+                        run.lifecycle
+                            .executor_failed
+                            .store(true, atomic::Ordering::Relaxed);
+                    }
+
+                    let _ = run.lifecycle.finished.set(Instant::now());
+                }
+            });
+
+        run_id
+    }
+
+    /// Remove a run from the queue.
+    ///
+    /// Returns `false` if the run wasn't queued.
+    pub fn cancel(&self, run_id: RunId, cancelled_by: &str) -> bool {
+        let cancelled = self.scheduler.cancel(run_id);
+
+        if cancelled {
+            if let Some(run) = self.get(run_id) {
+                run.lifecycle
+                    .cancelled
+                    .store(true, atomic::Ordering::Relaxed);
+                self.audit_log
+                    .record(cancelled_by, Action::Cancel, run_id, run.workflow().name());
+            }
+        }
+
+        cancelled
+    }
+
+    /// Pause or resume a run.
+    ///
+    /// A queued run that's paused stops before it's first statement, once it
+    /// has a worker. Returns `false` if there's no such run, or it's finished.
+    /// See [`ThreadRunState::pause`].
+    pub fn pause(&self, run_id: RunId, paused: bool, paused_by: &str) -> bool {
+        let Some(run) = self.get(run_id) else {
+            return false;
+        };
+
+        if matches!(
+            run.status(),
+            RunStatus::Cancelled | RunStatus::Successful | RunStatus::Failed
+        ) {
+            return false;
+        }
+
+        run.thread_run_state.pause().set(paused);
+        let action = if paused {
+            Action::Pause
+        } else {
+            Action::Resume
+        };
+        self.audit_log
+            .record(paused_by, action, run_id, run.workflow().name());
+
+        true
+    }
+
+    /// The runs that are waiting for a worker, in queue order.
+    pub fn pending(&self) -> Vec<PendingRun> {
+        self.scheduler.pending()
+    }
+
+    pub fn get(&self, run_id: RunId) -> Option<Run> {
+        self.read().runs.get(&run_id).cloned()
+    }
+
+    /// Get `run_id` for it's worker, if `token` is the run's [`WorkerToken`].
+    pub(crate) fn worker_run(&self, run_id: RunId, token: &str) -> Option<Run> {
+        self.get(run_id)
+            .filter(|run| run.worker_token.matches(token))
+    }
+
+    /// All the runs, in the order they were started.
+    pub fn all(&self) -> Vec<Run> {
+        self.read().runs.values().cloned().collect()
+    }
+
+    /// The most recently started run
+    pub fn latest(&self) -> Option<Run> {
+        self.read()
+            .runs
+            .last_key_value()
+            .map(|(_id, run)| run.clone())
+    }
+
+    fn read(&self) -> RwLockReadGuard<'_, SharedRuns> {
+        self.runs.read().unwrap()
+    }
+
+    fn write(&self) -> RwLockWriteGuard<'_, SharedRuns> {
+        self.runs.write().unwrap()
+    }
+}
+
+#[derive(Clone)]
+pub struct Run {
+    id: RunId,
+    worker_token: WorkerToken,
+    workflow: Arc<Workflow>,
+    input: Value,
+    started_by: String,
+    thread_run_state: ThreadRunState,
+    lifecycle: Arc<Lifecycle>,
+}
+
+/// When a run started and finished, if it has.
+#[derive(Default)]
+struct Lifecycle {
+    started: OnceLock<Instant>,
+    finished: OnceLock<Instant>,
+    cancelled: AtomicBool,
+    /// The interpreter panicked, or the worker process failed. The run may
+    /// not have recorded any state if this happened early on.
+    executor_failed: AtomicBool,
+}
+
+impl Run {
+    pub fn id(&self) -> RunId {
+        self.id
+    }
+
+    pub fn workflow(&self) -> &Arc<Workflow> {
+        &self.workflow
+    }
+
+    pub fn input(&self) -> &Value {
+        &self.input
+    }
+
+    /// The user or trigger that started the run.
+    pub fn started_by(&self) -> &str {
+        &self.started_by
+    }
+
+    pub fn thread_run_state(&self) -> &ThreadRunState {
+        &self.thread_run_state
+    }
+
+    pub fn status(&self) -> RunStatus {
+        let lifecycle = &self.lifecycle;
+
+        if lifecycle.cancelled.load(atomic::Ordering::Relaxed) {
+            RunStatus::Cancelled
+        } else if lifecycle.started.get().is_none() {
+            RunStatus::Queued
+        } else if lifecycle.finished.get().is_none() {
+            RunStatus::Running
+        } else if lifecycle.executor_failed.load(atomic::Ordering::Relaxed)
+            || self.thread_run_state.status() == RunState::Failed
+        {
+            RunStatus::Failed
+        } else {
+            RunStatus::Successful
+        }
+    }
+
+    /// How long the run took, if it's finished.
+    pub fn duration(&self) -> Option<Duration> {
+        let started = self.lifecycle.started.get()?;
+        let finished = self.lifecycle.finished.get()?;
+        Some(finished.duration_since(*started))
+    }
+}
+
+#[derive(Copy, Clone, Eq, PartialEq, Debug)]
+pub enum RunStatus {
+    /// Waiting for a worker
+    Queued,
+    /// Removed from the queue before it started
+    Cancelled,
+    Running,
+    Successful,
+    Failed,
+}
+
+impl RunStatus {
+    pub const ALL: [Self; 5] = [
+        Self::Queued,
+        Self::Cancelled,
+        Self::Running,
+        Self::Successful,
+        Self::Failed,
+    ];
+
+    pub fn name(self) -> &'static str {
+        match self {
+            Self::Queued => "queued",
+            Self::Cancelled => "cancelled",
+            Self::Running => "running",
+            Self::Successful => "successful",
+            Self::Failed => "failed",
+        }
+    }
+}
+
+#[derive(Error, Debug)]
+pub enum LoadWorkflowError {
+    #[error("Unable to read '{}': {source}", path.display())]
+    Io { path: PathBuf, source: io::Error },
+    #[error("Unable to load workflow '{workflow}': {source}")]
+    Source {
+        workflow: String,
+        source: Box<SourceError>,
+    },
+}
+
+pub const DEFAULT_WORKER_COUNT: usize = 4;
+
+#[cfg(test)]
+mod tests {
+    use std::{env, fs, process, sync::Arc, time::Duration};
+
+    use indoc::indoc;
+    use serpent_automation_executor::syntax_tree::Value;
+    use tokio::time::{sleep, Instant};
+
+    use super::{RunStatus, Runs, Workflow, Workflows};
+    use crate::remote::RemoteWorker;
+
+    #[test]
+    fn skip_helpers() {
+        let dir = env::temp_dir().join(format!(
+            "serpent-automation-{}-runs-skip-helpers",
+            process::id()
+        ));
+        fs::create_dir_all(&dir).unwrap();
+        fs::write(
+            dir.join("main.py"),
+            indoc! {"
+                import helpers
+
+                def main():
+                    helpers.setup()
+            "},
+        )
+        .unwrap();
+        fs::write(dir.join("helpers.py"), "def setup():\n    pass\n").unwrap();
+
+        let workflows = Workflows::load(&dir);
+        fs::remove_dir_all(&dir).unwrap();
+        let names: Vec<&str> = workflows
+            .as_ref()
+            .unwrap()
+            .iter()
+            .map(|workflow| workflow.name())
+            .collect();
+        assert_eq!(names, ["main"]);
+    }
+
+    #[tokio::test]
+    async fn worker_failed() {
+        let runs = Runs::remote(
+            1,
+            RemoteWorker::new("serpent-automation-missing-worker", "http://127.0.0.1:0"),
+        );
+        let workflow = Workflow::parse(
+            "main",
+            indoc! {r#"
+                def main():
+                    print("Hello")
+            "#},
+        )
+        .unwrap();
+        let run_id = runs.start(&Arc::new(workflow), Value::None, "test");
+        let run = runs.get(run_id).unwrap();
+        let deadline = Instant::now() + Duration::from_secs(10);
+
+        while matches!(run.status(), RunStatus::Queued | RunStatus::Running) {
+            assert!(Instant::now() < deadline, "Timed out");
+            sleep(Duration::from_millis(50)).await;
+        }
+
+        assert_eq!(run.status(), RunStatus::Failed);
+    }
+}
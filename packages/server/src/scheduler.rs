@@ -0,0 +1,276 @@
+//! A bounded pool of worker threads with a FIFO queue of jobs.
+use std::{
+    collections::{HashMap, VecDeque},
+    sync::{Arc, Condvar, Mutex, MutexGuard},
+    thread,
+};
+
+use serpent_automation_server_api::{PendingRun, RunId};
+
+/// Runs jobs on a fixed number of worker threads.
+///
+/// Jobs are started in the order they were queued, except where a job would
+/// exceed the concurrency limit for it's workflow. In that case, it's left in
+/// the queue and later jobs are considered.
+///
+/// The worker threads are stopped when the [`Scheduler`] is dropped. Jobs that
+/// are already running will run to completion.
+pub struct Scheduler(Arc<SharedScheduler>);
+
+struct SharedScheduler {
+    queue: Mutex<Queue>,
+    queue_changed: Condvar,
+}
+
+#[derive(Default)]
+struct Queue {
+    pending: VecDeque<Job>,
+    running: HashMap<String, usize>,
+    shutdown: bool,
+}
+
+struct Job {
+    run_id: RunId,
+    workflow: String,
+    concurrency_limit: Option<usize>,
+    run: Box<dyn FnOnce() + Send>,
+}
+
+impl Scheduler {
+    /// Start `worker_count` worker threads.
+    ///
+    /// # Panics
+    ///
+    /// If `worker_count` is 0.
+    pub fn new(worker_count: usize) -> Self {
+        assert!(worker_count > 0, "There must be at least 1 worker");
+
+        let shared = Arc::new(SharedScheduler {
+            queue: Mutex::new(Queue::default()),
+            queue_changed: Condvar::new(),
+        });
+
+        for _ in 0..worker_count {
+            let shared = shared.clone();
+            thread::spawn(move || shared.work());
+        }
+
+        Self(shared)
+    }
+
+    /// Add a job to the back of the queue.
+    ///
+    /// No more than `concurrency_limit` jobs with the same `workflow` will run
+    /// at once.
+    pub fn enqueue(
+        &self,
+        run_id: RunId,
+        workflow: &str,
+        concurrency_limit: Option<usize>,
+        run: impl FnOnce() + Send + 'static,
+    ) {
+        self.0.lock().pending.push_back(Job {
+            run_id,
+            workflow: workflow.to_owned(),
+            concurrency_limit,
+            run: Box::new(run),
+        });
+        self.0.queue_changed.notify_one();
+    }
+
+    /// Remove a job from the queue.
+    ///
+    /// Returns `false` if the job wasn't queued, for example if it's already
+    /// started.
+    pub fn cancel(&self, run_id: RunId) -> bool {
+        let mut queue = self.0.lock();
+        let len_before = queue.pending.len();
+        queue.pending.retain(|job| job.run_id != run_id);
+        queue.pending.len() != len_before
+    }
+
+    /// The jobs that are waiting to run, in queue order.
+    pub fn pending(&self) -> Vec<PendingRun> {
+        self.0
+            .lock()
+            .pending
+            .iter()
+            .map(|job| PendingRun {
+                run_id: job.run_id,
+                workflow: job.workflow.clone(),
+            })
+            .collect()
+    }
+}
+
+impl Drop for Scheduler {
+    fn drop(&mut self) {
+        self.0.lock().shutdown = true;
+        self.0.queue_changed.notify_all();
+    }
+}
+
+impl SharedScheduler {
+    fn work(&self) {
+        while let Some(job) = self.next_job() {
+            (job.run)();
+
+            let mut queue = self.lock();
+
+            if let Some(running) = queue.running.get_mut(&job.workflow) {
+                *running -= 1;
+            }
+
+            drop(queue);
+            // Another job for this workflow may be able to run now.
+            self.queue_changed.notify_all();
+        }
+    }
+
+    fn next_job(&self) -> Option<Job> {
+        let mut queue = self.lock();
+
+        loop {
+            if queue.shutdown {
+                return None;
+            }
+
+            if let Some(index) = queue.next_runnable() {
+                let job = queue.pending.remove(index)?;
+                *queue.running.entry(job.workflow.clone()).or_default() += 1;
+                return Some(job);
+            }
+
+            queue = self.queue_changed.wait(queue).unwrap();
+        }
+    }
+
+    fn lock(&self) -> MutexGuard<'_, Queue> {
+        self.queue.lock().unwrap()
+    }
+}
+
+impl Queue {
+    fn next_runnable(&self) -> Option<usize> {
+        self.pending.iter().position(|job| {
+            let running = self.running.get(&job.workflow).copied().unwrap_or(0);
+
+            match job.concurrency_limit {
+                Some(concurrency_limit) => running < concurrency_limit,
+                None => true,
+            }
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{
+        sync::mpsc::{channel, Receiver, Sender},
+        time::Duration,
+    };
+
+    use serpent_automation_server_api::RunId;
+
+    use super::Scheduler;
+
+    #[test]
+    fn fifo() {
+        let scheduler = Scheduler::new(1);
+        let release = block_worker(&scheduler, run_id(0), "blocker", None);
+        let (finished_sender, finished) = channel();
+
+        for id in 1..=3 {
+            let finished_sender = finished_sender.clone();
+            scheduler.enqueue(run_id(id), "test", None, move || {
+                finished_sender.send(id).unwrap()
+            });
+        }
+
+        assert_eq!(pending_ids(&scheduler), [run_id(1), run_id(2), run_id(3)]);
+        release.send(()).unwrap();
+        assert_eq!(receive(&finished, 3), [1, 2, 3]);
+    }
+
+    #[test]
+    fn concurrency_limit() {
+        let scheduler = Scheduler::new(2);
+        let release = block_worker(&scheduler, run_id(0), "limited", Some(1));
+        let (finished_sender, finished) = channel();
+
+        for (id, workflow) in [(1, "limited"), (2, "other")] {
+            let finished_sender = finished_sender.clone();
+            scheduler.enqueue(run_id(id), workflow, Some(1), move || {
+                finished_sender.send(id).unwrap()
+            });
+        }
+
+        // "limited" is already running, so 2 should jump the queue.
+        assert_eq!(receive(&finished, 1), [2]);
+        assert_eq!(pending_ids(&scheduler), [run_id(1)]);
+        release.send(()).unwrap();
+        assert_eq!(receive(&finished, 1), [1]);
+    }
+
+    #[test]
+    fn cancel() {
+        let scheduler = Scheduler::new(1);
+        let release = block_worker(&scheduler, run_id(0), "blocker", None);
+        let (finished_sender, finished) = channel();
+
+        for id in 1..=2 {
+            let finished_sender = finished_sender.clone();
+            scheduler.enqueue(run_id(id), "test", None, move || {
+                finished_sender.send(id).unwrap()
+            });
+        }
+
+        assert!(scheduler.cancel(run_id(1)));
+        assert!(!scheduler.cancel(run_id(1)));
+        assert!(!scheduler.cancel(run_id(0)));
+        assert_eq!(pending_ids(&scheduler), [run_id(2)]);
+        release.send(()).unwrap();
+        assert_eq!(receive(&finished, 1), [2]);
+        assert!(finished.recv_timeout(SHORT_TIMEOUT).is_err());
+    }
+
+    fn run_id(id: u64) -> RunId {
+        (0..id).fold(RunId::default(), |run_id, _| run_id.next())
+    }
+
+    /// Queue a job that runs until we send to the returned channel.
+    fn block_worker(
+        scheduler: &Scheduler,
+        run_id: RunId,
+        workflow: &str,
+        concurrency_limit: Option<usize>,
+    ) -> Sender<()> {
+        let (started_sender, started) = channel();
+        let (release, released) = channel();
+
+        scheduler.enqueue(run_id, workflow, concurrency_limit, move || {
+            started_sender.send(()).unwrap();
+            released.recv().unwrap();
+        });
+
+        started.recv_timeout(TIMEOUT).unwrap();
+        release
+    }
+
+    fn pending_ids(scheduler: &Scheduler) -> Vec<RunId> {
+        scheduler
+            .pending()
+            .into_iter()
+            .map(|run| run.run_id)
+            .collect()
+    }
+
+    fn receive(finished: &Receiver<u64>, count: usize) -> Vec<u64> {
+        (0..count)
+            .map(|_| finished.recv_timeout(TIMEOUT).unwrap())
+            .collect()
+    }
+
+    const TIMEOUT: Duration = Duration::from_secs(10);
+    const SHORT_TIMEOUT: Duration = Duration::from_millis(100);
+}
@@ -0,0 +1,301 @@
+use std::{str::FromStr, sync::Arc, time::Duration};
+
+use chrono::Utc;
+use clonelet::clone;
+use cron::Schedule;
+use notify::{Event, RecommendedWatcher, RecursiveMode, Watcher};
+use serpent_automation_executor::syntax_tree::{Trigger, Value};
+use thiserror::Error;
+use tokio::{
+    spawn,
+    sync::mpsc,
+    task::JoinHandle,
+    time::{sleep, Instant},
+};
+
+use crate::runs::{Runs, Workflow};
+
+/// The running triggers for a workflow.
+///
+/// The triggers stop when this is dropped.
+pub struct Triggers {
+    tasks: Vec<JoinHandle<()>>,
+    _watchers: Vec<RecommendedWatcher>,
+}
+
+impl Triggers {
+    /// Start all the triggers declared by `workflow`.
+    ///
+    /// Each time a trigger fires, a new run of `workflow` is started, with a
+    /// description of what fired as it's input. `watch` paths are relative to
+    /// the workflow's directory.
+    pub fn spawn(workflow: &Arc<Workflow>, runs: &Runs) -> Result<Self, TriggerError> {
+        let mut tasks = Vec::new();
+        let mut watchers = Vec::new();
+
+        for trigger in workflow.triggers() {
+            match trigger {
+                Trigger::Cron { schedule, .. } => tasks.push(spawn(run_on_schedule(
+                    schedule.clone(),
+                    cron_schedule(schedule)?,
+                    workflow.clone(),
+                    runs.clone(),
+                ))),
+                Trigger::Watch { path, .. } => {
+                    let (watcher, task) = watch(path, workflow, runs)?;
+                    watchers.push(watcher);
+                    tasks.push(task);
+                }
+            }
+        }
+
+        Ok(Self {
+            tasks,
+            _watchers: watchers,
+        })
+    }
+}
+
+impl Drop for Triggers {
+    fn drop(&mut self) {
+        for task in &self.tasks {
+            task.abort();
+        }
+    }
+}
+
+fn cron_schedule(schedule: &str) -> Result<Schedule, TriggerError> {
+    // The `cron` crate requires a seconds field, but we want to accept standard 5
+    // field schedules as well.
+    let schedule_with_seconds = if schedule.split_whitespace().count() == 5 {
+        format!("0 {schedule}")
+    } else {
+        schedule.to_owned()
+    };
+
+    Schedule::from_str(&schedule_with_seconds).map_err(|source| TriggerError::Schedule {
+        schedule: schedule.to_owned(),
+        source,
+    })
+}
+
+async fn run_on_schedule(
+    schedule_text: String,
+    schedule: Schedule,
+    workflow: Arc<Workflow>,
+    runs: Runs,
+) {
+    while let Some(next) = schedule.upcoming(Utc).next() {
+        sleep((next - Utc::now()).to_std().unwrap_or_default()).await;
+        runs.start(
+            &workflow,
+            Value::String(format!("cron(\"{schedule_text}\") at {next}")),
+            "cron",
+        );
+    }
+}
+
+fn watch(
+    path: &str,
+    workflow: &Arc<Workflow>,
+    runs: &Runs,
+) -> Result<(RecommendedWatcher, JoinHandle<()>), TriggerError> {
+    let watch_error = |source| TriggerError::Watch {
+        path: path.to_owned(),
+        source,
+    };
+    let resolved_path = match workflow.dir() {
+        Some(dir) => dir.join(path),
+        None => path.into(),
+    };
+    let (event_sender, mut event_receiver) = mpsc::unbounded_channel();
+    let mut watcher = notify::recommended_watcher(move |event: notify::Result<Event>| {
+        // The receiver is only dropped when the triggers are stopped, so we can ignore
+        // errors.
+        let _ = event_sender.send(event);
+    })
+    .map_err(watch_error)?;
+    watcher
+        .watch(&resolved_path, RecursiveMode::Recursive)
+        .map_err(watch_error)?;
+
+    let task = spawn({
+        clone!(workflow);
+        clone!(runs);
+        let path = path.to_owned();
+
+        async move {
+            while let Some(event) = event_receiver.recv().await {
+                let mut changed_paths = match event {
+                    Ok(event) if !event.kind.is_access() => event.paths,
+                    Ok(_) => continue,
+                    Err(e) => {
+                        eprintln!("Error watching '{path}': {e}");
+                        continue;
+                    }
+                };
+
+                // Editors and build tools tend to touch several files at once, so wait
+                // for things to settle and start a single run for all of them.
+                let settle_until = Instant::now() + WATCH_SETTLE_TIME;
+
+                while let Ok(Some(event)) =
+                    tokio::time::timeout_at(settle_until, event_receiver.recv()).await
+                {
+                    if let Ok(event) = event {
+                        changed_paths.extend(event.paths);
+                    }
+                }
+
+                changed_paths.sort();
+                changed_paths.dedup();
+                let changed_paths: Vec<_> = changed_paths
+                    .iter()
+                    .map(|path| path.display().to_string())
+                    .collect();
+
+                runs.start(
+                    &workflow,
+                    Value::String(format!("watch(\"{path}\"): {}", changed_paths.join(", "))),
+                    "watch",
+                );
+            }
+        }
+    });
+
+    Ok((watcher, task))
+}
+
+const WATCH_SETTLE_TIME: Duration = Duration::from_millis(500);
+
+#[derive(Error, Debug)]
+pub enum TriggerError {
+    #[error("Invalid cron schedule \"{schedule}\": {source}")]
+    Schedule {
+        schedule: String,
+        source: cron::error::Error,
+    },
+    #[error("Unable to watch \"{path}\": {source}")]
+    Watch { path: String, source: notify::Error },
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{env, fs, path::PathBuf, process, sync::Arc, time::Duration};
+
+    use indoc::indoc;
+    use serpent_automation_executor::{
+        run::{CallStack, StackFrame},
+        syntax_tree::Value,
+    };
+    use tokio::time::{sleep, Instant};
+
+    use super::Triggers;
+    use crate::runs::{Run, Runs, Workflow};
+
+    #[tokio::test]
+    async fn cron() {
+        let workflow = Arc::new(
+            Workflow::parse(
+                "test",
+                indoc! {r#"
+                    triggers = [cron("* * * * * *")]
+
+                    def main():
+                        print(run_input())
+                "#},
+            )
+            .unwrap(),
+        );
+        let runs = Runs::default();
+        let _triggers = Triggers::spawn(&workflow, &runs).unwrap();
+
+        let run = first_run(&runs, "cron").await;
+        let Value::String(input) = run.input() else {
+            panic!("Expected a string input");
+        };
+        assert!(input.starts_with(r#"cron("* * * * * *") at "#));
+
+        // The input is passed to the interpreter
+        let print = CallStack::new()
+            .push_cloned(StackFrame::Call(workflow.library().main_id().unwrap()))
+            .push_cloned(StackFrame::Statement(0))
+            .push_cloned(StackFrame::Call(
+                workflow.library().function_id("print").unwrap(),
+            ));
+
+        wait_for(|| !run.thread_run_state().logs(&print).is_empty()).await;
+        assert_eq!(
+            run.thread_run_state().logs(&print),
+            [format!("print([String({input:?})])")]
+        );
+    }
+
+    #[tokio::test]
+    async fn watch() {
+        let dir = temp_dir("watch");
+        let watched = dir.join("watched");
+        fs::create_dir_all(&watched).unwrap();
+        fs::write(
+            dir.join("test.py"),
+            indoc! {r#"
+                triggers = [watch("watched")]
+
+                def main():
+                    pass
+            "#},
+        )
+        .unwrap();
+        let workflow = Arc::new(Workflow::load(&dir, "test").unwrap());
+        let runs = Runs::default();
+        let _triggers = Triggers::spawn(&workflow, &runs).unwrap();
+
+        // Give the watcher time to start
+        sleep(Duration::from_millis(100)).await;
+        fs::write(watched.join("changed.txt"), "changed").unwrap();
+
+        let run = first_run(&runs, "watch").await;
+        let Value::String(input) = run.input() else {
+            panic!("Expected a string input");
+        };
+        assert!(input.starts_with(r#"watch("watched"): "#));
+        assert!(input.contains("changed.txt"));
+
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn invalid_schedule() {
+        let workflow =
+            Arc::new(Workflow::parse("test", r#"triggers = [cron("not a schedule")]"#).unwrap());
+
+        assert!(Triggers::spawn(&workflow, &Runs::default()).is_err());
+    }
+
+    /// Wait for the first run started by `started_by`.
+    async fn first_run(runs: &Runs, started_by: &str) -> Run {
+        wait_for(|| runs.all().iter().any(|run| run.started_by() == started_by)).await;
+        runs.all()
+            .into_iter()
+            .find(|run| run.started_by() == started_by)
+            .unwrap()
+    }
+
+    async fn wait_for(mut condition: impl FnMut() -> bool) {
+        let deadline = Instant::now() + TIMEOUT;
+
+        while !condition() {
+            assert!(Instant::now() < deadline, "Timed out");
+            sleep(Duration::from_millis(50)).await;
+        }
+    }
+
+    fn temp_dir(name: &str) -> PathBuf {
+        env::temp_dir().join(format!(
+            "serpent-automation-{}-triggers-{name}",
+            process::id()
+        ))
+    }
+
+    const TIMEOUT: Duration = Duration::from_secs(10);
+}
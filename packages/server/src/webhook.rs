@@ -0,0 +1,248 @@
+//! An HTTP endpoint to start workflows from things like git hooks.
+//!
+//! `POST /webhook/<workflow>` with a JSON body starts a new run of
+//! `<workflow>`, with the body as it's input. The response is a JSON
+//! [`WebhookResponse`].
+//!
+//! If a secret is configured, requests must have an `X-Hub-Signature-256`
+//! header containing `sha256=<hex HMAC of the body>`, which is the same scheme
+//! GitHub uses. Without a secret, the server wraps the router in
+//! [`auth::require`](crate::auth::require) with
+//! [`Role::Operator`](crate::auth::Role::Operator) when it's checking tokens.
+use std::sync::Arc;
+
+use axum::{
+    body::Bytes,
+    extract::{Path, State},
+    http::{HeaderMap, StatusCode},
+    response::{IntoResponse, Response},
+    routing::post,
+    Json, Router,
+};
+use hmac::{Hmac, Mac};
+use serpent_automation_executor::syntax_tree::Value;
+use serpent_automation_server_api::WebhookResponse;
+use sha2::Sha256;
+use thiserror::Error;
+
+use crate::runs::{Runs, Workflows};
+
+/// A router with the webhook endpoint at `/webhook/:workflow`.
+pub fn router(workflows: Workflows, runs: Runs, secret: Option<String>) -> Router {
+    Router::new()
+        .route("/webhook/:workflow", post(start_run))
+        .with_state(Webhook {
+            workflows,
+            runs,
+            secret: secret.map(|secret| secret.into_bytes().into()),
+        })
+}
+
+#[derive(Clone)]
+struct Webhook {
+    workflows: Workflows,
+    runs: Runs,
+    secret: Option<Arc<[u8]>>,
+}
+
+impl Webhook {
+    fn check_signature(&self, headers: &HeaderMap, body: &[u8]) -> Result<(), WebhookError> {
+        let Some(secret) = &self.secret else {
+            return Ok(());
+        };
+
+        let signature = headers
+            .get(SIGNATURE_HEADER)
+            .and_then(|signature| signature.to_str().ok())
+            .and_then(|signature| signature.strip_prefix("sha256="))
+            .ok_or(WebhookError::MissingSignature)?;
+        let signature = hex::decode(signature).map_err(|_| WebhookError::InvalidSignature)?;
+        let mut mac =
+            Hmac::<Sha256>::new_from_slice(secret).expect("HMAC can take a key of any size");
+        mac.update(body);
+        mac.verify_slice(&signature)
+            .map_err(|_| WebhookError::InvalidSignature)
+    }
+}
+
+async fn start_run(
+    State(webhook): State<Webhook>,
+    Path(workflow_name): Path<String>,
+    headers: HeaderMap,
+    body: Bytes,
+) -> Result<Json<WebhookResponse>, WebhookError> {
+    webhook.check_signature(&headers, &body)?;
+
+    let workflow = webhook
+        .workflows
+        .get(&workflow_name)
+        .ok_or_else(|| WebhookError::UnknownWorkflow(workflow_name.clone()))?;
+    let payload: serde_json::Value =
+        serde_json::from_slice(&body).map_err(|e| WebhookError::InvalidPayload(e.to_string()))?;
+    let run_id = webhook
+        .runs
+        .start(workflow, Value::String(payload.to_string()), "webhook");
+
+    Ok(Json(WebhookResponse { run_id }))
+}
+
+const SIGNATURE_HEADER: &str = "X-Hub-Signature-256";
+
+#[derive(Error, Debug)]
+enum WebhookError {
+    #[error("Unknown workflow \"{0}\"")]
+    UnknownWorkflow(String),
+    #[error("Missing {SIGNATURE_HEADER} header")]
+    MissingSignature,
+    #[error("Invalid signature")]
+    InvalidSignature,
+    #[error("Invalid JSON payload: {0}")]
+    InvalidPayload(String),
+}
+
+impl IntoResponse for WebhookError {
+    fn into_response(self) -> Response {
+        let status = match self {
+            Self::UnknownWorkflow(_) => StatusCode::NOT_FOUND,
+            Self::MissingSignature | Self::InvalidSignature => StatusCode::UNAUTHORIZED,
+            Self::InvalidPayload(_) => StatusCode::BAD_REQUEST,
+        };
+
+        (status, self.to_string()).into_response()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::net::{SocketAddr, TcpListener};
+
+    use axum::Server;
+    use hmac::{Hmac, Mac};
+    use hyper::{body::to_bytes, Body, Client, Request, StatusCode};
+    use indoc::indoc;
+    use serpent_automation_executor::syntax_tree::Value;
+    use serpent_automation_server_api::WebhookResponse;
+    use sha2::Sha256;
+
+    use super::{router, SIGNATURE_HEADER};
+    use crate::runs::{Runs, Workflow, Workflows};
+
+    const PAYLOAD: &str = r#"{"ref":"refs/heads/main"}"#;
+    const SECRET: &str = "It's a secret";
+
+    #[tokio::test]
+    async fn start_run() {
+        let (address, runs) = serve(None);
+        let (status, body) = post(address, "test", PAYLOAD, None).await;
+
+        assert_eq!(status, StatusCode::OK);
+        let response: WebhookResponse = serde_json::from_slice(&body).unwrap();
+        let run = runs.get(response.run_id).unwrap();
+        assert_eq!(run.workflow().name(), "test");
+        assert_eq!(run.input(), &Value::String(PAYLOAD.to_owned()));
+    }
+
+    #[tokio::test]
+    async fn unknown_workflow() {
+        let (address, runs) = serve(None);
+        let (status, _body) = post(address, "unknown", PAYLOAD, None).await;
+
+        assert_eq!(status, StatusCode::NOT_FOUND);
+        assert!(runs.latest().is_none());
+    }
+
+    #[tokio::test]
+    async fn invalid_payload() {
+        let (address, runs) = serve(None);
+        let (status, _body) = post(address, "test", "not json", None).await;
+
+        assert_eq!(status, StatusCode::BAD_REQUEST);
+        assert!(runs.latest().is_none());
+    }
+
+    #[tokio::test]
+    async fn valid_signature() {
+        let (address, runs) = serve(Some(SECRET));
+        let signature = sign(SECRET, PAYLOAD);
+        let (status, _body) = post(address, "test", PAYLOAD, Some(&signature)).await;
+
+        assert_eq!(status, StatusCode::OK);
+        assert!(runs.latest().is_some());
+    }
+
+    #[tokio::test]
+    async fn invalid_signature() {
+        let (address, runs) = serve(Some(SECRET));
+        let signature = sign("Not the secret", PAYLOAD);
+        let (status, _body) = post(address, "test", PAYLOAD, Some(&signature)).await;
+
+        assert_eq!(status, StatusCode::UNAUTHORIZED);
+        assert!(runs.latest().is_none());
+    }
+
+    #[tokio::test]
+    async fn missing_signature() {
+        let (address, runs) = serve(Some(SECRET));
+        let (status, _body) = post(address, "test", PAYLOAD, None).await;
+
+        assert_eq!(status, StatusCode::UNAUTHORIZED);
+        assert!(runs.latest().is_none());
+    }
+
+    fn serve(secret: Option<&str>) -> (SocketAddr, Runs) {
+        let workflow = Workflow::parse(
+            "test",
+            indoc! {"
+                def main():
+                    pass
+            "},
+        )
+        .unwrap();
+        let runs = Runs::default();
+        let app = router(
+            Workflows::from_iter([workflow]),
+            runs.clone(),
+            secret.map(str::to_owned),
+        );
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let address = listener.local_addr().unwrap();
+
+        tokio::spawn(
+            Server::from_tcp(listener)
+                .unwrap()
+                .serve(app.into_make_service()),
+        );
+
+        (address, runs)
+    }
+
+    async fn post(
+        address: SocketAddr,
+        workflow: &str,
+        payload: &'static str,
+        signature: Option<&str>,
+    ) -> (StatusCode, Vec<u8>) {
+        let mut request = Request::post(format!("http://{address}/webhook/{workflow}"));
+
+        if let Some(signature) = signature {
+            request = request.header(SIGNATURE_HEADER, signature);
+        }
+
+        let response = Client::new()
+            .request(request.body(Body::from(payload)).unwrap())
+            .await
+            .unwrap();
+        let status = response.status();
+
+        (
+            status,
+            to_bytes(response.into_body()).await.unwrap().to_vec(),
+        )
+    }
+
+    fn sign(secret: &str, payload: &str) -> String {
+        let mut mac = Hmac::<Sha256>::new_from_slice(secret.as_bytes()).unwrap();
+        mac.update(payload.as_bytes());
+        format!("sha256={}", hex::encode(mac.finalize().into_bytes()))
+    }
+}
@@ -0,0 +1,98 @@
+use std::{
+    net::{SocketAddr, TcpListener},
+    sync::Arc,
+    time::Duration,
+};
+
+use axum::Server;
+use indoc::indoc;
+use serpent_automation_executor::{
+    run::{CallStack, RunState, StackFrame},
+    syntax_tree::Value,
+};
+use serpent_automation_server::{
+    remote::{router, RemoteWorker},
+    runs::{Runs, Workflow},
+};
+use tokio::time::{sleep, timeout};
+
+#[tokio::test]
+async fn successful_run() {
+    let (runs, workflow) = serve(indoc! {r#"
+        def main():
+            print("Hello, world!")
+    "#});
+    let run_id = runs.start(&workflow, Value::None, "test");
+    let thread_run_state = runs.get(run_id).unwrap().thread_run_state().clone();
+    let main = main_call_stack(&workflow);
+
+    assert_eq!(
+        wait_for_completion(&runs, &workflow).await,
+        RunState::Successful
+    );
+
+    let print = main
+        .push_cloned(StackFrame::Statement(0))
+        .push_cloned(StackFrame::Call(
+            workflow.library().function_id("print").unwrap(),
+        ));
+    assert_eq!(
+        thread_run_state.logs(&print),
+        [r#"print([String("Hello, world!")])"#]
+    );
+}
+
+#[tokio::test]
+async fn interpreter_panic() {
+    // Variables aren't implemented yet, so this panics.
+    let (runs, workflow) = serve(indoc! {"
+        def main():
+            x
+    "});
+    runs.start(&workflow, Value::None, "test");
+
+    assert_eq!(
+        wait_for_completion(&runs, &workflow).await,
+        RunState::Failed
+    );
+}
+
+fn serve(source: &str) -> (Runs, Arc<Workflow>) {
+    let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+    let address: SocketAddr = listener.local_addr().unwrap();
+    let runs = Runs::remote(
+        1,
+        RemoteWorker::new(
+            env!("CARGO_BIN_EXE_serpent-automation-worker"),
+            format!("http://{address}"),
+        ),
+    );
+
+    tokio::spawn(
+        Server::from_tcp(listener)
+            .unwrap()
+            .serve(router(runs.clone()).into_make_service()),
+    );
+
+    (runs, Arc::new(Workflow::parse("test", source).unwrap()))
+}
+
+async fn wait_for_completion(runs: &Runs, workflow: &Workflow) -> RunState {
+    let thread_run_state = runs.latest().unwrap().thread_run_state().clone();
+    let main = main_call_stack(workflow);
+
+    timeout(Duration::from_secs(30), async {
+        loop {
+            match thread_run_state.run_state(&main) {
+                RunState::NotRun | RunState::Running => sleep(Duration::from_millis(100)).await,
+                run_state => return run_state,
+            }
+        }
+    })
+    .await
+    .unwrap()
+}
+
+fn main_call_stack(workflow: &Workflow) -> CallStack {
+    CallStack::new().push_cloned(StackFrame::Call(workflow.library().main_id().unwrap()))
+}
@@ -13,6 +13,12 @@ export function codemirror_new(doc) {
   });
 }
 
+export function set_doc(view, doc) {
+  view.dispatch({
+    changes: { from: 0, to: view.state.doc.length, insert: doc },
+  });
+}
+
 export function set_selection(view, from, to) {
   view.dispatch({
     selection: EditorSelection.create([EditorSelection.range(from, to)]),
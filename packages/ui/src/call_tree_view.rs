@@ -2,7 +2,7 @@ use derive_more::Into;
 use futures_signals::signal::{Mutable, ReadOnlyMutable, Signal, SignalExt};
 use serpent_automation_executor::{run::RunState, syntax_tree::SrcSpan};
 use serpent_automation_frontend::{
-    call_tree::{Body, Call, CallTree, Statement},
+    call_tree::{Body, Call, CallTree, Parallel, Statement, With},
     tree::{Expandable, TreeNode},
 };
 use silkenweb::{
@@ -28,7 +28,7 @@ use silkenweb_bootstrap::{
     },
 };
 
-use self::conditional::if_node;
+use self::conditional::{branch_body, if_node};
 use crate::{animation::AnimatedExpand, component};
 
 mod conditional;
@@ -200,6 +200,7 @@ fn node_dropdown(node: &NodeData, style: ButtonStyle, actions: &impl CallTreeAct
             }
             RunState::PredicateSuccessful(false) => Icon::circle_fill().colour(Colour::Success),
             RunState::Failed => Icon::exclamation_circle_fill().colour(Colour::Danger),
+            RunState::Cached => Icon::check_circle().colour(Colour::Info),
         }
         .margin_on_side((Some(Size2), Side::End))
         .class(class::node_status_icon())
@@ -243,11 +244,55 @@ fn body_statements<'a>(
     stmts.map(|stmt| match stmt {
         Statement::Call(call) => call_node(&NodeData::from_call(call), call.body(), actions),
         Statement::If(if_stmt) => if_node(if_stmt, actions),
+        Statement::With(with) => with_node(with, actions),
+        Statement::Parallel(parallel) => parallel_node(parallel, actions),
+        Statement::Attempt(attempt) => call_node(
+            &NodeData::new(attempt.span(), attempt.name(), attempt.run_state()),
+            attempt.body(),
+            actions,
+        ),
     })
 }
 
+fn with_node(with: &With, actions: &impl CallTreeActions) -> GenericElement {
+    column()
+        .align_items(Align::Start)
+        .child(branch_body(
+            &NodeData::new(with.span(), "with", with.run_state()),
+            with.context_manager(),
+            with.body(),
+            actions,
+        ))
+        .into()
+}
+
+fn parallel_node(parallel: &Parallel, actions: &impl CallTreeActions) -> GenericElement {
+    let style = ButtonStyle::Solid(PARALLEL_COLOUR);
+
+    column()
+        .align_items(Align::Start)
+        .child(node_container(PARALLEL_COLOUR).child(dropdown(
+            icon_button("button", Icon::diagram_3(), style).text("parallel"),
+            dropdown_menu().child(dropdown_item("View code").on_click({
+                clone!(actions);
+                let span = parallel.span();
+                move |_, _| actions.view_code(span)
+            })),
+        )))
+        .child(
+            indented_block().children(
+                parallel
+                    .branches()
+                    .iter()
+                    .map(|call| call_node(&NodeData::from_call(call), call.body(), actions)),
+            ),
+        )
+        .into()
+}
+
 pub trait CallTreeActions: Clone + 'static {
     fn view_code(&self, span: SrcSpan);
 }
 
 const FUNCTION_COLOUR: Colour = Colour::Primary;
+const PARALLEL_COLOUR: Colour = Colour::Info;
@@ -36,7 +36,7 @@ pub fn if_node(if_stmt: &If, actions: &impl CallTreeActions) -> GenericElement {
         .into()
 }
 
-fn branch_body(
+pub fn branch_body(
     node: &NodeData,
     condition: &TreeNode<Expandable<Vec<Call>>>,
     body: &Body,
@@ -0,0 +1,40 @@
+use derive_more::Into;
+use futures_signals::signal::{ReadOnlyMutable, SignalExt};
+use serpent_automation_frontend::ConnectionStatus;
+use silkenweb::{elements::html::div, node::Node, prelude::ParentElement, value::Sig, Value};
+use silkenweb_bootstrap::{
+    icon::Icon,
+    utility::{
+        Colour, SetSpacing, Side,
+        Size::{Size2, Size3},
+    },
+};
+
+#[derive(Into, Value)]
+pub struct ConnectionStatusView(Node);
+
+impl ConnectionStatusView {
+    pub fn new(status: ReadOnlyMutable<ConnectionStatus>) -> Self {
+        let icon = status.signal().map(|status| {
+            match status {
+                ConnectionStatus::Connecting => Icon::circle_fill().colour(Colour::Warning),
+                ConnectionStatus::Connected => Icon::circle_fill().colour(Colour::Success),
+                ConnectionStatus::Disconnected => Icon::circle_fill().colour(Colour::Danger),
+            }
+            .margin_on_side((Some(Size2), Side::End))
+        });
+        let text = status.signal().map(|status| match status {
+            ConnectionStatus::Connecting => "Connecting...",
+            ConnectionStatus::Connected => "Connected",
+            ConnectionStatus::Disconnected => "Disconnected. Retrying...",
+        });
+
+        Self(
+            div()
+                .padding_on_side((Size3, Side::Start))
+                .child(Sig(icon))
+                .text(Sig(text))
+                .into(),
+        )
+    }
+}
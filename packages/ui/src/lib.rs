@@ -1,17 +1,21 @@
 use std::rc::Rc;
 
-use serpent_automation_executor::library::Library;
+use connection_status_view::ConnectionStatusView;
+use run_controls_view::RunControlsView;
+use serpent_automation_executor::{library::Library, sources::Sources};
 use serpent_automation_frontend::{call_tree::CallTree, ServerConnection};
 use silkenweb::{
     node::element::ChildElement,
     prelude::{Element, ParentElement},
     task::spawn_local,
 };
-use silkenweb_bootstrap::column;
+use silkenweb_bootstrap::{column, row};
 use thread_view::ThreadView;
 
 mod animation;
 mod call_tree_view;
+mod connection_status_view;
+mod run_controls_view;
 mod source_view;
 mod splitter;
 mod thread_view;
@@ -35,15 +39,45 @@ use component;
 use tokio::sync::mpsc;
 use tokio_stream::wrappers::UnboundedReceiverStream;
 
-pub fn app(library: &Rc<Library>) -> impl ChildElement {
+pub fn app(
+    library: &Rc<Library>,
+    sources: &Rc<Sources>,
+    server_connection: ServerConnection,
+) -> impl ChildElement {
     let main_id = library.main_id().unwrap();
     let (opened_nodes_sender, opened_nodes_receiver) = mpsc::unbounded_channel();
     let call_tree = CallTree::root(main_id, library, opened_nodes_sender);
 
     let opened_nodes_receiver = UnboundedReceiverStream::new(opened_nodes_receiver);
-    spawn_local(call_tree.update_run_state(ServerConnection::default(), opened_nodes_receiver));
+    let connection_status = server_connection.status();
+    let run_controls = RunControlsView::new(server_connection.clone());
+    spawn_local(call_tree.update_run_state(server_connection, opened_nodes_receiver));
 
     column()
         .class(css::HEIGHT_FULLSCREEN)
-        .child(ThreadView::new(call_tree))
+        .child(
+            row()
+                .child(ConnectionStatusView::new(connection_status))
+                .child(run_controls),
+        )
+        .child(ThreadView::new(call_tree, sources))
+}
+
+/// The server API is on the same host that served the UI.
+///
+/// The query string is passed on to the API, so an access token can be given
+/// with `?token=<token>`.
+pub fn api_url() -> String {
+    let location = web_sys::window().unwrap().location();
+    let protocol = if location.protocol().unwrap() == "https:" {
+        "wss"
+    } else {
+        "ws"
+    };
+
+    format!(
+        "{protocol}://{}/api{}",
+        location.host().unwrap(),
+        location.search().unwrap()
+    )
 }
@@ -1,12 +1,28 @@
 use std::rc::Rc;
 
-use serpent_automation_executor::{library::Library, syntax_tree::parse, CODE};
-use serpent_automation_ui::app;
-use silkenweb::mount;
+use gloo_console::error;
+use serpent_automation_frontend::ServerConnection;
+use serpent_automation_ui::{api_url, app};
+use silkenweb::{mount, task::spawn_local};
 
 fn main() {
-    let module = parse(CODE).unwrap();
-    let library = Rc::new(Library::link(module));
+    spawn_local(async {
+        let server_connection = ServerConnection::new(&api_url());
 
-    mount("app", app(&library));
+        // The call tree is built from the workflow, so we need it before we can
+        // show anything.
+        match server_connection.workflow_sources().await {
+            Ok(Some(sources)) => match sources.link() {
+                Ok(library) => {
+                    mount(
+                        "app",
+                        app(&Rc::new(library), &Rc::new(sources), server_connection),
+                    );
+                }
+                Err(e) => error!(format!("Unable to link workflow: {e}")),
+            },
+            Ok(None) => error!("There are no runs to show"),
+            Err(e) => error!(format!("Unable to load workflow: {e}")),
+        }
+    });
 }
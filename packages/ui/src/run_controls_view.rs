@@ -0,0 +1,63 @@
+use derive_more::Into;
+use futures_signals::signal::SignalExt;
+use gloo_console::warn;
+use serpent_automation_frontend::ServerConnection;
+use silkenweb::{
+    clone,
+    elements::html::div,
+    node::Node,
+    prelude::{ElementEvents, ParentElement},
+    task::spawn_local,
+    value::Sig,
+    Value,
+};
+use silkenweb_bootstrap::{
+    button::{icon_button, ButtonStyle},
+    icon::{icon, IconType},
+    utility::{Colour, SetSpacing, Side, Size::Size3},
+};
+
+/// Pause and resume the run we're viewing.
+#[derive(Into, Value)]
+pub struct RunControlsView(Node);
+
+impl RunControlsView {
+    pub fn new(server_connection: ServerConnection) -> Self {
+        let paused = server_connection.paused();
+        let pause_icon = paused.signal().map(|paused| {
+            if paused {
+                IconType::PlayFill
+            } else {
+                IconType::PauseFill
+            }
+        });
+        let text = paused
+            .signal()
+            .map(|paused| if paused { "Resume" } else { "Pause" });
+
+        Self(
+            div()
+                .padding_on_side((Size3, Side::Start))
+                .child(
+                    icon_button(
+                        "button",
+                        icon(Sig(pause_icon)),
+                        ButtonStyle::Outline(Colour::Secondary),
+                    )
+                    .text(Sig(text))
+                    .on_click(move |_, _| {
+                        clone!(server_connection);
+
+                        spawn_local(async move {
+                            let paused = !server_connection.paused().get();
+
+                            if let Err(e) = server_connection.pause_run(paused).await {
+                                warn!(e);
+                            }
+                        })
+                    }),
+                )
+                .into(),
+        )
+    }
+}
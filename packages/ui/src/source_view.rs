@@ -1,5 +1,10 @@
+use std::{cell::Cell, rc::Rc};
+
 use derive_more::Into;
-use serpent_automation_executor::syntax_tree::SrcSpan;
+use serpent_automation_executor::{
+    sources::Sources,
+    syntax_tree::{FileId, SrcSpan},
+};
 use silkenweb::{
     node::{element::Element, Node},
     Value,
@@ -20,24 +25,56 @@ impl SourceView {
         codemirror_container
             .handle()
             .dom_element()
-            .append_child(&editor.0.dom())
+            .append_child(&editor.view.dom())
             .unwrap();
 
         Self(codemirror_container.into())
     }
 }
 
+/// A read only view of the workflow's source files.
 #[derive(Clone)]
-pub struct Editor(EditorView);
+pub struct Editor {
+    view: EditorView,
+    sources: Rc<Sources>,
+    file: Rc<Cell<FileId>>,
+}
 
 impl Editor {
-    pub fn new(code: &str) -> Self {
-        Self(codemirror_new(code))
+    /// Constructor
+    ///
+    /// The editor starts with the main module open.
+    pub fn new(sources: Rc<Sources>) -> Self {
+        Self {
+            view: codemirror_new(sources.main().source()),
+            sources,
+            file: Rc::new(Cell::new(FileId::default())),
+        }
     }
 
+    /// Select `span`, opening it's file if it's not already open.
     pub fn set_selection(&self, span: SrcSpan) {
-        let start_pos = self.0.state().doc().line(span.line()).from() + span.column() - 1;
-        set_selection(&self.0, start_pos, start_pos + span.len());
+        let file = span.file();
+
+        if file != self.file.get() {
+            if let Some(source_file) = self.sources.file(file) {
+                set_doc(&self.view, source_file.source());
+                self.file.set(file);
+            }
+        }
+
+        let Some(source_file) = self.sources.file(self.file.get()) else {
+            return;
+        };
+        let source = source_file.source();
+        // CodeMirror positions are in UTF-16 code units.
+        let utf16_offset = |offset: usize| source[..offset].encode_utf16().count();
+
+        set_selection(
+            &self.view,
+            utf16_offset(span.start().offset()),
+            utf16_offset(span.end().offset()),
+        );
     }
 }
 
@@ -53,24 +90,9 @@ extern "C" {
     #[wasm_bindgen(method, getter)]
     fn dom(this: &EditorView) -> web_sys::HtmlElement;
 
-    #[wasm_bindgen(method, getter)]
-    fn state(this: &EditorView) -> EditorState;
-
-    type EditorState;
-
-    #[wasm_bindgen(method, getter)]
-    fn doc(this: &EditorState) -> Text;
-
-    type Text;
-
-    #[wasm_bindgen(method)]
-    fn line(this: &Text, line_num: usize) -> Line;
-
-    type Line;
-
-    #[wasm_bindgen(method, getter)]
-    fn from(this: &Line) -> usize;
-
     #[wasm_bindgen]
     fn set_selection(editor: &EditorView, from: usize, to: usize) -> usize;
+
+    #[wasm_bindgen]
+    fn set_doc(editor: &EditorView, doc: &str);
 }
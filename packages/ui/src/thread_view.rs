@@ -1,6 +1,8 @@
+use std::rc::Rc;
+
 use derive_more::Into;
 use futures_signals::signal::{Mutable, SignalExt};
-use serpent_automation_executor::{syntax_tree::SrcSpan, CODE};
+use serpent_automation_executor::{sources::Sources, syntax_tree::SrcSpan};
 use serpent_automation_frontend::call_tree::CallTree;
 use silkenweb::{
     clone,
@@ -27,9 +29,9 @@ use crate::{
 pub struct ThreadView(Node);
 
 impl ThreadView {
-    pub fn new(call_tree: CallTree) -> Self {
+    pub fn new(call_tree: CallTree, sources: &Rc<Sources>) -> Self {
         let active = Mutable::new(Tab::CallTree);
-        let editor = Editor::new(CODE);
+        let editor = Editor::new(sources.clone());
         let call_tree_view = CallTreeView::new(
             call_tree,
             Actions {